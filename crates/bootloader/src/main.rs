@@ -5,6 +5,8 @@
 #![feature(maybe_uninit_uninit_array)]
 #![feature(maybe_uninit_slice)]
 
+mod paging;
+
 use anyhow::{anyhow, bail, Context as _, Error, Result};
 use core::{arch::asm, fmt::Write, mem::MaybeUninit};
 use object::{elf, read::elf::ProgramHeader as _, Endianness};
@@ -22,6 +24,8 @@ use uefi::{
     table::boot,
 };
 
+use crate::paging::{PageTableBuilder, PAGE_SIZE};
+
 const MAX_MEMORY_MAP_ENTRY_COUNT: usize = 128;
 const MEMORY_MAP_BUF_SIZE: usize = 16 * 1024;
 const FILE_INFO_BUF_SIZE: usize = 8 * 1024;
@@ -47,9 +51,20 @@ fn actual_main(handle: Handle, mut st: SystemTable<Boot>) -> Result<()> {
     write_memory_map_file(st.boot_services(), &mut root, "\\memmap")?;
     writeln!(st.stdout(), "Wrote memory map file").expect("Failed to write to stdout");
 
-    let kernel_main =
-        prepare_kernel::<elf::FileHeader64<Endianness>>(st.boot_services(), &mut root, "\\kernel")?;
+    let mut page_table = PageTableBuilder::new(st.boot_services())?;
+    page_table.map_physical_memory()?;
+    let kernel_main = prepare_kernel::<elf::FileHeader64<Endianness>>(
+        st.boot_services(),
+        &mut root,
+        "\\kernel",
+        &mut page_table,
+    )?;
     writeln!(st.stdout(), "Loaded kernel").expect("Failed to write to stdout");
+    let initrd = load_initrd(st.boot_services(), &mut root, "\\initrd")?;
+    if initrd.is_some() {
+        writeln!(st.stdout(), "Loaded initrd").expect("Failed to write to stdout");
+    }
+    let pml4_address = page_table.pml4_address();
 
     let graphic_config = read_graphic_config(&mut st)?;
 
@@ -58,6 +73,10 @@ fn actual_main(handle: Handle, mut st: SystemTable<Boot>) -> Result<()> {
         .exit_boot_services(handle, unsafe { &mut MEMORY_MAP })
         .expect_success("Failed to exit boot services");
 
+    // From here on, the kernel is mapped in the higher half, and the physical memory is accessible
+    // both through the identity mapping and the direct map.
+    unsafe { asm!("mov cr3, {}", in(reg) pml4_address) };
+
     static mut DESCRIPTORS: [MaybeUninit<MemoryDescriptor>; MAX_MEMORY_MAP_ENTRY_COUNT] =
         MaybeUninit::uninit_array();
     let mut initialized_count = 0;
@@ -69,8 +88,12 @@ fn actual_main(handle: Handle, mut st: SystemTable<Boot>) -> Result<()> {
         unsafe { &mut DESCRIPTORS[initialized_count] }.write(*descriptor);
         initialized_count += 1;
     }
-    let initialized_descriptors =
-        unsafe { MaybeUninit::slice_assume_init_ref(&DESCRIPTORS[0..initialized_count]) };
+    let initialized_descriptors = unsafe {
+        core::slice::from_raw_parts(
+            paging::to_direct_map(MaybeUninit::slice_as_ptr(&DESCRIPTORS)),
+            initialized_count,
+        )
+    };
 
     // We'd like to store the arguments to the kernel main in the heap instead of the stack.
     static mut BOOT_INFO: MaybeUninit<BootInfo> = MaybeUninit::uninit();
@@ -84,8 +107,9 @@ fn actual_main(handle: Handle, mut st: SystemTable<Boot>) -> Result<()> {
         graphic_config,
         MemoryMapping::new(initialized_descriptors),
         acpi2_rsdp,
+        initrd,
     ));
-    let boot_info = unsafe { BOOT_INFO.assume_init_ref() };
+    let boot_info = unsafe { &*paging::to_direct_map(BOOT_INFO.as_ptr()) };
     kernel_main(boot_info);
 
     #[allow(clippy::empty_loop)]
@@ -149,6 +173,7 @@ fn prepare_kernel<Elf: object::read::elf::FileHeader<Endian = Endianness>>(
     bs: &BootServices,
     root: &mut Directory,
     filename: &str,
+    page_table: &mut PageTableBuilder,
 ) -> Result<KernelMain> {
     let kernel_file = root
         .open(filename, FileMode::Read, FileAttribute::empty())
@@ -196,7 +221,7 @@ fn prepare_kernel<Elf: object::read::elf::FileHeader<Endian = Endianness>>(
         .map_err(|_| anyhow!("Unable to determin endian of the kernel file"))?;
 
     let entry_point = elf.e_entry(endian).into() as usize;
-    let (kernel_base_address, kernel_length) = {
+    let (kernel_virtual_base, kernel_length) = {
         let mut start = u64::MAX;
         let mut end = u64::MIN;
 
@@ -213,26 +238,35 @@ fn prepare_kernel<Elf: object::read::elf::FileHeader<Endian = Endianness>>(
         }
         (start as usize, (end - start) as usize)
     };
-    const PAGE_SIZE: usize = 0x1000;
-    let allocate_page_count = kernel_length.div_ceil(PAGE_SIZE);
-    bs.allocate_pages(
-        boot::AllocateType::Address(kernel_base_address),
-        boot::MemoryType::LOADER_DATA,
-        allocate_page_count,
-    )
-    .expect_success("Failed to allocate pages");
+    if kernel_virtual_base % PAGE_SIZE as usize != 0 {
+        bail!("The kernel is not page aligned");
+    }
+    let allocate_page_count = kernel_length.div_ceil(PAGE_SIZE as usize);
+    let kernel_physical_base = bs
+        .allocate_pages(
+            boot::AllocateType::AnyPages,
+            boot::MemoryType::LOADER_DATA,
+            allocate_page_count,
+        )
+        .expect_success("Failed to allocate pages");
     let allocated_slice = unsafe {
         core::slice::from_raw_parts_mut(
-            kernel_base_address as *mut u8,
-            allocate_page_count * PAGE_SIZE,
+            kernel_physical_base as *mut u8,
+            allocate_page_count * PAGE_SIZE as usize,
         )
     };
+    for i in 0..allocate_page_count as u64 {
+        page_table.map_page(
+            kernel_virtual_base as u64 + i * PAGE_SIZE,
+            kernel_physical_base + i * PAGE_SIZE,
+        )?;
+    }
     for segment in elf
         .program_headers(endian, &kernel_content.1[..])
         .map_err(|_| anyhow!("Unable to parse program headers of the kernel"))?
     {
         if segment.p_type(endian) == elf::PT_LOAD {
-            let start_pos = segment.p_vaddr(endian).into() as usize - kernel_base_address;
+            let start_pos = segment.p_vaddr(endian).into() as usize - kernel_virtual_base;
             let end_pos = start_pos + segment.p_memsz(endian).into() as usize;
             let data = segment
                 .data(endian, &kernel_content.1[..])
@@ -247,6 +281,60 @@ fn prepare_kernel<Elf: object::read::elf::FileHeader<Endian = Endianness>>(
     Ok(entry_point)
 }
 
+/// Reads the file `filename` into LOADER_DATA pages if it exists, and returns its physical address
+/// and size.
+fn load_initrd(
+    bs: &BootServices,
+    root: &mut Directory,
+    filename: &str,
+) -> Result<Option<(u64, usize)>> {
+    let file = match root
+        .open(filename, FileMode::Read, FileAttribute::empty())
+        .warning_as_error()
+    {
+        Ok(file) => file,
+        // The initrd is optional.
+        Err(_) => return Ok(None),
+    };
+    let mut file = match file
+        .into_type()
+        .expect_success("Failed to get type of a file")
+    {
+        FileType::Regular(f) => f,
+        _ => bail!("initrd file exists as non-regular-file"),
+    };
+    let mut file_info_buffer = [0; FILE_INFO_BUF_SIZE];
+    let size = file
+        .get_info::<FileInfo>(&mut file_info_buffer)
+        .expect_success("Failed to get file info")
+        .file_size() as usize;
+    if size == 0 {
+        return Ok(None);
+    }
+    let physical_base = bs
+        .allocate_pages(
+            boot::AllocateType::AnyPages,
+            boot::MemoryType::LOADER_DATA,
+            size.div_ceil(PAGE_SIZE as usize),
+        )
+        .warning_as_error()
+        .map_err(|_| anyhow!("Unable to allocate memory for the initrd"))?;
+    // The physical memory is identity-mapped while the boot services are alive.
+    let content = unsafe { core::slice::from_raw_parts_mut(physical_base as *mut u8, size) };
+    let mut read = 0;
+    while read < size {
+        let length = file
+            .read(&mut content[read..])
+            .warning_as_error()
+            .map_err(|_| anyhow!("Unable to read the initrd"))?;
+        if length == 0 {
+            bail!("The initrd is shorter than its file info says");
+        }
+        read += length;
+    }
+    Ok(Some((physical_base, size)))
+}
+
 fn write_memory_map_file(bs: &BootServices, root: &mut Directory, filename: &str) -> Result<()> {
     let mut memory_map = [0; MEMORY_MAP_BUF_SIZE];
     let (_map_key, desc_iter) = bs
@@ -0,0 +1,97 @@
+use anyhow::{anyhow, Result};
+use pomelo_common::{DIRECT_MAP_SIZE, PHYSICAL_MEMORY_OFFSET};
+use uefi::{prelude::*, table::boot};
+
+pub const PAGE_SIZE: u64 = 0x1000;
+const HUGE_PAGE_SIZE: u64 = 0x20_0000;
+const ENTRY_COUNT: usize = 512;
+
+const PRESENT: u64 = 1 << 0;
+const WRITABLE: u64 = 1 << 1;
+const HUGE_PAGE: u64 = 1 << 7;
+const ADDRESS_MASK: u64 = 0x000F_FFFF_FFFF_F000;
+
+#[repr(C, align(4096))]
+struct PageTable([u64; ENTRY_COUNT]);
+
+/// Builds the page table the kernel starts with.
+///
+/// Tables are allocated as LOADER_DATA so that the kernel won't reuse them as free memory, and
+/// accessed through the identity mapping UEFI provides.
+pub struct PageTableBuilder<'a> {
+    bs: &'a BootServices,
+    pml4: &'static mut PageTable,
+}
+
+impl<'a> PageTableBuilder<'a> {
+    pub fn new(bs: &'a BootServices) -> Result<Self> {
+        let pml4 = allocate_table(bs)?;
+        Ok(Self { bs, pml4 })
+    }
+
+    /// The physical address of the level 4 table, i.e. the value to be loaded into CR3.
+    pub fn pml4_address(&self) -> u64 {
+        self.pml4 as *const PageTable as u64
+    }
+
+    fn next_table(bs: &BootServices, entry: &mut u64) -> Result<&'static mut PageTable> {
+        if *entry & PRESENT == 0 {
+            let table = allocate_table(bs)?;
+            *entry = (table as *const PageTable as u64) | PRESENT | WRITABLE;
+        } else if *entry & HUGE_PAGE != 0 {
+            return Err(anyhow!("Tried to map a page inside of a huge page"));
+        }
+        Ok(unsafe { &mut *((*entry & ADDRESS_MASK) as *mut PageTable) })
+    }
+
+    /// Maps the 4 KiB page at `virtual_address` to `physical_address`.
+    pub fn map_page(&mut self, virtual_address: u64, physical_address: u64) -> Result<()> {
+        let index = |level: u32| ((virtual_address >> (12 + 9 * level)) as usize) % ENTRY_COUNT;
+        let pdpt = Self::next_table(self.bs, &mut self.pml4.0[index(3)])?;
+        let pd = Self::next_table(self.bs, &mut pdpt.0[index(2)])?;
+        let pt = Self::next_table(self.bs, &mut pd.0[index(1)])?;
+        let entry = &mut pt.0[index(0)];
+        if *entry & PRESENT != 0 {
+            return Err(anyhow!("Tried to map {:x} twice", virtual_address));
+        }
+        *entry = (physical_address & ADDRESS_MASK) | PRESENT | WRITABLE;
+        Ok(())
+    }
+
+    /// Maps the first `DIRECT_MAP_SIZE` bytes of the physical memory at `PHYSICAL_MEMORY_OFFSET`
+    /// with 2 MiB pages. The same mapping is also installed at the address 0, so that the
+    /// bootloader keeps running until it jumps into the kernel.
+    pub fn map_physical_memory(&mut self) -> Result<()> {
+        let mut physical_address = 0;
+        while physical_address < DIRECT_MAP_SIZE {
+            let virtual_address = PHYSICAL_MEMORY_OFFSET + physical_address;
+            let index = |level: u32| ((virtual_address >> (12 + 9 * level)) as usize) % ENTRY_COUNT;
+            let pdpt = Self::next_table(self.bs, &mut self.pml4.0[index(3)])?;
+            let pd = Self::next_table(self.bs, &mut pdpt.0[index(2)])?;
+            pd.0[index(1)] = physical_address | PRESENT | WRITABLE | HUGE_PAGE;
+            physical_address += HUGE_PAGE_SIZE;
+        }
+        let direct_map_index = ((PHYSICAL_MEMORY_OFFSET >> 39) as usize) % ENTRY_COUNT;
+        self.pml4.0[0] = self.pml4.0[direct_map_index];
+        Ok(())
+    }
+}
+
+fn allocate_table(bs: &BootServices) -> Result<&'static mut PageTable> {
+    let address = bs
+        .allocate_pages(
+            boot::AllocateType::AnyPages,
+            boot::MemoryType::LOADER_DATA,
+            1,
+        )
+        .warning_as_error()
+        .map_err(|_| anyhow!("Failed to allocate a page table"))?;
+    let table = unsafe { &mut *(address as *mut PageTable) };
+    table.0.fill(0);
+    Ok(table)
+}
+
+/// Converts a pointer to the bootloader's memory into the corresponding one in the direct map.
+pub fn to_direct_map<T>(pointer: *const T) -> *const T {
+    (pointer as u64 + PHYSICAL_MEMORY_OFFSET) as *const T
+}
@@ -31,6 +31,7 @@ impl PixelFormat {
 
 #[repr(C)]
 pub struct GraphicConfig {
+    /// The physical address of the frame buffer.
     pub frame_buffer_base: *mut u8,
     pub frame_buffer_size: usize,
     pub pixel_format: PixelFormat,
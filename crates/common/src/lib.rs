@@ -5,14 +5,23 @@ pub mod memory_mapping;
 
 pub type KernelMain = extern "sysv64" fn(&BootInfo);
 
+/// The virtual address where the bootloader and the kernel map the physical memory (the direct
+/// map). Physical address `p` is accessible at `PHYSICAL_MEMORY_OFFSET + p`.
+pub const PHYSICAL_MEMORY_OFFSET: u64 = 0xFFFF_8000_0000_0000;
+/// The size of the physical memory covered by the direct map.
+pub const DIRECT_MAP_SIZE: u64 = 64 * 1024 * 1024 * 1024;
+
 use graphics::GraphicConfig;
 use memory_mapping::MemoryMapping;
 
+/// All the pointers in this struct, and the reference to this struct itself, point into the
+/// direct map, except for the ones explicitly documented as physical addresses.
 #[repr(C)]
 pub struct BootInfo {
     graphic_config: GraphicConfig,
     memory_mapping: MemoryMapping,
     acpi2_rsdp: Option<*const core::ffi::c_void>,
+    initrd: Option<(u64, usize)>,
 }
 
 impl BootInfo {
@@ -20,11 +29,13 @@ impl BootInfo {
         graphic_config: GraphicConfig,
         memory_mapping: MemoryMapping,
         acpi2_rsdp: Option<*const core::ffi::c_void>,
+        initrd: Option<(u64, usize)>,
     ) -> Self {
         Self {
             graphic_config,
             memory_mapping,
             acpi2_rsdp,
+            initrd,
         }
     }
 
@@ -36,7 +47,14 @@ impl BootInfo {
         &self.memory_mapping
     }
 
+    /// The physical address of the RSDP.
     pub fn acpi2_rsdp(&self) -> Option<*const core::ffi::c_void> {
         self.acpi2_rsdp
     }
+
+    /// The physical address and the size of the initial ramdisk loaded from `\initrd`, if any.
+    /// It's in LOADER_DATA pages, which the kernel never reuses.
+    pub fn initrd(&self) -> Option<(u64, usize)> {
+        self.initrd
+    }
 }
@@ -0,0 +1,27 @@
+use std::{env, path::PathBuf, process::Command};
+
+/// Assembles the programs in apps/ into OUT_DIR, from where the kernel embeds them.
+fn main() {
+    let out_dir = PathBuf::from(env::var_os("OUT_DIR").unwrap());
+    for app in ["hello"] {
+        let source = format!("apps/{}.S", app);
+        let object = out_dir.join(format!("{}.o", app));
+        let elf = out_dir.join(format!("{}.elf", app));
+        println!("cargo:rerun-if-changed={}", source);
+        run(Command::new("as").arg(&source).arg("-o").arg(&object));
+        run(Command::new("ld")
+            .args(["-s", "-static", "-nostdlib", "-z", "noexecstack"])
+            .args(["-z", "max-page-size=0x1000", "-Ttext-segment=0x8000000000"])
+            .args(["-e", "_start"])
+            .arg(&object)
+            .arg("-o")
+            .arg(&elf));
+    }
+}
+
+fn run(command: &mut Command) {
+    let status = command
+        .status()
+        .unwrap_or_else(|e| panic!("Failed to run {:?}: {}", command, e));
+    assert!(status.success(), "{:?} failed with {}", command, status);
+}
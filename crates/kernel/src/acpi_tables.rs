@@ -0,0 +1,48 @@
+//! The ACPI tables from the firmware, read with the acpi crate through the direct map.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use x86_64::PhysAddr;
+
+use crate::{paging, prelude::*};
+
+/// The physical address of the RSDP, or 0 if the firmware didn't give one.
+static RSDP: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Copy, Clone)]
+pub struct Handler;
+
+impl acpi::AcpiHandler for Handler {
+    unsafe fn map_physical_region<T>(
+        &self,
+        physical_address: usize,
+        size: usize,
+    ) -> acpi::PhysicalMapping<Self, T> {
+        let virtual_start = paging::phys_to_virt(PhysAddr::new(physical_address as u64));
+        let virtual_start = core::ptr::NonNull::new(virtual_start.as_mut_ptr()).unwrap();
+        acpi::PhysicalMapping::new(
+            physical_address,
+            virtual_start,
+            physical_address,
+            size,
+            Handler,
+        )
+    }
+
+    fn unmap_physical_region<T>(_region: &acpi::PhysicalMapping<Self, T>) {}
+}
+
+pub fn initialize(acpi2_rsdp: Option<*const core::ffi::c_void>) {
+    if let Some(rsdp) = acpi2_rsdp {
+        RSDP.store(rsdp as usize, Ordering::Release);
+    }
+}
+
+/// Parses the tables from the RSDP.
+pub fn tables() -> Result<acpi::AcpiTables<Handler>> {
+    let rsdp = RSDP.load(Ordering::Acquire);
+    if rsdp == 0 {
+        return Err(Error::Whatever("No ACPI RSDP"));
+    }
+    Ok(unsafe { acpi::AcpiTables::from_rsdp(Handler, rsdp)? })
+}
@@ -0,0 +1,628 @@
+//! A driver for AHCI host bus adapters and the SATA disks attached to them.
+
+use core::{
+    mem::ManuallyDrop,
+    sync::atomic::{fence, AtomicBool, AtomicU32, Ordering},
+};
+
+use alloc::{collections::VecDeque, string::String, sync::Arc, vec::Vec};
+use spinning_top::Spinlock;
+use x86_64::{instructions::interrupts, VirtAddr};
+
+use crate::{
+    block::{self, BlockDevice},
+    dma::DmaMemory,
+    driver::{DeviceMatch, Driver},
+    interrupts::{allocate_vector, free_vectors, InterruptHandler},
+    msi::{configure_msi_fixed_destination, DeliveryMode, TriggerMode},
+    pci,
+    prelude::*,
+    task::{self, TaskHandle},
+};
+
+static CONTROLLERS: Spinlock<Vec<Controller>> = Spinlock::new(Vec::new());
+
+// Generic host control registers
+const HBA_CAPABILITIES: usize = 0x00;
+const HBA_GLOBAL_HOST_CONTROL: usize = 0x04;
+const HBA_INTERRUPT_STATUS: usize = 0x08;
+const HBA_PORTS_IMPLEMENTED: usize = 0x0C;
+const CAPABILITIES_64BIT_ADDRESSING: u32 = 1 << 31;
+const GLOBAL_HOST_CONTROL_INTERRUPT_ENABLE: u32 = 1 << 1;
+const GLOBAL_HOST_CONTROL_AHCI_ENABLE: u32 = 1 << 31;
+
+// Port registers, relative to the registers of each port
+const PORT_REGISTERS_BASE: usize = 0x100;
+const PORT_REGISTERS_SIZE: usize = 0x80;
+const PORT_COMMAND_LIST_BASE: usize = 0x00;
+const PORT_COMMAND_LIST_BASE_UPPER: usize = 0x04;
+const PORT_FIS_BASE: usize = 0x08;
+const PORT_FIS_BASE_UPPER: usize = 0x0C;
+const PORT_INTERRUPT_STATUS: usize = 0x10;
+const PORT_INTERRUPT_ENABLE: usize = 0x14;
+const PORT_COMMAND: usize = 0x18;
+const PORT_TASK_FILE_DATA: usize = 0x20;
+const PORT_SIGNATURE: usize = 0x24;
+const PORT_SATA_STATUS: usize = 0x28;
+const PORT_SATA_ERROR: usize = 0x30;
+const PORT_COMMAND_ISSUE: usize = 0x38;
+
+const COMMAND_START: u32 = 1 << 0;
+const COMMAND_FIS_RECEIVE_ENABLE: u32 = 1 << 4;
+const COMMAND_FIS_RECEIVE_RUNNING: u32 = 1 << 14;
+const COMMAND_LIST_RUNNING: u32 = 1 << 15;
+
+const INTERRUPT_DEVICE_TO_HOST_REGISTER_FIS: u32 = 1 << 0;
+const INTERRUPT_PIO_SETUP_FIS: u32 = 1 << 1;
+const INTERRUPT_ERRORS: u32 = 0b1111 << 27;
+
+const TASK_FILE_ERROR: u32 = 1 << 0;
+const TASK_FILE_DATA_REQUEST: u32 = 1 << 3;
+const TASK_FILE_BUSY: u32 = 1 << 7;
+
+const SATA_STATUS_DEVICE_PRESENT: u32 = 0x3;
+const SATA_STATUS_INTERFACE_ACTIVE: u32 = 0x1;
+const SIGNATURE_ATA: u32 = 0x0000_0101;
+
+const ATA_IDENTIFY_DEVICE: u8 = 0xEC;
+const ATA_READ_DMA_EXT: u8 = 0x25;
+const ATA_WRITE_DMA_EXT: u8 = 0x35;
+
+// The layout of the memory each port shares with the controller, besides the buffer
+const TABLE_SIZE: usize = 4096;
+const COMMAND_LIST_OFFSET: usize = 0x000;
+const RECEIVED_FIS_OFFSET: usize = 0x400;
+const COMMAND_TABLE_OFFSET: usize = 0x500;
+const PHYSICAL_REGION_OFFSET: usize = COMMAND_TABLE_OFFSET + 0x80;
+
+/// The size of the bounce buffer of each port, which bounds the size of a single command.
+const BUFFER_SIZE: usize = 64 * 1024;
+
+/// Register-to-device FIS, which carries an ATA command.
+const FIS_TYPE_REGISTER_HOST_TO_DEVICE: u8 = 0x27;
+const FIS_LENGTH: usize = 20;
+
+pub const DRIVER: Driver = Driver {
+    name: "ahci",
+    matches: &[DeviceMatch::Class(pci::PCIClass::MassStorageController(
+        pci::MassStorageSubclass::SATAController(pci::SATAProgramInterface::AHCI),
+    ))],
+    probe: |_| true,
+    attach: initialize,
+};
+
+/// Sets up the AHCI controller `func` and registers the disks attached to it as block devices.
+/// Commands complete with MSI if the controller supports it, or are polled otherwise.
+pub fn initialize(func: &pci::PCIFunction) -> Result<()> {
+    if !matches!(
+        func.class(),
+        pci::PCIClass::MassStorageController(pci::MassStorageSubclass::SATAController(
+            pci::SATAProgramInterface::AHCI
+        ))
+    ) {
+        return Err(Error::Whatever("The function is not an AHCI controller"));
+    }
+    // ABAR is always BAR5.
+    let registers = Registers {
+        base: func.map_memory_bar(5)?,
+    };
+    // Enable the memory space and bus mastering, leaving the status bits as they are.
+    let command = func.read_conf_register(0x04);
+    func.write_conf_register(0x04, (command & 0xFFFF) | 0b110);
+
+    registers.write(
+        HBA_GLOBAL_HOST_CONTROL,
+        registers.read(HBA_GLOBAL_HOST_CONTROL) | GLOBAL_HOST_CONTROL_AHCI_ENABLE,
+    );
+    let allows_64bit = registers.read(HBA_CAPABILITIES) & CAPABILITIES_64BIT_ADDRESSING != 0;
+    let implemented = registers.read(HBA_PORTS_IMPLEMENTED);
+    let mut ports = Vec::new();
+    for number in (0..32).filter(|i| implemented & (1 << i) != 0) {
+        let port_registers = registers.port(number);
+        let status = port_registers.read(PORT_SATA_STATUS);
+        if status & 0xF != SATA_STATUS_DEVICE_PRESENT
+            || (status >> 8) & 0xF != SATA_STATUS_INTERFACE_ACTIVE
+        {
+            continue;
+        }
+        if port_registers.read(PORT_SIGNATURE) != SIGNATURE_ATA {
+            log::info!("AHCI port {} has a device other than a disk", number);
+            continue;
+        }
+        let disk = Port::new(port_registers, allows_64bit).and_then(Disk::identify);
+        match disk {
+            Ok(disk) => ports.push((number, Arc::new(disk))),
+            Err(e) => log::warn!("Failed to set up AHCI port {}: {:?}", number, e),
+        }
+    }
+
+    let use_interrupts = allocate_vector("ahci", InterruptHandler::Function(handle_interrupt))
+        .and_then(|vector| {
+            configure_msi_fixed_destination(func, TriggerMode::Edge, DeliveryMode::Fixed, vector, 0)
+                .map_err(|e| {
+                    free_vectors(&[vector]);
+                    e
+                })
+        })
+        .map_err(|e| log::warn!("AHCI commands will be polled: {:?}", e))
+        .is_ok();
+    for (_, disk) in &ports {
+        let name = block::register("sata", disk.clone());
+        log::info!(
+            "{}: {} ({} blocks of {} bytes)",
+            name,
+            disk.model,
+            disk.block_count,
+            disk.block_size
+        );
+    }
+    interrupts::without_interrupts(|| {
+        CONTROLLERS.lock().push(Controller {
+            registers,
+            ports: ports.clone(),
+        })
+    });
+    if use_interrupts {
+        registers.write(HBA_INTERRUPT_STATUS, registers.read(HBA_INTERRUPT_STATUS));
+        registers.write(
+            HBA_GLOBAL_HOST_CONTROL,
+            registers.read(HBA_GLOBAL_HOST_CONTROL) | GLOBAL_HOST_CONTROL_INTERRUPT_ENABLE,
+        );
+        for (_, disk) in &ports {
+            disk.port.use_interrupts.store(true, Ordering::SeqCst);
+        }
+    }
+    Ok(())
+}
+
+/// Acknowledges the interrupts from all the controllers and wakes up the tasks waiting for them.
+fn handle_interrupt() {
+    let controllers = CONTROLLERS.lock();
+    for controller in controllers.iter() {
+        let pending = controller.registers.read(HBA_INTERRUPT_STATUS);
+        if pending == 0 {
+            continue;
+        }
+        for (number, disk) in &controller.ports {
+            if pending & (1 << number) != 0 {
+                disk.port.collect_interrupt_status();
+                if let Some(waiter) = disk.port.waiter.lock().as_ref() {
+                    waiter.awake();
+                }
+            }
+        }
+        controller.registers.write(HBA_INTERRUPT_STATUS, pending);
+    }
+}
+
+/// Memory-mapped registers of a controller or one of its ports.
+#[derive(Copy, Clone, Debug)]
+struct Registers {
+    base: VirtAddr,
+}
+
+impl Registers {
+    fn read(&self, offset: usize) -> u32 {
+        unsafe { core::ptr::read_volatile((self.base + offset).as_ptr()) }
+    }
+
+    fn write(&self, offset: usize, value: u32) {
+        unsafe { core::ptr::write_volatile((self.base + offset).as_mut_ptr(), value) }
+    }
+
+    fn port(&self, number: u8) -> Registers {
+        Registers {
+            base: self.base + PORT_REGISTERS_BASE + number as usize * PORT_REGISTERS_SIZE,
+        }
+    }
+}
+
+/// Spins until `condition` holds, giving up after a while.
+fn wait_until<F: FnMut() -> bool>(mut condition: F, error: &'static str) -> Result<()> {
+    const MAX_ATTEMPTS: usize = 1_000_000;
+    for _ in 0..MAX_ATTEMPTS {
+        if condition() {
+            return Ok(());
+        }
+        core::hint::spin_loop();
+    }
+    Err(Error::Whatever(error))
+}
+
+struct Controller {
+    registers: Registers,
+    ports: Vec<(u8, Arc<Disk>)>,
+}
+
+/// The memory a port shares with the controller.
+struct DmaArea {
+    /// Holds the command list, the received FISes and the only command table.
+    table: DmaMemory,
+    buffer: DmaMemory,
+}
+
+impl DmaArea {
+    fn write_u32(&mut self, offset: usize, value: u32) {
+        self.table.write(offset, value);
+    }
+
+    fn buffer(&mut self) -> &mut [u8] {
+        &mut self.buffer.as_mut_slice()[..BUFFER_SIZE]
+    }
+}
+
+/// Who runs a command on a port, so that the others sleep rather than spin while it waits for
+/// the disk.
+#[derive(Default)]
+struct CommandQueue {
+    busy: bool,
+    waiters: VecDeque<TaskHandle>,
+}
+
+/// The right to run a command on a port, which is passed to the next waiter when dropped.
+struct CommandClaim<'a> {
+    port: &'a Port,
+}
+
+impl Drop for CommandClaim<'_> {
+    fn drop(&mut self) {
+        let next = interrupts::without_interrupts(|| {
+            let mut queue = self.port.queue.lock();
+            queue.busy = false;
+            queue.waiters.pop_front()
+        });
+        if let Some(next) = next {
+            next.awake();
+        }
+    }
+}
+
+/// The data moved by a command.
+enum Transfer<'a> {
+    FromDevice(&'a mut [u8]),
+    ToDevice(&'a [u8]),
+}
+
+/// A port with a SATA device, which runs one command at a time in the command slot 0.
+struct Port {
+    registers: Registers,
+    /// Only freed once the port is stopped, since the controller may still write to it otherwise.
+    dma: Spinlock<ManuallyDrop<DmaArea>>,
+    queue: Spinlock<CommandQueue>,
+    /// The interrupt status collected since the current command was issued.
+    interrupt_status: AtomicU32,
+    /// The task waiting for the current command, if it sleeps until the interrupt.
+    waiter: Spinlock<Option<TaskHandle>>,
+    use_interrupts: AtomicBool,
+}
+
+impl Port {
+    fn new(registers: Registers, allows_64bit: bool) -> Result<Self> {
+        let dma = DmaArea {
+            table: DmaMemory::new(TABLE_SIZE)?,
+            buffer: DmaMemory::new(BUFFER_SIZE)?,
+        };
+        let end = dma.table.end().max(dma.buffer.end());
+        if !allows_64bit && end.as_u64() > 1 << 32 {
+            return Err(Error::Whatever(
+                "The controller can't access memory above 4 GiB",
+            ));
+        }
+        let base = dma.table.physical_address(0).as_u64();
+        let port = Self {
+            registers,
+            dma: Spinlock::new(ManuallyDrop::new(dma)),
+            queue: Spinlock::new(CommandQueue::default()),
+            interrupt_status: AtomicU32::new(0),
+            waiter: Spinlock::new(None),
+            use_interrupts: AtomicBool::new(false),
+        };
+        port.stop()?;
+        let command_list = base + COMMAND_LIST_OFFSET as u64;
+        let received_fis = base + RECEIVED_FIS_OFFSET as u64;
+        registers.write(PORT_COMMAND_LIST_BASE, command_list as u32);
+        registers.write(PORT_COMMAND_LIST_BASE_UPPER, (command_list >> 32) as u32);
+        registers.write(PORT_FIS_BASE, received_fis as u32);
+        registers.write(PORT_FIS_BASE_UPPER, (received_fis >> 32) as u32);
+        registers.write(
+            PORT_INTERRUPT_ENABLE,
+            INTERRUPT_DEVICE_TO_HOST_REGISTER_FIS | INTERRUPT_PIO_SETUP_FIS | INTERRUPT_ERRORS,
+        );
+        port.start()?;
+        Ok(port)
+    }
+
+    fn stop(&self) -> Result<()> {
+        let command = self.registers.read(PORT_COMMAND) & !COMMAND_START;
+        self.registers.write(PORT_COMMAND, command);
+        wait_until(
+            || self.registers.read(PORT_COMMAND) & COMMAND_LIST_RUNNING == 0,
+            "The command list didn't stop",
+        )?;
+        let command = self.registers.read(PORT_COMMAND) & !COMMAND_FIS_RECEIVE_ENABLE;
+        self.registers.write(PORT_COMMAND, command);
+        wait_until(
+            || self.registers.read(PORT_COMMAND) & COMMAND_FIS_RECEIVE_RUNNING == 0,
+            "The FIS receive didn't stop",
+        )
+    }
+
+    fn start(&self) -> Result<()> {
+        self.registers.write(PORT_SATA_ERROR, u32::MAX);
+        self.registers.write(PORT_INTERRUPT_STATUS, u32::MAX);
+        let command = self.registers.read(PORT_COMMAND) | COMMAND_FIS_RECEIVE_ENABLE;
+        self.registers.write(PORT_COMMAND, command);
+        wait_until(
+            || {
+                self.registers.read(PORT_TASK_FILE_DATA) & (TASK_FILE_BUSY | TASK_FILE_DATA_REQUEST)
+                    == 0
+            },
+            "The device stays busy",
+        )?;
+        let command = self.registers.read(PORT_COMMAND) | COMMAND_START;
+        self.registers.write(PORT_COMMAND, command);
+        Ok(())
+    }
+
+    /// Clears the pending interrupts of the port and returns all the ones since the current
+    /// command was issued.
+    fn collect_interrupt_status(&self) -> u32 {
+        let status = self.registers.read(PORT_INTERRUPT_STATUS);
+        self.registers.write(PORT_INTERRUPT_STATUS, status);
+        self.interrupt_status.fetch_or(status, Ordering::SeqCst) | status
+    }
+
+    /// Waits until no other task runs a command on the port.
+    fn claim(&self) -> CommandClaim<'_> {
+        let try_claim = |waiter: Option<&TaskHandle>| {
+            interrupts::without_interrupts(|| {
+                let mut queue = self.queue.lock();
+                if !queue.busy {
+                    queue.busy = true;
+                    return true;
+                }
+                if let Some(waiter) = waiter {
+                    if !queue.waiters.iter().any(|w| w.id() == waiter.id()) {
+                        queue.waiters.push_back(waiter.clone());
+                    }
+                }
+                false
+            })
+        };
+        // The disks are identified before there may be other tasks, so only the contended case
+        // needs the current task.
+        if !try_claim(None) {
+            let current = task::current_task();
+            loop {
+                let state = current.load_state();
+                if try_claim(Some(&current)) {
+                    break;
+                }
+                current.try_compare_and_sleep(state);
+            }
+        }
+        CommandClaim { port: self }
+    }
+
+    /// Runs the ATA command in `fis` and waits for its completion.
+    fn execute(&self, fis: &[u8; FIS_LENGTH], transfer: Transfer) -> Result<()> {
+        let (length, is_write) = match &transfer {
+            Transfer::FromDevice(buffer) => (buffer.len(), false),
+            Transfer::ToDevice(data) => (data.len(), true),
+        };
+        assert!(length > 0 && length <= BUFFER_SIZE);
+        let _claim = self.claim();
+        let mut dma = self.dma.lock();
+        if let Transfer::ToDevice(data) = &transfer {
+            dma.buffer()[..length].copy_from_slice(data);
+        }
+
+        // The command table: the FIS, then a single physical region for the whole buffer.
+        for (i, word) in fis.chunks(4).enumerate() {
+            let word = u32::from_le_bytes(word.try_into().unwrap());
+            dma.write_u32(COMMAND_TABLE_OFFSET + i * 4, word);
+        }
+        let buffer = dma.buffer.physical_address(0).as_u64();
+        dma.write_u32(PHYSICAL_REGION_OFFSET, buffer as u32);
+        dma.write_u32(PHYSICAL_REGION_OFFSET + 0x4, (buffer >> 32) as u32);
+        dma.write_u32(PHYSICAL_REGION_OFFSET + 0x8, 0);
+        dma.write_u32(PHYSICAL_REGION_OFFSET + 0xC, (length - 1) as u32);
+        // The command header in the slot 0.
+        let table = dma.table.physical_address(0).as_u64() + COMMAND_TABLE_OFFSET as u64;
+        let flags = (FIS_LENGTH / 4) as u32 | ((is_write as u32) << 6) | (1 << 16);
+        dma.write_u32(COMMAND_LIST_OFFSET, flags);
+        dma.write_u32(COMMAND_LIST_OFFSET + 0x4, 0);
+        dma.write_u32(COMMAND_LIST_OFFSET + 0x8, table as u32);
+        dma.write_u32(COMMAND_LIST_OFFSET + 0xC, (table >> 32) as u32);
+        // The claim keeps the others away from the area, so the lock isn't held while waiting.
+        drop(dma);
+
+        self.collect_interrupt_status();
+        self.interrupt_status.store(0, Ordering::SeqCst);
+        fence(Ordering::SeqCst);
+        let result = self.issue_and_wait();
+        fence(Ordering::SeqCst);
+        if let Err(e) = result {
+            // Restart the port so that the next command can run.
+            self.stop().and_then(|()| self.start())?;
+            return Err(e);
+        }
+        if let Transfer::FromDevice(buffer) = transfer {
+            let length = buffer.len();
+            buffer.copy_from_slice(&self.dma.lock().buffer()[..length]);
+        }
+        Ok(())
+    }
+
+    fn issue_and_wait(&self) -> Result<()> {
+        // Sleep until the interrupt if possible, or poll otherwise.
+        let waiter = (self.use_interrupts.load(Ordering::SeqCst) && interrupts::are_enabled())
+            .then(task::current_task);
+        interrupts::without_interrupts(|| *self.waiter.lock() = waiter.clone());
+        self.registers.write(PORT_COMMAND_ISSUE, 1);
+        let is_done = || {
+            let status = self.collect_interrupt_status();
+            if status & INTERRUPT_ERRORS != 0 {
+                Some(Err(Error::Whatever("The disk reported an error")))
+            } else if self.registers.read(PORT_COMMAND_ISSUE) & 1 == 0 {
+                Some(Ok(()))
+            } else {
+                None
+            }
+        };
+        let result = match &waiter {
+            Some(waiter) => loop {
+                let state = waiter.load_state();
+                if let Some(result) = is_done() {
+                    break result;
+                }
+                waiter.try_compare_and_sleep(state);
+            },
+            None => {
+                let mut result = Err(Error::Whatever("The disk didn't respond"));
+                wait_until(
+                    || match is_done() {
+                        Some(r) => {
+                            result = r;
+                            true
+                        }
+                        None => false,
+                    },
+                    "The disk didn't respond",
+                )
+                .and(result)
+            }
+        };
+        interrupts::without_interrupts(|| *self.waiter.lock() = None);
+        result?;
+        if self.registers.read(PORT_TASK_FILE_DATA) & TASK_FILE_ERROR != 0 {
+            return Err(Error::Whatever("The disk reported an error"));
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Port {
+    fn drop(&mut self) {
+        match self.stop() {
+            Ok(()) => unsafe { ManuallyDrop::drop(self.dma.get_mut()) },
+            Err(e) => log::warn!(
+                "Leaking the memory of an AHCI port that won't stop: {:?}",
+                e
+            ),
+        }
+    }
+}
+
+/// Builds a register FIS for an ATA command with 48-bit addressing.
+fn command_fis(command: u8, lba: u64, count: u16) -> [u8; FIS_LENGTH] {
+    let mut fis = [0; FIS_LENGTH];
+    let lba = lba.to_le_bytes();
+    fis[0] = FIS_TYPE_REGISTER_HOST_TO_DEVICE;
+    fis[1] = 1 << 7; // This is a command, not a device control.
+    fis[2] = command;
+    fis[4..7].copy_from_slice(&lba[0..3]);
+    fis[7] = 1 << 6; // LBA mode
+    fis[8..11].copy_from_slice(&lba[3..6]);
+    fis[12..14].copy_from_slice(&count.to_le_bytes());
+    fis
+}
+
+/// A SATA disk, which is accessed with DMA.
+pub struct Disk {
+    port: Port,
+    model: String,
+    block_size: usize,
+    block_count: u64,
+}
+
+impl Disk {
+    fn identify(port: Port) -> Result<Self> {
+        let mut data = [0u8; 512];
+        port.execute(
+            &command_fis(ATA_IDENTIFY_DEVICE, 0, 0),
+            Transfer::FromDevice(&mut data),
+        )?;
+        let word = |i: usize| u16::from_le_bytes([data[2 * i], data[2 * i + 1]]);
+        // The model name is in big endian words, padded with spaces.
+        let model = (27..47)
+            .flat_map(|i| word(i).to_be_bytes())
+            .map(char::from)
+            .collect::<String>()
+            .trim()
+            .into();
+        if word(83) & (1 << 10) == 0 {
+            return Err(Error::Whatever(
+                "The disk doesn't support 48-bit addressing",
+            ));
+        }
+        let block_count = (100..104)
+            .rev()
+            .fold(0, |count, i| (count << 16) | word(i) as u64);
+        // Word 106 tells if the logical sectors are larger than 512 bytes, and words 117-118
+        // tell how many words they have then.
+        let block_size = if word(106) & 0xC000 == 0x4000 && word(106) & (1 << 12) != 0 {
+            (((word(118) as usize) << 16) | word(117) as usize) * 2
+        } else {
+            512
+        };
+        if block_size == 0 || block_size > BUFFER_SIZE || BUFFER_SIZE % block_size != 0 {
+            return Err(Error::Whatever("The disk has unsupported sector size"));
+        }
+        Ok(Self {
+            port,
+            model,
+            block_size,
+            block_count,
+        })
+    }
+
+    pub fn model(&self) -> &str {
+        &self.model
+    }
+
+    /// Checks the arguments of a transfer of `length` bytes from `lba`.
+    fn check_range(&self, lba: u64, length: usize) -> Result<()> {
+        if length % self.block_size != 0 {
+            return Err(Error::Whatever(
+                "The buffer is not a multiple of the block size",
+            ));
+        }
+        lba.checked_add((length / self.block_size) as u64)
+            .filter(|&end| end <= self.block_count)
+            .map(drop)
+            .ok_or(Error::Whatever("Accessing beyond the end of the device"))
+    }
+}
+
+impl BlockDevice for Disk {
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    fn block_count(&self) -> u64 {
+        self.block_count
+    }
+
+    fn read_blocks(&self, lba: u64, buffer: &mut [u8]) -> Result<()> {
+        self.check_range(lba, buffer.len())?;
+        for (i, chunk) in buffer.chunks_mut(BUFFER_SIZE).enumerate() {
+            let lba = lba + (i * BUFFER_SIZE / self.block_size) as u64;
+            let count = (chunk.len() / self.block_size) as u16;
+            let fis = command_fis(ATA_READ_DMA_EXT, lba, count);
+            self.port.execute(&fis, Transfer::FromDevice(chunk))?;
+        }
+        Ok(())
+    }
+
+    fn write_blocks(&self, lba: u64, data: &[u8]) -> Result<()> {
+        self.check_range(lba, data.len())?;
+        for (i, chunk) in data.chunks(BUFFER_SIZE).enumerate() {
+            let lba = lba + (i * BUFFER_SIZE / self.block_size) as u64;
+            let count = (chunk.len() / self.block_size) as u16;
+            let fis = command_fis(ATA_WRITE_DMA_EXT, lba, count);
+            self.port.execute(&fis, Transfer::ToDevice(chunk))?;
+        }
+        Ok(())
+    }
+}
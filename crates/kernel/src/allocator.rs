@@ -4,7 +4,10 @@ use linked_list_allocator::LockedHeap;
 use pomelo_common::memory_mapping::MemoryMapping;
 use x86_64::structures::paging::page::PageSize;
 
-use crate::memory_manager::{self, FrameSize};
+use crate::{
+    memory_manager::{self, FrameSize},
+    paging,
+};
 
 #[global_allocator]
 static ALLOCATOR: UninterruptedAlloc = UninterruptedAlloc::empty();
@@ -16,10 +19,9 @@ pub fn initialize(memory_mapping: &MemoryMapping) {
     let allocated = mm
         .allocate(MEMORY_LIMIT / FrameSize::SIZE as usize)
         .expect("Unable to allocate memory frame......");
-    // TODO: Need physical to virtual conversion when we do something other than identity mapping
-    let start_address = allocated.start.start_address().as_u64() as usize;
-    let size = allocated.end.start_address().as_u64() as usize - start_address;
-    ALLOCATOR.init(start_address, size);
+    let start_address = paging::phys_to_virt(allocated.start.start_address());
+    let size = (allocated.end.start_address() - allocated.start.start_address()) as usize;
+    ALLOCATOR.init(start_address.as_u64() as usize, size);
 }
 
 struct UninterruptedAlloc(LockedHeap);
@@ -0,0 +1,118 @@
+//! Storage devices addressed in fixed-size blocks.
+
+use alloc::{format, string::String, sync::Arc, vec, vec::Vec};
+use spinning_top::Spinlock;
+
+use crate::prelude::*;
+
+pub mod partition;
+
+static DEVICES: Spinlock<Vec<(String, Arc<dyn BlockDevice>)>> = Spinlock::new(Vec::new());
+
+pub trait BlockDevice: Send + Sync {
+    /// The size of a block in bytes.
+    fn block_size(&self) -> usize;
+    /// The number of blocks on the device.
+    fn block_count(&self) -> u64;
+    /// Reads `buffer.len() / block_size()` blocks starting from the block `lba`.
+    /// `buffer.len()` must be a multiple of the block size.
+    fn read_blocks(&self, lba: u64, buffer: &mut [u8]) -> Result<()>;
+    /// Writes `data.len() / block_size()` blocks starting from the block `lba`.
+    /// `data.len()` must be a multiple of the block size.
+    fn write_blocks(&self, _lba: u64, _data: &[u8]) -> Result<()> {
+        Err(Error::Whatever("The device is read-only"))
+    }
+}
+
+/// Registers `device` under the first unused name of `prefix` followed by a number, and returns
+/// the name.
+pub fn register(prefix: &str, device: Arc<dyn BlockDevice>) -> String {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut devices = DEVICES.lock();
+        let name = (0..)
+            .map(|i| format!("{}{}", prefix, i))
+            .find(|name| devices.iter().all(|(n, _)| n != name))
+            .unwrap();
+        devices.push((name.clone(), device));
+        name
+    })
+}
+
+/// The registered devices and their names.
+pub fn devices() -> Vec<(String, Arc<dyn BlockDevice>)> {
+    x86_64::instructions::interrupts::without_interrupts(|| DEVICES.lock().clone())
+}
+
+/// Reads `buffer.len()` bytes from the byte offset `offset`, which doesn't have to be aligned to
+/// blocks.
+pub fn read_bytes<D: BlockDevice + ?Sized>(
+    device: &D,
+    offset: u64,
+    buffer: &mut [u8],
+) -> Result<()> {
+    let block_size = device.block_size();
+    let end = offset
+        .checked_add(buffer.len() as u64)
+        .filter(|&end| end <= device.block_count() * block_size as u64)
+        .ok_or(Error::Whatever("Reading beyond the end of the device"))?;
+    let mut block = vec![0; block_size];
+    let mut position = offset;
+    while position < end {
+        let lba = position / block_size as u64;
+        let in_block = (position % block_size as u64) as usize;
+        let length = usize::min(block_size - in_block, (end - position) as usize);
+        let destination = &mut buffer[(position - offset) as usize..][..length];
+        if in_block == 0 && length == block_size {
+            device.read_blocks(lba, destination)?;
+        } else {
+            device.read_blocks(lba, &mut block)?;
+            destination.copy_from_slice(&block[in_block..][..length]);
+        }
+        position += length as u64;
+    }
+    Ok(())
+}
+
+/// A read-only device backed by memory.
+pub struct RamDisk {
+    data: &'static [u8],
+}
+
+impl RamDisk {
+    const BLOCK_SIZE: usize = 512;
+
+    pub fn new(data: &'static [u8]) -> Self {
+        Self { data }
+    }
+}
+
+impl BlockDevice for RamDisk {
+    fn block_size(&self) -> usize {
+        Self::BLOCK_SIZE
+    }
+
+    fn block_count(&self) -> u64 {
+        self.data.len().div_ceil(Self::BLOCK_SIZE) as u64
+    }
+
+    fn read_blocks(&self, lba: u64, buffer: &mut [u8]) -> Result<()> {
+        if buffer.len() % Self::BLOCK_SIZE != 0 {
+            return Err(Error::Whatever(
+                "The buffer is not a multiple of the block size",
+            ));
+        }
+        let start = lba
+            .checked_mul(Self::BLOCK_SIZE as u64)
+            .filter(|&start| {
+                start + buffer.len() as u64 <= self.block_count() * Self::BLOCK_SIZE as u64
+            })
+            .ok_or(Error::Whatever("Reading beyond the end of the device"))?
+            as usize;
+        // The last block may be partial, and the rest of it reads as zeros.
+        let data = self.data.get(start..).unwrap_or_default();
+        let available = data.len().min(buffer.len());
+        buffer[..available].copy_from_slice(&data[..available]);
+        buffer[available..].fill(0);
+        Ok(())
+    }
+}
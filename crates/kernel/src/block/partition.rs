@@ -0,0 +1,158 @@
+//! The partition tables on block devices, GPT and MBR.
+
+use alloc::{sync::Arc, vec, vec::Vec};
+
+use super::{read_bytes, BlockDevice};
+use crate::prelude::*;
+
+const GPT_SIGNATURE: &[u8; 8] = b"EFI PART";
+/// The largest partition entry array read, which is what the GPT usually reserves.
+const GPT_MAX_ENTRY_ARRAY_SIZE: u64 = 128 * 128;
+const MBR_SIGNATURE: [u8; 2] = [0x55, 0xAA];
+const MBR_ENTRIES_OFFSET: usize = 0x1BE;
+const MBR_ENTRY_SIZE: usize = 16;
+const MBR_TYPE_EMPTY: u8 = 0x00;
+const MBR_TYPE_EXTENDED: [u8; 3] = [0x05, 0x0F, 0x85];
+const MBR_TYPE_PROTECTIVE: u8 = 0xEE;
+
+/// A range of the blocks of a device, which is a device of its own.
+pub struct Partition {
+    device: Arc<dyn BlockDevice>,
+    /// The number of the partition in the table, from 1.
+    number: usize,
+    start_lba: u64,
+    block_count: u64,
+}
+
+impl Partition {
+    pub fn number(&self) -> usize {
+        self.number
+    }
+
+    fn check_range(&self, lba: u64, length: usize) -> Result<u64> {
+        let blocks = (length / self.block_size()) as u64;
+        lba.checked_add(blocks)
+            .filter(|&end| end <= self.block_count)
+            .map(|_| self.start_lba + lba)
+            .ok_or(Error::Whatever("Accessing beyond the end of the partition"))
+    }
+}
+
+impl BlockDevice for Partition {
+    fn block_size(&self) -> usize {
+        self.device.block_size()
+    }
+
+    fn block_count(&self) -> u64 {
+        self.block_count
+    }
+
+    fn read_blocks(&self, lba: u64, buffer: &mut [u8]) -> Result<()> {
+        let lba = self.check_range(lba, buffer.len())?;
+        self.device.read_blocks(lba, buffer)
+    }
+
+    fn write_blocks(&self, lba: u64, data: &[u8]) -> Result<()> {
+        let lba = self.check_range(lba, data.len())?;
+        self.device.write_blocks(lba, data)
+    }
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+/// Reads the partitions of `device` from its GPT, or else from its MBR. Returns an empty list if
+/// the device has neither. The extended partitions of the MBR aren't followed.
+pub fn partitions(device: &Arc<dyn BlockDevice>) -> Result<Vec<Partition>> {
+    let block_size = device.block_size() as u64;
+    let mut header = vec![0; block_size as usize];
+    device.read_blocks(1, &mut header)?;
+    if header[..8] == GPT_SIGNATURE[..] {
+        return gpt_partitions(device, &header);
+    }
+    device.read_blocks(0, &mut header)?;
+    if header[510..512] != MBR_SIGNATURE {
+        return Ok(Vec::new());
+    }
+    let mut partitions = Vec::new();
+    for i in 0..4 {
+        let entry = &header[MBR_ENTRIES_OFFSET + i * MBR_ENTRY_SIZE..][..MBR_ENTRY_SIZE];
+        let partition_type = entry[4];
+        if partition_type == MBR_TYPE_EMPTY
+            || partition_type == MBR_TYPE_PROTECTIVE
+            || MBR_TYPE_EXTENDED.contains(&partition_type)
+        {
+            continue;
+        }
+        push_partition(
+            &mut partitions,
+            device,
+            i + 1,
+            u64::from(read_u32(entry, 8)),
+            u64::from(read_u32(entry, 12)),
+        );
+    }
+    Ok(partitions)
+}
+
+fn gpt_partitions(device: &Arc<dyn BlockDevice>, header: &[u8]) -> Result<Vec<Partition>> {
+    let entries_lba = read_u64(header, 0x48);
+    let entry_count = u64::from(read_u32(header, 0x50));
+    let entry_size = u64::from(read_u32(header, 0x54));
+    if entry_size < 0x38 {
+        return Err(Error::Whatever("Invalid GPT header"));
+    }
+    let array_size = (entry_count * entry_size).min(GPT_MAX_ENTRY_ARRAY_SIZE);
+    let mut entries = vec![0; array_size as usize];
+    read_bytes(
+        &**device,
+        entries_lba * device.block_size() as u64,
+        &mut entries,
+    )?;
+    let mut partitions = Vec::new();
+    for (i, entry) in entries.chunks_exact(entry_size as usize).enumerate() {
+        // An unused entry has no type.
+        if entry[..16].iter().all(|&b| b == 0) {
+            continue;
+        }
+        let first_lba = read_u64(entry, 0x20);
+        let last_lba = read_u64(entry, 0x28);
+        if last_lba < first_lba {
+            continue;
+        }
+        push_partition(
+            &mut partitions,
+            device,
+            i + 1,
+            first_lba,
+            last_lba - first_lba + 1,
+        );
+    }
+    Ok(partitions)
+}
+
+/// Adds the partition unless it doesn't fit in the device.
+fn push_partition(
+    partitions: &mut Vec<Partition>,
+    device: &Arc<dyn BlockDevice>,
+    number: usize,
+    start_lba: u64,
+    block_count: u64,
+) {
+    let fits = start_lba
+        .checked_add(block_count)
+        .map_or(false, |end| block_count > 0 && end <= device.block_count());
+    if fits {
+        partitions.push(Partition {
+            device: device.clone(),
+            number,
+            start_lba,
+            block_count,
+        });
+    }
+}
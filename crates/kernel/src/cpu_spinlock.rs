@@ -0,0 +1,72 @@
+//! A spinlock that remembers the CPU holding it.
+//!
+//! The page fault handler may interrupt code holding a lock it needs. Waiting is right if another
+//! CPU holds the lock, but never ends if the faulting CPU does, and the holder tells them apart.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use lock_api::{GuardSend, MappedMutexGuard, MutexGuard, RawMutex};
+
+use crate::percpu;
+
+const NO_OWNER: usize = usize::MAX;
+
+pub struct RawCpuSpinlock {
+    /// The CPU holding the lock, or `NO_OWNER`.
+    owner: AtomicUsize,
+}
+
+unsafe impl RawMutex for RawCpuSpinlock {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const INIT: Self = Self {
+        owner: AtomicUsize::new(NO_OWNER),
+    };
+
+    type GuardMarker = GuardSend;
+
+    fn lock(&self) {
+        while !self.try_lock() {
+            core::hint::spin_loop();
+        }
+    }
+
+    fn try_lock(&self) -> bool {
+        self.owner
+            .compare_exchange(
+                NO_OWNER,
+                percpu::current().cpu(),
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            )
+            .is_ok()
+    }
+
+    unsafe fn unlock(&self) {
+        self.owner.store(NO_OWNER, Ordering::Release);
+    }
+}
+
+/// Locking needs the per-CPU data, so this can't be taken before `percpu::initialize`.
+pub type CpuSpinlock<T> = lock_api::Mutex<RawCpuSpinlock, T>;
+pub type CpuSpinlockGuard<'a, T> = MutexGuard<'a, RawCpuSpinlock, T>;
+pub type MappedCpuSpinlockGuard<'a, T> = MappedMutexGuard<'a, RawCpuSpinlock, T>;
+
+/// `CpuSpinlock::new` for statics.
+pub const fn const_cpu_spinlock<T>(value: T) -> CpuSpinlock<T> {
+    CpuSpinlock::const_new(RawCpuSpinlock::INIT, value)
+}
+
+/// Waits for `lock` unless the calling CPU holds it, in which case this returns `None` instead
+/// of waiting forever.
+pub fn lock_unless_held_here<T: ?Sized>(lock: &CpuSpinlock<T>) -> Option<CpuSpinlockGuard<T>> {
+    let cpu = percpu::current().cpu();
+    loop {
+        if let Some(guard) = lock.try_lock() {
+            return Some(guard);
+        }
+        if unsafe { lock.raw() }.owner.load(Ordering::Relaxed) == cpu {
+            return None;
+        }
+        core::hint::spin_loop();
+    }
+}
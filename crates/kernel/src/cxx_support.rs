@@ -23,8 +23,11 @@
 /// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
 /// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 /// SOFTWARE.
+use alloc::sync::Arc;
 use core::ptr;
 
+use crate::fs::{self, FileType, OpenFile, SeekFrom};
+
 #[no_mangle]
 #[allow(unused)]
 extern "C" fn sabios_log(
@@ -64,9 +67,19 @@ extern "C" {
 
 #[allow(non_camel_case_types)]
 type pid_t = i32;
+const EIO: i32 = 5;
 const EBADF: i32 = 9;
 const ENOMEM: i32 = 12;
 const EINVAL: i32 = 22;
+const ENOTTY: i32 = 25;
+
+const SEEK_SET: i32 = 0;
+const SEEK_CUR: i32 = 1;
+const SEEK_END: i32 = 2;
+
+const S_IFCHR: u32 = 0o020000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFREG: u32 = 0o100000;
 
 #[no_mangle]
 extern "C" fn sbrk(_increment: isize) -> *const u8 {
@@ -96,52 +109,161 @@ extern "C" fn getpid() -> pid_t {
     -1
 }
 
-#[no_mangle]
-extern "C" fn close() -> i32 {
+fn set_errno(errno: i32) {
     unsafe {
-        *__errno() = EBADF;
+        *__errno() = errno;
     }
-    -1
+}
+
+/// Looks up the file opened as `fd` in the current task, setting `errno` if there's none.
+fn file(fd: i32) -> Option<Arc<OpenFile>> {
+    let file = usize::try_from(fd).ok().and_then(|fd| fs::file(fd).ok());
+    if file.is_none() {
+        set_errno(EBADF);
+    }
+    file
 }
 
 #[no_mangle]
-extern "C" fn read(_fd: i32, _buf: *mut u8, _count: usize) -> isize {
-    unsafe {
-        *__errno() = EBADF;
+extern "C" fn close(fd: i32) -> i32 {
+    match usize::try_from(fd).ok().map(fs::close) {
+        Some(Ok(())) => 0,
+        _ => {
+            set_errno(EBADF);
+            -1
+        }
     }
-    -1
 }
 
 #[no_mangle]
-extern "C" fn write(_fd: i32, _buf: *const u8, _count: usize) -> isize {
-    unsafe {
-        *__errno() = EBADF;
+extern "C" fn read(fd: i32, buf: *mut u8, count: usize) -> isize {
+    let file = match file(fd) {
+        Some(file) => file,
+        None => return -1,
+    };
+    let buffer = unsafe { core::slice::from_raw_parts_mut(buf, count) };
+    match file.read(buffer) {
+        Ok(read) => read as isize,
+        Err(_) => {
+            set_errno(EIO);
+            -1
+        }
     }
-    -1
 }
 
 #[no_mangle]
-extern "C" fn lseek(_fd: i32, _offset: isize, _whence: i32) -> isize {
-    unsafe {
-        *__errno() = EBADF;
+extern "C" fn write(fd: i32, buf: *const u8, count: usize) -> isize {
+    let file = match file(fd) {
+        Some(file) => file,
+        None => return -1,
+    };
+    let data = unsafe { core::slice::from_raw_parts(buf, count) };
+    match file.write(data) {
+        Ok(written) => written as isize,
+        Err(_) => {
+            set_errno(EIO);
+            -1
+        }
     }
-    -1
 }
 
 #[no_mangle]
-extern "C" fn fstat(_fd: i32, _buf: *mut u8) -> i32 {
-    unsafe {
-        *__errno() = EBADF;
+extern "C" fn lseek(fd: i32, offset: isize, whence: i32) -> isize {
+    let file = match file(fd) {
+        Some(file) => file,
+        None => return -1,
+    };
+    let position = match whence {
+        SEEK_SET if offset >= 0 => SeekFrom::Start(offset as u64),
+        SEEK_CUR => SeekFrom::Current(offset as i64),
+        SEEK_END => SeekFrom::End(offset as i64),
+        _ => {
+            set_errno(EINVAL);
+            return -1;
+        }
+    };
+    match file.seek(position) {
+        Ok(offset) => offset as isize,
+        Err(_) => {
+            set_errno(EINVAL);
+            -1
+        }
     }
-    -1
 }
 
+/// `struct timespec` of newlib.
+#[repr(C)]
+#[derive(Default)]
+struct Timespec {
+    tv_sec: i64,
+    tv_nsec: i64,
+}
+
+/// `struct stat` of newlib. There are no timestamps, so they're zero.
+#[repr(C)]
+struct Stat {
+    st_dev: i16,
+    st_ino: u16,
+    st_mode: u32,
+    st_nlink: u16,
+    st_uid: u16,
+    st_gid: u16,
+    st_rdev: i16,
+    st_size: i64,
+    st_atim: Timespec,
+    st_mtim: Timespec,
+    st_ctim: Timespec,
+    st_blksize: i64,
+    st_blocks: i64,
+    st_spare4: [i64; 2],
+}
+
+/// The block size `fstat` reports, in which `st_blocks` is counted.
+const STAT_BLOCK_SIZE: u64 = 512;
+
 #[no_mangle]
-extern "C" fn isatty(_fd: i32) -> i32 {
+extern "C" fn fstat(fd: i32, buf: *mut u8) -> i32 {
+    let file = match file(fd) {
+        Some(file) => file,
+        None => return -1,
+    };
+    let metadata = file.inode().metadata();
+    let st_mode = match metadata.file_type {
+        FileType::Regular => S_IFREG,
+        FileType::Directory => S_IFDIR,
+        FileType::CharacterDevice => S_IFCHR,
+    } | 0o666;
     unsafe {
-        *__errno() = EBADF;
+        (buf as *mut Stat).write_unaligned(Stat {
+            st_dev: 0,
+            st_ino: 0,
+            st_mode,
+            st_nlink: 1,
+            st_uid: 0,
+            st_gid: 0,
+            st_rdev: 0,
+            st_size: metadata.size as i64,
+            st_atim: Timespec::default(),
+            st_mtim: Timespec::default(),
+            st_ctim: Timespec::default(),
+            st_blksize: STAT_BLOCK_SIZE as i64,
+            st_blocks: metadata.size.div_ceil(STAT_BLOCK_SIZE) as i64,
+            st_spare4: [0; 2],
+        })
+    };
+    0
+}
+
+#[no_mangle]
+extern "C" fn isatty(fd: i32) -> i32 {
+    match file(fd) {
+        Some(file) if file.inode().is_terminal() => 1,
+        Some(_) => {
+            set_errno(ENOTTY);
+            0
+        }
+        None => 0,
     }
-    -1
 }
 
 #[no_mangle]
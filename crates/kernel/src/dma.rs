@@ -0,0 +1,68 @@
+//! Memory shared with devices, which the AHCI and virtio drivers hand to them.
+
+use x86_64::{
+    structures::paging::{frame::PhysFrameRange, page::PageSize},
+    PhysAddr, VirtAddr,
+};
+
+use crate::{
+    memory_manager::{self, FrameSize},
+    paging,
+    prelude::*,
+};
+
+/// Physically contiguous memory shared with a device. It's accessed through the direct map, and
+/// the physical addresses are handed to the device. The frames are freed when dropped, so the
+/// device must be stopped before that.
+pub struct DmaMemory {
+    frames: PhysFrameRange<FrameSize>,
+}
+
+impl DmaMemory {
+    /// Allocates zeroed memory of at least `size` bytes.
+    pub fn new(size: usize) -> Result<Self> {
+        let count = size.div_ceil(FrameSize::SIZE as usize);
+        let frames = memory_manager::with_memory_manager(|mm| mm.allocate(count))
+            .ok_or(Error::Whatever("Out of physical memory"))?;
+        let memory = Self { frames };
+        unsafe { core::ptr::write_bytes(memory.address(0).as_mut_ptr::<u8>(), 0, memory.size()) };
+        Ok(memory)
+    }
+
+    pub fn size(&self) -> usize {
+        (self.end() - self.physical_address(0)) as usize
+    }
+
+    pub fn physical_address(&self, offset: usize) -> PhysAddr {
+        self.frames.start.start_address() + offset
+    }
+
+    /// The physical address right after the memory.
+    pub fn end(&self) -> PhysAddr {
+        self.frames.end.start_address()
+    }
+
+    pub fn address(&self, offset: usize) -> VirtAddr {
+        paging::phys_to_virt(self.physical_address(offset))
+    }
+
+    pub fn read<T: Copy>(&self, offset: usize) -> T {
+        assert!(offset + core::mem::size_of::<T>() <= self.size());
+        unsafe { core::ptr::read_volatile(self.address(offset).as_ptr()) }
+    }
+
+    pub fn write<T: Copy>(&mut self, offset: usize, value: T) {
+        assert!(offset + core::mem::size_of::<T>() <= self.size());
+        unsafe { core::ptr::write_volatile(self.address(offset).as_mut_ptr(), value) }
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { core::slice::from_raw_parts_mut(self.address(0).as_mut_ptr(), self.size()) }
+    }
+}
+
+impl Drop for DmaMemory {
+    fn drop(&mut self) {
+        memory_manager::with_memory_manager(|mm| mm.free(self.frames));
+    }
+}
@@ -0,0 +1,117 @@
+//! The registry of the drivers for PCI functions. Each enumerated function is attached to the
+//! first registered driver that matches it.
+
+use alloc::vec::Vec;
+use spinning_top::Spinlock;
+use x86_64::instructions::interrupts;
+
+use crate::{
+    pci::{self, PCIClass, PCIFunction},
+    prelude::*,
+};
+
+/// What functions a driver is for.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum DeviceMatch {
+    Class(PCIClass),
+    Id { vendor_id: u16, device_id: u16 },
+}
+
+impl DeviceMatch {
+    fn matches(&self, func: &PCIFunction) -> bool {
+        match *self {
+            DeviceMatch::Class(class) => *func.class() == class,
+            DeviceMatch::Id {
+                vendor_id,
+                device_id,
+            } => *func.vendor_id() == vendor_id && *func.device_id() == device_id,
+        }
+    }
+}
+
+pub struct Driver {
+    pub name: &'static str,
+    /// The function has to match one of these to be probed.
+    pub matches: &'static [DeviceMatch],
+    /// Tells whether the driver can drive the function, for what `matches` can't tell.
+    pub probe: fn(&PCIFunction) -> bool,
+    /// Sets up the function.
+    pub attach: fn(&PCIFunction) -> Result<()>,
+}
+
+struct Attachment {
+    function: PCIFunction,
+    driver: &'static str,
+}
+
+struct Registry {
+    drivers: Vec<&'static Driver>,
+    attachments: Vec<Attachment>,
+}
+
+static REGISTRY: Spinlock<Registry> = Spinlock::new(Registry {
+    drivers: Vec::new(),
+    attachments: Vec::new(),
+});
+
+fn with_registry<T, F: FnOnce(&mut Registry) -> T>(f: F) -> T {
+    interrupts::without_interrupts(|| f(&mut REGISTRY.lock()))
+}
+
+pub fn register(driver: &'static Driver) {
+    with_registry(|registry| registry.drivers.push(driver));
+}
+
+/// Attaches every function no driver has attached yet to the first driver that matches it and
+/// probes it. A function without a driver, or that fails to be attached, is left alone.
+pub fn attach_all() {
+    let drivers = with_registry(|registry| registry.drivers.clone());
+    for func in pci::functions() {
+        if attached_driver(&func).is_some() {
+            continue;
+        }
+        let driver = drivers.iter().find(|driver| {
+            driver.matches.iter().any(|m| m.matches(&func)) && (driver.probe)(&func)
+        });
+        let driver = match driver {
+            Some(driver) => driver,
+            None => continue,
+        };
+        match (driver.attach)(&func) {
+            Ok(()) => {
+                log::info!(
+                    "{} is attached to {:02x}:{:02x}.{}",
+                    driver.name,
+                    func.bus(),
+                    func.device(),
+                    func.function()
+                );
+                with_registry(|registry| {
+                    registry.attachments.push(Attachment {
+                        function: func,
+                        driver: driver.name,
+                    })
+                });
+            }
+            Err(e) => log::warn!(
+                "{} failed to initialize the device at {:02x}:{:02x}.{}: {:?}",
+                driver.name,
+                func.bus(),
+                func.device(),
+                func.function(),
+                e
+            ),
+        }
+    }
+}
+
+/// The name of the driver attached to `func`, if any.
+pub fn attached_driver(func: &PCIFunction) -> Option<&'static str> {
+    with_registry(|registry| {
+        registry
+            .attachments
+            .iter()
+            .find(|attachment| attachment.function == *func)
+            .map(|attachment| attachment.driver)
+    })
+}
@@ -7,7 +7,10 @@ use x86_64::instructions::interrupts;
 
 use crate::{
     graphics::Rectangle,
-    gui::{window_manager::WindowId, GUI},
+    gui::{
+        window_manager::{WindowId, WindowManager},
+        GUI,
+    },
     keyboard::{self, KeyCode},
     prelude::*,
     task::{self, spawn_task, Receiver, TypedTaskHandle},
@@ -18,7 +21,11 @@ lazy_static! {
     static ref XHCI_HANDLE: AtomicPtr<TypedTaskHandle<u8>> = AtomicPtr::default();
     static ref GUI_HANDLE: AtomicPtr<TypedTaskHandle<Event>> = AtomicPtr::default();
     static ref REDRAW_QUEUE: Spinlock<VecDeque<Event>> = Spinlock::new(VecDeque::new());
+    static ref WINDOW_MANAGER_REQUESTS: Spinlock<VecDeque<WindowManagerRequest>> =
+        Spinlock::new(VecDeque::new());
 }
+/// Something to be done with the window manager, which only the GUI task owns.
+pub type WindowManagerRequest = Box<dyn FnOnce(&mut WindowManager) + Send>;
 const MAX_PENDING_REDRAW: usize = 10;
 
 pub fn initialize() -> Receiver<Event> {
@@ -37,7 +44,7 @@ extern "sysv64" fn xhci_handler(mut receiver: Box<Receiver<u8>>) {
     loop {
         x86_64::instructions::interrupts::enable();
         let _ = receiver.dequeue_or_wait();
-        log::info!("Got a XHCI event");
+        log::debug!("Got a XHCI event");
         xhci::handle_events();
     }
 }
@@ -110,11 +117,22 @@ pub fn fire_redraw_area(area: Rectangle) {
     with_handle(&GUI_HANDLE, |q| q.awake());
 }
 
+/// Asks the GUI task to run `f` with the window manager.
+pub fn request_window_manager(f: WindowManagerRequest) {
+    interrupts::without_interrupts(|| WINDOW_MANAGER_REQUESTS.lock().push_back(f));
+    with_handle(&GUI_HANDLE, |q| q.awake());
+}
+
 pub fn event_loop(mut gui: GUI) -> Result<!> {
     // crate::task::current_task().set_priority(3);
     log::info!("Start event loop");
     loop {
         let state = gui.event_receiver.handle().load_state();
+        while let Some(f) =
+            interrupts::without_interrupts(|| WINDOW_MANAGER_REQUESTS.lock().pop_front())
+        {
+            gui.with_window_manager(f);
+        }
         let event = gui
             .event_receiver
             .try_dequeue()
@@ -125,7 +143,7 @@ pub fn event_loop(mut gui: GUI) -> Result<!> {
             gui.event_receiver.handle().try_compare_and_sleep(state);
             continue;
         };
-        log::info!("Got an event {:?}", event);
+        log::debug!("Got an event {:?}", event);
         match event {
             Event::Drag { start, end } => {
                 gui.drag(start, end);
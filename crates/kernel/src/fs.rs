@@ -0,0 +1,492 @@
+//! Filesystems, and the virtual filesystem that puts them together under a single tree.
+//!
+//! Filesystems are mounted at absolute paths, and a path is resolved in the filesystem mounted at
+//! its longest prefix. The directories above the mount points that no filesystem covers (e.g. `/`)
+//! are made up from the mount table.
+
+use core::any::Any;
+
+use alloc::{format, string::String, sync::Arc, vec, vec::Vec};
+use bitflags::bitflags;
+use pomelo_common::graphics::GraphicConfig;
+use spinning_top::Spinlock;
+
+use crate::{
+    block::{self, partition, BlockDevice},
+    initrd,
+    prelude::*,
+    task,
+};
+
+pub mod dev;
+pub mod fat;
+pub mod tmpfs;
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub enum FileType {
+    Regular,
+    Directory,
+    CharacterDevice,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Metadata {
+    pub file_type: FileType,
+    pub size: u64,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct DirectoryEntry {
+    pub name: String,
+    pub file_type: FileType,
+}
+
+/// A file, a directory or a device in a filesystem. The operations that don't make sense for the
+/// node fail by default.
+pub trait Inode: Send + Sync {
+    fn metadata(&self) -> Metadata;
+
+    fn read_at(&self, _offset: u64, _buffer: &mut [u8]) -> Result<usize> {
+        Err(Error::Whatever("Not readable"))
+    }
+
+    fn write_at(&self, _offset: u64, _data: &[u8]) -> Result<usize> {
+        Err(Error::Whatever("Not writable"))
+    }
+
+    fn lookup(&self, _name: &str) -> Result<Arc<dyn Inode>> {
+        Err(Error::Whatever("Not a directory"))
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirectoryEntry>> {
+        Err(Error::Whatever("Not a directory"))
+    }
+
+    /// Creates an empty file or directory named `name` in this directory.
+    fn create(&self, _name: &str, _file_type: FileType) -> Result<Arc<dyn Inode>> {
+        Err(Error::Whatever("Not supported by the filesystem"))
+    }
+
+    /// Removes the file or the empty directory named `name` from this directory.
+    fn unlink(&self, _name: &str) -> Result<()> {
+        Err(Error::Whatever("Not supported by the filesystem"))
+    }
+
+    /// Moves the entry `old_name` in this directory to `new_name` in `new_parent`, which is in the
+    /// same filesystem.
+    fn rename(&self, _old_name: &str, _new_parent: &dyn Inode, _new_name: &str) -> Result<()> {
+        Err(Error::Whatever("Not supported by the filesystem"))
+    }
+
+    /// Changes the size of the file, filling the extended part with zeros.
+    fn truncate(&self, _size: u64) -> Result<()> {
+        Err(Error::Whatever("Not writable"))
+    }
+
+    /// Whether this is an interactive terminal, for `isatty`.
+    fn is_terminal(&self) -> bool {
+        false
+    }
+
+    /// Lets a filesystem recognize its own nodes, e.g. the destination of `rename`.
+    fn as_any(&self) -> Option<&dyn Any> {
+        None
+    }
+}
+
+pub trait FileSystem: Send + Sync {
+    fn root(self: Arc<Self>) -> Arc<dyn Inode>;
+}
+
+struct Mount {
+    components: Vec<String>,
+    file_system: Arc<dyn FileSystem>,
+}
+
+static MOUNTS: Spinlock<Vec<Mount>> = Spinlock::new(Vec::new());
+
+fn with_mounts<T, F: FnOnce(&mut Vec<Mount>) -> T>(f: F) -> T {
+    x86_64::instructions::interrupts::without_interrupts(|| f(&mut MOUNTS.lock()))
+}
+
+/// Mounts `/dev`, a tmpfs at `/tmp`, and the initrd at `/initrd` if it has a filesystem.
+pub fn initialize(graphic_config: &GraphicConfig) -> Result<()> {
+    mount("/dev", Arc::new(dev::DevFs::new(graphic_config)))?;
+    mount("/tmp", Arc::new(tmpfs::TmpFs::default()))?;
+    if let Some(file_system) = initrd::file_system() {
+        mount("/initrd", file_system)?;
+    }
+    Ok(())
+}
+
+/// Where the volume the kernel was loaded from is mounted.
+pub const BOOT_VOLUME_PATH: &str = "/boot";
+/// The kernel file on the boot volume, which the bootloader loads as `\kernel`.
+const KERNEL_FILE: &str = "kernel";
+
+/// Mounts the FAT volumes on the block devices but the initrd, which are the partitions of a
+/// device, or the whole device if it has no partition table. The boot volume is mounted at
+/// `BOOT_VOLUME_PATH`, and the others at `/mnt/<device>` or `/mnt/<device>p<partition>`.
+/// This has to come after the drivers have registered their devices.
+pub fn mount_volumes() {
+    for (name, device) in block::devices() {
+        if name.starts_with("initrd") {
+            continue;
+        }
+        for (volume_name, volume) in volumes(&name, device) {
+            let file_system = match fat::FatFileSystem::new(volume) {
+                Ok(file_system) => Arc::new(file_system),
+                Err(_) => continue,
+            };
+            let is_boot_volume = file_system
+                .lookup(KERNEL_FILE)
+                .map_or(false, |entry| !entry.is_directory());
+            let path = if is_boot_volume && lookup(BOOT_VOLUME_PATH).is_err() {
+                String::from(BOOT_VOLUME_PATH)
+            } else {
+                format!("/mnt/{}", volume_name)
+            };
+            match mount(&path, file_system.clone()) {
+                Ok(()) => log::info!(
+                    "Mounted {} ({:?}) at {}",
+                    volume_name,
+                    file_system.fat_type(),
+                    path
+                ),
+                Err(e) => log::warn!("Failed to mount {}: {:?}", volume_name, e),
+            }
+        }
+    }
+}
+
+/// The volumes on the device `name` with their names.
+fn volumes(name: &str, device: Arc<dyn BlockDevice>) -> Vec<(String, Arc<dyn BlockDevice>)> {
+    // A volume on the whole device starts with its boot sector, which isn't a partition table.
+    if fat::FatFileSystem::new(device.clone()).is_ok() {
+        return vec![(String::from(name), device)];
+    }
+    match partition::partitions(&device) {
+        Ok(partitions) => partitions
+            .into_iter()
+            .map(|p| {
+                let volume_name = format!("{}p{}", name, p.number());
+                (volume_name, Arc::new(p) as Arc<dyn BlockDevice>)
+            })
+            .collect(),
+        Err(e) => {
+            log::warn!("Failed to read the partitions of {}: {:?}", name, e);
+            Vec::new()
+        }
+    }
+}
+
+/// Splits `path` into its components, resolving `.` and `..`. Relative paths are resolved from the
+/// root, since there's no working directory.
+fn components(path: &str) -> Vec<&str> {
+    let mut components = Vec::new();
+    for component in path.split('/') {
+        match component {
+            "" | "." => {}
+            ".." => {
+                components.pop();
+            }
+            c => components.push(c),
+        }
+    }
+    components
+}
+
+fn is_prefix(prefix: &[String], components: &[&str]) -> bool {
+    prefix.len() <= components.len() && prefix.iter().zip(components).all(|(a, b)| a == b)
+}
+
+pub fn mount(path: &str, file_system: Arc<dyn FileSystem>) -> Result<()> {
+    let components: Vec<String> = components(path).into_iter().map(String::from).collect();
+    with_mounts(|mounts| {
+        if mounts.iter().any(|m| m.components == components) {
+            return Err(Error::Whatever("Something is already mounted there"));
+        }
+        mounts.push(Mount {
+            components,
+            file_system,
+        });
+        Ok(())
+    })
+}
+
+/// Finds the node at `path`.
+pub fn lookup(path: &str) -> Result<Arc<dyn Inode>> {
+    let components = components(path);
+    let mount = with_mounts(|mounts| {
+        mounts
+            .iter()
+            .filter(|m| is_prefix(&m.components, &components))
+            .max_by_key(|m| m.components.len())
+            .map(|m| (m.file_system.clone(), m.components.len()))
+    });
+    let (file_system, depth) = match mount {
+        Some(mount) => mount,
+        None => {
+            let is_above_mount_point = with_mounts(|mounts| {
+                mounts.iter().any(|m| {
+                    m.components.len() > components.len()
+                        && is_prefix(&m.components[..components.len()], &components)
+                })
+            });
+            return if is_above_mount_point {
+                Ok(Arc::new(MountPointDirectory {
+                    components: components.into_iter().map(String::from).collect(),
+                }))
+            } else {
+                Err(Error::Whatever("No such file or directory"))
+            };
+        }
+    };
+    let mut inode = file_system.root();
+    for component in &components[depth..] {
+        inode = inode.lookup(component)?;
+    }
+    Ok(inode)
+}
+
+/// The parent directory of `path`, and the last component of `path`.
+fn parent(path: &str) -> Result<(Arc<dyn Inode>, &str)> {
+    let mut components = components(path);
+    let name = components
+        .pop()
+        .ok_or(Error::Whatever("The root has no parent"))?;
+    Ok((lookup(&format!("/{}", components.join("/")))?, name))
+}
+
+/// Creates an empty file or directory at `path`.
+pub fn create(path: &str, file_type: FileType) -> Result<Arc<dyn Inode>> {
+    let (parent, name) = parent(path)?;
+    parent.create(name, file_type)
+}
+
+/// Removes the file or the empty directory at `path`.
+pub fn unlink(path: &str) -> Result<()> {
+    let (parent, name) = parent(path)?;
+    parent.unlink(name)
+}
+
+/// Moves the file or the directory at `old_path` to `new_path` in the same filesystem.
+pub fn rename(old_path: &str, new_path: &str) -> Result<()> {
+    let old_components = components(old_path);
+    let new_components = components(new_path);
+    if new_components.len() > old_components.len()
+        && new_components[..old_components.len()] == old_components[..]
+    {
+        return Err(Error::Whatever("Can't move a directory into itself"));
+    }
+    let (old_parent, old_name) = parent(old_path)?;
+    let (new_parent, new_name) = parent(new_path)?;
+    old_parent.rename(old_name, &*new_parent, new_name)
+}
+
+/// Reads the whole content of a file.
+pub fn read_all(inode: &dyn Inode) -> Result<Vec<u8>> {
+    let mut data = vec![0; inode.metadata().size as usize];
+    let mut read = 0;
+    while read < data.len() {
+        match inode.read_at(read as u64, &mut data[read..])? {
+            0 => break,
+            n => read += n,
+        }
+    }
+    data.truncate(read);
+    Ok(data)
+}
+
+/// A directory above mount points, which lists the next components of them.
+struct MountPointDirectory {
+    components: Vec<String>,
+}
+
+impl Inode for MountPointDirectory {
+    fn metadata(&self) -> Metadata {
+        Metadata {
+            file_type: FileType::Directory,
+            size: 0,
+        }
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>> {
+        lookup(&format!("/{}/{}", self.components.join("/"), name))
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirectoryEntry>> {
+        let depth = self.components.len();
+        let mut names: Vec<String> = with_mounts(|mounts| {
+            mounts
+                .iter()
+                .filter(|m| m.components.len() > depth && m.components[..depth] == self.components)
+                .map(|m| m.components[depth].clone())
+                .collect()
+        });
+        names.sort();
+        names.dedup();
+        Ok(names
+            .into_iter()
+            .map(|name| DirectoryEntry {
+                name,
+                file_type: FileType::Directory,
+            })
+            .collect())
+    }
+}
+
+bitflags! {
+    pub struct OpenFlags: u32 {
+        const READ     = 0b0001;
+        const WRITE    = 0b0010;
+        /// Creates the file if it doesn't exist.
+        const CREATE   = 0b0100;
+        /// Empties the file.
+        const TRUNCATE = 0b1000;
+    }
+}
+
+pub enum SeekFrom {
+    Start(u64),
+    Current(i64),
+    End(i64),
+}
+
+/// A file opened by a task. File descriptors duplicated from the same open share the offset.
+pub struct OpenFile {
+    inode: Arc<dyn Inode>,
+    flags: OpenFlags,
+    offset: Spinlock<u64>,
+}
+
+impl OpenFile {
+    pub fn new(inode: Arc<dyn Inode>, flags: OpenFlags) -> Arc<Self> {
+        Arc::new(Self {
+            inode,
+            flags,
+            offset: Spinlock::new(0),
+        })
+    }
+
+    pub fn inode(&self) -> &Arc<dyn Inode> {
+        &self.inode
+    }
+
+    pub fn read(&self, buffer: &mut [u8]) -> Result<usize> {
+        if !self.flags.contains(OpenFlags::READ) {
+            return Err(Error::Whatever("The file is not opened for reading"));
+        }
+        let mut offset = self.offset.lock();
+        let read = self.inode.read_at(*offset, buffer)?;
+        *offset += read as u64;
+        Ok(read)
+    }
+
+    pub fn write(&self, data: &[u8]) -> Result<usize> {
+        if !self.flags.contains(OpenFlags::WRITE) {
+            return Err(Error::Whatever("The file is not opened for writing"));
+        }
+        let mut offset = self.offset.lock();
+        let written = self.inode.write_at(*offset, data)?;
+        *offset += written as u64;
+        Ok(written)
+    }
+
+    /// Moves the offset, and returns the new one.
+    pub fn seek(&self, position: SeekFrom) -> Result<u64> {
+        let mut offset = self.offset.lock();
+        let new_offset = match position {
+            SeekFrom::Start(n) => Some(n),
+            SeekFrom::Current(n) => add_signed(*offset, n),
+            SeekFrom::End(n) => add_signed(self.inode.metadata().size, n),
+        }
+        .ok_or(Error::Whatever("Invalid offset"))?;
+        *offset = new_offset;
+        Ok(new_offset)
+    }
+}
+
+fn add_signed(base: u64, n: i64) -> Option<u64> {
+    if n >= 0 {
+        base.checked_add(n as u64)
+    } else {
+        base.checked_sub(n.unsigned_abs())
+    }
+}
+
+/// The files opened by a task, indexed by file descriptors.
+pub struct FileDescriptorTable {
+    files: Vec<Option<Arc<OpenFile>>>,
+}
+
+impl FileDescriptorTable {
+    /// A table with the console opened as the standard input, output and error.
+    pub fn with_console() -> Self {
+        let console = OpenFile::new(dev::console(), OpenFlags::READ | OpenFlags::WRITE);
+        Self {
+            files: vec![Some(console.clone()), Some(console.clone()), Some(console)],
+        }
+    }
+
+    /// Adds `file` at the lowest free file descriptor, and returns it.
+    pub fn insert(&mut self, file: Arc<OpenFile>) -> usize {
+        if let Some(fd) = self.files.iter().position(Option::is_none) {
+            self.files[fd] = Some(file);
+            fd
+        } else {
+            self.files.push(Some(file));
+            self.files.len() - 1
+        }
+    }
+
+    pub fn get(&self, fd: usize) -> Option<Arc<OpenFile>> {
+        self.files.get(fd).cloned().flatten()
+    }
+
+    /// Puts `file` at `fd`, and returns the file that was there.
+    pub fn set(&mut self, fd: usize, file: Arc<OpenFile>) -> Option<Arc<OpenFile>> {
+        if self.files.len() <= fd {
+            self.files.resize(fd + 1, None);
+        }
+        self.files[fd].replace(file)
+    }
+
+    pub fn remove(&mut self, fd: usize) -> Option<Arc<OpenFile>> {
+        self.files.get_mut(fd).and_then(Option::take)
+    }
+
+    pub fn clear(&mut self) {
+        self.files.clear();
+    }
+}
+
+/// Opens `path` in the current task, and returns the file descriptor.
+pub fn open(path: &str, flags: OpenFlags) -> Result<usize> {
+    let inode = match lookup(path) {
+        Ok(inode) => inode,
+        Err(_) if flags.contains(OpenFlags::CREATE) => create(path, FileType::Regular)?,
+        Err(e) => return Err(e),
+    };
+    if flags.contains(OpenFlags::TRUNCATE) {
+        inode.truncate(0)?;
+    }
+    let file = OpenFile::new(inode, flags);
+    Ok(task::current_task().with_files(|files| files.insert(file)))
+}
+
+/// The file opened as `fd` in the current task.
+pub fn file(fd: usize) -> Result<Arc<OpenFile>> {
+    task::current_task()
+        .with_files(|files| files.get(fd))
+        .ok_or(Error::Whatever("Bad file descriptor"))
+}
+
+pub fn close(fd: usize) -> Result<()> {
+    let file = task::current_task()
+        .with_files(|files| files.remove(fd))
+        .ok_or(Error::Whatever("Bad file descriptor"))?;
+    // The file is released outside of the lock of the table.
+    drop(file);
+    Ok(())
+}
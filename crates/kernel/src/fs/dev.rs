@@ -0,0 +1,226 @@
+//! Device files under `/dev`.
+
+use alloc::{boxed::Box, string::String, sync::Arc, vec, vec::Vec};
+use pomelo_common::graphics::GraphicConfig;
+use spinning_top::Spinlock;
+use x86_64::{PhysAddr, VirtAddr};
+
+use super::{DirectoryEntry, FileSystem, FileType, Inode, Metadata};
+use crate::{paging, prelude::*, serial, task};
+
+lazy_static! {
+    static ref CONSOLE: Arc<Console> = Arc::new(Console);
+}
+
+/// The console, which the tasks have opened as the standard input, output and error.
+pub fn console() -> Arc<dyn Inode> {
+    CONSOLE.clone()
+}
+
+/// A terminal whose writes go to `output`, e.g. the terminal window a task was started from.
+pub fn output(output: Box<dyn FnMut(&str) + Send>) -> Arc<dyn Inode> {
+    Arc::new(Output {
+        output: Spinlock::new(output),
+    })
+}
+
+pub struct DevFs {
+    root: Arc<DeviceDirectory>,
+}
+
+impl DevFs {
+    pub fn new(graphic_config: &GraphicConfig) -> Self {
+        let mut devices = vec![
+            ("console", console()),
+            (
+                "fb",
+                Arc::new(FrameBuffer::new(graphic_config)) as Arc<dyn Inode>,
+            ),
+        ];
+        if serial::is_ready() {
+            devices.push(("serial", Arc::new(Serial)));
+        }
+        Self {
+            root: Arc::new(DeviceDirectory { devices }),
+        }
+    }
+}
+
+impl FileSystem for DevFs {
+    fn root(self: Arc<Self>) -> Arc<dyn Inode> {
+        self.root.clone()
+    }
+}
+
+struct DeviceDirectory {
+    devices: Vec<(&'static str, Arc<dyn Inode>)>,
+}
+
+impl Inode for DeviceDirectory {
+    fn metadata(&self) -> Metadata {
+        Metadata {
+            file_type: FileType::Directory,
+            size: 0,
+        }
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>> {
+        self.devices
+            .iter()
+            .find(|(n, _)| *n == name)
+            .map(|(_, device)| device.clone())
+            .ok_or(Error::Whatever("No such device"))
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirectoryEntry>> {
+        Ok(self
+            .devices
+            .iter()
+            .map(|(name, _)| DirectoryEntry {
+                name: String::from(*name),
+                file_type: FileType::CharacterDevice,
+            })
+            .collect())
+    }
+}
+
+/// Writes go to the console window. There's no input from it yet, so reads always hit the end.
+struct Console;
+
+impl Inode for Console {
+    fn metadata(&self) -> Metadata {
+        Metadata {
+            file_type: FileType::CharacterDevice,
+            size: 0,
+        }
+    }
+
+    fn read_at(&self, _offset: u64, _buffer: &mut [u8]) -> Result<usize> {
+        Ok(0)
+    }
+
+    fn write_at(&self, _offset: u64, data: &[u8]) -> Result<usize> {
+        print!("{}", String::from_utf8_lossy(data));
+        Ok(data.len())
+    }
+
+    fn is_terminal(&self) -> bool {
+        true
+    }
+}
+
+struct Output {
+    output: Spinlock<Box<dyn FnMut(&str) + Send>>,
+}
+
+impl Inode for Output {
+    fn metadata(&self) -> Metadata {
+        Metadata {
+            file_type: FileType::CharacterDevice,
+            size: 0,
+        }
+    }
+
+    fn read_at(&self, _offset: u64, _buffer: &mut [u8]) -> Result<usize> {
+        Ok(0)
+    }
+
+    fn write_at(&self, _offset: u64, data: &[u8]) -> Result<usize> {
+        let text = String::from_utf8_lossy(data);
+        x86_64::instructions::interrupts::without_interrupts(|| (self.output.lock())(&text));
+        Ok(data.len())
+    }
+
+    fn is_terminal(&self) -> bool {
+        true
+    }
+}
+
+/// COM1. Reads wait for at least a byte, and only one task is woken up when bytes arrive.
+struct Serial;
+
+impl Inode for Serial {
+    fn metadata(&self) -> Metadata {
+        Metadata {
+            file_type: FileType::CharacterDevice,
+            size: 0,
+        }
+    }
+
+    fn read_at(&self, _offset: u64, buffer: &mut [u8]) -> Result<usize> {
+        if buffer.is_empty() {
+            return Ok(0);
+        }
+        let current = task::current_task();
+        serial::set_waiter(Some(current.clone()));
+        let read = loop {
+            let state = current.load_state();
+            let mut read = 0;
+            while read < buffer.len() {
+                match serial::try_read_byte() {
+                    Some(byte) => buffer[read] = byte,
+                    None => break,
+                }
+                read += 1;
+            }
+            if read > 0 {
+                break read;
+            }
+            current.try_compare_and_sleep(state);
+        };
+        serial::set_waiter(None);
+        Ok(read)
+    }
+
+    fn write_at(&self, _offset: u64, data: &[u8]) -> Result<usize> {
+        serial::write_fmt(format_args!("{}", String::from_utf8_lossy(data)));
+        Ok(data.len())
+    }
+
+    fn is_terminal(&self) -> bool {
+        true
+    }
+}
+
+/// The raw frame buffer. It's drawn over by the GUI, so it's mostly useful for screenshots.
+struct FrameBuffer {
+    base: VirtAddr,
+    size: usize,
+}
+
+impl FrameBuffer {
+    fn new(config: &GraphicConfig) -> Self {
+        Self {
+            base: paging::phys_to_virt(PhysAddr::new(config.frame_buffer_base as u64)),
+            size: config.frame_buffer_size,
+        }
+    }
+
+    /// The part of the frame buffer from `offset` up to `length` bytes.
+    fn range(&self, offset: u64, length: usize) -> (*mut u8, usize) {
+        let offset = usize::try_from(offset).unwrap_or(usize::MAX).min(self.size);
+        let length = length.min(self.size - offset);
+        ((self.base + offset).as_mut_ptr(), length)
+    }
+}
+
+impl Inode for FrameBuffer {
+    fn metadata(&self) -> Metadata {
+        Metadata {
+            file_type: FileType::CharacterDevice,
+            size: self.size as u64,
+        }
+    }
+
+    fn read_at(&self, offset: u64, buffer: &mut [u8]) -> Result<usize> {
+        let (pointer, length) = self.range(offset, buffer.len());
+        unsafe { core::ptr::copy_nonoverlapping(pointer, buffer.as_mut_ptr(), length) };
+        Ok(length)
+    }
+
+    fn write_at(&self, offset: u64, data: &[u8]) -> Result<usize> {
+        let (pointer, length) = self.range(offset, data.len());
+        unsafe { core::ptr::copy_nonoverlapping(data.as_ptr(), pointer, length) };
+        Ok(length)
+    }
+}
@@ -0,0 +1,501 @@
+//! A read-only driver for FAT12/16/32 volumes, including long file names.
+
+use alloc::{string::String, sync::Arc, vec, vec::Vec};
+use derive_getters::Getters;
+
+use super::{FileSystem, FileType, Inode, Metadata};
+use crate::{
+    block::{self, BlockDevice},
+    prelude::*,
+};
+
+const DIRECTORY_ENTRY_SIZE: usize = 32;
+const LONG_NAME_CHARS_PER_ENTRY: usize = 13;
+
+pub mod attributes {
+    pub const READ_ONLY: u8 = 0x01;
+    pub const HIDDEN: u8 = 0x02;
+    pub const SYSTEM: u8 = 0x04;
+    pub const VOLUME_ID: u8 = 0x08;
+    pub const DIRECTORY: u8 = 0x10;
+    pub const ARCHIVE: u8 = 0x20;
+    pub const LONG_NAME: u8 = READ_ONLY | HIDDEN | SYSTEM | VOLUME_ID;
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub enum FatType {
+    Fat12,
+    Fat16,
+    Fat32,
+}
+
+/// A file or a directory on the volume.
+#[derive(Clone, PartialEq, Eq, Debug, Getters)]
+pub struct DirectoryEntry {
+    name: String,
+    attributes: u8,
+    first_cluster: u32,
+    size: u32,
+}
+
+impl DirectoryEntry {
+    pub fn is_directory(&self) -> bool {
+        self.attributes & attributes::DIRECTORY != 0
+    }
+}
+
+pub struct FatFileSystem {
+    device: Arc<dyn BlockDevice>,
+    fat_type: FatType,
+    bytes_per_sector: u64,
+    sectors_per_cluster: u64,
+    /// The first sector of the first FAT.
+    fat_start_sector: u64,
+    /// The root directory of FAT12/16, which is outside of the data area.
+    root_directory_sectors: (u64, u64),
+    /// The root directory of FAT32.
+    root_cluster: u32,
+    first_data_sector: u64,
+    cluster_count: u32,
+}
+
+fn read_u16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([
+        data[offset],
+        data[offset + 1],
+        data[offset + 2],
+        data[offset + 3],
+    ])
+}
+
+impl FatFileSystem {
+    /// Parses the BIOS parameter block of the volume on `device`.
+    pub fn new(device: Arc<dyn BlockDevice>) -> Result<Self> {
+        let mut boot_sector = [0u8; 512];
+        block::read_bytes(&*device, 0, &mut boot_sector)?;
+        if boot_sector[510..512] != [0x55, 0xAA] {
+            return Err(Error::Whatever("No boot sector signature"));
+        }
+        let bytes_per_sector = u64::from(read_u16(&boot_sector, 0x0B));
+        let sectors_per_cluster = u64::from(boot_sector[0x0D]);
+        let reserved_sectors = u64::from(read_u16(&boot_sector, 0x0E));
+        let fat_count = u64::from(boot_sector[0x10]);
+        let root_entry_count = u64::from(read_u16(&boot_sector, 0x11));
+        let total_sectors = match read_u16(&boot_sector, 0x13) {
+            0 => u64::from(read_u32(&boot_sector, 0x20)),
+            n => u64::from(n),
+        };
+        let fat_size = match read_u16(&boot_sector, 0x16) {
+            0 => u64::from(read_u32(&boot_sector, 0x24)),
+            n => u64::from(n),
+        };
+        if !matches!(bytes_per_sector, 512 | 1024 | 2048 | 4096)
+            || !sectors_per_cluster.is_power_of_two()
+            || reserved_sectors == 0
+            || fat_count == 0
+            || fat_size == 0
+        {
+            return Err(Error::Whatever("Invalid BIOS parameter block"));
+        }
+
+        let fat_start_sector = reserved_sectors;
+        let root_directory_start = fat_start_sector + fat_count * fat_size;
+        let root_directory_size =
+            (root_entry_count * DIRECTORY_ENTRY_SIZE as u64).div_ceil(bytes_per_sector);
+        let first_data_sector = root_directory_start + root_directory_size;
+        let cluster_count = total_sectors
+            .checked_sub(first_data_sector)
+            .ok_or(Error::Whatever("Invalid BIOS parameter block"))?
+            / sectors_per_cluster;
+        // The type is determined only by the number of clusters.
+        let fat_type = match cluster_count {
+            0..=4084 => FatType::Fat12,
+            4085..=65524 => FatType::Fat16,
+            _ => FatType::Fat32,
+        };
+        let root_cluster = if fat_type == FatType::Fat32 {
+            read_u32(&boot_sector, 0x2C)
+        } else {
+            0
+        };
+        let file_system = Self {
+            device,
+            fat_type,
+            bytes_per_sector,
+            sectors_per_cluster,
+            fat_start_sector,
+            root_directory_sectors: (root_directory_start, root_directory_size),
+            root_cluster,
+            first_data_sector,
+            cluster_count: u32::try_from(cluster_count)
+                .map_err(|_| Error::Whatever("Invalid BIOS parameter block"))?,
+        };
+        if (total_sectors * bytes_per_sector).div_ceil(file_system.device.block_size() as u64)
+            > file_system.device.block_count()
+        {
+            return Err(Error::Whatever("The volume is larger than the device"));
+        }
+        if fat_type == FatType::Fat32 && !file_system.is_valid_cluster(root_cluster) {
+            return Err(Error::Whatever("Invalid root cluster"));
+        }
+        Ok(file_system)
+    }
+
+    pub fn fat_type(&self) -> FatType {
+        self.fat_type
+    }
+
+    fn cluster_size(&self) -> u64 {
+        self.bytes_per_sector * self.sectors_per_cluster
+    }
+
+    fn is_valid_cluster(&self, cluster: u32) -> bool {
+        (2..self.cluster_count + 2).contains(&cluster)
+    }
+
+    fn cluster_offset(&self, cluster: u32) -> u64 {
+        (self.first_data_sector + u64::from(cluster - 2) * self.sectors_per_cluster)
+            * self.bytes_per_sector
+    }
+
+    /// Looks up the FAT for the cluster following `cluster`. Returns `None` at the end of the
+    /// chain.
+    fn next_cluster(&self, cluster: u32) -> Result<Option<u32>> {
+        if !self.is_valid_cluster(cluster) {
+            return Err(Error::Whatever("Invalid cluster number"));
+        }
+        let fat_start = self.fat_start_sector * self.bytes_per_sector;
+        let cluster = u64::from(cluster);
+        let (next, end_of_chain) = match self.fat_type {
+            FatType::Fat12 => {
+                let mut entry = [0u8; 2];
+                block::read_bytes(&*self.device, fat_start + cluster * 3 / 2, &mut entry)?;
+                let entry = u16::from_le_bytes(entry);
+                let next = if cluster % 2 == 0 {
+                    entry & 0x0FFF
+                } else {
+                    entry >> 4
+                };
+                (u32::from(next), 0xFF8)
+            }
+            FatType::Fat16 => {
+                let mut entry = [0u8; 2];
+                block::read_bytes(&*self.device, fat_start + cluster * 2, &mut entry)?;
+                (u32::from(u16::from_le_bytes(entry)), 0xFFF8)
+            }
+            FatType::Fat32 => {
+                let mut entry = [0u8; 4];
+                block::read_bytes(&*self.device, fat_start + cluster * 4, &mut entry)?;
+                (u32::from_le_bytes(entry) & 0x0FFF_FFFF, 0x0FFF_FFF8)
+            }
+        };
+        if next >= end_of_chain {
+            Ok(None)
+        } else if self.is_valid_cluster(next) {
+            Ok(Some(next))
+        } else {
+            Err(Error::Whatever("Broken cluster chain"))
+        }
+    }
+
+    /// Reads all clusters of the chain starting from `first_cluster`.
+    fn read_chain(&self, first_cluster: u32) -> Result<Vec<u8>> {
+        let mut data = Vec::new();
+        let mut cluster = Some(first_cluster);
+        while let Some(current) = cluster {
+            // A chain can't be longer than the number of clusters unless it has a loop.
+            if !self.is_valid_cluster(current)
+                || data.len() as u64 >= u64::from(self.cluster_count) * self.cluster_size()
+            {
+                return Err(Error::Whatever("Broken cluster chain"));
+            }
+            let start = data.len();
+            data.resize(start + self.cluster_size() as usize, 0);
+            block::read_bytes(
+                &*self.device,
+                self.cluster_offset(current),
+                &mut data[start..],
+            )?;
+            cluster = self.next_cluster(current)?;
+        }
+        Ok(data)
+    }
+
+    /// The root directory of the volume.
+    pub fn root_entry(&self) -> DirectoryEntry {
+        DirectoryEntry {
+            name: String::new(),
+            attributes: attributes::DIRECTORY,
+            first_cluster: self.root_cluster,
+            size: 0,
+        }
+    }
+
+    /// Lists the entries in `directory`, except for `.` and `..`.
+    pub fn read_dir(&self, directory: &DirectoryEntry) -> Result<Vec<DirectoryEntry>> {
+        if !directory.is_directory() {
+            return Err(Error::Whatever("Not a directory"));
+        }
+        // The first cluster is 0 in the entries pointing to the root directory.
+        let data = match directory.first_cluster {
+            0 if self.fat_type != FatType::Fat32 => {
+                let (start, size) = self.root_directory_sectors;
+                let mut data = vec![0; (size * self.bytes_per_sector) as usize];
+                block::read_bytes(&*self.device, start * self.bytes_per_sector, &mut data)?;
+                data
+            }
+            0 => self.read_chain(self.root_cluster)?,
+            cluster => self.read_chain(cluster)?,
+        };
+        Ok(parse_directory(&data))
+    }
+
+    /// Finds the entry at `path`, whose components are separated by `/` and compared
+    /// case-insensitively.
+    pub fn lookup(&self, path: &str) -> Result<DirectoryEntry> {
+        let mut entry = self.root_entry();
+        for component in path.split('/').filter(|c| !c.is_empty()) {
+            entry = self
+                .read_dir(&entry)?
+                .into_iter()
+                .find(|e| e.name.eq_ignore_ascii_case(component))
+                .ok_or(Error::Whatever("No such file or directory"))?;
+        }
+        Ok(entry)
+    }
+
+    /// Reads the file `file` from `offset` into `buffer`, and returns the number of bytes read.
+    pub fn read(&self, file: &DirectoryEntry, offset: u64, buffer: &mut [u8]) -> Result<usize> {
+        if file.is_directory() {
+            return Err(Error::Whatever("Is a directory"));
+        }
+        let size = u64::from(file.size);
+        if offset >= size {
+            return Ok(0);
+        }
+        let length = u64::min(buffer.len() as u64, size - offset) as usize;
+        let cluster_size = self.cluster_size();
+        let mut cluster = file.first_cluster;
+        for _ in 0..offset / cluster_size {
+            cluster = self.next_cluster(cluster)?.ok_or(Error::Whatever(
+                "The cluster chain is shorter than the file",
+            ))?;
+        }
+        let mut read = 0;
+        let mut in_cluster = offset % cluster_size;
+        loop {
+            if !self.is_valid_cluster(cluster) {
+                return Err(Error::Whatever("Invalid cluster number"));
+            }
+            let chunk = usize::min(length - read, (cluster_size - in_cluster) as usize);
+            block::read_bytes(
+                &*self.device,
+                self.cluster_offset(cluster) + in_cluster,
+                &mut buffer[read..][..chunk],
+            )?;
+            read += chunk;
+            in_cluster = 0;
+            if read == length {
+                return Ok(read);
+            }
+            cluster = self.next_cluster(cluster)?.ok_or(Error::Whatever(
+                "The cluster chain is shorter than the file",
+            ))?;
+        }
+    }
+
+    /// Reads the whole content of `file`.
+    pub fn read_to_end(&self, file: &DirectoryEntry) -> Result<Vec<u8>> {
+        let mut data = vec![0; file.size as usize];
+        let read = self.read(file, 0, &mut data)?;
+        data.truncate(read);
+        Ok(data)
+    }
+}
+
+impl FileSystem for FatFileSystem {
+    fn root(self: Arc<Self>) -> Arc<dyn Inode> {
+        let entry = self.root_entry();
+        Arc::new(FatInode {
+            file_system: self,
+            entry,
+        })
+    }
+}
+
+struct FatInode {
+    file_system: Arc<FatFileSystem>,
+    entry: DirectoryEntry,
+}
+
+impl Inode for FatInode {
+    fn metadata(&self) -> Metadata {
+        Metadata {
+            file_type: if self.entry.is_directory() {
+                FileType::Directory
+            } else {
+                FileType::Regular
+            },
+            size: u64::from(self.entry.size),
+        }
+    }
+
+    fn read_at(&self, offset: u64, buffer: &mut [u8]) -> Result<usize> {
+        self.file_system.read(&self.entry, offset, buffer)
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>> {
+        let entry = self
+            .file_system
+            .read_dir(&self.entry)?
+            .into_iter()
+            .find(|e| e.name.eq_ignore_ascii_case(name))
+            .ok_or(Error::Whatever("No such file or directory"))?;
+        Ok(Arc::new(FatInode {
+            file_system: self.file_system.clone(),
+            entry,
+        }))
+    }
+
+    fn read_dir(&self) -> Result<Vec<super::DirectoryEntry>> {
+        Ok(self
+            .file_system
+            .read_dir(&self.entry)?
+            .into_iter()
+            .map(|e| super::DirectoryEntry {
+                file_type: if e.is_directory() {
+                    FileType::Directory
+                } else {
+                    FileType::Regular
+                },
+                name: e.name,
+            })
+            .collect())
+    }
+}
+
+/// A long file name being collected from the entries preceding the short one.
+struct LongName {
+    checksum: u8,
+    next_order: u8,
+    /// The parts of the name in the order they appear, i.e. the last part first.
+    parts: Vec<[u16; LONG_NAME_CHARS_PER_ENTRY]>,
+}
+
+fn parse_directory(data: &[u8]) -> Vec<DirectoryEntry> {
+    let mut entries = Vec::new();
+    let mut long_name: Option<LongName> = None;
+    for raw in data.chunks_exact(DIRECTORY_ENTRY_SIZE) {
+        match raw[0] {
+            // The end of the directory
+            0x00 => break,
+            // A deleted entry
+            0xE5 => {
+                long_name = None;
+                continue;
+            }
+            _ => {}
+        }
+        let attributes = raw[11];
+        if attributes & 0x3F == attributes::LONG_NAME {
+            long_name = collect_long_name(long_name.take(), raw);
+            continue;
+        }
+        let long_name = long_name.take();
+        if attributes & attributes::VOLUME_ID != 0 || raw[0] == b'.' {
+            continue;
+        }
+        let short_name: &[u8; 11] = raw[0..11].try_into().unwrap();
+        let name = long_name
+            .filter(|l| l.next_order == 0 && l.checksum == short_name_checksum(short_name))
+            .map(|l| decode_long_name(&l.parts))
+            .unwrap_or_else(|| decode_short_name(short_name, raw[12]));
+        entries.push(DirectoryEntry {
+            name,
+            attributes,
+            first_cluster: u32::from(read_u16(raw, 20)) << 16 | u32::from(read_u16(raw, 26)),
+            size: read_u32(raw, 28),
+        });
+    }
+    entries
+}
+
+fn collect_long_name(current: Option<LongName>, raw: &[u8]) -> Option<LongName> {
+    let order = raw[0] & 0x1F;
+    let checksum = raw[13];
+    let mut part = [0u16; LONG_NAME_CHARS_PER_ENTRY];
+    for (i, offset) in (1..11)
+        .step_by(2)
+        .chain((14..26).step_by(2))
+        .chain((28..32).step_by(2))
+        .enumerate()
+    {
+        part[i] = read_u16(raw, offset);
+    }
+    if order == 0 {
+        return None;
+    }
+    if raw[0] & 0x40 != 0 {
+        // The last part comes first.
+        return Some(LongName {
+            checksum,
+            next_order: order - 1,
+            parts: vec![part],
+        });
+    }
+    let mut current = current?;
+    if current.checksum != checksum || current.next_order != order {
+        return None;
+    }
+    current.next_order -= 1;
+    current.parts.push(part);
+    Some(current)
+}
+
+fn decode_long_name(parts: &[[u16; LONG_NAME_CHARS_PER_ENTRY]]) -> String {
+    let units = parts
+        .iter()
+        .rev()
+        .flatten()
+        .copied()
+        .take_while(|&c| c != 0x0000);
+    char::decode_utf16(units)
+        .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+        .collect()
+}
+
+/// Decodes an 8.3 name. `case_flags` is the byte Windows NT uses to mark the base name (0x08)
+/// and the extension (0x10) as lowercase.
+fn decode_short_name(name: &[u8; 11], case_flags: u8) -> String {
+    let decode = |bytes: &[u8], lowercase: bool| -> String {
+        bytes
+            .iter()
+            .enumerate()
+            .map(|(i, &b)| match b {
+                // 0x05 stands for 0xE5 at the beginning, which is the mark of deleted entries.
+                0x05 if i == 0 => 0xE5,
+                b => b,
+            })
+            .map(|b| if lowercase { b.to_ascii_lowercase() } else { b })
+            .map(char::from)
+            .collect::<String>()
+            .trim_end_matches(' ')
+            .into()
+    };
+    let mut result = decode(&name[0..8], case_flags & 0x08 != 0);
+    let extension = decode(&name[8..11], case_flags & 0x10 != 0);
+    if !extension.is_empty() {
+        result.push('.');
+        result.push_str(&extension);
+    }
+    result
+}
+
+fn short_name_checksum(name: &[u8; 11]) -> u8 {
+    name.iter()
+        .fold(0u8, |sum, &b| sum.rotate_right(1).wrapping_add(b))
+}
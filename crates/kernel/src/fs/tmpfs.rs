@@ -0,0 +1,232 @@
+//! A writable filesystem that keeps everything in the kernel heap.
+
+use core::any::Any;
+
+use alloc::{collections::BTreeMap, string::String, sync::Arc, vec::Vec};
+use spinning_top::Spinlock;
+
+use super::{DirectoryEntry, FileSystem, FileType, Inode, Metadata};
+use crate::prelude::*;
+
+/// The largest file, so that a single task can't eat up the whole heap by accident.
+const MAX_FILE_SIZE: u64 = 64 * 1024 * 1024;
+
+#[derive(Default)]
+pub struct TmpFs {
+    root: Arc<TmpDirectory>,
+}
+
+impl FileSystem for TmpFs {
+    fn root(self: Arc<Self>) -> Arc<dyn Inode> {
+        self.root.clone()
+    }
+}
+
+#[derive(Clone)]
+enum Node {
+    File(Arc<TmpFile>),
+    Directory(Arc<TmpDirectory>),
+}
+
+impl Node {
+    fn file_type(&self) -> FileType {
+        match self {
+            Node::File(_) => FileType::Regular,
+            Node::Directory(_) => FileType::Directory,
+        }
+    }
+
+    fn inode(&self) -> Arc<dyn Inode> {
+        match self {
+            Node::File(file) => file.clone(),
+            Node::Directory(directory) => directory.clone(),
+        }
+    }
+}
+
+#[derive(Default)]
+struct TmpFile {
+    data: Spinlock<Vec<u8>>,
+}
+
+/// Grows `data` to `size` bytes, failing instead of aborting if the heap is exhausted.
+fn resize(data: &mut Vec<u8>, size: u64) -> Result<()> {
+    if size > MAX_FILE_SIZE {
+        return Err(Error::Whatever("The file is too large"));
+    }
+    let size = size as usize;
+    if size > data.len() {
+        data.try_reserve(size - data.len())
+            .map_err(|_| Error::Whatever("Out of memory"))?;
+    }
+    data.resize(size, 0);
+    Ok(())
+}
+
+impl Inode for TmpFile {
+    fn metadata(&self) -> Metadata {
+        Metadata {
+            file_type: FileType::Regular,
+            size: self.data.lock().len() as u64,
+        }
+    }
+
+    fn read_at(&self, offset: u64, buffer: &mut [u8]) -> Result<usize> {
+        let data = self.data.lock();
+        let offset = usize::try_from(offset)
+            .unwrap_or(usize::MAX)
+            .min(data.len());
+        let length = buffer.len().min(data.len() - offset);
+        buffer[..length].copy_from_slice(&data[offset..][..length]);
+        Ok(length)
+    }
+
+    fn write_at(&self, offset: u64, data: &[u8]) -> Result<usize> {
+        let mut content = self.data.lock();
+        let end = offset
+            .checked_add(data.len() as u64)
+            .ok_or(Error::Whatever("The file is too large"))?;
+        if end > content.len() as u64 {
+            resize(&mut content, end)?;
+        }
+        content[offset as usize..end as usize].copy_from_slice(data);
+        Ok(data.len())
+    }
+
+    fn truncate(&self, size: u64) -> Result<()> {
+        let mut data = self.data.lock();
+        resize(&mut data, size)?;
+        data.shrink_to_fit();
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+struct TmpDirectory {
+    children: Spinlock<BTreeMap<String, Node>>,
+}
+
+fn check_name(name: &str) -> Result<()> {
+    if name.is_empty() || name == "." || name == ".." || name.contains('/') {
+        Err(Error::Whatever("Invalid file name"))
+    } else {
+        Ok(())
+    }
+}
+
+impl Inode for TmpDirectory {
+    fn metadata(&self) -> Metadata {
+        Metadata {
+            file_type: FileType::Directory,
+            size: 0,
+        }
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>> {
+        self.children
+            .lock()
+            .get(name)
+            .map(Node::inode)
+            .ok_or(Error::Whatever("No such file or directory"))
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirectoryEntry>> {
+        Ok(self
+            .children
+            .lock()
+            .iter()
+            .map(|(name, node)| DirectoryEntry {
+                name: name.clone(),
+                file_type: node.file_type(),
+            })
+            .collect())
+    }
+
+    fn create(&self, name: &str, file_type: FileType) -> Result<Arc<dyn Inode>> {
+        check_name(name)?;
+        let node = match file_type {
+            FileType::Regular => Node::File(Arc::default()),
+            FileType::Directory => Node::Directory(Arc::default()),
+            FileType::CharacterDevice => {
+                return Err(Error::Whatever("Devices can't be created in tmpfs"))
+            }
+        };
+        let mut children = self.children.lock();
+        if children.contains_key(name) {
+            return Err(Error::Whatever("Already exists"));
+        }
+        children.insert(name.into(), node.clone());
+        Ok(node.inode())
+    }
+
+    fn unlink(&self, name: &str) -> Result<()> {
+        let mut children = self.children.lock();
+        match children.get(name) {
+            None => return Err(Error::Whatever("No such file or directory")),
+            Some(Node::Directory(directory)) if !directory.children.lock().is_empty() => {
+                return Err(Error::Whatever("The directory is not empty"))
+            }
+            Some(_) => {}
+        }
+        children.remove(name);
+        Ok(())
+    }
+
+    fn rename(&self, old_name: &str, new_parent: &dyn Inode, new_name: &str) -> Result<()> {
+        check_name(new_name)?;
+        let new_parent = new_parent
+            .as_any()
+            .and_then(|any| any.downcast_ref::<TmpDirectory>())
+            .ok_or(Error::Whatever("Can't move across filesystems"))?;
+        if core::ptr::eq(self, new_parent) {
+            let mut children = self.children.lock();
+            return move_entry(&mut children, old_name, None, new_name);
+        }
+        // Lock the directories in the order of their addresses to avoid deadlocks with a rename
+        // in the opposite direction.
+        let is_self_first = (self as *const Self) < (new_parent as *const Self);
+        let (mut children, mut new_children) = if is_self_first {
+            let children = self.children.lock();
+            (children, new_parent.children.lock())
+        } else {
+            let new_children = new_parent.children.lock();
+            (self.children.lock(), new_children)
+        };
+        move_entry(&mut children, old_name, Some(&mut new_children), new_name)
+    }
+
+    fn as_any(&self) -> Option<&dyn Any> {
+        Some(self)
+    }
+}
+
+/// Moves `old_name` in `children` to `new_name` in `new_children`, or in `children` if it's
+/// `None`. An existing file at the destination is replaced.
+fn move_entry(
+    children: &mut BTreeMap<String, Node>,
+    old_name: &str,
+    new_children: Option<&mut BTreeMap<String, Node>>,
+    new_name: &str,
+) -> Result<()> {
+    let node = children
+        .get(old_name)
+        .cloned()
+        .ok_or(Error::Whatever("No such file or directory"))?;
+    if new_children.is_none() && old_name == new_name {
+        return Ok(());
+    }
+    let existing = match &new_children {
+        Some(new_children) => new_children.get(new_name),
+        None => children.get(new_name),
+    };
+    match (&node, existing) {
+        (_, None) | (Node::File(_), Some(Node::File(_))) => {}
+        _ => return Err(Error::Whatever("Already exists")),
+    }
+    children.remove(old_name);
+    match new_children {
+        Some(new_children) => new_children.insert(new_name.into(), node),
+        None => children.insert(new_name.into(), node),
+    };
+    Ok(())
+}
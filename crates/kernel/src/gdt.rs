@@ -1,3 +1,9 @@
+use core::{
+    ptr,
+    sync::atomic::{AtomicPtr, Ordering},
+};
+
+use alloc::{boxed::Box, vec};
 use x86_64::{
     registers::segmentation::SegmentSelector,
     structures::{
@@ -7,47 +13,71 @@ use x86_64::{
     VirtAddr,
 };
 
+use crate::{percpu, smp::MAX_CPUS};
+
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+pub const PAGE_FAULT_IST_INDEX: u16 = 1;
 
-lazy_static! {
-    static ref TSS: TaskStateSegment = {
-        let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096 * 5;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-
-            let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
-            stack_start + STACK_SIZE
-        };
-        tss
-    };
+/// The TSS of each CPU, indexed by the CPU number. It's written on every context switch to point
+/// RSP0 to the kernel stack of the next task, only by its own CPU with interrupts disabled.
+static TSS: [AtomicPtr<TaskStateSegment>; MAX_CPUS] =
+    [const { AtomicPtr::new(ptr::null_mut()) }; MAX_CPUS];
+/// The GDTs of all the CPUs have the same layout, so the selectors are shared.
+static SELECTORS: AtomicPtr<Selectors> = AtomicPtr::new(ptr::null_mut());
+
+fn create_tss() -> &'static mut TaskStateSegment {
+    const STACK_SIZE: usize = 4096 * 5;
+    let tss = Box::leak(Box::new(TaskStateSegment::new()));
+    // Page faults get their own stack, so that a fault on a not-yet-mapped part of a task's
+    // stack can be resolved.
+    for index in [DOUBLE_FAULT_IST_INDEX, PAGE_FAULT_IST_INDEX] {
+        let stack = Box::leak(vec![0u8; STACK_SIZE].into_boxed_slice());
+        tss.interrupt_stack_table[index as usize] = VirtAddr::from_ptr(stack.as_ptr()) + STACK_SIZE;
+    }
+    tss
 }
-lazy_static! {
-    static ref GDT: (GlobalDescriptorTable, Selectors) = {
-        let mut gdt = GlobalDescriptorTable::new();
-        let kernel_code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
-        let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
-        let kernel_data_selector = gdt.add_entry(Descriptor::kernel_data_segment());
-        (
-            gdt,
-            Selectors {
-                kernel_code_selector,
-                tss_selector,
-                kernel_data_selector,
-            },
-        )
-    };
+
+fn create_gdt(tss: &'static TaskStateSegment) -> (GlobalDescriptorTable, Selectors) {
+    let mut gdt = GlobalDescriptorTable::new();
+    // The order of the segments below is what SYSCALL/SYSRET expect:
+    // kernel code, kernel data, then user data, user code.
+    let kernel_code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
+    let kernel_data_selector = gdt.add_entry(Descriptor::kernel_data_segment());
+    let user_data_selector = gdt.add_entry(Descriptor::user_data_segment());
+    let user_code_selector = gdt.add_entry(Descriptor::user_code_segment());
+    let tss_selector = gdt.add_entry(Descriptor::tss_segment(tss));
+    (
+        gdt,
+        Selectors {
+            kernel_code_selector,
+            kernel_data_selector,
+            user_data_selector,
+            user_code_selector,
+            tss_selector,
+        },
+    )
 }
-struct Selectors {
-    kernel_code_selector: SegmentSelector,
-    tss_selector: SegmentSelector,
-    kernel_data_selector: SegmentSelector,
+
+#[derive(Copy, Clone, Debug)]
+pub struct Selectors {
+    pub kernel_code_selector: SegmentSelector,
+    pub kernel_data_selector: SegmentSelector,
+    pub user_data_selector: SegmentSelector,
+    pub user_code_selector: SegmentSelector,
+    pub tss_selector: SegmentSelector,
 }
 
-pub fn initialize() {
+/// Loads a GDT and a TSS of its own on the CPU `cpu`.
+pub fn initialize(cpu: usize) {
     use x86_64::instructions::segmentation::{Segment, CS, DS, ES, FS, GS, SS};
-    GDT.0.load();
-    let selectors = &GDT.1;
+    let tss: *mut TaskStateSegment = create_tss();
+    TSS[cpu].store(tss, Ordering::SeqCst);
+    let (gdt, selectors) = create_gdt(unsafe { &*tss });
+    let gdt = Box::leak(Box::new(gdt));
+    gdt.load();
+    if SELECTORS.load(Ordering::SeqCst).is_null() {
+        SELECTORS.store(Box::leak(Box::new(selectors)), Ordering::SeqCst);
+    }
     unsafe {
         CS::set_reg(selectors.kernel_code_selector);
         x86_64::instructions::tables::load_tss(selectors.tss_selector);
@@ -59,3 +89,21 @@ pub fn initialize() {
         GS::set_reg(selectors.kernel_data_selector);
     }
 }
+
+pub fn selectors() -> &'static Selectors {
+    let selectors = SELECTORS.load(Ordering::SeqCst);
+    assert!(!selectors.is_null(), "The GDT is not loaded yet");
+    unsafe { &*selectors }
+}
+
+/// Sets the stack the calling CPU switches to when an interrupt, exception or system call
+/// arrives in ring 3.
+pub fn set_kernel_stack(stack_top: VirtAddr) {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let per_cpu = percpu::current();
+        let tss = TSS[per_cpu.cpu()].load(Ordering::SeqCst);
+        unsafe { (*tss).privilege_stack_table[0] = stack_top };
+        // The SYSCALL entry has to switch to the kernel stack by itself.
+        per_cpu.set_kernel_stack_top(stack_top);
+    });
+}
@@ -1,4 +1,7 @@
 use pomelo_common::graphics::GraphicConfig;
+use x86_64::PhysAddr;
+
+use crate::paging;
 
 use super::{
     buffer::{BufferCanvas, ByteBuffer},
@@ -24,9 +27,10 @@ pub struct FrameBuffer {
 }
 impl FrameBuffer {
     fn new(config: &GraphicConfig) -> Self {
+        let base = paging::phys_to_virt(PhysAddr::new(config.frame_buffer_base as u64));
         Self {
             inner: unsafe {
-                core::slice::from_raw_parts_mut(config.frame_buffer_base, config.frame_buffer_size)
+                core::slice::from_raw_parts_mut(base.as_mut_ptr(), config.frame_buffer_size)
             },
         }
     }
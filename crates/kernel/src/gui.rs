@@ -150,6 +150,10 @@ impl GUI {
         self.window_manager.drag(start, end);
     }
 
+    pub fn with_window_manager<T>(&mut self, f: impl FnOnce(&mut WindowManager) -> T) -> T {
+        f(&mut self.window_manager)
+    }
+
     pub fn key_press(&mut self, key_code: KeyCode) {
         self.inc_counter();
         self.window_manager.key_press(key_code);
@@ -1,11 +1,15 @@
 use alloc::{
     boxed::Box,
     collections::VecDeque,
+    format,
     string::{String, ToString},
+    sync::Arc,
 };
 use pomelo_common::graphics::PixelFormat;
 
 use crate::{
+    driver,
+    fs::{self, FileType},
     graphics::{
         buffer::VecBufferCanvas,
         canvas::{Canvas, GLYPH_HEIGHT, GLYPH_WIDTH},
@@ -15,7 +19,10 @@ use crate::{
         window_manager::{TaskedWindowBuilder, WindowManager},
         windows::{Window, WindowEvent},
     },
-    task::Receiver,
+    loader, net,
+    pci::{self, PCIFunction},
+    prelude::{Error, Result},
+    task::{Receiver, TypedTaskHandle},
 };
 
 use super::{Framed, Widget};
@@ -23,6 +30,8 @@ use super::{Framed, Widget};
 const FG_COLOR: Color = Color::WHITE;
 const BG_COLOR: Color = Color::BLACK;
 const GLYPH_SIZE: Size = Size::new(GLYPH_WIDTH, GLYPH_HEIGHT);
+const PING_COUNT: u16 = 4;
+const ECHO_PORT: u16 = 7;
 
 pub fn create_terminal(wm: &mut WindowManager) {
     let terminal = Framed::new(
@@ -35,10 +44,12 @@ pub fn create_terminal(wm: &mut WindowManager) {
     );
 }
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Debug)]
 pub enum TerminalMessage {
     WindowEvent(WindowEvent),
     Blink,
+    /// Text written by a program started from the terminal.
+    Output(String),
 }
 impl From<WindowEvent> for TerminalMessage {
     fn from(e: WindowEvent) -> Self {
@@ -56,6 +67,7 @@ struct Terminal {
     cursor_visible: bool,
     focused: bool,
     dirty: bool,
+    handle: Option<TypedTaskHandle<TerminalMessage>>,
 }
 impl Terminal {
     fn new(pixel_format: PixelFormat, rows: usize, cols: usize) -> Self {
@@ -77,6 +89,7 @@ impl Terminal {
             cursor_visible: true,
             focused: true,
             dirty: true,
+            handle: None,
         };
         this.prompt();
         this
@@ -193,17 +206,31 @@ impl Terminal {
     }
 
     fn execute_command(&mut self, command: String) {
-        if let Some((command, args)) = command.split_once(' ') {
-            if command == "echo" {
-                for c in args.chars() {
-                    self.push_char_impl(c, false);
+        if let Some((name, args)) = command.split_once(' ') {
+            if name == "echo" {
+                if let Some((text, path)) = args.split_once(" > ") {
+                    let result = write_file(path.trim(), text);
+                    self.report_error("echo", result);
+                } else {
+                    for c in args.chars() {
+                        self.push_char_impl(c, false);
+                    }
+                    self.new_line();
                 }
-                self.new_line();
+            } else if let Some(result) = modify_files(name, args) {
+                self.report_error(name, result);
+            } else if name == "ls" {
+                self.list_directory(args.trim());
+            } else if name == "cat" {
+                self.print_file(args.trim());
+            } else if name == "ping" {
+                let result = self.ping(args.trim());
+                self.report_error("ping", result);
+            } else if name == "tcpecho" {
+                let result = start_tcp_echo(args.trim());
+                self.report_error("tcpecho", result);
             } else {
-                for c in "Unknown command".chars() {
-                    self.push_char_impl(c, false);
-                }
-                self.new_line();
+                self.run_program(&command);
             }
         } else if command == "clear" {
             self.cursor_row = 0;
@@ -211,33 +238,214 @@ impl Terminal {
             for buffer in self.buffers.iter_mut() {
                 buffer.fill_rectangle(BG_COLOR, buffer.bounding_box());
             }
+        } else if command == "ls" {
+            self.list_directory("/");
+        } else if command == "tcpecho" {
+            let result = start_tcp_echo("");
+            self.report_error("tcpecho", result);
+        } else if command == "lsblk" {
+            use core::fmt::Write;
+            for (name, device) in crate::block::devices() {
+                let size = device.block_count() * device.block_size() as u64;
+                writeln!(self.as_result_writer(), "{} {} KiB", name, size / 1024).ok();
+            }
+        } else if command == "lsirq" {
+            use core::fmt::Write;
+            for info in crate::interrupts::vectors() {
+                writeln!(
+                    self.as_result_writer(),
+                    "{:#04x} {} {}",
+                    info.vector,
+                    info.name,
+                    info.count
+                )
+                .ok();
+            }
         } else if command == "lspci" {
             use core::fmt::Write;
-            for device in crate::pci::scan_devices() {
-                for func in device.scan_functions() {
-                    let (base, sub, interface) = func.class().to_code();
+            let nodes = pci::with_device_tree(|tree| {
+                let nodes = tree.nodes().iter().enumerate();
+                nodes
+                    .map(|(i, node)| (tree.depth(i), node.function))
+                    .collect::<alloc::vec::Vec<_>>()
+            });
+            // The functions behind a bridge are indented under it.
+            for (depth, func) in nodes {
+                let indent = "  ".repeat(depth);
+                let line = describe_pci_function(&func);
+                writeln!(self.as_result_writer(), "{}{}", indent, line).ok();
+                let regions = func.regions().iter().enumerate();
+                for (i, region) in regions.filter_map(|(i, region)| Some((i, (*region)?))) {
                     writeln!(
                         self.as_result_writer(),
-                        "{:02x}:{:02x}.{} vend={:04x} head={:02x} class={:02x}.{:02x}.{:02x}",
-                        func.bus(),
-                        func.device(),
-                        func.function(),
-                        func.vendor_id(),
-                        func.header_type(),
-                        base,
-                        sub,
-                        interface
+                        "{}    Region {}: {}",
+                        indent,
+                        i,
+                        region
                     )
                     .ok();
                 }
+                if let Some(driver) = driver::attached_driver(&func) {
+                    writeln!(self.as_result_writer(), "{}    Driver: {}", indent, driver).ok();
+                }
+                for capability in func.capability_headers() {
+                    writeln!(self.as_result_writer(), "{}    {}", indent, capability).ok();
+                }
+                for capability in func.extended_capability_headers() {
+                    writeln!(self.as_result_writer(), "{}    {}", indent, capability).ok();
+                }
             }
-        } else {
-            for c in "Unknown command".chars() {
-                self.push_char_impl(c, false);
+        } else if !command.is_empty() {
+            self.run_program(&command);
+        }
+    }
+
+    fn run_program(&mut self, command_line: &str) {
+        use core::fmt::Write;
+        let args: alloc::vec::Vec<&str> = command_line.split_whitespace().collect();
+        let (name, image) = match args.first().and_then(|&name| loader::find_program(name)) {
+            Some(program) => program,
+            None => {
+                for c in "Unknown command".chars() {
+                    self.push_char_impl(c, false);
+                }
+                self.new_line();
+                return;
             }
-            self.new_line();
+        };
+        if let Err(e) = loader::spawn_program(name, &image, &args, self.output()) {
+            writeln!(self.as_result_writer(), "Failed to run {}: {:?}", name, e).ok();
         }
     }
+
+    /// Something that prints to this terminal from other tasks.
+    fn output(&self) -> Option<Box<dyn FnMut(&str) + Send>> {
+        self.handle.clone().map(|handle| {
+            Box::new(move |s: &str| handle.send(TerminalMessage::Output(s.to_string())))
+                as Box<dyn FnMut(&str) + Send>
+        })
+    }
+
+    fn ping(&self, target: &str) -> Result<()> {
+        let target = target.parse()?;
+        let output = self
+            .output()
+            .ok_or(Error::Whatever("The terminal isn't running"))?;
+        net::icmp::ping(target, PING_COUNT, output)
+    }
+
+    fn report_error(&mut self, command: &str, result: Result<()>) {
+        use core::fmt::Write;
+        if let Err(e) = result {
+            writeln!(self.as_result_writer(), "{}: {:?}", command, e).ok();
+        }
+    }
+
+    fn list_directory(&mut self, path: &str) {
+        use core::fmt::Write;
+        match fs::lookup(path).and_then(|directory| directory.read_dir()) {
+            Ok(entries) => {
+                for entry in entries {
+                    let suffix = match entry.file_type {
+                        FileType::Directory => "/",
+                        _ => "",
+                    };
+                    writeln!(self.as_result_writer(), "{}{}", entry.name, suffix).ok();
+                }
+            }
+            Err(e) => {
+                writeln!(self.as_result_writer(), "ls: {}: {:?}", path, e).ok();
+            }
+        }
+    }
+
+    fn print_file(&mut self, path: &str) {
+        use core::fmt::Write;
+        match fs::lookup(path).and_then(|file| fs::read_all(&*file)) {
+            Ok(content) => {
+                for c in String::from_utf8_lossy(&content).chars() {
+                    self.push_char_impl(c, false);
+                }
+                self.new_line();
+            }
+            Err(e) => {
+                writeln!(self.as_result_writer(), "cat: {}: {:?}", path, e).ok();
+            }
+        }
+    }
+}
+
+/// Echoes back what the clients send to `port`, 7 if it's empty.
+fn start_tcp_echo(port: &str) -> Result<()> {
+    let port = if port.is_empty() {
+        ECHO_PORT
+    } else {
+        port.parse().map_err(|_| Error::Whatever("Invalid port"))?
+    };
+    net::tcp::listen(port, Arc::new(|data: &[u8]| data.to_vec()))
+}
+
+/// A line of lspci like "00:03.0 Ethernet controller [0200]: Red Hat, Inc. Virtio network device
+/// [1af4:1000]".
+fn describe_pci_function(func: &PCIFunction) -> String {
+    let class = func.class();
+    let (base, sub, interface) = class.to_code();
+    let class_name = match (class.subclass_name(), class.name()) {
+        (Some(name), _) | (None, Some(name)) => name,
+        (None, None) => "Unknown class",
+    };
+    let interface_name = class
+        .interface_name()
+        .map(|name| format!(" ({})", name))
+        .unwrap_or_default();
+    let vendor = pci::vendor_name(*func.vendor_id()).unwrap_or("Unknown vendor");
+    let device = pci::device_name(*func.vendor_id(), *func.device_id()).unwrap_or("Device");
+    format!(
+        "{:02x}:{:02x}.{} {} [{:02x}{:02x}]{}: {} {} [{:04x}:{:04x}] (prog-if {:02x})",
+        func.bus(),
+        func.device(),
+        func.function(),
+        class_name,
+        base,
+        sub,
+        interface_name,
+        vendor,
+        device,
+        func.vendor_id(),
+        func.device_id(),
+        interface
+    )
+}
+
+/// Runs the commands that change files, or returns `None` if `command` isn't one of them.
+fn modify_files(command: &str, args: &str) -> Option<Result<()>> {
+    let args: alloc::vec::Vec<&str> = args.split_whitespace().collect();
+    let result = match (command, &args[..]) {
+        ("mkdir", &[path]) => fs::create(path, FileType::Directory).map(drop),
+        ("touch", &[path]) => fs::lookup(path)
+            .or_else(|_| fs::create(path, FileType::Regular))
+            .map(drop),
+        ("rm", &[path]) => fs::unlink(path),
+        ("mv", &[from, to]) => fs::rename(from, to),
+        ("truncate", &[path, size]) => size
+            .parse()
+            .map_err(|_| Error::Whatever("Invalid size"))
+            .and_then(|size| fs::lookup(path)?.truncate(size)),
+        ("mkdir" | "touch" | "rm" | "mv" | "truncate", _) => {
+            Err(Error::Whatever("Wrong number of arguments"))
+        }
+        _ => return None,
+    };
+    Some(result)
+}
+
+/// Replaces the content of the file at `path` with a line of `text`, creating the file if needed.
+fn write_file(path: &str, text: &str) -> Result<()> {
+    let file = fs::lookup(path).or_else(|_| fs::create(path, FileType::Regular))?;
+    file.truncate(0)?;
+    file.write_at(0, text.as_bytes())?;
+    file.write_at(text.len() as u64, b"\n")?;
+    Ok(())
 }
 
 impl Widget for Terminal {
@@ -275,6 +483,7 @@ extern "sysv64" fn terminal_main(
     mut window: Box<Window<Framed<Terminal>>>,
 ) {
     crate::timer::schedule(500, 500, receiver.handle(), TerminalMessage::Blink);
+    window.widget_mut().widget_mut().handle = Some(receiver.handle());
     loop {
         let message = receiver.dequeue_or_wait();
         let terminal = window.widget_mut().widget_mut();
@@ -287,6 +496,11 @@ extern "sysv64" fn terminal_main(
                     terminal.flip_cursor_visibility();
                 }
             }
+            TerminalMessage::Output(s) => {
+                for c in s.chars() {
+                    terminal.push_char_impl(c, false);
+                }
+            }
         }
         let terminal = window.widget_mut().widget_mut();
         if terminal.dirty {
@@ -36,6 +36,10 @@ impl WindowId {
         let id = WINDOW_ID_GENERATOR.fetch_add(1, Ordering::SeqCst);
         Self(id)
     }
+
+    pub fn as_usize(&self) -> usize {
+        self.0
+    }
 }
 
 #[derive(Debug)]
@@ -290,6 +294,28 @@ impl WindowManager {
         }
     }
 
+    /// Creates a window whose events go to the handler `f` makes out of the window.
+    pub fn create_with_handler<W: Widget, H: 'static + WindowEventHandler>(
+        &mut self,
+        builder: WindowBuilder<W>,
+        f: impl FnOnce(Window<W>) -> H,
+    ) -> WindowId {
+        let (mut handle, mut window) = self.create_window(builder.widget);
+        window.buffer();
+        let id = handle.id;
+        let mut state = handle.state.locked();
+        state.position = builder.position;
+        state.draggable = builder.draggable;
+        drop(state);
+        handle.event_handler = Box::new(f(window));
+        if builder.top {
+            self.top_layers.push(handle);
+        } else {
+            self.layers.push(handle);
+        }
+        id
+    }
+
     pub fn draw_window<C: Canvas>(&mut self, canvas: &mut C, id: WindowId) -> Option<Rectangle> {
         let mut redraw_area = None;
         for window in self.layers.iter_mut().chain(self.top_layers.iter_mut()) {
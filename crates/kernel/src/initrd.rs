@@ -0,0 +1,58 @@
+//! The initial ramdisk, which the bootloader loads from `\initrd` on the boot volume.
+
+use alloc::sync::Arc;
+use spinning_top::Spinlock;
+use x86_64::PhysAddr;
+
+use crate::{
+    block::{self, BlockDevice, RamDisk},
+    fs::fat::FatFileSystem,
+    paging,
+};
+
+static DEVICE: Spinlock<Option<Arc<dyn BlockDevice>>> = Spinlock::new(None);
+static FILE_SYSTEM: Spinlock<Option<Arc<FatFileSystem>>> = Spinlock::new(None);
+
+/// Exposes the initrd passed from the bootloader as a block device, and mounts it if it's a FAT
+/// image.
+pub fn initialize(initrd: Option<(u64, usize)>) {
+    let (physical_start, size) = match initrd {
+        Some(initrd) => initrd,
+        None => {
+            log::info!("No initrd was loaded");
+            return;
+        }
+    };
+    let data = unsafe {
+        core::slice::from_raw_parts(
+            paging::phys_to_virt(PhysAddr::new(physical_start)).as_ptr::<u8>(),
+            size,
+        )
+    };
+    let device: Arc<dyn BlockDevice> = Arc::new(RamDisk::new(data));
+    block::register("initrd", device.clone());
+    let file_system = match FatFileSystem::new(device.clone()) {
+        Ok(file_system) => {
+            log::info!("Mounted the initrd as {:?}", file_system.fat_type());
+            Some(Arc::new(file_system))
+        }
+        Err(e) => {
+            log::warn!("The initrd is not a FAT image: {:?}", e);
+            None
+        }
+    };
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        *DEVICE.lock() = Some(device);
+        *FILE_SYSTEM.lock() = file_system;
+    });
+}
+
+/// The initrd as a block device.
+pub fn device() -> Option<Arc<dyn BlockDevice>> {
+    x86_64::instructions::interrupts::without_interrupts(|| DEVICE.lock().clone())
+}
+
+/// The filesystem on the initrd, if it's a FAT image.
+pub fn file_system() -> Option<Arc<FatFileSystem>> {
+    x86_64::instructions::interrupts::without_interrupts(|| FILE_SYSTEM.lock().clone())
+}
@@ -1,13 +1,20 @@
+use alloc::vec::Vec;
 use lazy_static::lazy_static;
-use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
+use spinning_top::Spinlock;
+use x86_64::{
+    instructions::interrupts,
+    structures::idt::{InterruptDescriptorTable, InterruptStackFrame},
+    PhysAddr,
+};
 
-use crate::gdt;
+use crate::{gdt, ioapic, percpu, prelude::*, task::TaskHandle};
 
 lazy_static! {
+    /// Shared by all the CPUs, as it's never changed once built: the dynamic vectors dispatch
+    /// through the registrations, and the IST indices select the stacks in the TSS of each CPU.
     static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
         idt.breakpoint.set_handler_fn(breakpoint_handler);
-        idt.page_fault.set_handler_fn(page_fault_handler);
         idt.general_protection_fault
             .set_handler_fn(general_protection_fault_handler);
         idt.segment_not_present
@@ -16,38 +23,209 @@ lazy_static! {
             idt.double_fault
                 .set_handler_fn(double_fault_handler)
                 .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+            idt.page_fault
+                .set_handler_fn(page_fault_handler)
+                .set_stack_index(gdt::PAGE_FAULT_IST_INDEX);
         }
-        idt[InterruptIndex::XHCI as usize].set_handler_fn(interrupt_handler_xhci);
         idt[InterruptIndex::LAPICTimer as usize].set_handler_fn(interrupt_handler_lapic_timer);
+        idt[InterruptIndex::TLBShootdown as usize]
+            .set_handler_fn(interrupt_handler_tlb_shootdown);
+        idt[InterruptIndex::Reschedule as usize].set_handler_fn(interrupt_handler_reschedule);
+        idt[InterruptIndex::Spurious as usize].set_handler_fn(spurious_interrupt_handler);
+        // The masked 8259 PICs may still raise their spurious IRQ 7 and 15.
+        for vector in [ioapic::LEGACY_PIC_VECTOR_BASE + 7, ioapic::LEGACY_PIC_VECTOR_BASE + 15] {
+            idt[vector as usize].set_handler_fn(spurious_interrupt_handler);
+        }
+        for (i, handler) in DYNAMIC_HANDLER_ENTRIES.iter().enumerate() {
+            idt[DYNAMIC_VECTOR_BASE as usize + i].set_handler_fn(*handler);
+        }
         idt
     };
 }
 
+/// Loads the IDT and enables the local APIC of the calling CPU. The IDT is shared by all the
+/// CPUs, and each CPU calls this for itself.
 pub fn initialize() {
     IDT.load();
+    // The APs start with the local APIC disabled.
+    unsafe {
+        core::ptr::write_volatile(
+            local_apic_register(SPURIOUS_INTERRUPT_VECTOR_REGISTER),
+            APIC_SOFTWARE_ENABLE | InterruptIndex::Spurious as u32,
+        );
+    }
 }
 
 #[repr(u8)]
 pub enum InterruptIndex {
-    XHCI = 0x40,
     LAPICTimer = 0x41,
+    TLBShootdown = 0x42,
+    Reschedule = 0x43,
+    Spurious = 0xFF,
 }
 
-fn end_of_interrupt() {
-    unsafe {
-        core::ptr::write_volatile(0xFEE000B0 as *mut u32, 0);
-    }
+/// The vectors from `DYNAMIC_VECTOR_BASE` on are handed out to the drivers at runtime.
+const DYNAMIC_VECTOR_BASE: u8 = 0x50;
+const DYNAMIC_VECTOR_COUNT: usize = 64;
+
+/// What happens when an allocated vector is raised.
+#[derive(Clone)]
+pub enum InterruptHandler {
+    /// Called in the interrupt context, before the end of interrupt.
+    Function(fn()),
+    /// Wakes up the task, which then checks the device itself.
+    Wake(TaskHandle),
+}
+
+struct Registration {
+    vector: u8,
+    name: &'static str,
+    handler: InterruptHandler,
+    count: u64,
+}
+
+/// The allocated vectors, for diagnostics.
+#[derive(Clone, Debug)]
+pub struct VectorInfo {
+    pub vector: u8,
+    pub name: &'static str,
+    pub count: u64,
+}
+
+static REGISTRATIONS: Spinlock<Vec<Registration>> = Spinlock::new(Vec::new());
+
+type HandlerEntry = extern "x86-interrupt" fn(InterruptStackFrame);
+
+macro_rules! dynamic_handler_entries {
+    ($($index:literal),*) => {
+        [$({
+            extern "x86-interrupt" fn handler(stack_frame: InterruptStackFrame) {
+                let _gs = percpu::enter_interrupt(&stack_frame);
+                handle_dynamic_interrupt(DYNAMIC_VECTOR_BASE + $index)
+            }
+            handler as HandlerEntry
+        }),*]
+    };
+}
+
+const DYNAMIC_HANDLER_ENTRIES: [HandlerEntry; DYNAMIC_VECTOR_COUNT] = dynamic_handler_entries!(
+    0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25,
+    26, 27, 28, 29, 30, 31, 32, 33, 34, 35, 36, 37, 38, 39, 40, 41, 42, 43, 44, 45, 46, 47, 48, 49,
+    50, 51, 52, 53, 54, 55, 56, 57, 58, 59, 60, 61, 62, 63
+);
+
+fn with_registrations<T, F: FnOnce(&mut Vec<Registration>) -> T>(f: F) -> T {
+    interrupts::without_interrupts(|| f(&mut REGISTRATIONS.lock()))
+}
+
+/// Reserves a vector that runs `handler`. `name` tells who uses it in the diagnostics.
+pub fn allocate_vector(name: &'static str, handler: InterruptHandler) -> Result<u8> {
+    allocate_vectors(name, &[handler]).map(|vectors| vectors[0])
+}
+
+/// Reserves a vector for each of `handlers`, and returns the vectors in the same order.
+pub fn allocate_vectors(name: &'static str, handlers: &[InterruptHandler]) -> Result<Vec<u8>> {
+    with_registrations(|registrations| {
+        let free: Vec<u8> = (0..DYNAMIC_VECTOR_COUNT as u8)
+            .map(|i| DYNAMIC_VECTOR_BASE + i)
+            .filter(|&vector| registrations.iter().all(|r| r.vector != vector))
+            .take(handlers.len())
+            .collect();
+        if free.len() < handlers.len() {
+            return Err(Error::Whatever("Ran out of interrupt vectors"));
+        }
+        for (&vector, handler) in free.iter().zip(handlers) {
+            registrations.push(Registration {
+                vector,
+                name,
+                handler: handler.clone(),
+                count: 0,
+            });
+        }
+        Ok(free)
+    })
+}
+
+/// Replaces the handler of the allocated `vector`.
+pub fn set_handler(vector: u8, handler: InterruptHandler) -> Result<()> {
+    with_registrations(|registrations| {
+        let registration = registrations
+            .iter_mut()
+            .find(|r| r.vector == vector)
+            .ok_or(Error::Whatever("The vector is not allocated"))?;
+        registration.handler = handler;
+        Ok(())
+    })
+}
+
+/// Releases the vectors returned by `allocate_vector` or `allocate_vectors`.
+pub fn free_vectors(vectors: &[u8]) {
+    with_registrations(|registrations| registrations.retain(|r| !vectors.contains(&r.vector)))
+}
+
+/// The allocated vectors and how many times each has been raised.
+pub fn vectors() -> Vec<VectorInfo> {
+    with_registrations(|registrations| {
+        registrations
+            .iter()
+            .map(|r| VectorInfo {
+                vector: r.vector,
+                name: r.name,
+                count: r.count,
+            })
+            .collect()
+    })
 }
 
-extern "x86-interrupt" fn interrupt_handler_xhci(_stack_frame: InterruptStackFrame) {
-    log::trace!("Handling XHCI interruption");
-    crate::events::fire_xhci();
+fn handle_dynamic_interrupt(vector: u8) {
+    let handler = with_registrations(|registrations| {
+        registrations
+            .iter_mut()
+            .find(|r| r.vector == vector)
+            .map(|registration| {
+                registration.count += 1;
+                registration.handler.clone()
+            })
+    });
+    match handler {
+        Some(InterruptHandler::Function(f)) => f(),
+        Some(InterruptHandler::Wake(handle)) => handle.awake(),
+        None => log::warn!("Unexpected interrupt on vector {:#x}", vector),
+    }
     end_of_interrupt()
 }
 
-extern "x86-interrupt" fn interrupt_handler_lapic_timer(_stack_frame: InterruptStackFrame) {
+const LOCAL_APIC_BASE: u64 = 0xFEE00000;
+const LOCAL_APIC_ID_REGISTER: u64 = 0x20;
+const END_OF_INTERRUPT_REGISTER: u64 = 0xB0;
+const SPURIOUS_INTERRUPT_VECTOR_REGISTER: u64 = 0xF0;
+const APIC_SOFTWARE_ENABLE: u32 = 1 << 8;
+
+/// Returns the pointer to the local APIC register at `offset`.
+pub(crate) fn local_apic_register(offset: u64) -> *mut u32 {
+    crate::paging::phys_to_virt(PhysAddr::new(LOCAL_APIC_BASE + offset)).as_mut_ptr()
+}
+
+/// The ID of the local APIC of the calling CPU.
+pub(crate) fn local_apic_id() -> u8 {
+    let value = unsafe { core::ptr::read_volatile(local_apic_register(LOCAL_APIC_ID_REGISTER)) };
+    (value >> 24) as u8
+}
+
+fn end_of_interrupt() {
+    unsafe {
+        core::ptr::write_volatile(local_apic_register(END_OF_INTERRUPT_REGISTER), 0);
+    }
+}
+
+extern "x86-interrupt" fn interrupt_handler_lapic_timer(stack_frame: InterruptStackFrame) {
+    // The guard is dropped when this task is back here, which is on the same CPU.
+    let _gs = percpu::enter_interrupt(&stack_frame);
     log::trace!("Handling LAPIC timer interruption");
-    crate::timer::tick();
+    // Every CPU has its own timer, and the BSP keeps the time.
+    if crate::smp::is_bsp() {
+        crate::timer::tick();
+    }
     let need_context_switch = crate::task::tick_and_check_context_switch();
     end_of_interrupt();
     if need_context_switch {
@@ -57,7 +235,26 @@ extern "x86-interrupt" fn interrupt_handler_lapic_timer(_stack_frame: InterruptS
     }
 }
 
+extern "x86-interrupt" fn interrupt_handler_tlb_shootdown(stack_frame: InterruptStackFrame) {
+    let _gs = percpu::enter_interrupt(&stack_frame);
+    crate::smp::handle_tlb_shootdown();
+    end_of_interrupt();
+}
+
+/// Sent when a task of the CPU wakes up with a higher priority than the one running.
+extern "x86-interrupt" fn interrupt_handler_reschedule(stack_frame: InterruptStackFrame) {
+    let _gs = percpu::enter_interrupt(&stack_frame);
+    end_of_interrupt();
+    if let Err(e) = crate::task::try_switch_context() {
+        log::error!("Failed to switch context: {:?}", e);
+    }
+}
+
+/// Spurious interrupts don't take the end of interrupt.
+extern "x86-interrupt" fn spurious_interrupt_handler(_stack_frame: InterruptStackFrame) {}
+
 extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
+    let _gs = percpu::enter_interrupt(&stack_frame);
     log::error!("EXCEPTION: BREAKPOINT");
     log::error!("{:#?}", stack_frame);
     end_of_interrupt()
@@ -67,13 +264,39 @@ extern "x86-interrupt" fn page_fault_handler(
     stack_frame: InterruptStackFrame,
     error_code: x86_64::structures::idt::PageFaultErrorCode,
 ) {
+    let gs = percpu::enter_interrupt(&stack_frame);
+    use crate::paging::{kernel_stack, PageFaultError, USER_SPACE_END};
     use x86_64::registers::control::Cr2;
 
-    log::error!("EXCEPTION: PAGE FAULT");
-    log::error!("Accessed Address: {:?}", Cr2::read());
-    log::error!("Error Code: {:x}", error_code);
-    log::error!("{:#?}", stack_frame);
+    let address = Cr2::read();
+    let result = if kernel_stack::contains(address) {
+        kernel_stack::resolve_fault(address, error_code)
+    } else if address < USER_SPACE_END {
+        crate::task::resolve_page_fault(address, error_code)
+    } else {
+        Err(PageFaultError::NotResolvable)
+    };
+    match result {
+        Ok(()) => return,
+        Err(PageFaultError::StackOverflow(owner)) => {
+            log::error!("Stack overflow in task {}", owner.unwrap_or("(unknown)"));
+            log::error!("Accessed Address: {:?}", address);
+        }
+        Err(e) => {
+            log::error!("EXCEPTION: PAGE FAULT ({:?})", e);
+            log::error!("Accessed Address: {:?}", address);
+            log::error!("Error Code: {:x}", error_code);
+            log::error!("{:#?}", stack_frame);
+        }
+    }
 
+    // The kernel may be holding locks, and can't go on without the code that faulted.
+    if !gs.from_user() {
+        panic!("Unresolvable page fault in the kernel at {:?}", address);
+    }
+    if let Err(e) = crate::task::exit_faulting_task() {
+        log::error!("Failed to kill the faulting task: {:?}", e);
+    }
     loop {
         x86_64::instructions::hlt();
     }
@@ -83,10 +306,17 @@ extern "x86-interrupt" fn general_protection_fault_handler(
     stack_frame: InterruptStackFrame,
     error_code: u64,
 ) {
+    let gs = percpu::enter_interrupt(&stack_frame);
     log::error!("EXCEPTION: GENERAL PROTECTION FAULT");
     log::error!("Error Code: {:x}", error_code);
     log::error!("{:#?}", stack_frame);
 
+    if gs.from_user() {
+        if let Err(e) = crate::task::exit_faulting_task() {
+            log::error!("Failed to kill the faulting task: {:?}", e);
+        }
+    }
+
     loop {
         x86_64::instructions::hlt();
     }
@@ -96,6 +326,7 @@ extern "x86-interrupt" fn segment_not_present_handler(
     stack_frame: InterruptStackFrame,
     error_code: u64,
 ) {
+    let _gs = percpu::enter_interrupt(&stack_frame);
     log::error!("EXCEPTION: STACK NOT PRESENT");
     log::error!("Error Code: {:x}", error_code);
     log::error!("{:#?}", stack_frame);
@@ -109,6 +340,7 @@ extern "x86-interrupt" fn double_fault_handler(
     stack_frame: InterruptStackFrame,
     error_code: u64,
 ) -> ! {
+    let _gs = percpu::enter_interrupt(&stack_frame);
     panic!(
         "EXCEPTION: DOUBLE FAULT\nError Code: {:x}\n{:#?}",
         error_code, stack_frame
@@ -0,0 +1,269 @@
+//! The I/O APICs, which deliver the interrupts of devices without MSI.
+//!
+//! The I/O APICs and the interrupt source overrides for the ISA IRQs come from the MADT. The
+//! legacy 8259 PICs are masked once the I/O APICs take over.
+
+use acpi::platform::interrupt::{
+    InterruptModel, Polarity as AcpiPolarity, TriggerMode as AcpiTriggerMode,
+};
+use alloc::vec::Vec;
+use spinning_top::Spinlock;
+use x86_64::{
+    instructions::{interrupts, port::PortWriteOnly},
+    PhysAddr, VirtAddr,
+};
+
+use crate::{acpi_tables, msi::TriggerMode, paging, prelude::*};
+
+#[repr(u8)]
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub enum Polarity {
+    ActiveHigh = 0,
+    ActiveLow = 1,
+}
+
+/// Where an ISA IRQ arrives, after the interrupt source overrides.
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub struct LegacyIRQ {
+    pub gsi: u32,
+    pub polarity: Polarity,
+    pub trigger_mode: TriggerMode,
+}
+
+impl LegacyIRQ {
+    /// ISA IRQs are edge triggered and active high unless overridden.
+    const fn isa(irq: u8) -> Self {
+        Self {
+            gsi: irq as u32,
+            polarity: Polarity::ActiveHigh,
+            trigger_mode: TriggerMode::Edge,
+        }
+    }
+
+    /// All the ISA IRQs without overrides.
+    const fn isa_all() -> [Self; 16] {
+        let mut irqs = [Self::isa(0); 16];
+        let mut irq = 0;
+        while irq < irqs.len() {
+            irqs[irq] = Self::isa(irq as u8);
+            irq += 1;
+        }
+        irqs
+    }
+}
+
+const REGISTER_SELECT: u64 = 0x00;
+const REGISTER_WINDOW: u64 = 0x10;
+const VERSION_REGISTER: u32 = 0x01;
+const REDIRECTION_TABLE_BASE: u32 = 0x10;
+
+const REDIRECTION_MASKED: u32 = 1 << 16;
+const REDIRECTION_LEVEL_TRIGGERED: u32 = 1 << 15;
+const REDIRECTION_ACTIVE_LOW: u32 = 1 << 13;
+
+/// Where the 8259 PICs are moved before being masked, so that their spurious interrupts don't
+/// look like exceptions.
+pub const LEGACY_PIC_VECTOR_BASE: u8 = 0x20;
+const PIC1_COMMAND: u16 = 0x20;
+const PIC1_DATA: u16 = 0x21;
+const PIC2_COMMAND: u16 = 0xA0;
+const PIC2_DATA: u16 = 0xA1;
+
+struct IOAPIC {
+    id: u8,
+    registers: VirtAddr,
+    gsi_base: u32,
+    num_entries: u32,
+}
+
+impl IOAPIC {
+    fn new(id: u8, address: PhysAddr, gsi_base: u32) -> Result<Self> {
+        let mut io_apic = Self {
+            id,
+            registers: paging::mmio::map(address, 0x20)?,
+            gsi_base,
+            num_entries: 0,
+        };
+        io_apic.num_entries = ((io_apic.read(VERSION_REGISTER) >> 16) & 0xFF) + 1;
+        Ok(io_apic)
+    }
+
+    fn read(&self, register: u32) -> u32 {
+        unsafe {
+            core::ptr::write_volatile(
+                (self.registers + REGISTER_SELECT).as_mut_ptr::<u32>(),
+                register,
+            );
+            core::ptr::read_volatile((self.registers + REGISTER_WINDOW).as_ptr::<u32>())
+        }
+    }
+
+    fn write(&self, register: u32, value: u32) {
+        unsafe {
+            core::ptr::write_volatile(
+                (self.registers + REGISTER_SELECT).as_mut_ptr::<u32>(),
+                register,
+            );
+            core::ptr::write_volatile(
+                (self.registers + REGISTER_WINDOW).as_mut_ptr::<u32>(),
+                value,
+            );
+        }
+    }
+
+    fn handles(&self, gsi: u32) -> bool {
+        (self.gsi_base..self.gsi_base + self.num_entries).contains(&gsi)
+    }
+
+    fn write_redirection(&self, gsi: u32, low: u32, high: u32) {
+        let register = REDIRECTION_TABLE_BASE + (gsi - self.gsi_base) * 2;
+        // Mask the entry while it's half written.
+        self.write(register, REDIRECTION_MASKED);
+        self.write(register + 1, high);
+        self.write(register, low);
+    }
+}
+
+struct IOAPICs {
+    io_apics: Vec<IOAPIC>,
+    /// Indexed by the ISA IRQ.
+    legacy_irqs: [LegacyIRQ; 16],
+}
+
+static IO_APICS: Spinlock<IOAPICs> = Spinlock::new(IOAPICs {
+    io_apics: Vec::new(),
+    legacy_irqs: LegacyIRQ::isa_all(),
+});
+
+fn with_io_apics<T, F: FnOnce(&mut IOAPICs) -> T>(f: F) -> T {
+    interrupts::without_interrupts(|| f(&mut IO_APICS.lock()))
+}
+
+/// Finds the I/O APICs in the MADT, masks all of their entries, and disables the 8259 PICs.
+pub fn initialize() -> Result<()> {
+    let apic = match acpi_tables::tables()?.platform_info()?.interrupt_model {
+        InterruptModel::Apic(apic) => apic,
+        _ => return Err(Error::Whatever("The MADT describes no APIC")),
+    };
+    if apic.also_has_legacy_pics {
+        disable_legacy_pics();
+    }
+    let mut io_apics = Vec::new();
+    for io_apic in apic.io_apics.iter() {
+        let io_apic = IOAPIC::new(
+            io_apic.id,
+            PhysAddr::new(io_apic.address as u64),
+            io_apic.global_system_interrupt_base,
+        )?;
+        for gsi in io_apic.gsi_base..io_apic.gsi_base + io_apic.num_entries {
+            io_apic.write_redirection(gsi, REDIRECTION_MASKED, 0);
+        }
+        log::info!(
+            "I/O APIC {} handles GSI {}-{}",
+            io_apic.id,
+            io_apic.gsi_base,
+            io_apic.gsi_base + io_apic.num_entries - 1
+        );
+        io_apics.push(io_apic);
+    }
+    let mut legacy_irqs = LegacyIRQ::isa_all();
+    for source_override in apic.interrupt_source_overrides.iter() {
+        let legacy_irq = match legacy_irqs.get_mut(source_override.isa_source as usize) {
+            Some(legacy_irq) => legacy_irq,
+            None => continue,
+        };
+        legacy_irq.gsi = source_override.global_system_interrupt;
+        legacy_irq.polarity = match source_override.polarity {
+            AcpiPolarity::ActiveLow => Polarity::ActiveLow,
+            AcpiPolarity::ActiveHigh | AcpiPolarity::SameAsBus => Polarity::ActiveHigh,
+        };
+        legacy_irq.trigger_mode = match source_override.trigger_mode {
+            AcpiTriggerMode::Level => TriggerMode::Level,
+            AcpiTriggerMode::Edge | AcpiTriggerMode::SameAsBus => TriggerMode::Edge,
+        };
+    }
+    with_io_apics(|state| {
+        state.io_apics = io_apics;
+        state.legacy_irqs = legacy_irqs;
+    });
+    Ok(())
+}
+
+/// Remaps the 8259 PICs out of the exception vectors, and masks all of their IRQs.
+fn disable_legacy_pics() {
+    unsafe {
+        let mut pic1_command = PortWriteOnly::<u8>::new(PIC1_COMMAND);
+        let mut pic1_data = PortWriteOnly::<u8>::new(PIC1_DATA);
+        let mut pic2_command = PortWriteOnly::<u8>::new(PIC2_COMMAND);
+        let mut pic2_data = PortWriteOnly::<u8>::new(PIC2_DATA);
+        // ICW1: initialize, with ICW4
+        pic1_command.write(0x11);
+        pic2_command.write(0x11);
+        // ICW2: the vector offsets
+        pic1_data.write(LEGACY_PIC_VECTOR_BASE);
+        pic2_data.write(LEGACY_PIC_VECTOR_BASE + 8);
+        // ICW3: the secondary is on IRQ 2
+        pic1_data.write(1 << 2);
+        pic2_data.write(2);
+        // ICW4: 8086 mode
+        pic1_data.write(0x01);
+        pic2_data.write(0x01);
+        pic1_data.write(0xFF);
+        pic2_data.write(0xFF);
+    }
+}
+
+/// Where the ISA IRQ `irq` arrives, with the interrupt source overrides applied.
+pub fn legacy_irq(irq: u8) -> Result<LegacyIRQ> {
+    with_io_apics(|state| state.legacy_irqs.get(irq as usize).copied())
+        .ok_or(Error::Whatever("No such ISA IRQ"))
+}
+
+/// Delivers the GSI `gsi` to `vector` on the calling CPU.
+pub fn route_gsi(
+    gsi: u32,
+    vector: u8,
+    polarity: Polarity,
+    trigger_mode: TriggerMode,
+) -> Result<()> {
+    let destination = crate::interrupts::local_apic_id();
+    let mut low = vector as u32;
+    if polarity == Polarity::ActiveLow {
+        low |= REDIRECTION_ACTIVE_LOW;
+    }
+    if trigger_mode == TriggerMode::Level {
+        low |= REDIRECTION_LEVEL_TRIGGERED;
+    }
+    with_io_apics(|state| {
+        let io_apic = find_io_apic(&state.io_apics, gsi)?;
+        io_apic.write_redirection(gsi, low, (destination as u32) << 24);
+        Ok(())
+    })
+}
+
+/// Delivers the ISA IRQ `irq` to `vector` on the calling CPU.
+pub fn route_legacy_irq(irq: u8, vector: u8) -> Result<()> {
+    let legacy_irq = legacy_irq(irq)?;
+    route_gsi(
+        legacy_irq.gsi,
+        vector,
+        legacy_irq.polarity,
+        legacy_irq.trigger_mode,
+    )
+}
+
+/// Stops delivering the GSI `gsi`.
+pub fn mask_gsi(gsi: u32) -> Result<()> {
+    with_io_apics(|state| {
+        let io_apic = find_io_apic(&state.io_apics, gsi)?;
+        io_apic.write_redirection(gsi, REDIRECTION_MASKED, 0);
+        Ok(())
+    })
+}
+
+fn find_io_apic(io_apics: &[IOAPIC], gsi: u32) -> Result<&IOAPIC> {
+    io_apics
+        .iter()
+        .find(|io_apic| io_apic.handles(gsi))
+        .ok_or(Error::Whatever("No I/O APIC handles the GSI"))
+}
@@ -7,6 +7,7 @@ pub extern "C" fn observe_keyboard_event(modifiers: u8, keycode: u8) {
 }
 
 bitflags! {
+    #[derive(Default)]
     pub struct Modifiers: u8 {
         const L_CONTROL = 0b00000001;
         const L_SHIFT   = 0b00000010;
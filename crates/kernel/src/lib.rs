@@ -6,31 +6,49 @@
 #![feature(int_roundings)]
 #![feature(generic_const_exprs)]
 #![feature(naked_functions)]
+#![feature(asm_sym)]
 
 #[macro_use]
 extern crate lazy_static;
 extern crate alloc;
 
+pub mod acpi_tables;
+pub mod ahci;
 pub mod allocator;
 pub(crate) mod bitset;
+pub mod block;
+pub(crate) mod cpu_spinlock;
 mod cxx_support;
+pub(crate) mod dma;
+pub mod driver;
 pub mod events;
+pub mod fs;
 pub mod gdt;
 pub mod graphics;
 pub mod gui;
+pub mod initrd;
 pub mod interrupts;
+pub mod ioapic;
 pub(crate) mod keyboard;
+pub mod loader;
 pub mod logger;
 pub(crate) mod memory_manager;
 pub mod mpsc;
 pub mod msi;
+pub mod net;
 pub mod paging;
 pub mod pci;
+pub mod percpu;
+pub mod ps2;
 pub(crate) mod ring_buffer;
+pub mod serial;
+pub mod smp;
+pub mod syscall;
 pub mod task;
 #[allow(unused)]
 pub mod timer;
 pub mod triple_buffer;
+pub mod virtio;
 pub mod xhci;
 
 #[macro_export]
@@ -0,0 +1,223 @@
+//! Loads ELF executables into fresh address spaces and runs them in ring 3.
+
+use alloc::{borrow::Cow, boxed::Box, collections::BTreeSet, format, vec::Vec};
+use object::{
+    elf,
+    read::elf::{FileHeader as _, ProgramHeader as _},
+    Endianness,
+};
+use spinning_top::Spinlock;
+use x86_64::{
+    structures::paging::{Page, PageSize, PageTableFlags, Size4KiB},
+    VirtAddr,
+};
+
+use crate::{
+    fs::{self, FileType},
+    paging::{self, AddressSpace, USER_SPACE_END},
+    prelude::*,
+    syscall::{self, UserMessage},
+    task::{self, TypedTaskHandle},
+};
+
+type FileHeader = elf::FileHeader64<Endianness>;
+
+/// The stack is placed at the top of the user space. The page right below the end is left
+/// unmapped so that no code can sit at the very end of the canonical addresses.
+const STACK_TOP: u64 = USER_SPACE_END.as_u64() - Size4KiB::SIZE;
+const STACK_SIZE: u64 = 1024 * 1024;
+const STACK_GUARD_SIZE: u64 = 64 * 1024;
+
+/// Programs built into the kernel.
+const BUILTIN_PROGRAMS: &[(&str, &[u8])] = &[(
+    "hello",
+    include_bytes!(concat!(env!("OUT_DIR"), "/hello.elf")),
+)];
+
+/// Finds a program built into the kernel by its name.
+pub fn find_builtin(name: &str) -> Option<(&'static str, &'static [u8])> {
+    BUILTIN_PROGRAMS
+        .iter()
+        .find(|(n, _)| *n == name)
+        .map(|&(n, image)| (n, image))
+}
+
+/// Task names are `&'static str`, so the name of each distinct program loaded from a file is
+/// leaked once and reused afterwards.
+static PROGRAM_NAMES: Spinlock<BTreeSet<&'static str>> = Spinlock::new(BTreeSet::new());
+
+fn intern_name(name: &str) -> &'static str {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut names = PROGRAM_NAMES.lock();
+        if let Some(&interned) = names.get(name) {
+            return interned;
+        }
+        let interned: &'static str = Box::leak(name.into());
+        names.insert(interned);
+        interned
+    })
+}
+
+/// Finds a program built into the kernel, or else the file at the path `name`. A name without a
+/// `/` is looked up in `apps` on the boot volume.
+pub fn find_program(name: &str) -> Option<(&'static str, Cow<'static, [u8]>)> {
+    if let Some((name, image)) = find_builtin(name) {
+        return Some((name, Cow::Borrowed(image)));
+    }
+    let path = if name.contains('/') {
+        name.into()
+    } else {
+        format!("{}/apps/{}", fs::BOOT_VOLUME_PATH, name)
+    };
+    let file = fs::lookup(&path)
+        .ok()
+        .filter(|f| f.metadata().file_type == FileType::Regular)?;
+    match fs::read_all(&*file) {
+        Ok(image) => {
+            let file_name = name.rsplit('/').next().unwrap_or(name);
+            Some((intern_name(file_name), Cow::Owned(image)))
+        }
+        Err(e) => {
+            log::warn!("Failed to read {}: {:?}", path, e);
+            None
+        }
+    }
+}
+
+/// Loads `image` and runs it as a task with the arguments `args`, where `args[0]` is
+/// conventionally the name of the program. The standard output and error of the program go to
+/// `output`, or to the console if it's `None`.
+pub fn spawn_program(
+    name: &'static str,
+    image: &[u8],
+    args: &[&str],
+    output: Option<Box<dyn FnMut(&str) + Send>>,
+) -> Result<TypedTaskHandle<UserMessage>> {
+    let mut address_space = AddressSpace::new()?;
+    let entry = load_segments(&mut address_space, image)?;
+    let stack_pointer = prepare_stack(&mut address_space, args)?;
+    let builder = task::user_builder(name, entry, stack_pointer).set_address_space(address_space);
+    Ok(syscall::spawn_user_task(builder, output))
+}
+
+/// Maps the PT_LOAD segments of `image` and returns the entry point.
+fn load_segments(address_space: &mut AddressSpace, image: &[u8]) -> Result<VirtAddr> {
+    let header =
+        FileHeader::parse(image).map_err(|_| Error::Whatever("Unable to parse the ELF header"))?;
+    let endian = header
+        .endian()
+        .map_err(|_| Error::Whatever("Unable to determine the endian of the ELF file"))?;
+    if header.e_type(endian) != elf::ET_EXEC || header.e_machine(endian) != elf::EM_X86_64 {
+        return Err(Error::Whatever("Not an x86-64 executable"));
+    }
+    let program_headers = header
+        .program_headers(endian, image)
+        .map_err(|_| Error::Whatever("Unable to parse the program headers"))?;
+    for segment in program_headers {
+        if segment.p_type(endian) != elf::PT_LOAD {
+            continue;
+        }
+        let start = segment.p_vaddr(endian);
+        let memory_size = segment.p_memsz(endian);
+        let end = start
+            .checked_add(memory_size)
+            .filter(|&end| end <= STACK_TOP - STACK_SIZE - STACK_GUARD_SIZE)
+            .ok_or(Error::Whatever("A segment is out of the user space"))?;
+        let data = segment
+            .data(endian, image)
+            .map_err(|_| Error::Whatever("A segment is out of the file"))?;
+        if data.len() as u64 > memory_size {
+            return Err(Error::Whatever(
+                "A segment is larger in the file than in memory",
+            ));
+        }
+        let mut flags = PageTableFlags::USER_ACCESSIBLE;
+        if segment.p_flags(endian) & elf::PF_W != 0 {
+            flags |= PageTableFlags::WRITABLE;
+        }
+        let pages = Page::range_inclusive(
+            Page::containing_address(
+                VirtAddr::try_new(start)
+                    .map_err(|_| Error::Whatever("A segment is out of the user space"))?,
+            ),
+            Page::containing_address(VirtAddr::new(end.max(start + 1) - 1)),
+        );
+        for page in pages {
+            // Segments can share a page at their boundary.
+            match address_space.flags(page.start_address()) {
+                Some(existing) => address_space.update_flags(page, existing | flags)?,
+                None => address_space.map_anonymous_page(page, flags)?,
+            }
+        }
+        write_memory(address_space, VirtAddr::new(start), data)?;
+    }
+    Ok(VirtAddr::new(header.e_entry(endian)))
+}
+
+/// Writes `data` to `address` in `address_space`, which doesn't have to be the current one.
+fn write_memory(address_space: &mut AddressSpace, address: VirtAddr, data: &[u8]) -> Result<()> {
+    let mut written = 0;
+    while written < data.len() {
+        let current = address + written;
+        let physical = address_space
+            .translate(current)
+            .ok_or(Error::Whatever("Writing to an unmapped page"))?;
+        let length = usize::min(
+            data.len() - written,
+            (Size4KiB::SIZE - u64::from(current.page_offset())) as usize,
+        );
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                data[written..].as_ptr(),
+                paging::phys_to_virt(physical).as_mut_ptr::<u8>(),
+                length,
+            );
+        }
+        written += length;
+    }
+    Ok(())
+}
+
+/// Sets up the stack with argc, argv, an empty envp and an empty auxv, as the System V ABI
+/// describes, and returns the initial stack pointer.
+fn prepare_stack(address_space: &mut AddressSpace, args: &[&str]) -> Result<VirtAddr> {
+    let flags = PageTableFlags::USER_ACCESSIBLE | PageTableFlags::WRITABLE;
+    // The arguments are placed on the top page, and the rest is populated on demand.
+    let top_page = Page::<Size4KiB>::containing_address(VirtAddr::new(STACK_TOP - 1));
+    let stack_bottom = Page::containing_address(VirtAddr::new(STACK_TOP - STACK_SIZE));
+    address_space.map_anonymous_page(top_page, flags)?;
+    address_space.reserve_lazy(Page::range(stack_bottom, top_page), flags)?;
+    address_space.reserve_guard(Page::range(
+        stack_bottom - STACK_GUARD_SIZE / Size4KiB::SIZE,
+        stack_bottom,
+    ))?;
+
+    let mut string_pointer = STACK_TOP;
+    let mut argv = Vec::with_capacity(args.len());
+    for arg in args {
+        string_pointer -= arg.len() as u64 + 1;
+        argv.push(string_pointer);
+    }
+    // argc, argv, NULL, envp NULL, and AT_NULL
+    let words = 1 + argv.len() as u64 + 1 + 1 + 2;
+    let stack_pointer = (string_pointer - words * 8) & !0xf;
+    if stack_pointer < top_page.start_address().as_u64() {
+        return Err(Error::Whatever("The arguments are too long"));
+    }
+
+    let mut page = [0u8; Size4KiB::SIZE as usize];
+    let offset = |address: u64| (address - top_page.start_address().as_u64()) as usize;
+    for (arg, &address) in args.iter().zip(argv.iter()) {
+        page[offset(address)..][..arg.len()].copy_from_slice(arg.as_bytes());
+    }
+    let mut word_pointer = stack_pointer;
+    let mut push_word = |value: u64| {
+        page[offset(word_pointer)..][..8].copy_from_slice(&value.to_le_bytes());
+        word_pointer += 8;
+    };
+    push_word(args.len() as u64);
+    argv.iter().for_each(|&address| push_word(address));
+    // The terminators are already zero.
+    write_memory(address_space, top_page.start_address(), &page)?;
+    Ok(VirtAddr::new(stack_pointer))
+}
@@ -1,11 +1,23 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+
 use log::{LevelFilter, Metadata, Record, SetLoggerError};
 
 use crate::prelude::*;
 
 static LOGGER: GlobalConsoleLogger = GlobalConsoleLogger;
+/// The `LevelFilter`s of the console and of the serial port, as `usize`.
+static CONSOLE_LEVEL: AtomicUsize = AtomicUsize::new(LevelFilter::Off as usize);
+static SERIAL_LEVEL: AtomicUsize = AtomicUsize::new(LevelFilter::Off as usize);
 
-pub fn initialize(level_filter: LevelFilter) -> core::result::Result<(), SetLoggerError> {
-    log::set_logger(&LOGGER).map(|()| log::set_max_level(level_filter))
+/// Logs up to `console_level` on the console, and up to `serial_level` on the serial port, which
+/// has room for more.
+pub fn initialize(
+    console_level: LevelFilter,
+    serial_level: LevelFilter,
+) -> core::result::Result<(), SetLoggerError> {
+    CONSOLE_LEVEL.store(console_level as usize, Ordering::Relaxed);
+    SERIAL_LEVEL.store(serial_level as usize, Ordering::Relaxed);
+    log::set_logger(&LOGGER).map(|()| log::set_max_level(console_level.max(serial_level)))
 }
 
 struct GlobalConsoleLogger;
@@ -15,7 +27,15 @@ impl log::Log for GlobalConsoleLogger {
     }
 
     fn log(&self, record: &Record) {
-        if self.enabled(record.metadata()) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let level = record.level() as usize;
+        // The serial port goes first, as the console may drop the text.
+        if level <= SERIAL_LEVEL.load(Ordering::Relaxed) {
+            crate::serial::write_fmt(format_args!("{} - {}\n", record.level(), record.args()));
+        }
+        if level <= CONSOLE_LEVEL.load(Ordering::Relaxed) {
             println!("{} - {}", record.level(), record.args());
         }
     }
@@ -9,14 +9,11 @@ use core::{arch::asm, mem::MaybeUninit};
 use pomelo_common::BootInfo;
 
 use pomelo_kernel::{
-    allocator, events, gdt,
+    acpi_tables, ahci, allocator, driver, events, fs, gdt,
     gui::{self, widgets::console, GUI},
-    interrupts::{self, InterruptIndex},
-    logger,
-    msi::{configure_msi_fixed_destination, DeliveryMode, TriggerMode},
-    paging, pci,
+    initrd, interrupts, ioapic, logger, paging, pci, percpu,
     prelude::*,
-    timer, xhci,
+    ps2, serial, smp, syscall, timer, virtio, xhci,
 };
 
 #[no_mangle]
@@ -54,47 +51,57 @@ fn initialize(boot_info: &BootInfo) -> Result<GUI> {
     console::initialize(boot_info.graphic_config());
     paging::initialize();
     allocator::initialize(boot_info.memory_mapping());
-    gdt::initialize();
-    logger::initialize(log::LevelFilter::Warn)?;
-    timer::initialize(boot_info.acpi2_rsdp());
+    gdt::initialize(0);
+    percpu::initialize(0);
+    syscall::initialize();
+    logger::initialize(log::LevelFilter::Warn, log::LevelFilter::Info)?;
+    if let Err(e) = serial::initialize() {
+        log::warn!("Failed to initialize the serial port: {:?}", e);
+    }
+    initrd::initialize(boot_info.initrd());
+    fs::initialize(boot_info.graphic_config())?;
+    acpi_tables::initialize(boot_info.acpi2_rsdp());
+    timer::initialize();
+    pci::initialize();
     let mut gui = gui::create_gui(boot_info.graphic_config());
     gui.render();
     interrupts::initialize();
+    if let Err(e) = ioapic::initialize() {
+        log::warn!("Failed to initialize the I/O APICs: {:?}", e);
+    }
+    if let Err(e) = serial::initialize_interrupts() {
+        log::warn!("Failed to enable the serial port interrupt: {:?}", e);
+    }
+    if let Err(e) = smp::initialize() {
+        log::warn!("Failed to start the other CPUs: {:?}", e);
+    }
     Ok(gui)
 }
 
 fn main(boot_info: &BootInfo) -> Result<!> {
     let gui = initialize(boot_info)?;
     println!("Welcome to Pomelo OS");
-    let xhc = pci::scan_devices()
-        .flat_map(|device| device.scan_functions())
-        .find(|func| {
-            matches!(
-                func.class(),
-                pci::PCIClass::SerialBusController(pci::SerialBusSubclass::USBController(
-                    pci::USBProgramInterface::XHCI
-                ))
-            )
-        })
-        .expect("No xHCI was found");
-
-    configure_msi_fixed_destination(
-        &xhc,
-        TriggerMode::Level,
-        DeliveryMode::Fixed,
-        InterruptIndex::XHCI as u8,
-        0,
-    )?;
-    log::info!("Initialized xhc interruption");
-
-    xhci::initialize(&xhc);
-    log::info!("Initialized xhci");
+    for driver in [
+        &xhci::DRIVER,
+        &ahci::DRIVER,
+        &virtio::blk::DRIVER,
+        &virtio::net::DRIVER,
+    ] {
+        driver::register(driver);
+    }
+    driver::attach_all();
+    fs::mount_volumes();
+    // The input goes through USB if there's a controller, and through PS/2 as well if it's there.
+    if let Err(e) = ps2::initialize() {
+        log::warn!("Failed to initialize the PS/2 controller: {:?}", e);
+    }
     events::event_loop(gui)
 }
 
 #[cfg(not(test))]
 #[panic_handler]
 fn panic(info: &core::panic::PanicInfo) -> ! {
+    pomelo_kernel::serial::write_fmt(format_args!("I'm panicked!!!! {}\n", info));
     println!("I'm panicked!!!! {}", info);
     #[allow(clippy::empty_loop)]
     loop {
@@ -1,9 +1,15 @@
-use pomelo_common::memory_mapping::{MemoryMapping, MemoryType};
+use core::ops::Range;
+
+use arrayvec::ArrayVec;
+use pomelo_common::{
+    memory_mapping::{MemoryMapping, MemoryType},
+    DIRECT_MAP_SIZE,
+};
 use spinning_top::{MappedSpinlockGuard, Spinlock, SpinlockGuard};
 use x86_64::{
     structures::paging::{
         frame::{PhysFrame, PhysFrameRange},
-        page::{PageSize, Size1GiB, Size4KiB},
+        page::{PageSize, Size4KiB},
         FrameAllocator, FrameDeallocator,
     },
     PhysAddr,
@@ -13,10 +19,17 @@ use crate::bitset::BitSet;
 
 pub type FrameSize = Size4KiB;
 const UEFI_PAGE_SIZE: usize = 4096;
-const MAX_PHYSICAL_MEMORY_SIZE: usize = 128 * Size1GiB::SIZE as usize;
+/// Only the memory in the direct map is handed out, since the kernel accesses frames through it.
+const MAX_PHYSICAL_MEMORY_SIZE: usize = DIRECT_MAP_SIZE as usize;
 const NUM_FRAMES: usize = MAX_PHYSICAL_MEMORY_SIZE.div_floor(FrameSize::SIZE as usize);
 static MEMORY_MANAGER: Spinlock<Option<BitmapMemoryManager>> = Spinlock::new(None);
 
+/// Frames set aside for the page fault handler. It can't wait for `MEMORY_MANAGER` since the
+/// faulting code might be the one holding it.
+const RESERVED_FRAME_COUNT: usize = 16;
+static RESERVED_FRAMES: Spinlock<ArrayVec<PhysFrame<FrameSize>, RESERVED_FRAME_COUNT>> =
+    Spinlock::new(ArrayVec::new_const());
+
 pub(crate) fn initialize(
     memory_mapping: &MemoryMapping,
 ) -> MappedSpinlockGuard<BitmapMemoryManager> {
@@ -48,6 +61,61 @@ pub(crate) fn initialize(
     })
 }
 
+/// Runs `f` with the global memory manager locked.
+/// Panics if the memory manager hasn't been initialized yet.
+pub(crate) fn with_memory_manager<T, F: FnOnce(&mut BitmapMemoryManager) -> T>(f: F) -> T {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut locked = MEMORY_MANAGER.lock();
+        let mm = locked
+            .as_mut()
+            .expect("Memory manager is not initialized yet");
+        let ret = f(mm);
+        if let Some(mut reserved) = RESERVED_FRAMES.try_lock() {
+            while !reserved.is_full() {
+                match mm.allocate_frame() {
+                    Some(frame) => reserved.push(frame),
+                    None => break,
+                }
+            }
+        }
+        ret
+    })
+}
+
+/// Allocates a frame without blocking, falling back to the reserved frames if the memory
+/// manager is locked. Intended to be used from the page fault handler.
+pub(crate) fn allocate_frame_in_fault() -> Option<PhysFrame<FrameSize>> {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        if let Some(mut locked) = MEMORY_MANAGER.try_lock() {
+            if let Some(frame) = locked.as_mut().and_then(|mm| mm.allocate_frame()) {
+                return Some(frame);
+            }
+        }
+        RESERVED_FRAMES
+            .try_lock()
+            .and_then(|mut reserved| reserved.pop())
+    })
+}
+
+/// Gives back a frame taken by `allocate_frame_in_fault` without blocking.
+/// The frame is leaked if both the memory manager and the reserved frames are locked.
+pub(crate) fn free_frame_in_fault(frame: PhysFrame<FrameSize>) {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        if let Some(mut reserved) = RESERVED_FRAMES.try_lock() {
+            if reserved.try_push(frame).is_ok() {
+                return;
+            }
+        }
+        if let Some(mut locked) = MEMORY_MANAGER.try_lock() {
+            if let Some(mm) = locked.as_mut() {
+                unsafe { mm.deallocate_frame(frame) };
+                return;
+            }
+        }
+        log::warn!("Leaking a frame: {:?}", frame);
+    })
+}
+
 pub(crate) struct BitmapMemoryManager {
     /// 0 -> unavailable, 1 -> available
     bitset: BitSet<NUM_FRAMES>,
@@ -60,8 +128,29 @@ impl BitmapMemoryManager {
     }
 
     pub fn allocate(&mut self, num_frames: usize) -> Option<PhysFrameRange<FrameSize>> {
-        let mut s = 0;
-        'search: while s < NUM_FRAMES - num_frames {
+        self.allocate_in(num_frames, 0..NUM_FRAMES)
+    }
+
+    /// Allocates `num_frames` contiguous frames from below `limit`, for the devices that can't
+    /// address the whole memory.
+    pub fn allocate_below(
+        &mut self,
+        num_frames: usize,
+        limit: PhysAddr,
+    ) -> Option<PhysFrameRange<FrameSize>> {
+        let end = usize::min((limit.as_u64() / FrameSize::SIZE) as usize, NUM_FRAMES);
+        // Frame 0 is avoided, as its address is indistinguishable from none.
+        self.allocate_in(num_frames, 1..end)
+    }
+
+    /// Allocates `num_frames` contiguous frames within the frame numbers `frames`.
+    fn allocate_in(
+        &mut self,
+        num_frames: usize,
+        frames: Range<usize>,
+    ) -> Option<PhysFrameRange<FrameSize>> {
+        let mut s = frames.start;
+        'search: while s + num_frames < frames.end {
             for i in 0..num_frames {
                 if !self.bitset.contains(s + i) {
                     s += i + 1;
@@ -1,7 +1,10 @@
+use alloc::vec::Vec;
 use bitfield::bitfield;
+use x86_64::VirtAddr;
 
 use crate::{
-    pci::{PCICapabilityHeader, PCIFunction},
+    interrupts::{self, InterruptHandler},
+    pci::{PCICapabilityHeader, PCIFunction, CAPABILITY_MSI, CAPABILITY_MSIX},
     prelude::*,
 };
 
@@ -54,21 +57,42 @@ pub fn configure_msi_fixed_destination(
     vector: u8,
     num_vector_exponent: u8,
 ) -> Result<()> {
-    let apic_id = read_local_apic_id();
+    let (message_address, message_data) =
+        fixed_destination_message(trigger_mode, delivery_mode, vector);
+    configure_msi(func, message_address, message_data, num_vector_exponent)
+}
+
+/// Turns off the MSI that `configure_msi_fixed_destination` has set up on `func`, for a driver
+/// that fails after that.
+pub fn disable_msi(func: &PCIFunction) {
+    let msi_capability = func
+        .capability_headers()
+        .find_map(|pci_capability_header| read_msi_capability(func, pci_capability_header));
+    if let Some(mut msi_capability) = msi_capability {
+        msi_capability.header.set_msi_enable(false);
+        func.write_conf_register(msi_capability.capability_address, msi_capability.header.0);
+    }
+}
+
+/// The message that delivers `vector` to this processor.
+fn fixed_destination_message(
+    trigger_mode: TriggerMode,
+    delivery_mode: DeliveryMode,
+    vector: u8,
+) -> (u32, u32) {
+    let apic_id = interrupts::local_apic_id();
     let message_address = 0xFEE00000 | ((apic_id as u32) << 12);
     let mut message_data = ((delivery_mode as u32) << 8) | (vector as u32);
     if trigger_mode == TriggerMode::Level {
         message_data |= 0xC000;
     }
-    configure_msi(func, message_address, message_data, num_vector_exponent)
+    (message_address, message_data)
 }
 
 fn read_msi_capability(
     func: &PCIFunction,
     pci_capability_header: PCICapabilityHeader,
 ) -> Option<MSICapability> {
-    const CAPABILITY_MSI: u8 = 0x05;
-    // const CAPABILITY_MSIX: u8 = 0x11;
     if pci_capability_header.data().capability_id() != CAPABILITY_MSI {
         return None;
     }
@@ -145,7 +169,170 @@ fn configure_msi(
     Ok(())
 }
 
-fn read_local_apic_id() -> u8 {
-    let value = unsafe { core::ptr::read_volatile(0xFEE00020 as *const u32) };
-    (value >> 24) as u8
+bitfield! {
+    #[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+    pub struct MSIXCapabilityHeaderData(u32);
+    impl Debug;
+    u8; pub capability_id, _: 7, 0;
+    u8; next_ptr, _: 15, 8;
+    u16; table_size_minus_one, _: 26, 16;
+    function_mask, set_function_mask: 30;
+    msix_enable, set_msix_enable: 31;
+}
+
+const MSIX_TABLE_ENTRY_SIZE: u64 = 16;
+const MSIX_VECTOR_CONTROL_MASKED: u32 = 1;
+const PCI_COMMAND_REGISTER: u8 = 0x04;
+const PCI_COMMAND_INTERRUPT_DISABLE: u32 = 1 << 10;
+
+/// The MSI-X capability of a function, and the vectors it delivers. The table and the pending
+/// bit array are mapped uncacheable.
+#[derive(Debug)]
+pub struct MSIX {
+    func: PCIFunction,
+    capability_address: u8,
+    table: VirtAddr,
+    pending_bits: VirtAddr,
+    table_size: u16,
+    vectors: Vec<u8>,
+}
+
+/// Enables MSI-X on `func` with a vector for each of `handlers`, which are assigned to the table
+/// entries from 0 in order. The other entries stay masked.
+pub fn configure_msix_fixed_destination(
+    func: &PCIFunction,
+    trigger_mode: TriggerMode,
+    delivery_mode: DeliveryMode,
+    name: &'static str,
+    handlers: &[InterruptHandler],
+) -> Result<MSIX> {
+    let mut msix = MSIX::new(func)?;
+    if handlers.len() > msix.table_size as usize {
+        return Err(Error::Whatever("The MSI-X table is too small"));
+    }
+    // Nothing is touched until the vectors are there, and dropping `msix` frees them.
+    msix.vectors = interrupts::allocate_vectors(name, handlers)?;
+    msix.write_control(|header| header.set_function_mask(true));
+    for entry in 0..msix.table_size {
+        msix.set_masked(entry, true);
+    }
+    for (entry, &vector) in msix.vectors.iter().enumerate() {
+        let (message_address, message_data) =
+            fixed_destination_message(trigger_mode, delivery_mode, vector);
+        msix.write_entry(entry as u16, 0, message_address);
+        msix.write_entry(entry as u16, 4, 0);
+        msix.write_entry(entry as u16, 8, message_data);
+        msix.set_masked(entry as u16, false);
+    }
+    msix.set_intx_disabled(true);
+    msix.write_control(|header| {
+        header.set_msix_enable(true);
+        header.set_function_mask(false);
+    });
+    Ok(msix)
+}
+
+impl MSIX {
+    fn new(func: &PCIFunction) -> Result<Self> {
+        let capability_address = func
+            .capability_headers()
+            .find(|header| header.data().capability_id() == CAPABILITY_MSIX)
+            .map(|header| *header.capability_address())
+            .ok_or(Error::Whatever(
+                "The PCI device wasn't capable of handling MSI-X",
+            ))?;
+        let header = MSIXCapabilityHeaderData(func.read_conf_register(capability_address));
+        // The low 3 bits of the offsets select the BAR.
+        let locate = |register: u32| -> Result<VirtAddr> {
+            let base = func.map_memory_bar((register & 0b111) as usize)?;
+            Ok(base + (register & !0b111) as u64)
+        };
+        let table = locate(func.read_conf_register(capability_address + 4))?;
+        let pending_bits = locate(func.read_conf_register(capability_address + 8))?;
+        Ok(Self {
+            func: *func,
+            capability_address,
+            table,
+            pending_bits,
+            table_size: header.table_size_minus_one() + 1,
+            vectors: Vec::new(),
+        })
+    }
+
+    /// The number of entries in the table.
+    pub fn table_size(&self) -> u16 {
+        self.table_size
+    }
+
+    /// The vectors of the table entries from 0.
+    pub fn vectors(&self) -> &[u8] {
+        &self.vectors
+    }
+
+    /// Stops or resumes the delivery of the table entry `entry`. The device remembers the
+    /// messages it couldn't send while masked in the pending bits.
+    pub fn set_masked(&self, entry: u16, masked: bool) {
+        let control = self.read_entry(entry, 12);
+        let control = if masked {
+            control | MSIX_VECTOR_CONTROL_MASKED
+        } else {
+            control & !MSIX_VECTOR_CONTROL_MASKED
+        };
+        self.write_entry(entry, 12, control);
+    }
+
+    pub fn is_pending(&self, entry: u16) -> bool {
+        assert!(entry < self.table_size);
+        let address = self.pending_bits + (entry / 64) as u64 * 8;
+        let bits = unsafe { core::ptr::read_volatile(address.as_ptr::<u64>()) };
+        bits & (1 << (entry % 64)) != 0
+    }
+
+    fn read_entry(&self, entry: u16, offset: u64) -> u32 {
+        assert!(entry < self.table_size);
+        let address = self.table + entry as u64 * MSIX_TABLE_ENTRY_SIZE + offset;
+        unsafe { core::ptr::read_volatile(address.as_ptr()) }
+    }
+
+    fn write_entry(&self, entry: u16, offset: u64, value: u32) {
+        assert!(entry < self.table_size);
+        let address = self.table + entry as u64 * MSIX_TABLE_ENTRY_SIZE + offset;
+        unsafe { core::ptr::write_volatile(address.as_mut_ptr(), value) }
+    }
+
+    fn write_control<F: FnOnce(&mut MSIXCapabilityHeaderData)>(&self, f: F) {
+        let mut header =
+            MSIXCapabilityHeaderData(self.func.read_conf_register(self.capability_address));
+        f(&mut header);
+        // Only the upper half, the message control, is writable.
+        self.func
+            .write_conf_register(self.capability_address, header.0);
+    }
+
+    /// Stops or resumes the INTx interrupts of the function, which MSI-X replaces.
+    fn set_intx_disabled(&self, disabled: bool) {
+        // The upper half is the status, whose bits are cleared by writing 1.
+        let command = self.func.read_conf_register(PCI_COMMAND_REGISTER) & 0xFFFF;
+        let command = if disabled {
+            command | PCI_COMMAND_INTERRUPT_DISABLE
+        } else {
+            command & !PCI_COMMAND_INTERRUPT_DISABLE
+        };
+        self.func.write_conf_register(PCI_COMMAND_REGISTER, command);
+    }
+}
+
+impl Drop for MSIX {
+    fn drop(&mut self) {
+        // Nothing is set up before the vectors are allocated.
+        if self.vectors.is_empty() {
+            return;
+        }
+        for entry in 0..self.vectors.len() {
+            self.set_masked(entry as u16, true);
+        }
+        self.write_control(|header| header.set_msix_enable(false));
+        self.set_intx_disabled(false);
+        interrupts::free_vectors(&self.vectors);
+    }
 }
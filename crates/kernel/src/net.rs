@@ -0,0 +1,304 @@
+//! A minimal TCP/IP stack over Ethernet. The address is fixed to the one QEMU's user-mode
+//! networking gives to the guest.
+
+mod arp;
+pub mod icmp;
+mod ipv4;
+pub mod tcp;
+pub mod udp;
+
+use core::{fmt, str::FromStr};
+
+use alloc::{boxed::Box, collections::VecDeque, sync::Arc, vec::Vec};
+use spinning_top::Spinlock;
+use x86_64::instructions::interrupts;
+
+use crate::{
+    prelude::*,
+    task::{self, Receiver, TypedTaskHandle},
+    timer,
+};
+
+const ADDRESS: Ipv4Address = Ipv4Address::new(10, 0, 2, 15);
+const NETMASK: Ipv4Address = Ipv4Address::new(255, 255, 255, 0);
+const GATEWAY: Ipv4Address = Ipv4Address::new(10, 0, 2, 2);
+
+const ETHERNET_HEADER_SIZE: usize = 14;
+/// Shorter frames have to be padded.
+const ETHERNET_MIN_FRAME_SIZE: usize = 60;
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_ARP: u16 = 0x0806;
+/// The largest IPv4 packet in a frame.
+const MTU: usize = 1500;
+/// How many frames wait for room in the device before new ones are dropped.
+const TRANSMIT_QUEUE_LIMIT: usize = 64;
+
+/// How often the stack looks at timeouts, and at the device if it doesn't interrupt.
+const POLL_INTERVAL_MILLIS: u64 = 10;
+const TIMER_INTERVAL_MILLIS: u64 = 100;
+
+static INTERFACE: Spinlock<Option<Interface>> = Spinlock::new(None);
+static TASK: Spinlock<Option<TypedTaskHandle<()>>> = Spinlock::new(None);
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct MacAddress(pub [u8; 6]);
+
+impl MacAddress {
+    pub const BROADCAST: Self = Self([0xFF; 6]);
+}
+
+impl fmt::Display for MacAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let [a, b, c, d, e, g] = self.0;
+        write!(
+            f,
+            "{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}",
+            a, b, c, d, e, g
+        )
+    }
+}
+
+impl fmt::Debug for MacAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct Ipv4Address(pub [u8; 4]);
+
+impl Ipv4Address {
+    pub const BROADCAST: Self = Self([0xFF; 4]);
+
+    pub const fn new(a: u8, b: u8, c: u8, d: u8) -> Self {
+        Self([a, b, c, d])
+    }
+
+    fn to_u32(self) -> u32 {
+        u32::from_be_bytes(self.0)
+    }
+
+    fn is_in_same_subnet(self, other: Self) -> bool {
+        (self.to_u32() ^ other.to_u32()) & NETMASK.to_u32() == 0
+    }
+}
+
+impl FromStr for Ipv4Address {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut octets = [0; 4];
+        let mut parts = s.split('.');
+        for octet in octets.iter_mut() {
+            *octet = parts
+                .next()
+                .and_then(|part| part.parse().ok())
+                .ok_or(Error::Whatever("Invalid IPv4 address"))?;
+        }
+        if parts.next().is_some() {
+            return Err(Error::Whatever("Invalid IPv4 address"));
+        }
+        Ok(Self(octets))
+    }
+}
+
+impl fmt::Display for Ipv4Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let [a, b, c, d] = self.0;
+        write!(f, "{}.{}.{}.{}", a, b, c, d)
+    }
+}
+
+impl fmt::Debug for Ipv4Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+/// A network card that sends and receives Ethernet frames.
+pub trait NetworkDevice: Send + Sync {
+    fn mac_address(&self) -> MacAddress;
+    fn transmit(&self, frame: &[u8]) -> Result<()>;
+    /// Whether `transmit` has room for another frame.
+    fn can_transmit(&self) -> bool;
+    /// Takes a received frame, if any.
+    fn receive(&self) -> Option<Vec<u8>>;
+}
+
+/// Brings up the interface on `device`, and starts the task that processes its packets.
+/// If the device doesn't notify the stack with `fire_interrupt`, the task polls it instead.
+pub fn initialize(device: Arc<dyn NetworkDevice>, has_interrupts: bool) -> Result<()> {
+    let mac = device.mac_address();
+    interrupts::without_interrupts(|| {
+        let mut interface = INTERFACE.lock();
+        if interface.is_some() {
+            return Err(Error::Whatever("Only one network interface is supported"));
+        }
+        *interface = Some(Interface::new(device, mac));
+        Ok(())
+    })?;
+    let handle = task::spawn_task(task::builder("net", net_main));
+    let interval = if has_interrupts {
+        TIMER_INTERVAL_MILLIS
+    } else {
+        POLL_INTERVAL_MILLIS
+    };
+    timer::schedule(interval, interval, handle.clone(), ());
+    interrupts::without_interrupts(|| *TASK.lock() = Some(handle));
+    log::info!("Network interface {} at {}", mac, ADDRESS);
+    Ok(())
+}
+
+/// Lets the network task know that the device has something for it.
+pub fn fire_interrupt() {
+    if let Some(handle) = TASK.lock().as_ref() {
+        handle.send(());
+    }
+}
+
+extern "sysv64" fn net_main(mut receiver: Box<Receiver<()>>) {
+    loop {
+        interrupts::enable();
+        receiver.dequeue_or_wait();
+        while receiver.try_dequeue().is_some() {}
+        if let Err(e) = with_interface(Interface::poll) {
+            log::error!("Failed to process the network: {:?}", e);
+        }
+    }
+}
+
+/// Runs `f` on the interface, and then the callbacks it deferred.
+fn with_interface<T, F: FnOnce(&mut Interface) -> T>(f: F) -> Result<T> {
+    let (result, callbacks) = interrupts::without_interrupts(|| {
+        let mut interface = INTERFACE.lock();
+        let interface = interface
+            .as_mut()
+            .ok_or(Error::Whatever("There's no network interface"))?;
+        let result = f(interface);
+        Ok((result, core::mem::take(&mut interface.callbacks)))
+    })?;
+    for callback in callbacks {
+        callback();
+    }
+    Ok(result)
+}
+
+/// The internet checksum of the concatenation of `chunks`.
+fn checksum(chunks: &[&[u8]]) -> u16 {
+    let mut sum = 0u64;
+    for (i, byte) in chunks.iter().flat_map(|chunk| chunk.iter()).enumerate() {
+        sum += if i % 2 == 0 {
+            (*byte as u64) << 8
+        } else {
+            *byte as u64
+        };
+    }
+    while sum >> 16 != 0 {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+fn read_u16(data: &[u8], offset: usize) -> u16 {
+    u16::from_be_bytes([data[offset], data[offset + 1]])
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_ipv4_address(data: &[u8], offset: usize) -> Ipv4Address {
+    Ipv4Address(data[offset..offset + 4].try_into().unwrap())
+}
+
+/// Code outside the stack, which may take long or use the network itself.
+type Callback = Box<dyn FnOnce() + Send>;
+
+struct Interface {
+    device: Arc<dyn NetworkDevice>,
+    mac: MacAddress,
+    /// The frames the device had no room for.
+    transmit_queue: VecDeque<Vec<u8>>,
+    /// Run by `with_interface` once the lock is released.
+    callbacks: Vec<Callback>,
+    arp: arp::Cache,
+    next_ip_identification: u16,
+    icmp: icmp::Pings,
+    udp: udp::Sockets,
+    tcp: tcp::Tcp,
+}
+
+impl Interface {
+    fn new(device: Arc<dyn NetworkDevice>, mac: MacAddress) -> Self {
+        Self {
+            device,
+            mac,
+            transmit_queue: VecDeque::new(),
+            callbacks: Vec::new(),
+            arp: Default::default(),
+            next_ip_identification: 0,
+            icmp: Default::default(),
+            udp: Default::default(),
+            tcp: Default::default(),
+        }
+    }
+
+    fn poll(&mut self) {
+        while let Some(frame) = self.device.receive() {
+            self.handle_frame(&frame);
+        }
+        let now = timer::current_tick();
+        icmp::on_timer(self, now);
+        tcp::on_timer(self, now);
+        self.flush_transmit_queue();
+    }
+
+    /// Calls `callback` without the lock of the interface, before `with_interface` returns.
+    fn defer<F: FnOnce() + Send + 'static>(&mut self, callback: F) {
+        self.callbacks.push(Box::new(callback));
+    }
+
+    fn handle_frame(&mut self, frame: &[u8]) {
+        if frame.len() < ETHERNET_HEADER_SIZE {
+            return;
+        }
+        let destination = MacAddress(frame[0..6].try_into().unwrap());
+        if destination != self.mac && destination != MacAddress::BROADCAST {
+            return;
+        }
+        let payload = &frame[ETHERNET_HEADER_SIZE..];
+        match read_u16(frame, 12) {
+            ETHERTYPE_ARP => arp::handle(self, payload),
+            ETHERTYPE_IPV4 => ipv4::handle(self, payload),
+            _ => {}
+        }
+    }
+
+    fn send_frame(&mut self, destination: MacAddress, ethertype: u16, payload: &[u8]) {
+        let mut frame = Vec::with_capacity(ETHERNET_HEADER_SIZE + payload.len());
+        frame.extend_from_slice(&destination.0);
+        frame.extend_from_slice(&self.mac.0);
+        frame.extend_from_slice(&ethertype.to_be_bytes());
+        frame.extend_from_slice(payload);
+        if frame.len() < ETHERNET_MIN_FRAME_SIZE {
+            frame.resize(ETHERNET_MIN_FRAME_SIZE, 0);
+        }
+        if self.transmit_queue.len() >= TRANSMIT_QUEUE_LIMIT {
+            log::warn!("Dropping a frame as the transmit queue is full");
+            return;
+        }
+        self.transmit_queue.push_back(frame);
+        self.flush_transmit_queue();
+    }
+
+    /// Hands the queued frames to the device while it has room. The rest are sent once it
+    /// interrupts for the sent ones, or on the timer.
+    fn flush_transmit_queue(&mut self) {
+        while !self.transmit_queue.is_empty() && self.device.can_transmit() {
+            let frame = self.transmit_queue.pop_front().unwrap();
+            if let Err(e) = self.device.transmit(&frame) {
+                log::warn!("Failed to send a frame: {:?}", e);
+            }
+        }
+    }
+}
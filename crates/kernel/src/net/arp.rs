@@ -0,0 +1,94 @@
+//! ARP, which finds the MAC addresses of the hosts on the local network.
+
+use alloc::{collections::BTreeMap, vec::Vec};
+
+use super::{
+    read_ipv4_address, read_u16, Interface, Ipv4Address, MacAddress, ADDRESS, ETHERTYPE_ARP,
+    ETHERTYPE_IPV4,
+};
+
+const HARDWARE_TYPE_ETHERNET: u16 = 1;
+const OPERATION_REQUEST: u16 = 1;
+const OPERATION_REPLY: u16 = 2;
+const PACKET_SIZE: usize = 28;
+/// The number of packets kept while their destinations are being resolved.
+const MAX_PENDING_PACKETS: usize = 16;
+
+#[derive(Default)]
+pub(super) struct Cache {
+    entries: BTreeMap<Ipv4Address, MacAddress>,
+    /// IPv4 packets waiting for the MAC address of their next hop.
+    pending: Vec<(Ipv4Address, Vec<u8>)>,
+}
+
+pub(super) fn handle(interface: &mut Interface, packet: &[u8]) {
+    if packet.len() < PACKET_SIZE
+        || read_u16(packet, 0) != HARDWARE_TYPE_ETHERNET
+        || read_u16(packet, 2) != ETHERTYPE_IPV4
+        || packet[4] != 6
+        || packet[5] != 4
+    {
+        return;
+    }
+    let operation = read_u16(packet, 6);
+    let sender_mac = MacAddress(packet[8..14].try_into().unwrap());
+    let sender = read_ipv4_address(packet, 14);
+    let target = read_ipv4_address(packet, 24);
+    // Learn the sender if it talks to us, or update it if it's known already.
+    if target == ADDRESS || interface.arp.entries.contains_key(&sender) {
+        interface.arp.entries.insert(sender, sender_mac);
+        let (ready, waiting) = core::mem::take(&mut interface.arp.pending)
+            .into_iter()
+            .partition::<Vec<_>, _>(|(next_hop, _)| *next_hop == sender);
+        interface.arp.pending = waiting;
+        for (_, packet) in ready {
+            interface.send_frame(sender_mac, ETHERTYPE_IPV4, &packet);
+        }
+    }
+    if operation == OPERATION_REQUEST && target == ADDRESS {
+        send(interface, OPERATION_REPLY, sender_mac, sender);
+    }
+}
+
+/// Sends an IPv4 packet to `next_hop`, asking for its MAC address first if it's unknown.
+pub(super) fn send_ipv4(interface: &mut Interface, next_hop: Ipv4Address, packet: Vec<u8>) {
+    let mac = if next_hop == Ipv4Address::BROADCAST {
+        Some(MacAddress::BROADCAST)
+    } else {
+        interface.arp.entries.get(&next_hop).copied()
+    };
+    match mac {
+        Some(mac) => interface.send_frame(mac, ETHERTYPE_IPV4, &packet),
+        None => {
+            let pending = &mut interface.arp.pending;
+            if pending.len() >= MAX_PENDING_PACKETS {
+                pending.remove(0);
+            }
+            pending.push((next_hop, packet));
+            send(
+                interface,
+                OPERATION_REQUEST,
+                MacAddress::default(),
+                next_hop,
+            );
+        }
+    }
+}
+
+fn send(interface: &mut Interface, operation: u16, target_mac: MacAddress, target: Ipv4Address) {
+    let mut packet = Vec::with_capacity(PACKET_SIZE);
+    packet.extend_from_slice(&HARDWARE_TYPE_ETHERNET.to_be_bytes());
+    packet.extend_from_slice(&ETHERTYPE_IPV4.to_be_bytes());
+    packet.extend_from_slice(&[6, 4]);
+    packet.extend_from_slice(&operation.to_be_bytes());
+    packet.extend_from_slice(&interface.mac.0);
+    packet.extend_from_slice(&ADDRESS.0);
+    packet.extend_from_slice(&target_mac.0);
+    packet.extend_from_slice(&target.0);
+    let destination = if operation == OPERATION_REQUEST {
+        MacAddress::BROADCAST
+    } else {
+        target_mac
+    };
+    interface.send_frame(destination, ETHERTYPE_ARP, &packet);
+}
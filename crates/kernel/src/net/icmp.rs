@@ -0,0 +1,140 @@
+//! ICMP echo, which answers pings from others and sends our own.
+
+use alloc::{boxed::Box, collections::BTreeMap, format, string::String, sync::Arc, vec::Vec};
+use spinning_top::Spinlock;
+
+use super::{checksum, ipv4, read_u16, with_interface, Interface, Ipv4Address};
+use crate::{prelude::*, timer};
+
+const TYPE_ECHO_REPLY: u8 = 0;
+const TYPE_ECHO_REQUEST: u8 = 8;
+const HEADER_SIZE: usize = 8;
+const PING_DATA_SIZE: usize = 32;
+const PING_INTERVAL_TICKS: u64 = timer::TARGET_FREQUENCY as u64;
+const MILLIS_PER_TICK: u64 = 1000 / timer::TARGET_FREQUENCY as u64;
+
+/// Shared with the callbacks that write to it without the lock of the interface.
+type Output = Arc<Spinlock<Box<dyn FnMut(&str) + Send>>>;
+
+/// The pings in progress.
+#[derive(Default)]
+pub(super) struct Pings {
+    sessions: Vec<Ping>,
+    next_identifier: u16,
+}
+
+struct Ping {
+    target: Ipv4Address,
+    identifier: u16,
+    count: u16,
+    sent: u16,
+    received: u16,
+    next_tick: u64,
+    /// When each request without a reply was sent, by its sequence number.
+    outstanding: BTreeMap<u16, u64>,
+    output: Output,
+}
+
+/// Sends `count` echo requests to `target` a second apart, and reports the replies to `output`.
+pub fn ping(target: Ipv4Address, count: u16, output: Box<dyn FnMut(&str) + Send>) -> Result<()> {
+    let now = timer::current_tick();
+    with_interface(|interface| {
+        let pings = &mut interface.icmp;
+        let identifier = pings.next_identifier;
+        pings.next_identifier = identifier.wrapping_add(1);
+        pings.sessions.push(Ping {
+            target,
+            identifier,
+            count,
+            sent: 0,
+            received: 0,
+            next_tick: now,
+            outstanding: BTreeMap::new(),
+            output: Arc::new(Spinlock::new(output)),
+        });
+        on_timer(interface, now);
+    })
+}
+
+pub(super) fn handle(interface: &mut Interface, source: Ipv4Address, packet: &[u8]) {
+    if packet.len() < HEADER_SIZE || checksum(&[packet]) != 0 {
+        return;
+    }
+    match packet[0] {
+        TYPE_ECHO_REQUEST => {
+            let mut reply = packet.to_vec();
+            reply[0] = TYPE_ECHO_REPLY;
+            reply[2..4].fill(0);
+            let reply_checksum = checksum(&[&reply]);
+            reply[2..4].copy_from_slice(&reply_checksum.to_be_bytes());
+            ipv4::send(interface, source, ipv4::PROTOCOL_ICMP, &reply);
+        }
+        TYPE_ECHO_REPLY => {
+            let identifier = read_u16(packet, 4);
+            let sequence = read_u16(packet, 6);
+            let now = timer::current_tick();
+            let session = interface
+                .icmp
+                .sessions
+                .iter_mut()
+                .find(|ping| ping.identifier == identifier && ping.target == source);
+            if let Some(ping) = session {
+                if let Some(sent_tick) = ping.outstanding.remove(&sequence) {
+                    ping.received += 1;
+                    let text = format!(
+                        "{} bytes from {}: seq={} time={} ms\n",
+                        packet.len(),
+                        source,
+                        sequence,
+                        (now - sent_tick) * MILLIS_PER_TICK
+                    );
+                    let output = ping.output.clone();
+                    write_output(interface, output, text);
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Sends the requests that are due, and finishes the pings that have waited long enough for
+/// the last reply.
+pub(super) fn on_timer(interface: &mut Interface, now: u64) {
+    let mut sessions = core::mem::take(&mut interface.icmp.sessions);
+    sessions.retain_mut(|ping| {
+        if now < ping.next_tick {
+            return true;
+        }
+        if ping.sent == ping.count {
+            let text = format!(
+                "{}: {} sent, {} received\n",
+                ping.target, ping.sent, ping.received
+            );
+            write_output(interface, ping.output.clone(), text);
+            return false;
+        }
+        send_request(interface, ping.target, ping.identifier, ping.sent);
+        ping.outstanding.insert(ping.sent, now);
+        ping.sent += 1;
+        ping.next_tick = now + PING_INTERVAL_TICKS;
+        true
+    });
+    // Keep the ones started while sending, if any.
+    sessions.append(&mut interface.icmp.sessions);
+    interface.icmp.sessions = sessions;
+}
+
+fn write_output(interface: &mut Interface, output: Output, text: String) {
+    interface.defer(move || (*output.lock())(&text));
+}
+
+fn send_request(interface: &mut Interface, target: Ipv4Address, identifier: u16, sequence: u16) {
+    let mut packet = Vec::with_capacity(HEADER_SIZE + PING_DATA_SIZE);
+    packet.extend_from_slice(&[TYPE_ECHO_REQUEST, 0, 0, 0]);
+    packet.extend_from_slice(&identifier.to_be_bytes());
+    packet.extend_from_slice(&sequence.to_be_bytes());
+    packet.extend((0..PING_DATA_SIZE as u8).map(|i| b'a' + i % 26));
+    let request_checksum = checksum(&[&packet]);
+    packet[2..4].copy_from_slice(&request_checksum.to_be_bytes());
+    ipv4::send(interface, target, ipv4::PROTOCOL_ICMP, &packet);
+}
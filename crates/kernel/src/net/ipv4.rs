@@ -0,0 +1,102 @@
+//! IPv4, without options or fragmentation.
+
+use alloc::vec::Vec;
+
+use super::{
+    arp, checksum, icmp, read_ipv4_address, read_u16, tcp, udp, Interface, Ipv4Address, ADDRESS,
+    GATEWAY, MTU,
+};
+
+pub(super) const PROTOCOL_ICMP: u8 = 1;
+pub(super) const PROTOCOL_TCP: u8 = 6;
+pub(super) const PROTOCOL_UDP: u8 = 17;
+
+pub(super) const HEADER_SIZE: usize = 20;
+const TIME_TO_LIVE: u8 = 64;
+const FLAG_DONT_FRAGMENT: u16 = 0x4000;
+const FLAG_MORE_FRAGMENTS: u16 = 0x2000;
+const FRAGMENT_OFFSET_MASK: u16 = 0x1FFF;
+
+pub(super) fn handle(interface: &mut Interface, packet: &[u8]) {
+    if packet.len() < HEADER_SIZE || packet[0] >> 4 != 4 {
+        return;
+    }
+    let header_length = (packet[0] & 0xF) as usize * 4;
+    let total_length = read_u16(packet, 2) as usize;
+    if header_length < HEADER_SIZE || total_length < header_length || total_length > packet.len() {
+        return;
+    }
+    // The frame may have padding after the packet.
+    let packet = &packet[..total_length];
+    if checksum(&[&packet[..header_length]]) != 0 {
+        return;
+    }
+    let fragment = read_u16(packet, 6);
+    if fragment & (FLAG_MORE_FRAGMENTS | FRAGMENT_OFFSET_MASK) != 0 {
+        log::debug!("Dropped an IPv4 fragment");
+        return;
+    }
+    let source = read_ipv4_address(packet, 12);
+    let destination = read_ipv4_address(packet, 16);
+    if destination != ADDRESS && destination != Ipv4Address::BROADCAST {
+        return;
+    }
+    let payload = &packet[header_length..];
+    match packet[9] {
+        PROTOCOL_ICMP => icmp::handle(interface, source, payload),
+        PROTOCOL_TCP => tcp::handle(interface, source, destination, payload),
+        PROTOCOL_UDP => udp::handle(interface, source, destination, payload),
+        _ => {}
+    }
+}
+
+/// Sends `payload` to `destination`, through the gateway if it's not on the local network.
+pub(super) fn send(
+    interface: &mut Interface,
+    destination: Ipv4Address,
+    protocol: u8,
+    payload: &[u8],
+) {
+    let total_length = HEADER_SIZE + payload.len();
+    if total_length > MTU {
+        log::warn!("Dropped an IPv4 packet larger than the MTU");
+        return;
+    }
+    let identification = interface.next_ip_identification;
+    interface.next_ip_identification = identification.wrapping_add(1);
+    let mut packet = Vec::with_capacity(total_length);
+    packet.extend_from_slice(&[0x45, 0]);
+    packet.extend_from_slice(&(total_length as u16).to_be_bytes());
+    packet.extend_from_slice(&identification.to_be_bytes());
+    packet.extend_from_slice(&FLAG_DONT_FRAGMENT.to_be_bytes());
+    packet.extend_from_slice(&[TIME_TO_LIVE, protocol, 0, 0]);
+    packet.extend_from_slice(&ADDRESS.0);
+    packet.extend_from_slice(&destination.0);
+    let header_checksum = checksum(&[&packet]);
+    packet[10..12].copy_from_slice(&header_checksum.to_be_bytes());
+    packet.extend_from_slice(payload);
+
+    let next_hop =
+        if destination == Ipv4Address::BROADCAST || destination.is_in_same_subnet(ADDRESS) {
+            destination
+        } else {
+            GATEWAY
+        };
+    arp::send_ipv4(interface, next_hop, packet);
+}
+
+/// The checksum of TCP and UDP, which also covers the addresses in the IPv4 header.
+pub(super) fn checksum_with_pseudo_header(
+    source: Ipv4Address,
+    destination: Ipv4Address,
+    protocol: u8,
+    data: &[u8],
+) -> u16 {
+    checksum(&[
+        &source.0,
+        &destination.0,
+        &[0, protocol],
+        &(data.len() as u16).to_be_bytes(),
+        data,
+    ])
+}
@@ -0,0 +1,429 @@
+//! TCP, for passive opens only. A listener answers the data each connection receives, and the
+//! connection is closed once the peer closes its side.
+
+use alloc::{
+    collections::{BTreeMap, VecDeque},
+    sync::Arc,
+    vec::Vec,
+};
+
+use super::{ipv4, read_u16, read_u32, with_interface, Interface, Ipv4Address, ADDRESS, MTU};
+use crate::{prelude::*, timer};
+
+/// Gets the data received in order, and returns the data to send back.
+pub type Handler = Arc<dyn Fn(&[u8]) -> Vec<u8> + Send + Sync>;
+
+const FLAG_FIN: u8 = 1 << 0;
+const FLAG_SYN: u8 = 1 << 1;
+const FLAG_RST: u8 = 1 << 2;
+const FLAG_PSH: u8 = 1 << 3;
+const FLAG_ACK: u8 = 1 << 4;
+
+const HEADER_SIZE: usize = 20;
+const MAX_SEGMENT_SIZE: usize = MTU - ipv4::HEADER_SIZE - HEADER_SIZE;
+const WINDOW_SIZE: u16 = 8192;
+const RETRANSMISSION_TICKS: u64 = timer::TARGET_FREQUENCY as u64;
+const MAX_RETRANSMISSIONS: u32 = 5;
+/// No more data is taken while this much waits to be sent, so the peer has to retransmit it
+/// later. A single reply can still go over it.
+const SEND_BUFFER_LIMIT: usize = 64 * 1024;
+
+/// Passes the data the connections to `port` receive to `handler`.
+pub fn listen(port: u16, handler: Handler) -> Result<()> {
+    with_interface(|interface| {
+        let listeners = &mut interface.tcp.listeners;
+        if listeners.contains_key(&port) {
+            return Err(Error::Whatever("The port is already in use"));
+        }
+        listeners.insert(port, handler);
+        Ok(())
+    })?
+}
+
+/// Stops accepting connections to `port`. The existing ones stay open.
+pub fn unlisten(port: u16) -> Result<()> {
+    with_interface(|interface| interface.tcp.listeners.remove(&port).map(drop))?
+        .ok_or(Error::Whatever("The port is not listened"))
+}
+
+/// Whether the sequence number `a` comes before `b`.
+fn is_before(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) < 0
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
+struct Endpoint {
+    remote: Ipv4Address,
+    remote_port: u16,
+    local_port: u16,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+enum State {
+    /// Our SYN hasn't been acknowledged yet.
+    SynReceived,
+    Established,
+    /// The peer has closed its side, and our FIN is queued after the data.
+    LastAck,
+}
+
+struct Connection {
+    state: State,
+    handler: Handler,
+    /// The oldest sequence number the peer hasn't acknowledged.
+    send_unacknowledged: u32,
+    send_next: u32,
+    /// The data from `send_unacknowledged` on, including the part not sent yet.
+    send_buffer: VecDeque<u8>,
+    /// How many replies the handler is working on, which come before our FIN.
+    pending_replies: usize,
+    receive_next: u32,
+    peer_window: u16,
+    retransmit_at: Option<u64>,
+    retransmissions: u32,
+}
+
+#[derive(Default)]
+pub(super) struct Tcp {
+    listeners: BTreeMap<u16, Handler>,
+    connections: BTreeMap<Endpoint, Connection>,
+    next_initial_sequence: u32,
+}
+
+/// The fields of a received segment that matter here.
+struct Segment<'a> {
+    sequence: u32,
+    acknowledgment: u32,
+    flags: u8,
+    window: u16,
+    data: &'a [u8],
+}
+
+impl Segment<'_> {
+    /// The sequence number right after the segment.
+    fn end(&self) -> u32 {
+        let mut length = self.data.len() as u32;
+        if self.flags & FLAG_SYN != 0 {
+            length += 1;
+        }
+        if self.flags & FLAG_FIN != 0 {
+            length += 1;
+        }
+        self.sequence.wrapping_add(length)
+    }
+}
+
+pub(super) fn handle(
+    interface: &mut Interface,
+    source: Ipv4Address,
+    destination: Ipv4Address,
+    packet: &[u8],
+) {
+    if packet.len() < HEADER_SIZE
+        || ipv4::checksum_with_pseudo_header(source, destination, ipv4::PROTOCOL_TCP, packet) != 0
+    {
+        return;
+    }
+    let data_offset = (packet[12] >> 4) as usize * 4;
+    if data_offset < HEADER_SIZE || data_offset > packet.len() {
+        return;
+    }
+    let endpoint = Endpoint {
+        remote: source,
+        remote_port: read_u16(packet, 0),
+        local_port: read_u16(packet, 2),
+    };
+    let segment = Segment {
+        sequence: read_u32(packet, 4),
+        acknowledgment: read_u32(packet, 8),
+        flags: packet[13],
+        window: read_u16(packet, 14),
+        data: &packet[data_offset..],
+    };
+    // Take the connection out while it's updated, so that it can send through the interface.
+    if let Some(mut connection) = interface.tcp.connections.remove(&endpoint) {
+        if connection.on_segment(interface, endpoint, &segment) {
+            interface.tcp.connections.insert(endpoint, connection);
+        }
+        return;
+    }
+    if segment.flags & FLAG_RST != 0 {
+        return;
+    }
+    let listener = interface.tcp.listeners.get(&endpoint.local_port).cloned();
+    match listener {
+        Some(handler) if segment.flags & (FLAG_SYN | FLAG_ACK) == FLAG_SYN => {
+            accept(interface, endpoint, &segment, handler)
+        }
+        _ => reset(interface, endpoint, &segment),
+    }
+}
+
+/// Starts a connection with the SYN in `segment`.
+fn accept(interface: &mut Interface, endpoint: Endpoint, segment: &Segment, handler: Handler) {
+    let now = timer::current_tick();
+    let tcp = &mut interface.tcp;
+    let initial_sequence = tcp
+        .next_initial_sequence
+        .wrapping_add((now as u32).wrapping_mul(250_000));
+    tcp.next_initial_sequence = tcp.next_initial_sequence.wrapping_add(64_000);
+    let connection = Connection {
+        state: State::SynReceived,
+        handler,
+        send_unacknowledged: initial_sequence,
+        send_next: initial_sequence.wrapping_add(1),
+        send_buffer: VecDeque::new(),
+        pending_replies: 0,
+        receive_next: segment.sequence.wrapping_add(1),
+        peer_window: segment.window,
+        retransmit_at: Some(now + RETRANSMISSION_TICKS),
+        retransmissions: 0,
+    };
+    connection.send_syn(interface, endpoint);
+    interface.tcp.connections.insert(endpoint, connection);
+}
+
+/// Answers a segment that doesn't belong to any connection.
+fn reset(interface: &mut Interface, endpoint: Endpoint, segment: &Segment) {
+    if segment.flags & FLAG_ACK != 0 {
+        send_segment(
+            interface,
+            endpoint,
+            segment.acknowledgment,
+            0,
+            FLAG_RST,
+            &[],
+        );
+    } else {
+        send_segment(
+            interface,
+            endpoint,
+            0,
+            segment.end(),
+            FLAG_RST | FLAG_ACK,
+            &[],
+        );
+    }
+}
+
+/// Retransmits the segments that haven't been acknowledged in time.
+pub(super) fn on_timer(interface: &mut Interface, now: u64) {
+    let expired: Vec<Endpoint> = interface
+        .tcp
+        .connections
+        .iter()
+        .filter(|(_, connection)| matches!(connection.retransmit_at, Some(at) if at <= now))
+        .map(|(endpoint, _)| *endpoint)
+        .collect();
+    for endpoint in expired {
+        let mut connection = interface.tcp.connections.remove(&endpoint).unwrap();
+        connection.retransmissions += 1;
+        if connection.retransmissions > MAX_RETRANSMISSIONS {
+            log::info!(
+                "TCP connection from {}:{} timed out",
+                endpoint.remote,
+                endpoint.remote_port
+            );
+            send_segment(interface, endpoint, connection.send_next, 0, FLAG_RST, &[]);
+            continue;
+        }
+        if connection.state == State::SynReceived {
+            connection.send_syn(interface, endpoint);
+        } else {
+            // Go back to the oldest unacknowledged byte, and probe if the peer's window is closed.
+            connection.send_next = connection.send_unacknowledged;
+            connection.transmit(interface, endpoint, true);
+        }
+        connection.retransmit_at = Some(now + (RETRANSMISSION_TICKS << connection.retransmissions));
+        interface.tcp.connections.insert(endpoint, connection);
+    }
+}
+
+/// Sends the reply of the handler to the data a connection has received, unless the connection
+/// has been closed meanwhile.
+fn queue_reply(interface: &mut Interface, endpoint: Endpoint, reply: Vec<u8>) {
+    if let Some(mut connection) = interface.tcp.connections.remove(&endpoint) {
+        connection.pending_replies = connection.pending_replies.saturating_sub(1);
+        connection.send_buffer.extend(reply);
+        connection.transmit(interface, endpoint, false);
+        interface.tcp.connections.insert(endpoint, connection);
+    }
+}
+
+impl Connection {
+    fn send_syn(&self, interface: &mut Interface, endpoint: Endpoint) {
+        send_segment(
+            interface,
+            endpoint,
+            self.send_unacknowledged,
+            self.receive_next,
+            FLAG_SYN | FLAG_ACK,
+            &[],
+        );
+    }
+
+    /// Processes `segment`, and returns whether the connection is still open.
+    fn on_segment(
+        &mut self,
+        interface: &mut Interface,
+        endpoint: Endpoint,
+        segment: &Segment,
+    ) -> bool {
+        if segment.flags & FLAG_RST != 0 {
+            return false;
+        }
+        if segment.flags & FLAG_SYN != 0 {
+            // The peer didn't get our SYN-ACK.
+            if self.state == State::SynReceived {
+                self.send_syn(interface, endpoint);
+            }
+            return true;
+        }
+        if segment.flags & FLAG_ACK == 0 {
+            return true;
+        }
+        let acknowledgment = segment.acknowledgment;
+        if self.state == State::SynReceived {
+            if acknowledgment != self.send_next {
+                reset(interface, endpoint, segment);
+                return false;
+            }
+            self.state = State::Established;
+            self.send_unacknowledged = acknowledgment;
+            self.retransmit_at = None;
+            self.retransmissions = 0;
+        } else if is_before(self.send_unacknowledged, acknowledgment)
+            && !is_before(self.send_next, acknowledgment)
+        {
+            let acknowledged = acknowledgment.wrapping_sub(self.send_unacknowledged) as usize;
+            let data = acknowledged.min(self.send_buffer.len());
+            self.send_buffer.drain(..data);
+            self.send_unacknowledged = acknowledgment;
+            self.retransmissions = 0;
+            self.retransmit_at = None;
+            // Everything including our FIN has been acknowledged.
+            if self.state == State::LastAck && acknowledged > data {
+                return false;
+            }
+        }
+        self.peer_window = segment.window;
+
+        let mut needs_ack = false;
+        if !segment.data.is_empty() {
+            if segment.sequence == self.receive_next
+                && self.state == State::Established
+                && self.send_buffer.len() < SEND_BUFFER_LIMIT
+            {
+                self.receive_next = self.receive_next.wrapping_add(segment.data.len() as u32);
+                self.pending_replies += 1;
+                let handler = self.handler.clone();
+                let data = segment.data.to_vec();
+                interface.defer(move || {
+                    let reply = handler(&data);
+                    with_interface(|interface| queue_reply(interface, endpoint, reply)).ok();
+                });
+            }
+            // Acknowledge even out-of-order data, so that the peer knows what's missing.
+            needs_ack = true;
+        }
+        if segment.flags & FLAG_FIN != 0
+            && self.state == State::Established
+            && segment.end() == self.receive_next.wrapping_add(1)
+        {
+            self.receive_next = self.receive_next.wrapping_add(1);
+            self.state = State::LastAck;
+            needs_ack = true;
+        }
+        if !self.transmit(interface, endpoint, false) && needs_ack {
+            send_segment(
+                interface,
+                endpoint,
+                self.send_next,
+                self.receive_next,
+                FLAG_ACK,
+                &[],
+            );
+        }
+        true
+    }
+
+    /// Sends the data the peer has room for, followed by our FIN if we're closing. Returns
+    /// whether anything was sent.
+    fn transmit(&mut self, interface: &mut Interface, endpoint: Endpoint, probe: bool) -> bool {
+        // A probe sends a byte even if the window is closed, to find out when it opens.
+        let window = (self.peer_window as usize).max(probe as usize);
+        let mut sent_anything = false;
+        loop {
+            let offset = self.send_next.wrapping_sub(self.send_unacknowledged) as usize;
+            if offset >= self.send_buffer.len() || offset >= window {
+                break;
+            }
+            let length = (self.send_buffer.len() - offset)
+                .min(window - offset)
+                .min(MAX_SEGMENT_SIZE);
+            let data: Vec<u8> = self
+                .send_buffer
+                .range(offset..offset + length)
+                .copied()
+                .collect();
+            send_segment(
+                interface,
+                endpoint,
+                self.send_next,
+                self.receive_next,
+                FLAG_ACK | FLAG_PSH,
+                &data,
+            );
+            self.send_next = self.send_next.wrapping_add(length as u32);
+            sent_anything = true;
+        }
+        let offset = self.send_next.wrapping_sub(self.send_unacknowledged) as usize;
+        if self.state == State::LastAck
+            && offset == self.send_buffer.len()
+            && self.pending_replies == 0
+        {
+            send_segment(
+                interface,
+                endpoint,
+                self.send_next,
+                self.receive_next,
+                FLAG_FIN | FLAG_ACK,
+                &[],
+            );
+            self.send_next = self.send_next.wrapping_add(1);
+            sent_anything = true;
+        }
+        // Keep the timer running while anything waits for an acknowledgment or for the window.
+        let in_flight = self.send_next != self.send_unacknowledged;
+        let has_unsent = offset < self.send_buffer.len();
+        if !in_flight && !has_unsent {
+            self.retransmit_at = None;
+        } else if self.retransmit_at.is_none() {
+            self.retransmit_at = Some(timer::current_tick() + RETRANSMISSION_TICKS);
+        }
+        sent_anything
+    }
+}
+
+fn send_segment(
+    interface: &mut Interface,
+    endpoint: Endpoint,
+    sequence: u32,
+    acknowledgment: u32,
+    flags: u8,
+    data: &[u8],
+) {
+    let mut segment = Vec::with_capacity(HEADER_SIZE + data.len());
+    segment.extend_from_slice(&endpoint.local_port.to_be_bytes());
+    segment.extend_from_slice(&endpoint.remote_port.to_be_bytes());
+    segment.extend_from_slice(&sequence.to_be_bytes());
+    segment.extend_from_slice(&acknowledgment.to_be_bytes());
+    segment.extend_from_slice(&[(HEADER_SIZE as u8 / 4) << 4, flags]);
+    segment.extend_from_slice(&WINDOW_SIZE.to_be_bytes());
+    segment.extend_from_slice(&[0, 0, 0, 0]);
+    segment.extend_from_slice(data);
+    let segment_checksum =
+        ipv4::checksum_with_pseudo_header(ADDRESS, endpoint.remote, ipv4::PROTOCOL_TCP, &segment);
+    segment[16..18].copy_from_slice(&segment_checksum.to_be_bytes());
+    ipv4::send(interface, endpoint.remote, ipv4::PROTOCOL_TCP, &segment);
+}
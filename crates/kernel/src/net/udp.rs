@@ -0,0 +1,107 @@
+//! UDP, with handlers bound to local ports.
+
+use alloc::{boxed::Box, collections::BTreeMap, sync::Arc, vec::Vec};
+use spinning_top::Spinlock;
+
+use super::{ipv4, read_u16, with_interface, Interface, Ipv4Address};
+use crate::prelude::*;
+
+const HEADER_SIZE: usize = 8;
+
+/// Gets the address and the port of the sender and the data, and returns the reply to it if any.
+pub type Handler = Box<dyn FnMut(Ipv4Address, u16, &[u8]) -> Option<Vec<u8>> + Send>;
+
+#[derive(Default)]
+pub(super) struct Sockets {
+    /// Shared with the callbacks that run them without the lock of the interface.
+    handlers: BTreeMap<u16, Arc<Spinlock<Handler>>>,
+}
+
+/// Passes the datagrams arriving at `port` to `handler`.
+pub fn bind(port: u16, handler: Handler) -> Result<()> {
+    with_interface(|interface| {
+        let handlers = &mut interface.udp.handlers;
+        if handlers.contains_key(&port) {
+            return Err(Error::Whatever("The port is already in use"));
+        }
+        handlers.insert(port, Arc::new(Spinlock::new(handler)));
+        Ok(())
+    })?
+}
+
+pub fn unbind(port: u16) -> Result<()> {
+    with_interface(|interface| interface.udp.handlers.remove(&port).map(drop))?
+        .ok_or(Error::Whatever("The port is not bound"))
+}
+
+pub fn send(
+    destination: Ipv4Address,
+    source_port: u16,
+    destination_port: u16,
+    data: &[u8],
+) -> Result<()> {
+    with_interface(|interface| {
+        send_datagram(interface, destination, source_port, destination_port, data)
+    })
+}
+
+pub(super) fn handle(
+    interface: &mut Interface,
+    source: Ipv4Address,
+    destination: Ipv4Address,
+    datagram: &[u8],
+) {
+    if datagram.len() < HEADER_SIZE {
+        return;
+    }
+    let length = read_u16(datagram, 4) as usize;
+    if length < HEADER_SIZE || length > datagram.len() {
+        return;
+    }
+    let datagram = &datagram[..length];
+    // A zero checksum means the sender didn't compute it.
+    if read_u16(datagram, 6) != 0
+        && ipv4::checksum_with_pseudo_header(source, destination, ipv4::PROTOCOL_UDP, datagram) != 0
+    {
+        return;
+    }
+    let source_port = read_u16(datagram, 0);
+    let port = read_u16(datagram, 2);
+    if let Some(handler) = interface.udp.handlers.get(&port).cloned() {
+        let data = datagram[HEADER_SIZE..].to_vec();
+        interface.defer(move || {
+            let reply = (*handler.lock())(source, source_port, &data);
+            if let Some(reply) = reply {
+                send(source, port, source_port, &reply).ok();
+            }
+        });
+    }
+}
+
+fn send_datagram(
+    interface: &mut Interface,
+    destination: Ipv4Address,
+    source_port: u16,
+    destination_port: u16,
+    data: &[u8],
+) {
+    let length = (HEADER_SIZE + data.len()) as u16;
+    let mut datagram = Vec::with_capacity(length as usize);
+    datagram.extend_from_slice(&source_port.to_be_bytes());
+    datagram.extend_from_slice(&destination_port.to_be_bytes());
+    datagram.extend_from_slice(&length.to_be_bytes());
+    datagram.extend_from_slice(&[0, 0]);
+    datagram.extend_from_slice(data);
+    let mut datagram_checksum = ipv4::checksum_with_pseudo_header(
+        super::ADDRESS,
+        destination,
+        ipv4::PROTOCOL_UDP,
+        &datagram,
+    );
+    // Zero means no checksum, so it's sent as all ones instead.
+    if datagram_checksum == 0 {
+        datagram_checksum = 0xFFFF;
+    }
+    datagram[6..8].copy_from_slice(&datagram_checksum.to_be_bytes());
+    ipv4::send(interface, destination, ipv4::PROTOCOL_UDP, &datagram);
+}
@@ -1,44 +1,639 @@
-use core::mem::MaybeUninit;
+use core::{
+    mem::MaybeUninit,
+    sync::atomic::{AtomicU64, Ordering},
+};
 
+use alloc::collections::BTreeMap;
+use spinning_top::Spinlock;
 use x86_64::{
     registers::control::{Cr3, Cr3Flags},
-    structures::paging::{
-        frame::PhysFrame, PageSize, PageTable, PageTableFlags, Size1GiB, Size2MiB,
+    structures::{
+        idt::PageFaultErrorCode,
+        paging::{
+            frame::{PhysFrame, PhysFrameRange},
+            mapper::{MapToError, MapperFlush, TranslateResult, UnmapError},
+            page::PageRange,
+            page_table::PageTableEntry,
+            FrameAllocator, Mapper, OffsetPageTable, Page, PageSize, PageTable, PageTableFlags,
+            Size1GiB, Size2MiB, Size4KiB, Translate,
+        },
     },
-    PhysAddr,
+    PhysAddr, VirtAddr,
+};
+
+use crate::{
+    memory_manager::{self, BitmapMemoryManager},
+    prelude::*,
 };
 
+pub use pomelo_common::{DIRECT_MAP_SIZE, PHYSICAL_MEMORY_OFFSET};
+
+pub mod kernel_stack;
+pub mod mmio;
+
+/// 512 GiB per PML4 entry
+const PML4_ENTRY_SIZE: u64 = 512 * Size1GiB::SIZE;
+/// The PML4 entry for the identity mapping of the frames that are handed to drivers assuming
+/// virtual address == physical address (e.g. the memory pool of the USB driver).
+const IDENTITY_PML4_ENTRY: usize = 0;
+/// PML4 entries from this one are the kernel half.
+const KERNEL_HALF_PML4_ENTRY: usize = 256;
+const DIRECT_MAP_PML4_ENTRY: usize = (PHYSICAL_MEMORY_OFFSET / PML4_ENTRY_SIZE) as usize % 512;
+
+/// The lowest address that can be used for task-local mappings. It's the start of the first
+/// PML4 entry that isn't shared with the kernel.
+pub const USER_SPACE_START: VirtAddr =
+    VirtAddr::new_truncate((IDENTITY_PML4_ENTRY as u64 + 1) * PML4_ENTRY_SIZE);
+/// The end (exclusive) of the lower half of the canonical address space.
+pub const USER_SPACE_END: VirtAddr =
+    VirtAddr::new_truncate(KERNEL_HALF_PML4_ENTRY as u64 * PML4_ENTRY_SIZE);
+
+/// Marks a leaf entry whose frame was allocated by the address space and therefore has to be
+/// returned to the memory manager when it's unmapped.
+const OWNED_FRAME: PageTableFlags = PageTableFlags::BIT_9;
+
+static KERNEL_PML4_FRAME: AtomicU64 = AtomicU64::new(0);
+/// Serializes modifications to the kernel page table.
+static KERNEL_PAGE_TABLE_LOCK: Spinlock<()> = Spinlock::new(());
+
+fn is_kernel_pml4_entry(index: usize) -> bool {
+    index == IDENTITY_PML4_ENTRY || index >= KERNEL_HALF_PML4_ENTRY
+}
+
+/// Translates a physical address into the virtual address in the direct map.
+/// Panics if the address is beyond the direct map.
+pub fn phys_to_virt(address: PhysAddr) -> VirtAddr {
+    assert!(
+        address.as_u64() < DIRECT_MAP_SIZE,
+        "{:?} is beyond the direct map",
+        address
+    );
+    VirtAddr::new(PHYSICAL_MEMORY_OFFSET + address.as_u64())
+}
+
+/// Translates a virtual address in the kernel half into the physical address it's mapped to.
+pub fn virt_to_phys(address: VirtAddr) -> Option<PhysAddr> {
+    let offset = address.as_u64().wrapping_sub(PHYSICAL_MEMORY_OFFSET);
+    if offset < DIRECT_MAP_SIZE {
+        return Some(PhysAddr::new(offset));
+    }
+    let (pml4_frame, _) = Cr3::read();
+    // SAFETY: The physical memory is mapped in the direct map, and CR3 points to a valid table.
+    let mapper = unsafe {
+        OffsetPageTable::new(
+            &mut *frame_to_page_table(pml4_frame),
+            VirtAddr::new(PHYSICAL_MEMORY_OFFSET),
+        )
+    };
+    mapper.translate_addr(address)
+}
+
+/// Replaces the page table given by the bootloader with the one owned by the kernel.
+/// The new table keeps the kernel image mapping, maps the physical memory in the direct map, and
+/// drops the identity mapping the bootloader needed for itself.
 pub fn initialize() {
-    // Ah wait this seems to be wrong, I feel like I should obtain the page directory from
-    // Cr3::read().
     /// 1GB per page directory
-    const PAGE_DIRECTORY_COUNT: usize = 64;
+    const PAGE_DIRECTORY_COUNT: usize = (DIRECT_MAP_SIZE / Size1GiB::SIZE) as usize;
     static mut PML4_TABLE: PageTable = PageTable::new();
-    static mut PDP_TABLE: PageTable = PageTable::new();
+    static mut DIRECT_MAP_PDP_TABLE: PageTable = PageTable::new();
+    static mut IDENTITY_PDP_TABLE: PageTable = PageTable::new();
+    static mut KERNEL_STACK_PDP_TABLE: PageTable = PageTable::new();
+    static mut MMIO_PDP_TABLE: PageTable = PageTable::new();
     static mut PAGE_DIRECTORY: [MaybeUninit<PageTable>; PAGE_DIRECTORY_COUNT] =
         MaybeUninit::uninit_array();
 
     fn page_table_to_frame(page_table: &PageTable) -> PhysFrame {
-        let address = PhysAddr::new(page_table as *const PageTable as u64);
+        let address = virt_to_phys(VirtAddr::from_ptr(page_table))
+            .expect("Page tables in the kernel image should be mapped");
         PhysFrame::from_start_address(address).expect("Page table should be aligned")
     }
     let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
-    unsafe {
-        PML4_TABLE[0].set_frame(page_table_to_frame(&PDP_TABLE), flags);
+    let pml4 = unsafe { &mut PML4_TABLE };
+    let (bootloader_pml4_frame, _) = Cr3::read();
+    let bootloader_pml4 = unsafe { &*frame_to_page_table(bootloader_pml4_frame) };
+    for i in KERNEL_HALF_PML4_ENTRY..512 {
+        if i != DIRECT_MAP_PML4_ENTRY {
+            pml4[i] = bootloader_pml4[i].clone();
+        }
     }
+    pml4[DIRECT_MAP_PML4_ENTRY]
+        .set_frame(page_table_to_frame(unsafe { &DIRECT_MAP_PDP_TABLE }), flags);
+    pml4[IDENTITY_PML4_ENTRY].set_frame(page_table_to_frame(unsafe { &IDENTITY_PDP_TABLE }), flags);
+    pml4[kernel_stack::AREA_PML4_ENTRY].set_frame(
+        page_table_to_frame(unsafe { &KERNEL_STACK_PDP_TABLE }),
+        flags,
+    );
+    pml4[mmio::AREA_PML4_ENTRY].set_frame(page_table_to_frame(unsafe { &MMIO_PDP_TABLE }), flags);
     for (i_pdpt, uninitialized_page_table) in unsafe { &mut PAGE_DIRECTORY }.iter_mut().enumerate()
     {
-        let initialized = uninitialized_page_table.write(PageTable::new());
+        let directory = uninitialized_page_table.write(PageTable::new());
         unsafe {
-            PDP_TABLE[i_pdpt].set_frame(page_table_to_frame(initialized), flags);
+            DIRECT_MAP_PDP_TABLE[i_pdpt].set_frame(page_table_to_frame(directory), flags);
         }
-        for (i_pd, directory) in initialized.iter_mut().enumerate() {
+        for (i_pd, entry) in directory.iter_mut().enumerate() {
             let address =
                 PhysAddr::new((i_pdpt as u64) * Size1GiB::SIZE + (i_pd as u64) * Size2MiB::SIZE);
-            directory.set_addr(address, flags | PageTableFlags::HUGE_PAGE);
+            entry.set_addr(address, flags | PageTableFlags::HUGE_PAGE);
         }
     }
+    let pml4_frame = page_table_to_frame(pml4);
+    KERNEL_PML4_FRAME.store(pml4_frame.start_address().as_u64(), Ordering::SeqCst);
     unsafe {
-        Cr3::write(page_table_to_frame(&PML4_TABLE), Cr3Flags::empty());
+        Cr3::write(pml4_frame, Cr3Flags::empty());
+    }
+}
+
+fn kernel_pml4_frame() -> PhysFrame {
+    let address = PhysAddr::new(KERNEL_PML4_FRAME.load(Ordering::SeqCst));
+    assert!(!address.is_null(), "Paging is not initialized yet");
+    PhysFrame::containing_address(address)
+}
+
+/// The physical address of the kernel's PML4, which the APs start with.
+pub(crate) fn kernel_pml4_address() -> PhysAddr {
+    kernel_pml4_frame().start_address()
+}
+
+fn frame_to_page_table(frame: PhysFrame) -> *mut PageTable {
+    phys_to_virt(frame.start_address()).as_mut_ptr()
+}
+
+fn with_kernel_mapper<T, F: FnOnce(&mut OffsetPageTable, &mut BitmapMemoryManager) -> T>(
+    f: F,
+) -> T {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let _guard = KERNEL_PAGE_TABLE_LOCK.lock();
+        // SAFETY: The physical memory is mapped in the direct map, and the kernel PML4 is valid.
+        let mut mapper = unsafe {
+            OffsetPageTable::new(
+                &mut *frame_to_page_table(kernel_pml4_frame()),
+                VirtAddr::new(PHYSICAL_MEMORY_OFFSET),
+            )
+        };
+        memory_manager::with_memory_manager(|mm| f(&mut mapper, mm))
+    })
+}
+
+/// Maps `frames` at the same virtual addresses in the kernel half shared by all address spaces.
+/// This is for drivers that hand the virtual addresses of their buffers to devices as is.
+///
+/// # Safety
+/// The frames must not be used by anyone else.
+pub unsafe fn identity_map(frames: PhysFrameRange<Size4KiB>, flags: PageTableFlags) -> Result<()> {
+    if frames.end.start_address().as_u64() > PML4_ENTRY_SIZE {
+        return Err(Error::Whatever(
+            "The frames are out of the identity mapping window",
+        ));
+    }
+    with_kernel_mapper(|mapper, mm| {
+        for frame in frames {
+            let page = Page::containing_address(VirtAddr::new(frame.start_address().as_u64()));
+            mapper
+                .map_to(page, frame, flags | PageTableFlags::PRESENT, mm)
+                .map_err(map_error_to_error)?
+                .flush();
+        }
+        Ok(())
+    })
+}
+
+/// Returns the level 1 entry for `page` in the hierarchy rooted at `pml4`.
+/// Missing intermediate tables are allocated from `mm` if it's given, or `None` is returned.
+fn leaf_entry(
+    pml4: &mut PageTable,
+    page: Page<Size4KiB>,
+    mut mm: Option<&mut BitmapMemoryManager>,
+) -> Option<&'static mut PageTableEntry> {
+    let mut table: *mut PageTable = pml4;
+    for index in [page.p4_index(), page.p3_index(), page.p2_index()] {
+        let entry = unsafe { &mut (*table)[index] };
+        if entry.is_unused() {
+            let frame = mm.as_deref_mut()?.allocate_frame()?;
+            unsafe { (*frame_to_page_table(frame)).zero() };
+            let mut flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+            if page.start_address() < USER_SPACE_END {
+                flags |= PageTableFlags::USER_ACCESSIBLE;
+            }
+            entry.set_frame(frame, flags);
+        }
+        table = frame_to_page_table(entry.frame().ok()?);
+    }
+    Some(unsafe { &mut (*table)[page.p1_index()] })
+}
+
+/// Why a page fault couldn't be resolved.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum PageFaultError {
+    /// The address is in a guard page below a stack. Holds the name of the owner of the stack if
+    /// it's known.
+    StackOverflow(Option<&'static str>),
+    /// The address isn't in any region that is populated on demand, or the access violates the
+    /// protection of the page.
+    NotResolvable,
+    OutOfMemory,
+}
+
+fn map_error_to_error<S: PageSize>(e: MapToError<S>) -> Error {
+    match e {
+        MapToError::FrameAllocationFailed => Error::Whatever("Out of physical memory"),
+        MapToError::ParentEntryHugePage => Error::Whatever("Tried to map inside of a huge page"),
+        MapToError::PageAlreadyMapped(_) => Error::Whatever("The page is already mapped"),
+    }
+}
+
+fn unmap_error_to_error(e: UnmapError) -> Error {
+    match e {
+        UnmapError::ParentEntryHugePage => Error::Whatever("Tried to unmap inside of a huge page"),
+        UnmapError::PageNotMapped => Error::Whatever("The page is not mapped"),
+        UnmapError::InvalidFrameAddress(_) => Error::Whatever("The page maps an invalid frame"),
+    }
+}
+
+/// A 4-level page table hierarchy.
+///
+/// The entries for the kernel half are shared with the kernel page table, and the rest is owned
+/// by this address space; the page tables and the frames allocated through `map_anonymous` are
+/// freed on drop.
+pub struct AddressSpace {
+    pml4_frame: PhysFrame,
+    /// Regions populated on page faults, keyed by their start address.
+    regions: BTreeMap<VirtAddr, Region>,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+enum RegionKind {
+    /// Zero-filled pages are mapped with the flags on the first access.
+    Lazy(PageTableFlags),
+    /// Any access is a stack overflow.
+    Guard,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+struct Region {
+    pages: PageRange<Size4KiB>,
+    kind: RegionKind,
+}
+
+impl AddressSpace {
+    /// Creates an address space that has the kernel mappings only.
+    pub fn new() -> Result<Self> {
+        let pml4_frame = memory_manager::with_memory_manager(|mm| mm.allocate_frame())
+            .ok_or(Error::Whatever("Out of physical memory"))?;
+        let kernel = unsafe { &*frame_to_page_table(kernel_pml4_frame()) };
+        let pml4 = unsafe { &mut *frame_to_page_table(pml4_frame) };
+        pml4.zero();
+        for i in (0..512).filter(|&i| is_kernel_pml4_entry(i)) {
+            pml4[i] = kernel[i].clone();
+        }
+        Ok(Self {
+            pml4_frame,
+            regions: BTreeMap::new(),
+        })
+    }
+
+    pub fn pml4_frame(&self) -> PhysFrame {
+        self.pml4_frame
+    }
+
+    /// Whether this address space is currently loaded into CR3.
+    pub fn is_active(&self) -> bool {
+        Cr3::read().0 == self.pml4_frame
+    }
+
+    fn mapper(&mut self) -> OffsetPageTable<'_> {
+        // SAFETY: The physical memory is mapped in the direct map, and pml4_frame is a valid
+        // level 4 table that is owned by self.
+        unsafe {
+            OffsetPageTable::new(
+                &mut *frame_to_page_table(self.pml4_frame),
+                VirtAddr::new(PHYSICAL_MEMORY_OFFSET),
+            )
+        }
+    }
+
+    fn flush(&self, flush: MapperFlush<Size4KiB>) {
+        if self.is_active() {
+            flush.flush();
+        } else {
+            flush.ignore();
+        }
+    }
+
+    fn check_user_page(page: Page<Size4KiB>) -> Result<()> {
+        let address = page.start_address();
+        if address < USER_SPACE_START || address >= USER_SPACE_END {
+            Err(Error::Whatever(
+                "The page is out of the task-local part of the address space",
+            ))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Maps `page` to `frame`. The frame is not owned by this address space, i.e. it won't be
+    /// freed when the page gets unmapped.
+    ///
+    /// # Safety
+    /// Mapping arbitrary frames can alias memory used by someone else.
+    pub unsafe fn map(
+        &mut self,
+        page: Page<Size4KiB>,
+        frame: PhysFrame<Size4KiB>,
+        flags: PageTableFlags,
+    ) -> Result<()> {
+        Self::check_user_page(page)?;
+        self.map_impl(
+            page,
+            frame,
+            (flags | PageTableFlags::PRESENT) & !OWNED_FRAME,
+        )
+    }
+
+    unsafe fn map_impl(
+        &mut self,
+        page: Page<Size4KiB>,
+        frame: PhysFrame<Size4KiB>,
+        flags: PageTableFlags,
+    ) -> Result<()> {
+        let flush = memory_manager::with_memory_manager(|mm: &mut BitmapMemoryManager| {
+            self.mapper().map_to(page, frame, flags, mm)
+        })
+        .map_err(map_error_to_error)?;
+        self.flush(flush);
+        Ok(())
+    }
+
+    /// Allocates zero-filled frames and maps them to `pages`.
+    /// On failure, the pages mapped so far are unmapped again.
+    pub fn map_anonymous(
+        &mut self,
+        pages: PageRange<Size4KiB>,
+        flags: PageTableFlags,
+    ) -> Result<()> {
+        for page in pages {
+            if let Err(e) = self.map_anonymous_page(page, flags) {
+                for mapped in Page::range(pages.start, page) {
+                    self.unmap(mapped).ok();
+                }
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Allocates a zero-filled frame and maps it to `page`.
+    pub fn map_anonymous_page(
+        &mut self,
+        page: Page<Size4KiB>,
+        flags: PageTableFlags,
+    ) -> Result<()> {
+        Self::check_user_page(page)?;
+        let frame = allocate_zeroed_frame()?;
+        let flags = flags | PageTableFlags::PRESENT | OWNED_FRAME;
+        unsafe { self.map_impl(page, frame, flags) }.map_err(|e| {
+            free_frame(frame);
+            e
+        })
+    }
+
+    /// Unmaps `page` and returns the frame it was mapped to.
+    /// If the frame was allocated by this address space, it's freed and `None` is returned.
+    pub fn unmap(&mut self, page: Page<Size4KiB>) -> Result<Option<PhysFrame>> {
+        Self::check_user_page(page)?;
+        let owned = match self.mapper().translate(page.start_address()) {
+            TranslateResult::Mapped { flags, .. } => flags.contains(OWNED_FRAME),
+            _ => false,
+        };
+        let (frame, flush) = self.mapper().unmap(page).map_err(unmap_error_to_error)?;
+        self.flush(flush);
+        if owned {
+            free_frame(frame);
+            Ok(None)
+        } else {
+            Ok(Some(frame))
+        }
     }
+
+    /// Changes the protection of an already mapped `page`.
+    pub fn update_flags(&mut self, page: Page<Size4KiB>, flags: PageTableFlags) -> Result<()> {
+        Self::check_user_page(page)?;
+        let owned = match self.mapper().translate(page.start_address()) {
+            TranslateResult::Mapped { flags, .. } => flags & OWNED_FRAME,
+            _ => return Err(Error::Whatever("The page is not mapped")),
+        };
+        let flags = (flags & !OWNED_FRAME) | PageTableFlags::PRESENT | owned;
+        let flush = unsafe { self.mapper().update_flags(page, flags) }
+            .map_err(|_| Error::Whatever("Failed to update the page flags"))?;
+        self.flush(flush);
+        Ok(())
+    }
+
+    fn add_region(&mut self, pages: PageRange<Size4KiB>, kind: RegionKind) -> Result<()> {
+        if pages.is_empty() {
+            return Ok(());
+        }
+        Self::check_user_page(pages.start)?;
+        Self::check_user_page(pages.end - 1)?;
+        let overlapping = self
+            .regions
+            .range(..pages.end.start_address())
+            .next_back()
+            .map_or(false, |(_, region)| region.pages.end > pages.start);
+        if overlapping {
+            return Err(Error::Whatever("The region overlaps with another one"));
+        }
+        self.regions
+            .insert(pages.start.start_address(), Region { pages, kind });
+        Ok(())
+    }
+
+    /// Reserves `pages` to be backed by zero-filled frames on the first access.
+    pub fn reserve_lazy(
+        &mut self,
+        pages: PageRange<Size4KiB>,
+        flags: PageTableFlags,
+    ) -> Result<()> {
+        self.add_region(pages, RegionKind::Lazy(flags))
+    }
+
+    /// Reserves `pages` as a guard below a stack; touching them is reported as a stack overflow.
+    pub fn reserve_guard(&mut self, pages: PageRange<Size4KiB>) -> Result<()> {
+        self.add_region(pages, RegionKind::Guard)
+    }
+
+    /// Removes the region that starts at `start`, unmapping the pages populated so far.
+    pub fn release_region(&mut self, start: VirtAddr) -> Result<()> {
+        let region = self
+            .regions
+            .remove(&start)
+            .ok_or(Error::Whatever("No region starts at the address"))?;
+        for page in region.pages {
+            if self.translate(page.start_address()).is_some() {
+                self.unmap(page)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Tries to resolve a page fault at `address` by populating the region it belongs to.
+    pub fn resolve_fault(
+        &mut self,
+        address: VirtAddr,
+        error_code: PageFaultErrorCode,
+    ) -> core::result::Result<(), PageFaultError> {
+        let page = Page::containing_address(address);
+        let region = self.region(page).ok_or(PageFaultError::NotResolvable)?;
+        match region.kind {
+            RegionKind::Guard => Err(PageFaultError::StackOverflow(None)),
+            RegionKind::Lazy(_)
+                if error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) =>
+            {
+                Err(PageFaultError::NotResolvable)
+            }
+            RegionKind::Lazy(flags) => {
+                let frame =
+                    memory_manager::allocate_frame_in_fault().ok_or(PageFaultError::OutOfMemory)?;
+                unsafe { (*frame_to_page_table(frame)).zero() };
+                let flags = flags | PageTableFlags::PRESENT | OWNED_FRAME;
+                let result = unsafe {
+                    self.mapper()
+                        .map_to(page, frame, flags, &mut FaultFrameAllocator)
+                };
+                match result {
+                    Ok(flush) => {
+                        self.flush(flush);
+                        Ok(())
+                    }
+                    // Another CPU or a nested fault got there first.
+                    Err(MapToError::PageAlreadyMapped(_)) => {
+                        memory_manager::free_frame_in_fault(frame);
+                        Ok(())
+                    }
+                    Err(_) => {
+                        memory_manager::free_frame_in_fault(frame);
+                        Err(PageFaultError::OutOfMemory)
+                    }
+                }
+            }
+        }
+    }
+
+    fn region(&self, page: Page<Size4KiB>) -> Option<Region> {
+        self.regions
+            .range(..=page.start_address())
+            .next_back()
+            .map(|(_, region)| *region)
+            .filter(|region| page < region.pages.end)
+    }
+
+    /// Maps the lazy pages in `length` bytes from `start`, and checks that ring 3 can access them,
+    /// and write to them if `writable`. The kernel can then access them for ring 3 without
+    /// faulting, as nothing but the task itself changes the mappings of its address space.
+    pub fn populate(&mut self, start: VirtAddr, length: u64, writable: bool) -> Result<()> {
+        if length == 0 {
+            return Ok(());
+        }
+        let end = start
+            .as_u64()
+            .checked_add(length - 1)
+            .ok_or(Error::Whatever("The range is out of the address space"))?;
+        let pages = Page::range_inclusive(
+            Page::<Size4KiB>::containing_address(start),
+            Page::containing_address(
+                VirtAddr::try_new(end)
+                    .map_err(|_| Error::Whatever("The range is out of the address space"))?,
+            ),
+        );
+        for page in pages {
+            if self.flags(page.start_address()).is_none() {
+                match self.region(page).map(|region| region.kind) {
+                    Some(RegionKind::Lazy(flags)) => self.map_anonymous_page(page, flags)?,
+                    _ => return Err(Error::Whatever("The page is not mapped")),
+                }
+            }
+            let flags = self
+                .flags(page.start_address())
+                .ok_or(Error::Whatever("The page is not mapped"))?;
+            if !flags.contains(PageTableFlags::USER_ACCESSIBLE)
+                || (writable && !flags.contains(PageTableFlags::WRITABLE))
+            {
+                return Err(Error::Whatever("The page is protected"));
+            }
+        }
+        Ok(())
+    }
+
+    /// Translates `address` into the physical address it's mapped to, if any.
+    pub fn translate(&mut self, address: VirtAddr) -> Option<PhysAddr> {
+        self.mapper().translate_addr(address)
+    }
+
+    /// Returns the flags of the leaf entry `address` is mapped with, if any.
+    pub fn flags(&mut self, address: VirtAddr) -> Option<PageTableFlags> {
+        match self.mapper().translate(address) {
+            TranslateResult::Mapped { flags, .. } => Some(flags & !OWNED_FRAME),
+            _ => None,
+        }
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        assert!(
+            !self.is_active(),
+            "Dropping the address space that is currently in use"
+        );
+        fn free_table(frame: PhysFrame, level: u8) {
+            let table = unsafe { &mut *frame_to_page_table(frame) };
+            for entry in table.iter_mut() {
+                if entry.is_unused() {
+                    continue;
+                }
+                let flags = entry.flags();
+                if let Ok(child) = entry.frame() {
+                    if level > 1 {
+                        free_table(child, level - 1);
+                    } else if flags.contains(OWNED_FRAME) {
+                        free_frame(child);
+                    }
+                }
+                entry.set_unused();
+            }
+            free_frame(frame);
+        }
+        let pml4 = unsafe { &mut *frame_to_page_table(self.pml4_frame) };
+        for (i, entry) in pml4.iter_mut().enumerate() {
+            if is_kernel_pml4_entry(i) || entry.is_unused() {
+                continue;
+            }
+            if let Ok(frame) = entry.frame() {
+                free_table(frame, 3);
+            }
+            entry.set_unused();
+        }
+        free_frame(self.pml4_frame);
+    }
+}
+
+fn allocate_zeroed_frame() -> Result<PhysFrame> {
+    let frame = memory_manager::with_memory_manager(|mm| mm.allocate_frame())
+        .ok_or(Error::Whatever("Out of physical memory"))?;
+    unsafe { (*frame_to_page_table(frame)).zero() };
+    Ok(frame)
+}
+
+/// Allocates page tables from the frames usable in the page fault handler.
+struct FaultFrameAllocator;
+
+unsafe impl FrameAllocator<Size4KiB> for FaultFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
+        let frame = memory_manager::allocate_frame_in_fault()?;
+        unsafe { (*frame_to_page_table(frame)).zero() };
+        Some(frame)
+    }
+}
+
+fn free_frame(frame: PhysFrame) {
+    use x86_64::structures::paging::FrameDeallocator;
+    memory_manager::with_memory_manager(|mm| unsafe { mm.deallocate_frame(frame) });
 }
@@ -0,0 +1,204 @@
+//! Stacks of the tasks, placed in a dedicated area of the kernel half.
+//!
+//! Each stack gets a fixed-size slot in the area. Only the top few pages of a stack are mapped
+//! up front, and the rest gets mapped by the page fault handler on the first access. The unused
+//! part of the slot below the stack is never mapped, so that running off the stack is reported
+//! as a stack overflow instead of silently corrupting the neighbour.
+
+use alloc::{collections::BTreeMap, vec::Vec};
+use x86_64::{
+    structures::{
+        idt::PageFaultErrorCode,
+        paging::{FrameDeallocator, Page, PageSize, PageTableFlags, Size4KiB},
+    },
+    VirtAddr,
+};
+
+use super::{
+    frame_to_page_table, kernel_pml4_frame, leaf_entry, phys_to_virt, with_kernel_mapper,
+    PageFaultError, OWNED_FRAME, PML4_ENTRY_SIZE,
+};
+use crate::{
+    cpu_spinlock::{const_cpu_spinlock, lock_unless_held_here, CpuSpinlock},
+    memory_manager,
+    prelude::*,
+    smp,
+};
+
+pub(super) const AREA_PML4_ENTRY: usize = 257;
+const AREA_START: u64 = 0xFFFF_0000_0000_0000 | (AREA_PML4_ENTRY as u64 * PML4_ENTRY_SIZE);
+const SLOT_SIZE: u64 = 64 * 1024 * 1024;
+const SLOT_COUNT: usize = (PML4_ENTRY_SIZE / SLOT_SIZE) as usize;
+/// How much of the top of a stack is mapped eagerly.
+const EAGER_SIZE: u64 = 4 * Size4KiB::SIZE;
+
+struct StackInfo {
+    size: u64,
+    owner: &'static str,
+}
+
+struct Slots {
+    next_unused: usize,
+    released: Vec<usize>,
+    in_use: BTreeMap<usize, StackInfo>,
+}
+
+static SLOTS: CpuSpinlock<Slots> = const_cpu_spinlock(Slots {
+    next_unused: 0,
+    released: Vec::new(),
+    in_use: BTreeMap::new(),
+});
+
+fn slot_top(slot: usize) -> u64 {
+    AREA_START + (slot as u64 + 1) * SLOT_SIZE
+}
+
+pub struct KernelStack {
+    slot: usize,
+    size: u64,
+}
+
+impl KernelStack {
+    /// Reserves a stack of `size` bytes for the task `owner`.
+    pub fn new(size: usize, owner: &'static str) -> Result<Self> {
+        let size = (size as u64).align_up(Size4KiB::SIZE);
+        if size == 0 || size > SLOT_SIZE - Size4KiB::SIZE {
+            return Err(Error::Whatever("Unsupported stack size"));
+        }
+        let slot = x86_64::instructions::interrupts::without_interrupts(|| {
+            let mut slots = SLOTS.lock();
+            let slot = if let Some(slot) = slots.released.pop() {
+                slot
+            } else if slots.next_unused < SLOT_COUNT {
+                slots.next_unused += 1;
+                slots.next_unused - 1
+            } else {
+                return Err(Error::Whatever("Too many stacks"));
+            };
+            slots.in_use.insert(slot, StackInfo { size, owner });
+            Ok(slot)
+        })?;
+        let stack = Self { slot, size };
+        stack.prepare()?;
+        Ok(stack)
+    }
+
+    fn pages(&self) -> impl Iterator<Item = Page<Size4KiB>> {
+        let top = Page::containing_address(VirtAddr::new(slot_top(self.slot)));
+        Page::range(top - self.size / Size4KiB::SIZE, top)
+    }
+
+    /// Creates the page tables for the whole stack so that the page fault handler only has to
+    /// fill in the leaf entries, and maps the top of the stack.
+    fn prepare(&self) -> Result<()> {
+        let eager_start = slot_top(self.slot) - u64::min(EAGER_SIZE, self.size);
+        with_kernel_mapper(|mapper, mm| {
+            for page in self.pages() {
+                let entry = leaf_entry(mapper.level_4_table(), page, Some(&mut *mm))
+                    .ok_or(Error::Whatever("Out of physical memory"))?;
+                if page.start_address().as_u64() >= eager_start && entry.is_unused() {
+                    let frame = mm
+                        .allocate_frame()
+                        .ok_or(Error::Whatever("Out of physical memory"))?;
+                    zero_frame(frame);
+                    entry.set_frame(frame, stack_page_flags());
+                }
+            }
+            Ok(())
+        })
+    }
+
+    /// The address right above the stack, i.e. the initial stack pointer.
+    pub fn bottom_address(&self) -> u64 {
+        slot_top(self.slot)
+    }
+}
+
+/// Dropping a stack waits for the other CPUs to flush their TLBs, so it must not be done with a
+/// spinlock held.
+impl Drop for KernelStack {
+    fn drop(&mut self) {
+        let mut frames = Vec::new();
+        with_kernel_mapper(|mapper, _| {
+            for page in self.pages() {
+                if let Some(entry) = leaf_entry(mapper.level_4_table(), page, None) {
+                    if let Ok(frame) = entry.frame() {
+                        frames.push(frame);
+                    }
+                    entry.set_unused();
+                }
+            }
+        });
+        // The frames can be reused only once no CPU reaches them through its TLB.
+        smp::flush_tlb_everywhere(
+            VirtAddr::new(slot_top(self.slot) - self.size),
+            self.size / Size4KiB::SIZE,
+        );
+        memory_manager::with_memory_manager(|mm| {
+            for frame in frames {
+                unsafe { mm.deallocate_frame(frame) };
+            }
+        });
+        // The page tables are kept, and will be reused by the next stack in this slot.
+        x86_64::instructions::interrupts::without_interrupts(|| {
+            let mut slots = SLOTS.lock();
+            slots.in_use.remove(&self.slot);
+            slots.released.push(self.slot);
+        });
+    }
+}
+
+fn stack_page_flags() -> PageTableFlags {
+    PageTableFlags::PRESENT | PageTableFlags::WRITABLE | OWNED_FRAME
+}
+
+fn zero_frame(frame: x86_64::structures::paging::PhysFrame) {
+    unsafe {
+        core::ptr::write_bytes(
+            phys_to_virt(frame.start_address()).as_mut_ptr::<u8>(),
+            0,
+            Size4KiB::SIZE as usize,
+        )
+    };
+}
+
+/// Whether `address` is in the area for the kernel stacks.
+pub fn contains(address: VirtAddr) -> bool {
+    (AREA_START..AREA_START + PML4_ENTRY_SIZE).contains(&address.as_u64())
+}
+
+/// Tries to resolve a page fault at `address` in the area for the kernel stacks.
+/// This doesn't wait for the lock if the faulting code might be holding it, which is only when
+/// the faulting CPU holds it.
+pub fn resolve_fault(
+    address: VirtAddr,
+    error_code: PageFaultErrorCode,
+) -> core::result::Result<(), PageFaultError> {
+    if !contains(address) || error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+        return Err(PageFaultError::NotResolvable);
+    }
+    let slot = ((address.as_u64() - AREA_START) / SLOT_SIZE) as usize;
+    let (size, owner) = {
+        let slots = lock_unless_held_here(&SLOTS).ok_or(PageFaultError::NotResolvable)?;
+        let info = slots
+            .in_use
+            .get(&slot)
+            .ok_or(PageFaultError::NotResolvable)?;
+        (info.size, info.owner)
+    };
+    if address.as_u64() < slot_top(slot) - size {
+        return Err(PageFaultError::StackOverflow(Some(owner)));
+    }
+    let page = Page::<Size4KiB>::containing_address(address);
+    let pml4 = unsafe { &mut *frame_to_page_table(kernel_pml4_frame()) };
+    let entry = leaf_entry(pml4, page, None).ok_or(PageFaultError::NotResolvable)?;
+    if !entry.is_unused() {
+        // Someone else has resolved it.
+        return Ok(());
+    }
+    let frame = memory_manager::allocate_frame_in_fault().ok_or(PageFaultError::OutOfMemory)?;
+    zero_frame(frame);
+    entry.set_frame(frame, stack_page_flags());
+    x86_64::instructions::tlb::flush(page.start_address());
+    Ok(())
+}
@@ -0,0 +1,91 @@
+//! Uncacheable mappings of device memory, placed in a dedicated area of the kernel half.
+//!
+//! The direct map is cacheable, which is wrong for the registers of devices. The mappings here
+//! live as long as the kernel, and a range already mapped is handed out again.
+
+use alloc::vec::Vec;
+use spinning_top::Spinlock;
+use x86_64::{
+    structures::paging::{Mapper, Page, PageSize, PageTableFlags, PhysFrame, Size4KiB},
+    PhysAddr, VirtAddr,
+};
+
+use super::{map_error_to_error, with_kernel_mapper, PML4_ENTRY_SIZE};
+use crate::prelude::*;
+
+pub(super) const AREA_PML4_ENTRY: usize = 258;
+const AREA_START: u64 = 0xFFFF_0000_0000_0000 | (AREA_PML4_ENTRY as u64 * PML4_ENTRY_SIZE);
+
+#[derive(Copy, Clone, Debug)]
+struct Mapping {
+    physical: PhysAddr,
+    size: u64,
+    virtual_start: VirtAddr,
+}
+
+struct Mappings {
+    /// The offset of the unused part of the area.
+    next: u64,
+    mappings: Vec<Mapping>,
+}
+
+static MAPPINGS: Spinlock<Mappings> = Spinlock::new(Mappings {
+    next: 0,
+    mappings: Vec::new(),
+});
+
+/// Maps `size` bytes of device memory at `physical` as uncacheable, and returns where it's
+/// mapped.
+pub fn map(physical: PhysAddr, size: u64) -> Result<VirtAddr> {
+    if size == 0 {
+        return Err(Error::Whatever("The device memory is empty"));
+    }
+    let end = physical
+        .as_u64()
+        .checked_add(size)
+        .ok_or(Error::Whatever("The device memory is out of range"))?;
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut mappings = MAPPINGS.lock();
+        let existing = mappings.mappings.iter().find(|mapping| {
+            mapping.physical <= physical && end <= mapping.physical.as_u64() + mapping.size
+        });
+        if let Some(mapping) = existing {
+            return Ok(mapping.virtual_start + (physical - mapping.physical));
+        }
+        let first_frame = PhysFrame::<Size4KiB>::containing_address(physical);
+        let frames = PhysFrame::range(
+            first_frame,
+            PhysFrame::containing_address(PhysAddr::new(end - 1)) + 1,
+        );
+        let area_size = frames.count() as u64 * Size4KiB::SIZE;
+        if mappings.next + area_size > PML4_ENTRY_SIZE {
+            return Err(Error::Whatever("Out of the area for device memory"));
+        }
+        let virtual_start = VirtAddr::new(AREA_START + mappings.next);
+        let flags = PageTableFlags::PRESENT
+            | PageTableFlags::WRITABLE
+            | PageTableFlags::NO_CACHE
+            | PageTableFlags::WRITE_THROUGH;
+        with_kernel_mapper(|mapper, mm| {
+            let pages = Page::<Size4KiB>::range(
+                Page::containing_address(virtual_start),
+                Page::containing_address(virtual_start + area_size),
+            );
+            for (page, frame) in pages.zip(frames) {
+                // SAFETY: The page is in the area nobody else maps to, and the frame is device
+                // memory rather than something the memory manager hands out.
+                unsafe { mapper.map_to(page, frame, flags, mm) }
+                    .map_err(map_error_to_error)?
+                    .flush();
+            }
+            Ok(())
+        })?;
+        mappings.next += area_size;
+        mappings.mappings.push(Mapping {
+            physical: first_frame.start_address(),
+            size: area_size,
+            virtual_start,
+        });
+        Ok(virtual_start + (physical - first_frame.start_address()))
+    })
+}
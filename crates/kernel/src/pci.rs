@@ -1,12 +1,119 @@
 // use crate::x86_64;
+mod class;
+mod ids;
+mod tree;
+
+pub use class::*;
+pub use ids::{device_name, vendor_name};
+pub use tree::{
+    find_by_address, find_by_class, find_by_id, functions, with_device_tree, DeviceTree,
+    DeviceTreeNode,
+};
+
+use core::{
+    fmt,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+};
+
+use acpi::PciConfigRegions;
 use bitfield::bitfield;
 use derive_getters::Getters;
-use x86_64::instructions::port::{PortReadOnly, PortWriteOnly};
+use x86_64::{
+    instructions::port::{PortReadOnly, PortWriteOnly},
+    PhysAddr, VirtAddr,
+};
+
+use crate::{acpi_tables, paging, prelude::*};
 
 const CONFIG_ADDRESS: u16 = 0x0CF8;
 const CONFIG_DATA: u16 = 0x0CFC;
 
-fn read_pci_config(address: u32) -> u32 {
+/// The size of the configuration space of a function through ECAM. Only the first 256 bytes are
+/// accessible through the I/O ports.
+pub const EXTENDED_CONFIG_SPACE_SIZE: u16 = 0x1000;
+
+/// The size of the configuration spaces of a bus through ECAM, for 32 devices of 8 functions.
+const ECAM_BUS_SIZE: u64 = 32 * 8 * EXTENDED_CONFIG_SPACE_SIZE as u64;
+
+/// Where the ECAM of each bus is mapped, or 0 to use the I/O ports. Set once in `initialize`.
+static ECAM_BASES: [AtomicU64; 256] = [NO_ECAM; 256];
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_ECAM: AtomicU64 = AtomicU64::new(0);
+static HAS_ECAM: AtomicBool = AtomicBool::new(false);
+
+/// Switches to the memory-mapped configuration space (ECAM) if the ACPI MCFG table describes
+/// it, then enumerates the devices. Otherwise the configuration space is accessed through the I/O
+/// ports.
+pub fn initialize() {
+    let result = acpi_tables::tables()
+        .and_then(|tables| PciConfigRegions::new(&tables).map_err(Error::from))
+        .and_then(|regions| map_ecam(&regions));
+    match result {
+        Ok(()) => log::info!("Accessing the PCI configuration space through ECAM"),
+        Err(e) => {
+            for base in &ECAM_BASES {
+                base.store(0, Ordering::Release);
+            }
+            log::warn!(
+                "Accessing the PCI configuration space through the I/O ports: {:?}",
+                e
+            )
+        }
+    }
+    tree::initialize();
+}
+
+/// Maps the ECAM of the buses of the segment group 0 as device memory, a run of buses next to
+/// each other in memory at a time.
+fn map_ecam(regions: &PciConfigRegions) -> Result<()> {
+    let ecam_start = |bus: usize| regions.physical_address(0, bus as u8, 0, 0);
+    let mut bus = 0;
+    while bus < ECAM_BASES.len() {
+        let start = match ecam_start(bus) {
+            Some(start) => start,
+            None => {
+                bus += 1;
+                continue;
+            }
+        };
+        let mut count = 1;
+        while bus + count < ECAM_BASES.len()
+            && ecam_start(bus + count) == Some(start + count as u64 * ECAM_BUS_SIZE)
+        {
+            count += 1;
+        }
+        let base = paging::mmio::map(PhysAddr::new(start), count as u64 * ECAM_BUS_SIZE)?;
+        for i in 0..count {
+            ECAM_BASES[bus + i].store(base.as_u64() + i as u64 * ECAM_BUS_SIZE, Ordering::Release);
+        }
+        bus += count;
+    }
+    HAS_ECAM.store(true, Ordering::Release);
+    Ok(())
+}
+
+/// Whether the extended configuration space beyond the first 256 bytes is accessible.
+pub fn has_extended_config_space() -> bool {
+    HAS_ECAM.load(Ordering::Acquire)
+}
+
+/// Returns the configuration space of the function through ECAM, if available.
+fn ecam_address(bus: u8, device: u8, function: u8) -> Option<VirtAddr> {
+    let base = ECAM_BASES[bus as usize].load(Ordering::Acquire);
+    let offset = (u64::from(device) << 15) | (u64::from(function) << 12);
+    (base != 0).then(|| VirtAddr::new(base + offset))
+}
+
+fn read_pci_config(bus: u8, device: u8, function: u8, register_address: u16) -> u32 {
+    assert!(register_address < EXTENDED_CONFIG_SPACE_SIZE);
+    if let Some(base) = ecam_address(bus, device, function) {
+        let register = base + (register_address & !0b11) as u64;
+        return unsafe { core::ptr::read_volatile(register.as_ptr()) };
+    }
+    if register_address > u8::MAX as u16 {
+        return u32::MAX;
+    }
+    let address = make_address(bus, device, function, register_address as u8);
     let mut addr = PortWriteOnly::new(CONFIG_ADDRESS);
     let mut data = PortReadOnly::new(CONFIG_DATA);
     x86_64::instructions::interrupts::without_interrupts(|| unsafe {
@@ -14,7 +121,17 @@ fn read_pci_config(address: u32) -> u32 {
         data.read()
     })
 }
-fn write_pci_config(address: u32, value: u32) {
+fn write_pci_config(bus: u8, device: u8, function: u8, register_address: u16, value: u32) {
+    assert!(register_address < EXTENDED_CONFIG_SPACE_SIZE);
+    if let Some(base) = ecam_address(bus, device, function) {
+        let register = base + (register_address & !0b11) as u64;
+        unsafe { core::ptr::write_volatile(register.as_mut_ptr(), value) };
+        return;
+    }
+    if register_address > u8::MAX as u16 {
+        return;
+    }
+    let address = make_address(bus, device, function, register_address as u8);
     let mut addr = PortWriteOnly::new(CONFIG_ADDRESS);
     let mut data = PortWriteOnly::new(CONFIG_DATA);
     x86_64::instructions::interrupts::without_interrupts(|| unsafe {
@@ -34,7 +151,7 @@ fn make_address(bus: u8, device: u8, function: u8, register_address: u8) -> u32
 
 /// Returns Option<(vendor_id, device_id)>
 fn read_ids(bus: u8, device: u8, function: u8) -> Option<(u16, u16)> {
-    let ret = read_pci_config(make_address(bus, device, function, 0x00));
+    let ret = read_pci_config(bus, device, function, 0x00);
     let device_id = (ret >> 16) as u16;
     let vendor_id = ret as u16;
     if vendor_id == u16::MAX {
@@ -45,7 +162,7 @@ fn read_ids(bus: u8, device: u8, function: u8) -> Option<(u16, u16)> {
 }
 /// Assumes valid (bus, device, function) combination
 fn read_header_type(bus: u8, device: u8, function: u8) -> u8 {
-    let ret = read_pci_config(make_address(bus, device, function, 0x0C));
+    let ret = read_pci_config(bus, device, function, 0x0C);
     (ret >> 16) as u8
 }
 /// Assumes valid (bus, device, function) combination
@@ -54,7 +171,7 @@ fn is_singleton_type(bus: u8, device: u8, function: u8) -> bool {
 }
 /// Assumes valid (bus, device, function) combination
 fn read_class(bus: u8, device: u8, function: u8) -> PCIClass {
-    let ret = read_pci_config(make_address(bus, device, function, 0x08));
+    let ret = read_pci_config(bus, device, function, 0x08);
     let base = (ret >> 24) as u8;
     let sub = (ret >> 16) as u8;
     let interface = (ret >> 8) as u8;
@@ -64,32 +181,116 @@ fn read_class(bus: u8, device: u8, function: u8) -> PCIClass {
 fn read_bars(bus: u8, device: u8, function: u8) -> [u32; 6] {
     let mut bars = [0; 6];
     for i in 0..6 {
-        bars[i as usize] = read_pci_config(make_address(bus, device, function, 0x10 + 4 * i));
+        bars[i as usize] = read_pci_config(bus, device, function, 0x10 + 4 * i);
     }
     bars
 }
-
-// TODO: Add more from https://pci-ids.ucw.cz/read/PD
-#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
-pub enum PCIClass {
-    /// 0x0C
-    SerialBusController(SerialBusSubclass),
-    /// Other ones
-    Unimplemented(u8, u8, u8),
+/// Writes all ones to the BAR register and reads back which bits stuck, restoring it after.
+/// Assumes valid (bus, device, function) combination, with the decoding disabled.
+fn probe_bar_register(bus: u8, device: u8, function: u8, register_address: u16) -> u32 {
+    let original = read_pci_config(bus, device, function, register_address);
+    write_pci_config(bus, device, function, register_address, u32::MAX);
+    let mask = read_pci_config(bus, device, function, register_address);
+    write_pci_config(bus, device, function, register_address, original);
+    mask
 }
-#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
-pub enum SerialBusSubclass {
-    /// 0x03
-    USBController(USBProgramInterface),
-    /// Other ones
-    Unimplemented(u8, u8),
+/// Decodes the BARs and finds their sizes. The upper halves of the 64-bit BARs and the
+/// unimplemented ones are None.
+/// Assumes valid (bus, device, function) combination
+fn probe_bars(bus: u8, device: u8, function: u8, header_type: u8) -> [Option<BAR>; 6] {
+    let mut regions = [None; 6];
+    let count = match header_type & 0x7F {
+        0x00 => 6,
+        0x01 => 2,
+        _ => return regions,
+    };
+    // Keep the device from decoding the addresses while the BARs hold all ones.
+    let command = read_pci_config(bus, device, function, 0x04) & 0xFFFF;
+    write_pci_config(bus, device, function, 0x04, command & !0b11);
+    let mut index = 0;
+    while index < count {
+        let register_address = 0x10 + 4 * index as u16;
+        let bar = read_pci_config(bus, device, function, register_address);
+        let mask = probe_bar_register(bus, device, function, register_address);
+        if bar & 1 != 0 {
+            let mask = mask & 0xFFFC;
+            if mask != 0 {
+                regions[index] = Some(BAR::IO {
+                    port: (bar & 0xFFFC) as u16,
+                    size: ((!mask & 0xFFFF) + 1) as u16,
+                });
+            }
+            index += 1;
+            continue;
+        }
+        let is_64bit = (bar >> 1) & 0b11 == 0b10 && index + 1 < count;
+        let (upper, upper_mask) = if is_64bit {
+            let upper_address = register_address + 4;
+            (
+                read_pci_config(bus, device, function, upper_address),
+                probe_bar_register(bus, device, function, upper_address),
+            )
+        } else {
+            (0, u32::MAX)
+        };
+        let mask = ((upper_mask as u64) << 32) | (mask & !0xF) as u64;
+        let is_implemented = if is_64bit {
+            mask != 0
+        } else {
+            mask & 0xFFFF_FFFF != 0
+        };
+        if is_implemented {
+            regions[index] = Some(BAR::Memory {
+                address: PhysAddr::new(((upper as u64) << 32) | (bar & !0xF) as u64),
+                size: (!mask).wrapping_add(1),
+                is_64bit,
+                prefetchable: bar & 0x8 != 0,
+            });
+        }
+        index += if is_64bit { 2 } else { 1 };
+    }
+    write_pci_config(bus, device, function, 0x04, command);
+    regions
 }
+
+/// A region a BAR points to.
 #[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
-pub enum USBProgramInterface {
-    /// 0x30
-    XHCI,
-    /// Other ones
-    Unimplemented(u8),
+pub enum BAR {
+    Memory {
+        address: PhysAddr,
+        size: u64,
+        is_64bit: bool,
+        prefetchable: bool,
+    },
+    IO {
+        port: u16,
+        size: u16,
+    },
+}
+
+impl fmt::Display for BAR {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            BAR::Memory {
+                address,
+                size,
+                is_64bit,
+                prefetchable,
+            } => write!(
+                f,
+                "Memory at {:#x} ({}-bit, {}) [size={:#x}]",
+                address.as_u64(),
+                if is_64bit { 64 } else { 32 },
+                if prefetchable {
+                    "prefetchable"
+                } else {
+                    "non-prefetchable"
+                },
+                size
+            ),
+            BAR::IO { port, size } => write!(f, "I/O ports at {:#x} [size={:#x}]", port, size),
+        }
+    }
 }
 
 bitfield! {
@@ -106,48 +307,136 @@ pub struct PCICapabilityHeader {
     capability_address: u8,
 }
 
-impl PCIClass {
-    pub fn from_code(base: u8, sub: u8, interface: u8) -> Self {
-        match base {
-            0x0c => Self::SerialBusController(SerialBusSubclass::from_code(sub, interface)),
-            _ => Self::Unimplemented(base, sub, interface),
-        }
-    }
-    pub fn to_code(self) -> (u8, u8, u8) {
-        match self {
-            Self::SerialBusController(a) => {
-                let (b, c) = a.to_code();
-                (0x03, b, c)
-            }
-            Self::Unimplemented(a, b, c) => (a, b, c),
-        }
+bitfield! {
+    #[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+    pub struct PCIExtendedCapabilityHeaderData(u32);
+    impl Debug;
+    u16; pub capability_id, _: 15, 0;
+    u8; pub version, _: 19, 16;
+    u16; next_ptr, _: 31, 20;
+}
+#[derive(Getters, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub struct PCIExtendedCapabilityHeader {
+    data: PCIExtendedCapabilityHeaderData,
+    capability_address: u16,
+}
+
+impl PCIExtendedCapabilityHeader {
+    pub fn name(&self) -> Option<&'static str> {
+        Some(match self.data.capability_id() {
+            0x0001 => "Advanced Error Reporting",
+            0x0002 => "Virtual Channel",
+            0x0003 => "Device Serial Number",
+            0x0004 => "Power Budgeting",
+            0x000B => "Vendor Specific Information",
+            0x000D => "Access Control Services",
+            0x000E => "Alternative Routing-ID Interpretation",
+            0x0010 => "Single Root I/O Virtualization",
+            0x0015 => "Resizable BAR",
+            0x0017 => "Transaction Processing Hints",
+            0x0018 => "Latency Tolerance Reporting",
+            0x0019 => "Secondary PCI Express",
+            0x001E => "L1 PM Substates",
+            0x0025 => "Data Link Feature",
+            0x0026 => "Physical Layer 16.0 GT/s",
+            _ => return None,
+        })
     }
 }
-impl SerialBusSubclass {
-    pub fn from_code(sub: u8, interface: u8) -> Self {
-        match sub {
-            0x03 => Self::USBController(USBProgramInterface::from_code(interface)),
-            _ => Self::Unimplemented(sub, interface),
+
+impl fmt::Display for PCIExtendedCapabilityHeader {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.name() {
+            Some(name) => write!(f, "{}", name)?,
+            None => write!(f, "[{:04x}]", self.data.capability_id())?,
         }
+        write!(f, " v{}", self.data.version())
     }
-    pub fn to_code(self) -> (u8, u8) {
-        match self {
-            Self::USBController(a) => (0x03, a.to_code()),
-            Self::Unimplemented(a, b) => (a, b),
-        }
+}
+
+pub const CAPABILITY_POWER_MANAGEMENT: u8 = 0x01;
+pub const CAPABILITY_MSI: u8 = 0x05;
+pub const CAPABILITY_VENDOR_SPECIFIC: u8 = 0x09;
+pub const CAPABILITY_PCI_EXPRESS: u8 = 0x10;
+pub const CAPABILITY_MSIX: u8 = 0x11;
+
+impl PCICapabilityHeader {
+    pub fn name(&self) -> Option<&'static str> {
+        Some(match self.data.capability_id() {
+            CAPABILITY_POWER_MANAGEMENT => "Power Management",
+            0x02 => "AGP",
+            0x03 => "Vital Product Data",
+            0x04 => "Slot Identification",
+            CAPABILITY_MSI => "MSI",
+            0x06 => "CompactPCI Hot Swap",
+            0x07 => "PCI-X",
+            0x08 => "HyperTransport",
+            CAPABILITY_VENDOR_SPECIFIC => "Vendor Specific",
+            0x0A => "Debug port",
+            0x0B => "CompactPCI central resource control",
+            0x0C => "PCI Hot-plug",
+            0x0D => "Subsystem",
+            0x0E => "AGP 8x",
+            0x0F => "Secure device",
+            CAPABILITY_PCI_EXPRESS => "Express",
+            CAPABILITY_MSIX => "MSI-X",
+            0x12 => "SATA HBA",
+            0x13 => "PCI Advanced Features",
+            0x14 => "Enhanced Allocation",
+            0x15 => "Flattening Portal Bridge",
+            _ => return None,
+        })
     }
 }
-impl USBProgramInterface {
-    pub fn from_code(interface: u8) -> Self {
-        match interface {
-            0x30 => Self::XHCI,
-            _ => Self::Unimplemented(interface),
+
+/// Shows the name and the main properties in the capability register, like lspci does.
+impl fmt::Display for PCICapabilityHeader {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let id = self.data.capability_id();
+        let capability = self.data.capability();
+        match self.name() {
+            Some(name) => write!(f, "{}", name)?,
+            None => write!(f, "[{:02x}]", id)?,
         }
-    }
-    pub fn to_code(self) -> u8 {
-        match self {
-            Self::XHCI => 0x30,
-            Self::Unimplemented(a) => a,
+        match id {
+            CAPABILITY_POWER_MANAGEMENT => write!(f, " version {}", capability & 0b111),
+            CAPABILITY_MSI => write!(
+                f,
+                " (count {}{}{})",
+                1 << ((capability >> 1) & 0b111),
+                if capability & (1 << 7) != 0 {
+                    ", 64-bit"
+                } else {
+                    ""
+                },
+                if capability & 1 != 0 { ", enabled" } else { "" }
+            ),
+            CAPABILITY_PCI_EXPRESS => {
+                let port_type = match (capability >> 4) & 0xF {
+                    0x0 => "Endpoint",
+                    0x1 => "Legacy Endpoint",
+                    0x4 => "Root Port",
+                    0x5 => "Upstream Port",
+                    0x6 => "Downstream Port",
+                    0x7 => "PCI-Express to PCI/PCI-X Bridge",
+                    0x8 => "PCI/PCI-X to PCI-Express Bridge",
+                    0x9 => "Root Complex Integrated Endpoint",
+                    0xA => "Root Complex Event Collector",
+                    _ => "Unknown type",
+                };
+                write!(f, " v{} {}", capability & 0xF, port_type)
+            }
+            CAPABILITY_MSIX => write!(
+                f,
+                " (count {}{})",
+                (capability & 0x7FF) + 1,
+                if capability & (1 << 15) != 0 {
+                    ", enabled"
+                } else {
+                    ""
+                }
+            ),
+            _ => Ok(()),
         }
     }
 }
@@ -187,6 +476,8 @@ pub struct PCIFunction {
     header_type: u8,
     class: PCIClass,
     bars: [u32; 6],
+    /// The decoded BARs, indexed the same as `bars`.
+    regions: [Option<BAR>; 6],
 }
 
 impl PCIFunction {
@@ -194,6 +485,7 @@ impl PCIFunction {
         let header_type = read_header_type(bus, device, function);
         let class = read_class(bus, device, function);
         let bars = read_bars(bus, device, function);
+        let regions = probe_bars(bus, device, function, header_type);
 
         Self {
             bus,
@@ -204,24 +496,40 @@ impl PCIFunction {
             header_type,
             class,
             bars,
+            regions,
         }
     }
 
     pub fn read_conf_register(&self, register_address: u8) -> u32 {
-        read_pci_config(make_address(
+        self.read_extended_conf_register(register_address as u16)
+    }
+    pub fn write_conf_register(&self, register_address: u8, value: u32) {
+        self.write_extended_conf_register(register_address as u16, value)
+    }
+    /// Reads a register anywhere in the 4 KiB configuration space. Beyond the first 256 bytes,
+    /// it reads all ones without ECAM.
+    pub fn read_extended_conf_register(&self, register_address: u16) -> u32 {
+        read_pci_config(self.bus, self.device, self.function, register_address)
+    }
+    /// Writes a register anywhere in the 4 KiB configuration space. Beyond the first 256 bytes,
+    /// it's ignored without ECAM.
+    pub fn write_extended_conf_register(&self, register_address: u16, value: u32) {
+        write_pci_config(
             self.bus,
             self.device,
             self.function,
             register_address,
-        ))
-    }
-    pub fn write_conf_register(&self, register_address: u8, value: u32) {
-        log::info!("{:02x} <- {:08x}", register_address, value);
-        write_pci_config(
-            make_address(self.bus, self.device, self.function, register_address),
             value,
         )
     }
+    /// The bus behind the function if it's a PCI-to-PCI bridge the firmware has configured.
+    pub fn secondary_bus(&self) -> Option<u8> {
+        if self.header_type & 0x7F != 0x01 {
+            return None;
+        }
+        let secondary_bus = (self.read_conf_register(0x18) >> 8) as u8;
+        (secondary_bus != 0).then_some(secondary_bus)
+    }
     pub fn read_bars(&self) -> [u32; 6] {
         let mut bars = [0; 6];
         for i in 0..6 {
@@ -230,6 +538,15 @@ impl PCIFunction {
         bars
     }
 
+    /// Maps the region of the memory BAR `index` as uncacheable, and returns where it's mapped.
+    pub fn map_memory_bar(&self, index: usize) -> Result<VirtAddr> {
+        match self.regions.get(index).copied().flatten() {
+            Some(BAR::Memory { address, size, .. }) => paging::mmio::map(address, size),
+            Some(BAR::IO { .. }) => Err(Error::Whatever("The BAR is not in the memory space")),
+            None => Err(Error::Whatever("The BAR is not implemented")),
+        }
+    }
+
     fn read_capability_header(&self, capability_address: u8) -> Option<PCICapabilityHeader> {
         if capability_address == 0 {
             None
@@ -242,6 +559,24 @@ impl PCIFunction {
         }
     }
 
+    /// The PCI Express extended capabilities, which live in the extended configuration space.
+    pub fn extended_capability_headers(
+        &self,
+    ) -> impl Iterator<Item = PCIExtendedCapabilityHeader> + '_ {
+        let read = move |offset: u16| {
+            // The capabilities start right after the conventional configuration space.
+            if offset < 0x100 || !has_extended_config_space() {
+                return None;
+            }
+            let data = self.read_extended_conf_register(offset);
+            (data != 0 && data != u32::MAX).then(|| PCIExtendedCapabilityHeader {
+                data: PCIExtendedCapabilityHeaderData(data),
+                capability_address: offset,
+            })
+        };
+        core::iter::successors(read(0x100), move |prev| read(prev.data.next_ptr()))
+    }
+
     pub fn capability_headers(&self) -> impl Iterator<Item = PCICapabilityHeader> + '_ {
         let capability_address = self.read_capability_header(self.read_conf_register(0x34) as u8);
         core::iter::successors(capability_address, |prev| {
@@ -249,16 +584,3 @@ impl PCIFunction {
         })
     }
 }
-
-pub fn scan_devices() -> impl Iterator<Item = PCIDeviceInfo> {
-    (u8::MIN..=u8::MAX).flat_map(|bus| {
-        (0..32).flat_map(move |device| {
-            read_ids(bus, device, 0).map(|(vendor_id, device_id)| PCIDeviceInfo {
-                bus,
-                device,
-                vendor_id,
-                device_id,
-            })
-        })
-    })
-}
@@ -0,0 +1,499 @@
+//! The class codes of PCI functions, decoded into enums. The names follow
+//! https://pci-ids.ucw.cz/read/PD
+
+/// A subclass and a programming interface, as a part of a class.
+trait SubclassCode {
+    fn from_code(sub: u8, interface: u8) -> Self;
+    fn to_code(self) -> (u8, u8);
+    fn name(&self) -> Option<&'static str>;
+    fn interface_name(&self) -> Option<&'static str>;
+}
+
+/// A programming interface, as a part of a subclass.
+trait InterfaceCode {
+    fn from_code(interface: u8) -> Self;
+    fn to_code(self) -> u8;
+    fn name(&self) -> Option<&'static str>;
+}
+
+/// The programming interfaces that aren't decoded.
+impl InterfaceCode for u8 {
+    fn from_code(interface: u8) -> Self {
+        interface
+    }
+    fn to_code(self) -> u8 {
+        self
+    }
+    fn name(&self) -> Option<&'static str> {
+        None
+    }
+}
+
+macro_rules! classes {
+    (
+        $(#[$meta:meta])*
+        pub enum $name:ident {
+            $($variant:ident($subclass:ty) = $code:literal => $description:literal,)*
+        }
+    ) => {
+        $(#[$meta])*
+        #[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+        pub enum $name {
+            $(
+                #[doc = stringify!($code)]
+                $variant($subclass),
+            )*
+            /// Other ones
+            Unimplemented(u8, u8, u8),
+        }
+        impl $name {
+            pub fn from_code(base: u8, sub: u8, interface: u8) -> Self {
+                match base {
+                    $($code => Self::$variant(
+                        <$subclass as SubclassCode>::from_code(sub, interface)
+                    ),)*
+                    _ => Self::Unimplemented(base, sub, interface),
+                }
+            }
+            pub fn to_code(self) -> (u8, u8, u8) {
+                match self {
+                    $(Self::$variant(a) => {
+                        let (b, c) = SubclassCode::to_code(a);
+                        ($code, b, c)
+                    })*
+                    Self::Unimplemented(a, b, c) => (a, b, c),
+                }
+            }
+            pub fn name(&self) -> Option<&'static str> {
+                match self {
+                    $(Self::$variant(_) => Some($description),)*
+                    Self::Unimplemented(..) => None,
+                }
+            }
+            pub fn subclass_name(&self) -> Option<&'static str> {
+                match self {
+                    $(Self::$variant(a) => SubclassCode::name(a),)*
+                    Self::Unimplemented(..) => None,
+                }
+            }
+            pub fn interface_name(&self) -> Option<&'static str> {
+                match self {
+                    $(Self::$variant(a) => SubclassCode::interface_name(a),)*
+                    Self::Unimplemented(..) => None,
+                }
+            }
+        }
+    };
+}
+
+macro_rules! subclasses {
+    (
+        $(#[$meta:meta])*
+        pub enum $name:ident {
+            $($variant:ident($interface:ty) = $code:literal => $description:literal,)*
+        }
+    ) => {
+        $(#[$meta])*
+        #[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+        pub enum $name {
+            $(
+                #[doc = stringify!($code)]
+                $variant($interface),
+            )*
+            /// Other ones
+            Unimplemented(u8, u8),
+        }
+        impl $name {
+            pub fn from_code(sub: u8, interface: u8) -> Self {
+                match sub {
+                    $($code => Self::$variant(
+                        <$interface as InterfaceCode>::from_code(interface)
+                    ),)*
+                    _ => Self::Unimplemented(sub, interface),
+                }
+            }
+            pub fn to_code(self) -> (u8, u8) {
+                match self {
+                    $(Self::$variant(a) => ($code, InterfaceCode::to_code(a)),)*
+                    Self::Unimplemented(a, b) => (a, b),
+                }
+            }
+            pub fn name(&self) -> Option<&'static str> {
+                match self {
+                    $(Self::$variant(_) => Some($description),)*
+                    Self::Unimplemented(..) => None,
+                }
+            }
+            pub fn interface_name(&self) -> Option<&'static str> {
+                match self {
+                    $(Self::$variant(a) => InterfaceCode::name(a),)*
+                    Self::Unimplemented(..) => None,
+                }
+            }
+        }
+        impl SubclassCode for $name {
+            fn from_code(sub: u8, interface: u8) -> Self {
+                Self::from_code(sub, interface)
+            }
+            fn to_code(self) -> (u8, u8) {
+                Self::to_code(self)
+            }
+            fn name(&self) -> Option<&'static str> {
+                Self::name(self)
+            }
+            fn interface_name(&self) -> Option<&'static str> {
+                Self::interface_name(self)
+            }
+        }
+    };
+}
+
+macro_rules! interfaces {
+    (
+        $(#[$meta:meta])*
+        pub enum $name:ident {
+            $($variant:ident = $code:literal => $description:literal,)*
+        }
+    ) => {
+        $(#[$meta])*
+        #[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+        pub enum $name {
+            $(
+                #[doc = stringify!($code)]
+                $variant,
+            )*
+            /// Other ones
+            Unimplemented(u8),
+        }
+        impl $name {
+            pub fn from_code(interface: u8) -> Self {
+                match interface {
+                    $($code => Self::$variant,)*
+                    _ => Self::Unimplemented(interface),
+                }
+            }
+            pub fn to_code(self) -> u8 {
+                match self {
+                    $(Self::$variant => $code,)*
+                    Self::Unimplemented(a) => a,
+                }
+            }
+            pub fn name(&self) -> Option<&'static str> {
+                match self {
+                    $(Self::$variant => Some($description),)*
+                    Self::Unimplemented(_) => None,
+                }
+            }
+        }
+        impl InterfaceCode for $name {
+            fn from_code(interface: u8) -> Self {
+                Self::from_code(interface)
+            }
+            fn to_code(self) -> u8 {
+                Self::to_code(self)
+            }
+            fn name(&self) -> Option<&'static str> {
+                Self::name(self)
+            }
+        }
+    };
+}
+
+classes! {
+    pub enum PCIClass {
+        Unclassified(UnclassifiedSubclass) = 0x00 => "Unclassified device",
+        MassStorageController(MassStorageSubclass) = 0x01 => "Mass storage controller",
+        NetworkController(NetworkSubclass) = 0x02 => "Network controller",
+        DisplayController(DisplaySubclass) = 0x03 => "Display controller",
+        MultimediaController(MultimediaSubclass) = 0x04 => "Multimedia controller",
+        MemoryController(MemorySubclass) = 0x05 => "Memory controller",
+        Bridge(BridgeSubclass) = 0x06 => "Bridge",
+        CommunicationController(CommunicationSubclass) = 0x07 => "Communication controller",
+        GenericSystemPeripheral(SystemPeripheralSubclass) = 0x08 => "Generic system peripheral",
+        InputDeviceController(InputDeviceSubclass) = 0x09 => "Input device controller",
+        DockingStation(DockingStationSubclass) = 0x0A => "Docking station",
+        Processor(ProcessorSubclass) = 0x0B => "Processor",
+        SerialBusController(SerialBusSubclass) = 0x0C => "Serial bus controller",
+        WirelessController(WirelessSubclass) = 0x0D => "Wireless controller",
+        IntelligentController(IntelligentSubclass) = 0x0E => "Intelligent controller",
+        SatelliteCommunicationsController(SatelliteSubclass) = 0x0F
+            => "Satellite communications controller",
+        EncryptionController(EncryptionSubclass) = 0x10 => "Encryption controller",
+        SignalProcessingController(SignalProcessingSubclass) = 0x11
+            => "Signal processing controller",
+        ProcessingAccelerator(ProcessingAcceleratorSubclass) = 0x12 => "Processing accelerators",
+    }
+}
+
+subclasses! {
+    pub enum UnclassifiedSubclass {
+        NonVGACompatibleDevice(u8) = 0x00 => "Non-VGA unclassified device",
+        VGACompatibleDevice(u8) = 0x01 => "VGA compatible unclassified device",
+        ImageCoprocessor(u8) = 0x05 => "Image coprocessor",
+    }
+}
+
+subclasses! {
+    pub enum MassStorageSubclass {
+        SCSIBusController(u8) = 0x00 => "SCSI storage controller",
+        IDEController(u8) = 0x01 => "IDE interface",
+        FloppyDiskController(u8) = 0x02 => "Floppy disk controller",
+        IPIBusController(u8) = 0x03 => "IPI bus controller",
+        RAIDController(u8) = 0x04 => "RAID bus controller",
+        ATAController(u8) = 0x05 => "ATA controller",
+        SATAController(SATAProgramInterface) = 0x06 => "SATA controller",
+        SASController(u8) = 0x07 => "Serial Attached SCSI controller",
+        NonVolatileMemoryController(NonVolatileMemoryProgramInterface) = 0x08
+            => "Non-Volatile memory controller",
+        UniversalFlashStorageController(u8) = 0x09 => "Universal Flash Storage controller",
+        Other(u8) = 0x80 => "Mass storage controller",
+    }
+}
+
+interfaces! {
+    pub enum SATAProgramInterface {
+        VendorSpecific = 0x00 => "Vendor specific",
+        AHCI = 0x01 => "AHCI 1.0",
+        SerialStorageBus = 0x02 => "Serial Storage Bus",
+    }
+}
+
+interfaces! {
+    pub enum NonVolatileMemoryProgramInterface {
+        NVMHCI = 0x01 => "NVMHCI",
+        NVMExpress = 0x02 => "NVM Express",
+    }
+}
+
+subclasses! {
+    pub enum NetworkSubclass {
+        EthernetController(u8) = 0x00 => "Ethernet controller",
+        TokenRingController(u8) = 0x01 => "Token ring network controller",
+        FDDIController(u8) = 0x02 => "FDDI network controller",
+        ATMController(u8) = 0x03 => "ATM network controller",
+        ISDNController(u8) = 0x04 => "ISDN controller",
+        WorldFipController(u8) = 0x05 => "WorldFip controller",
+        PICMGController(u8) = 0x06 => "PICMG controller",
+        InfinibandController(u8) = 0x07 => "Infiniband controller",
+        FabricController(u8) = 0x08 => "Fabric controller",
+        Other(u8) = 0x80 => "Network controller",
+    }
+}
+
+subclasses! {
+    pub enum DisplaySubclass {
+        VGACompatibleController(VGAProgramInterface) = 0x00 => "VGA compatible controller",
+        XGACompatibleController(u8) = 0x01 => "XGA compatible controller",
+        ThreeDController(u8) = 0x02 => "3D controller",
+        Other(u8) = 0x80 => "Display controller",
+    }
+}
+
+interfaces! {
+    pub enum VGAProgramInterface {
+        VGAController = 0x00 => "VGA controller",
+        I8514Controller = 0x01 => "8514 controller",
+    }
+}
+
+subclasses! {
+    pub enum MultimediaSubclass {
+        VideoController(u8) = 0x00 => "Multimedia video controller",
+        AudioController(u8) = 0x01 => "Multimedia audio controller",
+        TelephonyDevice(u8) = 0x02 => "Computer telephony device",
+        AudioDevice(u8) = 0x03 => "Audio device",
+        Other(u8) = 0x80 => "Multimedia controller",
+    }
+}
+
+subclasses! {
+    pub enum MemorySubclass {
+        RAMMemory(u8) = 0x00 => "RAM memory",
+        FLASHMemory(u8) = 0x01 => "FLASH memory",
+        CXL(u8) = 0x02 => "CXL",
+        Other(u8) = 0x80 => "Memory controller",
+    }
+}
+
+subclasses! {
+    pub enum BridgeSubclass {
+        HostBridge(u8) = 0x00 => "Host bridge",
+        ISABridge(u8) = 0x01 => "ISA bridge",
+        EISABridge(u8) = 0x02 => "EISA bridge",
+        MicroChannelBridge(u8) = 0x03 => "MicroChannel bridge",
+        PCIBridge(PCIBridgeProgramInterface) = 0x04 => "PCI bridge",
+        PCMCIABridge(u8) = 0x05 => "PCMCIA bridge",
+        NuBusBridge(u8) = 0x06 => "NuBus bridge",
+        CardBusBridge(u8) = 0x07 => "CardBus bridge",
+        RACEwayBridge(u8) = 0x08 => "RACEway bridge",
+        SemiTransparentPCIBridge(u8) = 0x09 => "Semi-transparent PCI-to-PCI bridge",
+        InfiniBandToPCIHostBridge(u8) = 0x0A => "InfiniBand to PCI host bridge",
+        Other(u8) = 0x80 => "Bridge",
+    }
+}
+
+interfaces! {
+    pub enum PCIBridgeProgramInterface {
+        NormalDecode = 0x00 => "Normal decode",
+        SubtractiveDecode = 0x01 => "Subtractive decode",
+    }
+}
+
+subclasses! {
+    pub enum CommunicationSubclass {
+        SerialController(SerialProgramInterface) = 0x00 => "Serial controller",
+        ParallelController(u8) = 0x01 => "Parallel controller",
+        MultiportSerialController(u8) = 0x02 => "Multiport serial controller",
+        Modem(u8) = 0x03 => "Modem",
+        GPIBController(u8) = 0x04 => "GPIB controller",
+        SmartCardController(u8) = 0x05 => "Smart Card controller",
+        Other(u8) = 0x80 => "Communication controller",
+    }
+}
+
+interfaces! {
+    pub enum SerialProgramInterface {
+        I8250 = 0x00 => "8250",
+        I16450 = 0x01 => "16450",
+        I16550 = 0x02 => "16550",
+        I16650 = 0x03 => "16650",
+        I16750 = 0x04 => "16750",
+        I16850 = 0x05 => "16850",
+        I16950 = 0x06 => "16950",
+    }
+}
+
+subclasses! {
+    pub enum SystemPeripheralSubclass {
+        PIC(PICProgramInterface) = 0x00 => "PIC",
+        DMAController(u8) = 0x01 => "DMA controller",
+        Timer(u8) = 0x02 => "Timer",
+        RTC(u8) = 0x03 => "RTC",
+        PCIHotPlugController(u8) = 0x04 => "PCI Hot-plug controller",
+        SDHostController(u8) = 0x05 => "SD Host controller",
+        IOMMU(u8) = 0x06 => "IOMMU",
+        TimingCard(u8) = 0x99 => "Timing Card",
+        Other(u8) = 0x80 => "System peripheral",
+    }
+}
+
+interfaces! {
+    pub enum PICProgramInterface {
+        I8259 = 0x00 => "8259",
+        ISAPIC = 0x01 => "ISA PIC",
+        EISAPIC = 0x02 => "EISA PIC",
+        IOAPIC = 0x10 => "IO-APIC",
+        IOXAPIC = 0x20 => "IO(X)-APIC",
+    }
+}
+
+subclasses! {
+    pub enum InputDeviceSubclass {
+        KeyboardController(u8) = 0x00 => "Keyboard controller",
+        DigitizerPen(u8) = 0x01 => "Digitizer Pen",
+        MouseController(u8) = 0x02 => "Mouse controller",
+        ScannerController(u8) = 0x03 => "Scanner controller",
+        GameportController(u8) = 0x04 => "Gameport controller",
+        Other(u8) = 0x80 => "Input device controller",
+    }
+}
+
+subclasses! {
+    pub enum DockingStationSubclass {
+        Generic(u8) = 0x00 => "Generic Docking Station",
+        Other(u8) = 0x80 => "Docking Station",
+    }
+}
+
+subclasses! {
+    pub enum ProcessorSubclass {
+        I386(u8) = 0x00 => "386",
+        I486(u8) = 0x01 => "486",
+        Pentium(u8) = 0x02 => "Pentium",
+        Alpha(u8) = 0x10 => "Alpha",
+        PowerPC(u8) = 0x20 => "Power PC",
+        MIPS(u8) = 0x30 => "MIPS",
+        CoProcessor(u8) = 0x40 => "Co-processor",
+    }
+}
+
+subclasses! {
+    pub enum SerialBusSubclass {
+        FireWireController(u8) = 0x00 => "FireWire (IEEE 1394)",
+        ACCESSBus(u8) = 0x01 => "ACCESS Bus",
+        SSA(u8) = 0x02 => "SSA",
+        USBController(USBProgramInterface) = 0x03 => "USB controller",
+        FibreChannel(u8) = 0x04 => "Fibre Channel",
+        SMBus(u8) = 0x05 => "SMBus",
+        InfiniBand(u8) = 0x06 => "InfiniBand",
+        IPMIInterface(u8) = 0x07 => "IPMI Interface",
+        SERCOSInterface(u8) = 0x08 => "SERCOS interface",
+        CANBus(u8) = 0x09 => "CANBUS",
+        Other(u8) = 0x80 => "Serial bus controller",
+    }
+}
+
+interfaces! {
+    pub enum USBProgramInterface {
+        UHCI = 0x00 => "UHCI",
+        OHCI = 0x10 => "OHCI",
+        EHCI = 0x20 => "EHCI",
+        XHCI = 0x30 => "XHCI",
+        USB4 = 0x40 => "USB4 Host Interface",
+        Unspecified = 0x80 => "Unspecified",
+        Device = 0xFE => "USB Device",
+    }
+}
+
+subclasses! {
+    pub enum WirelessSubclass {
+        IRDAController(u8) = 0x00 => "IRDA controller",
+        ConsumerIRController(u8) = 0x01 => "Consumer IR controller",
+        RFController(u8) = 0x10 => "RF controller",
+        Bluetooth(u8) = 0x11 => "Bluetooth",
+        Broadband(u8) = 0x12 => "Broadband",
+        Wireless802_1a(u8) = 0x20 => "802.1a controller",
+        Wireless802_1b(u8) = 0x21 => "802.1b controller",
+        Other(u8) = 0x80 => "Wireless controller",
+    }
+}
+
+subclasses! {
+    pub enum IntelligentSubclass {
+        I2O(u8) = 0x00 => "I2O",
+    }
+}
+
+subclasses! {
+    pub enum SatelliteSubclass {
+        TV(u8) = 0x01 => "Satellite TV controller",
+        Audio(u8) = 0x02 => "Satellite audio communication controller",
+        Voice(u8) = 0x03 => "Satellite voice communication controller",
+        Data(u8) = 0x04 => "Satellite data communication controller",
+    }
+}
+
+subclasses! {
+    pub enum EncryptionSubclass {
+        NetworkAndComputing(u8) = 0x00 => "Network and computing encryption device",
+        Entertainment(u8) = 0x10 => "Entertainment encryption device",
+        Other(u8) = 0x80 => "Encryption controller",
+    }
+}
+
+subclasses! {
+    pub enum SignalProcessingSubclass {
+        DPIOModule(u8) = 0x00 => "DPIO module",
+        PerformanceCounters(u8) = 0x01 => "Performance counters",
+        CommunicationSynchronizer(u8) = 0x10 => "Communication synchronizer",
+        SignalProcessingManagement(u8) = 0x20 => "Signal processing management",
+        Other(u8) = 0x80 => "Signal processing controller",
+    }
+}
+
+subclasses! {
+    pub enum ProcessingAcceleratorSubclass {
+        ProcessingAccelerator(u8) = 0x00 => "Processing accelerators",
+        SDXIController(u8) = 0x01 => "SDXI controller",
+    }
+}
@@ -0,0 +1,112 @@
+//! Names of the vendors and the devices likely to be found on QEMU and common PCs. They mostly
+//! follow https://pci-ids.ucw.cz
+
+const VENDORS: &[(u16, &str)] = &[
+    (0x1002, "Advanced Micro Devices, Inc. [AMD/ATI]"),
+    (0x1013, "Cirrus Logic"),
+    (0x1022, "Advanced Micro Devices, Inc. [AMD]"),
+    (0x1033, "NEC Corporation"),
+    (0x10DE, "NVIDIA Corporation"),
+    (0x10EC, "Realtek Semiconductor Co., Ltd."),
+    (0x1234, "QEMU"),
+    (0x1274, "Ensoniq"),
+    (0x144D, "Samsung Electronics Co Ltd"),
+    (0x15AD, "VMware"),
+    (0x1AF4, "Red Hat, Inc."),
+    (0x1B21, "ASMedia Technology Inc."),
+    (0x1B36, "Red Hat, Inc."),
+    (0x80EE, "InnoTek Systemberatung GmbH"),
+    (0x8086, "Intel Corporation"),
+];
+
+const DEVICES: &[(u16, u16, &str)] = &[
+    (0x1013, 0x00B8, "GD 5446"),
+    (0x1033, 0x0194, "uPD720200 USB 3.0 Host Controller"),
+    (
+        0x10EC,
+        0x8139,
+        "RTL-8100/8101L/8139 PCI Fast Ethernet Adapter",
+    ),
+    (
+        0x10EC,
+        0x8168,
+        "RTL8111/8168/8411 PCI Express Gigabit Ethernet Controller",
+    ),
+    (0x1234, 0x1111, "Standard VGA"),
+    (0x1274, 0x5000, "ES1370 [AudioPCI]"),
+    (0x15AD, 0x0405, "SVGA II Adapter"),
+    (0x15AD, 0x0740, "Virtual Machine Communication Interface"),
+    (0x15AD, 0x0790, "PCI bridge"),
+    (0x15AD, 0x07A0, "PCI Express Root Port"),
+    (0x1AF4, 0x1000, "Virtio network device"),
+    (0x1AF4, 0x1001, "Virtio block device"),
+    (0x1AF4, 0x1002, "Virtio memory balloon"),
+    (0x1AF4, 0x1003, "Virtio console"),
+    (0x1AF4, 0x1004, "Virtio SCSI"),
+    (0x1AF4, 0x1005, "Virtio RNG"),
+    (0x1AF4, 0x1009, "Virtio filesystem"),
+    (0x1AF4, 0x1041, "Virtio 1.0 network device"),
+    (0x1AF4, 0x1042, "Virtio 1.0 block device"),
+    (0x1AF4, 0x1043, "Virtio 1.0 console"),
+    (0x1AF4, 0x1044, "Virtio 1.0 RNG"),
+    (0x1AF4, 0x1045, "Virtio 1.0 memory balloon"),
+    (0x1AF4, 0x1048, "Virtio 1.0 SCSI"),
+    (0x1AF4, 0x1049, "Virtio 1.0 filesystem"),
+    (0x1AF4, 0x1050, "Virtio 1.0 GPU"),
+    (0x1AF4, 0x1052, "Virtio 1.0 input"),
+    (0x1AF4, 0x1053, "Virtio 1.0 socket"),
+    (0x1B21, 0x1042, "ASM1042 SuperSpeed USB Host Controller"),
+    (0x1B36, 0x0001, "QEMU PCI-PCI bridge"),
+    (0x1B36, 0x0002, "QEMU PCI 16550A Adapter"),
+    (0x1B36, 0x0008, "QEMU PCIe Host bridge"),
+    (0x1B36, 0x000C, "QEMU PCIe Root port"),
+    (0x1B36, 0x000D, "QEMU XHCI Host Controller"),
+    (0x1B36, 0x0010, "QEMU NVM Express Controller"),
+    (0x80EE, 0xBEEF, "VirtualBox Graphics Adapter"),
+    (0x80EE, 0xCAFE, "VirtualBox Guest Service"),
+    (0x8086, 0x100E, "82540EM Gigabit Ethernet Controller"),
+    (0x8086, 0x10D3, "82574L Gigabit Network Connection"),
+    (0x8086, 0x1237, "440FX - 82441FX PMC [Natoma]"),
+    (
+        0x8086,
+        0x1E31,
+        "7 Series/C210 Series Chipset Family USB xHCI Host Controller",
+    ),
+    (
+        0x8086,
+        0x2668,
+        "82801FB/FBM/FR/FW/FRW (ICH6 Family) High Definition Audio Controller",
+    ),
+    (0x8086, 0x2918, "82801IB (ICH9) LPC Interface Controller"),
+    (
+        0x8086,
+        0x2922,
+        "82801IR/IO/IH (ICH9R/DO/DH) 6 port SATA Controller [AHCI mode]",
+    ),
+    (0x8086, 0x2930, "82801I (ICH9 Family) SMBus Controller"),
+    (0x8086, 0x293E, "82801I (ICH9 Family) HD Audio Controller"),
+    (0x8086, 0x29C0, "82G33/G31/P35/P31 Express DRAM Controller"),
+    (0x8086, 0x7000, "82371SB PIIX3 ISA [Natoma/Triton II]"),
+    (0x8086, 0x7010, "82371SB PIIX3 IDE [Natoma/Triton II]"),
+    (0x8086, 0x7020, "82371SB PIIX3 USB [Natoma/Triton II]"),
+    (0x8086, 0x7113, "82371AB/EB/MB PIIX4 ACPI"),
+    (
+        0x8086,
+        0x8C31,
+        "8 Series/C220 Series Chipset Family USB xHCI",
+    ),
+];
+
+pub fn vendor_name(vendor_id: u16) -> Option<&'static str> {
+    VENDORS
+        .iter()
+        .find(|&&(vendor, _)| vendor == vendor_id)
+        .map(|&(_, name)| name)
+}
+
+pub fn device_name(vendor_id: u16, device_id: u16) -> Option<&'static str> {
+    DEVICES
+        .iter()
+        .find(|&&(vendor, device, _)| vendor == vendor_id && device == device_id)
+        .map(|&(_, _, name)| name)
+}
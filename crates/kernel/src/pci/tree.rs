@@ -0,0 +1,144 @@
+//! The PCI topology, found by following the bridges from the host bridges.
+
+use alloc::vec::Vec;
+use spinning_top::Spinlock;
+use x86_64::instructions::interrupts;
+
+use super::{is_singleton_type, read_ids, PCIClass, PCIDeviceInfo, PCIFunction};
+
+/// Enumerated once in `pci::initialize`.
+static DEVICE_TREE: Spinlock<DeviceTree> = Spinlock::new(DeviceTree { nodes: Vec::new() });
+
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub struct DeviceTreeNode {
+    pub function: PCIFunction,
+    /// The index of the bridge the function is behind, or None on a root bus.
+    pub parent: Option<usize>,
+    /// The bus behind the function if it's a bridge.
+    pub secondary_bus: Option<u8>,
+}
+
+/// The functions in depth-first order, so that the functions behind a bridge come right after it.
+#[derive(Clone, Debug, Default)]
+pub struct DeviceTree {
+    nodes: Vec<DeviceTreeNode>,
+}
+
+impl DeviceTree {
+    fn enumerate() -> Self {
+        let mut tree = Self::default();
+        let mut visited = [false; 256];
+        if is_singleton_type(0, 0, 0) {
+            tree.scan_bus(0, None, &mut visited);
+        } else {
+            // Each function of 00:00 is the host bridge of the bus of the same number.
+            for function in 0..8 {
+                if read_ids(0, 0, function).is_some() {
+                    tree.scan_bus(function, None, &mut visited);
+                }
+            }
+        }
+        tree
+    }
+
+    fn scan_bus(&mut self, bus: u8, parent: Option<usize>, visited: &mut [bool; 256]) {
+        // Misconfigured bridges may point back to a bus already scanned.
+        if visited[bus as usize] {
+            return;
+        }
+        visited[bus as usize] = true;
+        for device in 0..32 {
+            let (vendor_id, device_id) = match read_ids(bus, device, 0) {
+                Some(ids) => ids,
+                None => continue,
+            };
+            let info = PCIDeviceInfo {
+                bus,
+                device,
+                vendor_id,
+                device_id,
+            };
+            for function in info.scan_functions() {
+                let index = self.nodes.len();
+                let secondary_bus = function.secondary_bus();
+                self.nodes.push(DeviceTreeNode {
+                    function,
+                    parent,
+                    secondary_bus,
+                });
+                if let Some(secondary_bus) = secondary_bus {
+                    self.scan_bus(secondary_bus, Some(index), visited);
+                }
+            }
+        }
+    }
+
+    pub fn nodes(&self) -> &[DeviceTreeNode] {
+        &self.nodes
+    }
+
+    /// The indices of the functions right behind the bridge at `index`.
+    pub fn children(&self, index: usize) -> impl Iterator<Item = usize> + '_ {
+        self.nodes
+            .iter()
+            .enumerate()
+            .filter(move |(_, node)| node.parent == Some(index))
+            .map(|(i, _)| i)
+    }
+
+    /// The number of bridges between the function at `index` and its host bridge.
+    pub fn depth(&self, index: usize) -> usize {
+        core::iter::successors(self.nodes[index].parent, |&parent| {
+            self.nodes[parent].parent
+        })
+        .count()
+    }
+
+    pub fn functions(&self) -> impl Iterator<Item = &PCIFunction> {
+        self.nodes.iter().map(|node| &node.function)
+    }
+}
+
+pub(super) fn initialize() {
+    let tree = DeviceTree::enumerate();
+    log::info!("Found {} PCI functions", tree.nodes.len());
+    interrupts::without_interrupts(|| *DEVICE_TREE.lock() = tree);
+}
+
+pub fn with_device_tree<T, F: FnOnce(&DeviceTree) -> T>(f: F) -> T {
+    interrupts::without_interrupts(|| f(&DEVICE_TREE.lock()))
+}
+
+/// All the functions, in depth-first order.
+pub fn functions() -> Vec<PCIFunction> {
+    with_device_tree(|tree| tree.functions().copied().collect())
+}
+
+pub fn find_by_class<F: Fn(&PCIClass) -> bool>(predicate: F) -> Vec<PCIFunction> {
+    with_device_tree(|tree| {
+        tree.functions()
+            .filter(|function| predicate(function.class()))
+            .copied()
+            .collect()
+    })
+}
+
+pub fn find_by_id(vendor_id: u16, device_id: u16) -> Vec<PCIFunction> {
+    with_device_tree(|tree| {
+        tree.functions()
+            .filter(|function| {
+                *function.vendor_id() == vendor_id && *function.device_id() == device_id
+            })
+            .copied()
+            .collect()
+    })
+}
+
+/// Finds the function at bus:device.function.
+pub fn find_by_address(bus: u8, device: u8, function: u8) -> Option<PCIFunction> {
+    with_device_tree(|tree| {
+        tree.functions()
+            .find(|f| *f.bus() == bus && *f.device() == device && *f.function() == function)
+            .copied()
+    })
+}
@@ -0,0 +1,146 @@
+//! The data each CPU keeps for itself, reached through the GS base.
+//!
+//! The GS base points to the `PerCpu` of the CPU while the kernel runs. Ring 3 can change its own
+//! GS base, so the `PerCpu` is kept in IA32_KERNEL_GS_BASE while ring 3 runs, and SWAPGS brings it
+//! back on every way into the kernel: the SYSCALL entry, and the interrupt and exception handlers
+//! through `enter_interrupt`. Nothing loads a selector into GS after `initialize`, as that clears
+//! the base.
+
+use core::{
+    arch::asm,
+    cell::UnsafeCell,
+    sync::atomic::{AtomicU32, AtomicU64, Ordering},
+};
+
+use alloc::boxed::Box;
+use x86_64::{
+    instructions::segmentation::GS,
+    registers::model_specific::{GsBase, KernelGsBase},
+    structures::idt::InterruptStackFrame,
+    VirtAddr,
+};
+
+use crate::task::TaskHandle;
+
+/// The layout of the first fields is known to `syscall::syscall_entry`.
+#[repr(C)]
+pub struct PerCpu {
+    /// Points to the `PerCpu` itself, so that `gs:[0]` gives its address. Offset 0x00.
+    self_ptr: *const PerCpu,
+    /// RSP0 of the current task, for the SYSCALL entry. Offset 0x08.
+    kernel_stack_top: AtomicU64,
+    /// Where the SYSCALL entry keeps the user RSP until it's on the kernel stack. Offset 0x10.
+    user_stack_pointer: AtomicU64,
+    cpu: usize,
+    ticks_until_next_preemption: AtomicU32,
+    /// The task fields are only touched by their own CPU with the interrupts disabled.
+    current_task: UnsafeCell<Option<TaskHandle>>,
+    idle_task: UnsafeCell<Option<TaskHandle>>,
+}
+
+impl PerCpu {
+    /// The number of the CPU.
+    pub fn cpu(&self) -> usize {
+        self.cpu
+    }
+
+    pub(crate) fn ticks_until_next_preemption(&self) -> &AtomicU32 {
+        &self.ticks_until_next_preemption
+    }
+
+    pub(crate) fn set_kernel_stack_top(&self, stack_top: VirtAddr) {
+        self.kernel_stack_top
+            .store(stack_top.as_u64(), Ordering::Relaxed);
+    }
+
+    pub(crate) fn current_task(&self) -> Option<TaskHandle> {
+        x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+            (*self.current_task.get()).clone()
+        })
+    }
+
+    pub(crate) fn set_current_task(&self, task: TaskHandle) {
+        x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+            *self.current_task.get() = Some(task);
+        })
+    }
+
+    /// The task the CPU runs when no other task of it is waking.
+    pub(crate) fn idle_task(&self) -> Option<TaskHandle> {
+        x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+            (*self.idle_task.get()).clone()
+        })
+    }
+
+    pub(crate) fn set_idle_task(&self, task: TaskHandle) {
+        x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+            *self.idle_task.get() = Some(task);
+        })
+    }
+}
+
+/// Allocates the data of the CPU `cpu` and points the GS base of the calling CPU to it.
+/// This has to come after the GDT is loaded, since loading GS clears the base.
+pub fn initialize(cpu: usize) {
+    let per_cpu = Box::leak(Box::new(PerCpu {
+        self_ptr: core::ptr::null(),
+        kernel_stack_top: AtomicU64::new(0),
+        user_stack_pointer: AtomicU64::new(0),
+        cpu,
+        ticks_until_next_preemption: AtomicU32::new(0),
+        current_task: UnsafeCell::new(None),
+        idle_task: UnsafeCell::new(None),
+    }));
+    per_cpu.self_ptr = &*per_cpu;
+    GsBase::write(VirtAddr::from_ptr(per_cpu));
+    // What ring 3 starts with.
+    KernelGsBase::write(VirtAddr::zero());
+}
+
+/// The data of the calling CPU. Tasks stay on the CPU they're spawned on, so the reference stays
+/// right across preemption.
+pub fn current() -> &'static PerCpu {
+    let per_cpu: *const PerCpu;
+    unsafe {
+        asm!(
+            "mov {}, gs:[0]",
+            out(reg) per_cpu,
+            options(nostack, preserves_flags, readonly)
+        );
+        &*per_cpu
+    }
+}
+
+/// Swaps the GS base back to the kernel's while an interrupt or exception handler runs, if it
+/// was raised in ring 3.
+#[must_use]
+pub struct InterruptGsGuard {
+    swapped: bool,
+}
+
+impl InterruptGsGuard {
+    /// Whether the handler was entered from ring 3, or from the return to it.
+    pub fn from_user(&self) -> bool {
+        self.swapped
+    }
+}
+
+impl Drop for InterruptGsGuard {
+    fn drop(&mut self) {
+        if self.swapped {
+            unsafe { GS::swap() };
+        }
+    }
+}
+
+/// Has to be the first thing in every interrupt and exception handler, before anything touches
+/// `current`. The guard has to live until the handler returns.
+pub fn enter_interrupt(stack_frame: &InterruptStackFrame) -> InterruptGsGuard {
+    // The IRETQ back to ring 3 faults in ring 0, after the GS base is swapped.
+    let swapped = stack_frame.code_segment & 0b11 == 3
+        || crate::syscall::is_return_to_user(stack_frame.instruction_pointer);
+    if swapped {
+        unsafe { GS::swap() };
+    }
+    InterruptGsGuard { swapped }
+}
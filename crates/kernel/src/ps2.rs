@@ -0,0 +1,396 @@
+//! The i8042 PS/2 controller, for the keyboard and the mouse on machines without USB.
+//!
+//! The interrupt handlers only take the bytes from the controller, and the "ps2" task turns them
+//! into the same events as the USB keyboard and mouse.
+
+use alloc::{boxed::Box, vec::Vec};
+use spinning_top::Spinlock;
+use x86_64::instructions::{
+    interrupts,
+    port::{Port, PortReadOnly},
+};
+
+use crate::{
+    gui::mouse,
+    interrupts::{allocate_vector, free_vectors, InterruptHandler},
+    ioapic,
+    keyboard::{self, Modifiers},
+    prelude::*,
+    task::{self, Receiver, TypedTaskHandle},
+    timer,
+};
+
+const DATA_PORT: u16 = 0x60;
+/// The status register when read, and the command register when written.
+const COMMAND_PORT: u16 = 0x64;
+
+const STATUS_OUTPUT_FULL: u8 = 1 << 0;
+const STATUS_INPUT_FULL: u8 = 1 << 1;
+/// The byte in the output buffer is from the mouse.
+const STATUS_AUX_OUTPUT_FULL: u8 = 1 << 5;
+
+const COMMAND_READ_CONFIG: u8 = 0x20;
+const COMMAND_WRITE_CONFIG: u8 = 0x60;
+const COMMAND_DISABLE_SECOND_PORT: u8 = 0xA7;
+const COMMAND_ENABLE_SECOND_PORT: u8 = 0xA8;
+const COMMAND_SELF_TEST: u8 = 0xAA;
+const COMMAND_DISABLE_FIRST_PORT: u8 = 0xAD;
+const COMMAND_ENABLE_FIRST_PORT: u8 = 0xAE;
+const COMMAND_WRITE_SECOND_PORT: u8 = 0xD4;
+
+const CONFIG_FIRST_PORT_INTERRUPT: u8 = 1 << 0;
+const CONFIG_SECOND_PORT_INTERRUPT: u8 = 1 << 1;
+/// The controller translates the keyboard's scan codes into set 1.
+const CONFIG_TRANSLATION: u8 = 1 << 6;
+
+const SELF_TEST_PASSED: u8 = 0x55;
+const DEVICE_SET_DEFAULTS: u8 = 0xF6;
+const DEVICE_ENABLE_REPORTING: u8 = 0xF4;
+const DEVICE_ACK: u8 = 0xFA;
+
+const KEYBOARD_IRQ: u8 = 1;
+const MOUSE_IRQ: u8 = 12;
+
+const TIMEOUT_MICROS: u64 = 100_000;
+const POLL_INTERVAL_MICROS: u64 = 10;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+enum Input {
+    Keyboard(u8),
+    Mouse(u8),
+}
+
+static TASK: Spinlock<Option<TypedTaskHandle<Input>>> = Spinlock::new(None);
+
+/// Sets up the controller, and starts taking the input of the keyboard and the mouse.
+/// Either of them may be missing.
+pub fn initialize() -> Result<()> {
+    // Nothing answers on the ports without a controller.
+    if read_status() == 0xFF {
+        return Err(Error::Whatever("There's no PS/2 controller"));
+    }
+    send_command(COMMAND_DISABLE_FIRST_PORT)?;
+    send_command(COMMAND_DISABLE_SECOND_PORT)?;
+    while read_status() & STATUS_OUTPUT_FULL != 0 {
+        read_data_now();
+    }
+    send_command(COMMAND_READ_CONFIG)?;
+    let config = (read_data()? & !(CONFIG_FIRST_PORT_INTERRUPT | CONFIG_SECOND_PORT_INTERRUPT))
+        | CONFIG_TRANSLATION;
+    // The self test may reset the configuration, so it's written afterwards.
+    send_command(COMMAND_SELF_TEST)?;
+    if read_data()? != SELF_TEST_PASSED {
+        return Err(Error::Whatever("The PS/2 controller failed its self test"));
+    }
+    write_config(config)?;
+
+    send_command(COMMAND_ENABLE_FIRST_PORT)?;
+    let has_keyboard = send_to_device(false, DEVICE_ENABLE_REPORTING).is_ok();
+    if !has_keyboard {
+        log::warn!("No PS/2 keyboard responded");
+        send_command(COMMAND_DISABLE_FIRST_PORT)?;
+    }
+    send_command(COMMAND_ENABLE_SECOND_PORT)?;
+    let has_mouse = send_to_device(true, DEVICE_SET_DEFAULTS)
+        .and_then(|()| send_to_device(true, DEVICE_ENABLE_REPORTING))
+        .is_ok();
+    if !has_mouse {
+        log::warn!("No PS/2 mouse responded");
+        send_command(COMMAND_DISABLE_SECOND_PORT)?;
+    }
+    if !has_keyboard && !has_mouse {
+        return Err(Error::Whatever("No PS/2 device responded"));
+    }
+
+    let mut routed = Vec::new();
+    let mut config = config;
+    for (present, irq, name, config_bit) in [
+        (
+            has_keyboard,
+            KEYBOARD_IRQ,
+            "ps2-keyboard",
+            CONFIG_FIRST_PORT_INTERRUPT,
+        ),
+        (
+            has_mouse,
+            MOUSE_IRQ,
+            "ps2-mouse",
+            CONFIG_SECOND_PORT_INTERRUPT,
+        ),
+    ] {
+        if !present {
+            continue;
+        }
+        match route_irq(irq, name) {
+            Ok(vector) => {
+                routed.push((irq, vector));
+                config |= config_bit;
+            }
+            Err(e) => {
+                disable(&routed);
+                return Err(e);
+            }
+        }
+    }
+    // The handlers drop the bytes until the task is there, so nothing can fail after spawning it.
+    if let Err(e) = write_config(config) {
+        disable(&routed);
+        return Err(e);
+    }
+    let handle = task::spawn_task(task::builder("ps2", ps2_main).set_priority(10));
+    interrupts::without_interrupts(|| *TASK.lock() = Some(handle));
+    log::info!("PS/2 controller is ready");
+    Ok(())
+}
+
+fn route_irq(irq: u8, name: &'static str) -> Result<u8> {
+    let vector = allocate_vector(name, InterruptHandler::Function(handle_interrupt))?;
+    if let Err(e) = ioapic::route_legacy_irq(irq, vector) {
+        free_vectors(&[vector]);
+        return Err(e);
+    }
+    Ok(vector)
+}
+
+/// Undoes `initialize` when it fails after the ports are enabled.
+fn disable(routed: &[(u8, u8)]) {
+    send_command(COMMAND_DISABLE_FIRST_PORT).ok();
+    send_command(COMMAND_DISABLE_SECOND_PORT).ok();
+    for &(irq, vector) in routed {
+        if let Ok(legacy_irq) = ioapic::legacy_irq(irq) {
+            ioapic::mask_gsi(legacy_irq.gsi).ok();
+        }
+        free_vectors(&[vector]);
+    }
+}
+
+fn read_status() -> u8 {
+    unsafe { PortReadOnly::<u8>::new(COMMAND_PORT).read() }
+}
+
+fn read_data_now() -> u8 {
+    unsafe { Port::<u8>::new(DATA_PORT).read() }
+}
+
+/// Waits for the controller until `ready` holds for its status.
+fn wait_for<F: Fn(u8) -> bool>(ready: F) -> Result<()> {
+    for _ in 0..TIMEOUT_MICROS / POLL_INTERVAL_MICROS {
+        if ready(read_status()) {
+            return Ok(());
+        }
+        timer::busy_wait_micros(POLL_INTERVAL_MICROS);
+    }
+    Err(Error::Whatever("The PS/2 controller timed out"))
+}
+
+fn read_data() -> Result<u8> {
+    wait_for(|status| status & STATUS_OUTPUT_FULL != 0)?;
+    Ok(read_data_now())
+}
+
+fn write_data(value: u8) -> Result<()> {
+    wait_for(|status| status & STATUS_INPUT_FULL == 0)?;
+    unsafe { Port::<u8>::new(DATA_PORT).write(value) };
+    Ok(())
+}
+
+fn send_command(command: u8) -> Result<()> {
+    wait_for(|status| status & STATUS_INPUT_FULL == 0)?;
+    unsafe { Port::<u8>::new(COMMAND_PORT).write(command) };
+    Ok(())
+}
+
+fn write_config(config: u8) -> Result<()> {
+    send_command(COMMAND_WRITE_CONFIG)?;
+    write_data(config)
+}
+
+/// Sends `command` to the keyboard, or to the mouse if `to_mouse`, and waits for the ACK.
+fn send_to_device(to_mouse: bool, command: u8) -> Result<()> {
+    if to_mouse {
+        send_command(COMMAND_WRITE_SECOND_PORT)?;
+    }
+    write_data(command)?;
+    if read_data()? != DEVICE_ACK {
+        return Err(Error::Whatever(
+            "The PS/2 device didn't acknowledge the command",
+        ));
+    }
+    Ok(())
+}
+
+fn send_input(input: Input) {
+    if let Some(handle) = TASK.lock().as_ref() {
+        handle.send(input);
+    }
+}
+
+/// Handles both IRQs, since a byte may be left for the other device when an interrupt arrives.
+fn handle_interrupt() {
+    loop {
+        let status = read_status();
+        if status & STATUS_OUTPUT_FULL == 0 {
+            break;
+        }
+        let byte = read_data_now();
+        if status & STATUS_AUX_OUTPUT_FULL != 0 {
+            send_input(Input::Mouse(byte));
+        } else {
+            send_input(Input::Keyboard(byte));
+        }
+    }
+}
+
+extern "sysv64" fn ps2_main(mut receiver: Box<Receiver<Input>>) {
+    let mut keyboard_decoder = KeyboardDecoder::default();
+    let mut mouse_decoder = MouseDecoder::default();
+    loop {
+        interrupts::enable();
+        match receiver.dequeue_or_wait() {
+            Input::Keyboard(byte) => {
+                if let Some((modifiers, keycode)) = keyboard_decoder.feed(byte) {
+                    keyboard::observe_keyboard_event(modifiers.bits(), keycode);
+                }
+            }
+            Input::Mouse(byte) => {
+                if let Some((buttons, x, y)) = mouse_decoder.feed(byte) {
+                    mouse::observe_cursor_move(buttons, x, y);
+                }
+            }
+        }
+    }
+}
+
+/// Turns scan codes in set 1 into the keycodes of the USB keyboards, the HID usage IDs.
+#[derive(Default)]
+struct KeyboardDecoder {
+    modifiers: Modifiers,
+    /// The last byte was 0xE0.
+    extended: bool,
+    /// The bytes left in the sequence of the Pause key.
+    to_skip: u8,
+}
+
+impl KeyboardDecoder {
+    /// Returns the modifiers and the keycode of a key press.
+    fn feed(&mut self, byte: u8) -> Option<(Modifiers, u8)> {
+        if self.to_skip > 0 {
+            self.to_skip -= 1;
+            return None;
+        }
+        match byte {
+            0xE0 => {
+                self.extended = true;
+                return None;
+            }
+            0xE1 => {
+                self.to_skip = 2;
+                return None;
+            }
+            _ => {}
+        }
+        let extended = core::mem::replace(&mut self.extended, false);
+        let released = byte & 0x80 != 0;
+        let code = byte & 0x7F;
+        let modifier = match (extended, code) {
+            (false, 0x1D) => Some(Modifiers::L_CONTROL),
+            (false, 0x2A) => Some(Modifiers::L_SHIFT),
+            (false, 0x38) => Some(Modifiers::L_ALT),
+            (false, 0x36) => Some(Modifiers::R_SHIFT),
+            (true, 0x1D) => Some(Modifiers::R_CONTROL),
+            (true, 0x38) => Some(Modifiers::R_ALT),
+            (true, 0x5B) => Some(Modifiers::L_GUI),
+            (true, 0x5C) => Some(Modifiers::R_GUI),
+            _ => None,
+        };
+        if let Some(modifier) = modifier {
+            self.modifiers.set(modifier, !released);
+            return None;
+        }
+        if released {
+            return None;
+        }
+        let keycode = if extended {
+            match code {
+                0x1C => 0x58, // Keypad Enter
+                0x35 => 0x54, // Keypad /
+                0x47 => 0x4A, // Home
+                0x48 => 0x52, // Up
+                0x49 => 0x4B, // Page Up
+                0x4B => 0x50, // Left
+                0x4D => 0x4F, // Right
+                0x4F => 0x4D, // End
+                0x50 => 0x51, // Down
+                0x51 => 0x4E, // Page Down
+                0x52 => 0x49, // Insert
+                0x53 => 0x4C, // Delete
+                _ => 0,
+            }
+        } else {
+            SCAN_CODE_TO_KEYCODE
+                .get(code as usize)
+                .copied()
+                .unwrap_or(0)
+        };
+        if keycode == 0 {
+            None
+        } else {
+            Some((self.modifiers, keycode))
+        }
+    }
+}
+
+#[rustfmt::skip]
+const SCAN_CODE_TO_KEYCODE: [u8; 0x59] = [
+    0x00, 0x29, 0x1E, 0x1F, 0x20, 0x21, 0x22, 0x23,
+    0x24, 0x25, 0x26, 0x27, 0x2D, 0x2E, 0x2A, 0x2B,
+    0x14, 0x1A, 0x08, 0x15, 0x17, 0x1C, 0x18, 0x0C,
+    0x12, 0x13, 0x2F, 0x30, 0x28, 0x00, 0x04, 0x16,
+    0x07, 0x09, 0x0A, 0x0B, 0x0D, 0x0E, 0x0F, 0x33,
+    0x34, 0x35, 0x00, 0x31, 0x1D, 0x1B, 0x06, 0x19,
+    0x05, 0x11, 0x10, 0x36, 0x37, 0x38, 0x00, 0x55,
+    0x00, 0x2C, 0x39, 0x3A, 0x3B, 0x3C, 0x3D, 0x3E,
+    0x3F, 0x40, 0x41, 0x42, 0x43, 0x53, 0x47, 0x5F,
+    0x60, 0x61, 0x56, 0x5C, 0x5D, 0x5E, 0x57, 0x59,
+    0x5A, 0x5B, 0x62, 0x63, 0x00, 0x00, 0x64, 0x44,
+    0x45,
+];
+
+/// Collects the 3-byte packets of the mouse.
+#[derive(Default)]
+struct MouseDecoder {
+    packet: [u8; 3],
+    received: usize,
+}
+
+impl MouseDecoder {
+    const ALWAYS_ONE: u8 = 1 << 3;
+    const X_SIGN: u8 = 1 << 4;
+    const Y_SIGN: u8 = 1 << 5;
+    const OVERFLOW: u8 = 0b11 << 6;
+
+    /// Returns the buttons and the move of a complete packet, with Y growing downwards.
+    fn feed(&mut self, byte: u8) -> Option<(u8, i8, i8)> {
+        // The first byte always has bit 3 set, which is how a lost byte is recovered from.
+        if self.received == 0 && byte & Self::ALWAYS_ONE == 0 {
+            return None;
+        }
+        self.packet[self.received] = byte;
+        self.received += 1;
+        if self.received < self.packet.len() {
+            return None;
+        }
+        self.received = 0;
+        let [flags, x, y] = self.packet;
+        if flags & Self::OVERFLOW != 0 {
+            return None;
+        }
+        let delta = |value: u8, negative: bool| {
+            let value = value as i16 - if negative { 0x100 } else { 0 };
+            value.clamp(i8::MIN as i16, i8::MAX as i16) as i8
+        };
+        let x = delta(x, flags & Self::X_SIGN != 0);
+        let y = delta(y, flags & Self::Y_SIGN != 0).saturating_neg();
+        Some((flags & 0b111, x, y))
+    }
+}
@@ -0,0 +1,220 @@
+//! The 16550 UART on COM1, where the kernel log is mirrored.
+//!
+//! Writing polls the transmitter, so that it works in any context, and received bytes are taken
+//! in the interrupt handler until someone reads them from `/dev/serial`.
+
+use core::{
+    fmt,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
+
+use spinning_top::Spinlock;
+use x86_64::instructions::{interrupts, port::Port};
+
+use crate::{
+    interrupts::{allocate_vector, free_vectors, InterruptHandler},
+    ioapic, percpu,
+    prelude::*,
+    ring_buffer::ArrayRingBuffer,
+    task::TaskHandle,
+};
+
+const COM1: u16 = 0x3F8;
+const COM1_IRQ: u8 = 4;
+
+const DATA_REGISTER: u16 = 0;
+const INTERRUPT_ENABLE_REGISTER: u16 = 1;
+/// The divisor latch takes the place of the data and interrupt enable registers with DLAB set.
+const DIVISOR_LOW_REGISTER: u16 = 0;
+const DIVISOR_HIGH_REGISTER: u16 = 1;
+const FIFO_CONTROL_REGISTER: u16 = 2;
+const LINE_CONTROL_REGISTER: u16 = 3;
+const MODEM_CONTROL_REGISTER: u16 = 4;
+const LINE_STATUS_REGISTER: u16 = 5;
+
+const INTERRUPT_DATA_AVAILABLE: u8 = 1 << 0;
+const LINE_DLAB: u8 = 1 << 7;
+const LINE_8N1: u8 = 0b11;
+/// Enabled, cleared, and interrupting at 14 bytes.
+const FIFO_SETTINGS: u8 = 0xC7;
+const MODEM_DTR: u8 = 1 << 0;
+const MODEM_RTS: u8 = 1 << 1;
+/// Connects the interrupt line of the UART.
+const MODEM_OUT2: u8 = 1 << 3;
+const MODEM_LOOPBACK: u8 = 1 << 4;
+const STATUS_DATA_READY: u8 = 1 << 0;
+const STATUS_TRANSMITTER_EMPTY: u8 = 1 << 5;
+
+/// 115200 baud
+const DIVISOR: u16 = 1;
+/// How many times the transmitter is polled before a byte is dropped.
+const TRANSMIT_RETRIES: usize = 100_000;
+const RECEIVE_BUFFER_SIZE: usize = 256;
+
+struct SerialPort {
+    base: u16,
+}
+
+impl SerialPort {
+    fn read(&self, register: u16) -> u8 {
+        unsafe { Port::<u8>::new(self.base + register).read() }
+    }
+
+    fn write(&self, register: u16, value: u8) {
+        unsafe { Port::<u8>::new(self.base + register).write(value) }
+    }
+
+    fn send(&self, byte: u8) {
+        for _ in 0..TRANSMIT_RETRIES {
+            if self.read(LINE_STATUS_REGISTER) & STATUS_TRANSMITTER_EMPTY != 0 {
+                self.write(DATA_REGISTER, byte);
+                return;
+            }
+            core::hint::spin_loop();
+        }
+    }
+}
+
+impl fmt::Write for SerialPort {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            // Terminals expect CRLF.
+            if byte == b'\n' {
+                self.send(b'\r');
+            }
+            self.send(byte);
+        }
+        Ok(())
+    }
+}
+
+struct ReceiveState {
+    buffer: ArrayRingBuffer<u8, RECEIVE_BUFFER_SIZE>,
+    /// Woken up when bytes arrive.
+    waiter: Option<TaskHandle>,
+}
+
+static PORT: Spinlock<Option<SerialPort>> = Spinlock::new(None);
+/// Whether `PORT` is set, for writing without the lock.
+static READY: AtomicBool = AtomicBool::new(false);
+/// The CPU writing the log to the port, or `usize::MAX`.
+static WRITING_CPU: AtomicUsize = AtomicUsize::new(usize::MAX);
+static RECEIVE_STATE: Spinlock<ReceiveState> = Spinlock::new(ReceiveState {
+    buffer: ArrayRingBuffer::new(),
+    waiter: None,
+});
+
+/// Sets up COM1 without interrupts, so that the log can go there from early on.
+pub fn initialize() -> Result<()> {
+    let port = SerialPort { base: COM1 };
+    port.write(INTERRUPT_ENABLE_REGISTER, 0);
+    port.write(LINE_CONTROL_REGISTER, LINE_DLAB);
+    port.write(DIVISOR_LOW_REGISTER, DIVISOR as u8);
+    port.write(DIVISOR_HIGH_REGISTER, (DIVISOR >> 8) as u8);
+    port.write(LINE_CONTROL_REGISTER, LINE_8N1);
+    port.write(FIFO_CONTROL_REGISTER, FIFO_SETTINGS);
+    // A byte sent in the loopback mode comes back if the UART is there.
+    port.write(
+        MODEM_CONTROL_REGISTER,
+        MODEM_LOOPBACK | MODEM_RTS | MODEM_OUT2,
+    );
+    port.write(DATA_REGISTER, 0xAE);
+    if port.read(DATA_REGISTER) != 0xAE {
+        return Err(Error::Whatever("There's no UART on COM1"));
+    }
+    port.write(MODEM_CONTROL_REGISTER, MODEM_DTR | MODEM_RTS | MODEM_OUT2);
+    interrupts::without_interrupts(|| *PORT.lock() = Some(port));
+    READY.store(true, Ordering::Release);
+    Ok(())
+}
+
+/// Starts taking the received bytes with interrupts. This needs the I/O APIC.
+pub fn initialize_interrupts() -> Result<()> {
+    if interrupts::without_interrupts(|| PORT.lock().is_none()) {
+        return Err(Error::Whatever("The serial port is not initialized"));
+    }
+    let vector = allocate_vector("serial", InterruptHandler::Function(handle_interrupt))?;
+    if let Err(e) = ioapic::route_legacy_irq(COM1_IRQ, vector) {
+        free_vectors(&[vector]);
+        return Err(e);
+    }
+    with_port(|port| port.write(INTERRUPT_ENABLE_REGISTER, INTERRUPT_DATA_AVAILABLE));
+    Ok(())
+}
+
+/// Whether there's a serial port.
+pub fn is_ready() -> bool {
+    READY.load(Ordering::Acquire)
+}
+
+fn with_port<F: FnOnce(&mut SerialPort)>(f: F) {
+    interrupts::without_interrupts(|| {
+        if let Some(port) = PORT.lock().as_mut() {
+            f(port)
+        }
+    })
+}
+
+/// Writes to the serial port, if there's one. Unlike the console, this never drops text for
+/// the lock, so it's where the log can be relied on.
+pub fn write_fmt(args: fmt::Arguments) {
+    if !is_ready() {
+        return;
+    }
+    interrupts::without_interrupts(|| {
+        let cpu = percpu::current().cpu();
+        // An exception or a panic in the middle of writing can't wait for the lock the CPU holds
+        // itself. Writing is only polling the port, so it's done without the lock.
+        if WRITING_CPU.load(Ordering::Relaxed) == cpu {
+            fmt::Write::write_fmt(&mut SerialPort { base: COM1 }, args).ok();
+            return;
+        }
+        if let Some(port) = PORT.lock().as_mut() {
+            WRITING_CPU.store(cpu, Ordering::Relaxed);
+            fmt::Write::write_fmt(port, args).ok();
+            WRITING_CPU.store(usize::MAX, Ordering::Relaxed);
+        }
+    })
+}
+
+fn handle_interrupt() {
+    let waiter = with_port_and_state(|port, state| {
+        let mut received = false;
+        while port.read(LINE_STATUS_REGISTER) & STATUS_DATA_READY != 0 {
+            let byte = port.read(DATA_REGISTER);
+            // The oldest bytes are dropped if nobody reads them.
+            if state.buffer.is_full() {
+                state.buffer.pop_front();
+            }
+            state.buffer.push_back(byte);
+            received = true;
+        }
+        if received {
+            state.waiter.clone()
+        } else {
+            None
+        }
+    });
+    // The waiter is woken up without the locks, since the log is written under the task manager.
+    if let Some(waiter) = waiter.flatten() {
+        waiter.awake();
+    }
+}
+
+fn with_port_and_state<T, F: FnOnce(&mut SerialPort, &mut ReceiveState) -> T>(f: F) -> Option<T> {
+    interrupts::without_interrupts(|| {
+        let mut port = PORT.lock();
+        let port = port.as_mut()?;
+        Some(f(port, &mut RECEIVE_STATE.lock()))
+    })
+}
+
+/// Takes a received byte, if any.
+pub fn try_read_byte() -> Option<u8> {
+    interrupts::without_interrupts(|| RECEIVE_STATE.lock().buffer.pop_front())
+}
+
+/// Wakes up `waiter` whenever bytes arrive, or stops waking anyone if it's `None`.
+pub fn set_waiter(waiter: Option<TaskHandle>) {
+    interrupts::without_interrupts(|| RECEIVE_STATE.lock().waiter = waiter);
+}
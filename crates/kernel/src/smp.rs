@@ -0,0 +1,389 @@
+//! Starting the application processors (APs).
+//!
+//! The local APICs are listed in the MADT. Each AP is woken up with INIT-SIPI-SIPI into a
+//! real-mode trampoline below 1 MiB, which switches to long mode on the kernel page table and
+//! jumps to `ap_main` on a stack of its own. The APs are started one by one, as they share the
+//! parameters in the trampoline.
+
+use core::{
+    arch::global_asm,
+    ptr::addr_of,
+    sync::atomic::{AtomicBool, AtomicU64, AtomicU8, AtomicUsize, Ordering},
+};
+
+use acpi::platform::ProcessorState;
+use alloc::vec;
+use x86_64::{
+    structures::paging::{Page, PageSize, PageTableFlags, Size4KiB},
+    PhysAddr, VirtAddr,
+};
+
+use crate::{
+    acpi_tables, gdt,
+    interrupts::{self, local_apic_register, InterruptIndex},
+    memory_manager, paging, percpu,
+    prelude::*,
+    syscall, task, timer,
+};
+
+/// The number of CPUs the kernel can handle.
+pub const MAX_CPUS: usize = 64;
+
+/// The number of CPUs started, numbered from the BSP as CPU 0.
+static CPU_COUNT: AtomicUsize = AtomicUsize::new(1);
+/// The local APIC ID of each CPU, for the IPIs sent to it.
+static APIC_IDS: [AtomicU8; MAX_CPUS] = [NO_APIC_ID; MAX_CPUS];
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_APIC_ID: AtomicU8 = AtomicU8::new(0);
+/// Set by an AP once it's done with the parameters in the trampoline.
+static AP_STARTED: AtomicBool = AtomicBool::new(false);
+
+/// Set while a CPU is shooting down TLB entries, which is done one at a time.
+static SHOOTDOWN_IN_PROGRESS: AtomicBool = AtomicBool::new(false);
+static SHOOTDOWN_START: AtomicU64 = AtomicU64::new(0);
+static SHOOTDOWN_PAGES: AtomicU64 = AtomicU64::new(0);
+/// A bit for each CPU yet to flush the range of the shootdown in progress.
+static SHOOTDOWN_PENDING: AtomicU64 = AtomicU64::new(0);
+
+const INTERRUPT_COMMAND_REGISTER_LOW: u64 = 0x300;
+const INTERRUPT_COMMAND_REGISTER_HIGH: u64 = 0x310;
+const DELIVERY_MODE_INIT: u32 = 0b101 << 8;
+const DELIVERY_MODE_STARTUP: u32 = 0b110 << 8;
+const LEVEL_ASSERT: u32 = 1 << 14;
+const DESTINATION_SELF: u32 = 0b01 << 18;
+const DESTINATION_ALL_EXCLUDING_SELF: u32 = 0b11 << 18;
+const DELIVERY_STATUS_PENDING: u32 = 1 << 12;
+
+/// The trampoline has to be where real mode can reach.
+const TRAMPOLINE_LIMIT: u64 = 0x10_0000;
+const AP_STACK_SIZE: usize = 128 * 1024;
+/// How long to wait for an AP to start after the second SIPI.
+const AP_START_TIMEOUT_MILLIS: u64 = 100;
+
+global_asm!(
+    ".global ap_trampoline_start",
+    ".global ap_trampoline_end",
+    ".global ap_trampoline_gdt",
+    ".global ap_trampoline_gdtr",
+    ".global ap_trampoline_long_mode_target",
+    ".global ap_trampoline_long_mode",
+    ".global ap_trampoline_cr3",
+    ".global ap_trampoline_stack",
+    ".global ap_trampoline_entry",
+    ".global ap_trampoline_argument",
+    // The SIPI starts the AP here with CS:IP = (the trampoline page):0.
+    ".code16",
+    "ap_trampoline_start:",
+    "    cli",
+    "    cld",
+    "    mov ax, cs",
+    "    mov ds, ax",
+    "    lgdt [ap_trampoline_gdtr - ap_trampoline_start]",
+    // PAE, OSFXSR and OSXMMEXCPT
+    "    mov eax, cr4",
+    "    or eax, 0x620",
+    "    mov cr4, eax",
+    "    mov eax, [ap_trampoline_cr3 - ap_trampoline_start]",
+    "    mov cr3, eax",
+    // EFER.LME
+    "    mov ecx, 0xC0000080",
+    "    rdmsr",
+    "    or eax, 0x100",
+    "    wrmsr",
+    // Enable the paging and the protection, and the caches the INIT has disabled.
+    "    mov eax, cr0",
+    "    and eax, 0x9FFFFFFB",
+    "    or eax, 0x80000023",
+    "    mov cr0, eax",
+    // A far jump to the 64-bit code segment, with a 32-bit offset filled in by the BSP.
+    "    .byte 0x66, 0xEA",
+    "ap_trampoline_long_mode_target:",
+    "    .long 0",
+    "    .word 0x08",
+    ".code64",
+    "ap_trampoline_long_mode:",
+    "    mov ax, 0x10",
+    "    mov ds, ax",
+    "    mov es, ax",
+    "    mov fs, ax",
+    "    mov gs, ax",
+    "    mov ss, ax",
+    "    mov rsp, [rip + ap_trampoline_stack]",
+    "    mov rdi, [rip + ap_trampoline_argument]",
+    "    mov rax, [rip + ap_trampoline_entry]",
+    "    call rax",
+    "2:",
+    "    hlt",
+    "    jmp 2b",
+    ".p2align 3",
+    "ap_trampoline_gdt:",
+    "    .quad 0",
+    "    .quad 0x00AF9A000000FFFF",
+    "    .quad 0x00CF92000000FFFF",
+    "ap_trampoline_gdtr:",
+    "    .word 3 * 8 - 1",
+    "    .long 0",
+    "ap_trampoline_cr3:",
+    "    .long 0",
+    ".p2align 3",
+    "ap_trampoline_stack:",
+    "    .quad 0",
+    "ap_trampoline_entry:",
+    "    .quad 0",
+    "ap_trampoline_argument:",
+    "    .quad 0",
+    "ap_trampoline_end:",
+);
+
+extern "C" {
+    static ap_trampoline_start: u8;
+    static ap_trampoline_end: u8;
+    static ap_trampoline_gdt: u8;
+    static ap_trampoline_gdtr: u8;
+    static ap_trampoline_long_mode_target: u8;
+    static ap_trampoline_long_mode: u8;
+    static ap_trampoline_cr3: u8;
+    static ap_trampoline_stack: u8;
+    static ap_trampoline_entry: u8;
+    static ap_trampoline_argument: u8;
+}
+
+/// The number of the calling CPU.
+pub fn current_cpu() -> usize {
+    percpu::current().cpu()
+}
+
+pub fn is_bsp() -> bool {
+    current_cpu() == 0
+}
+
+/// The number of the CPUs running.
+pub fn cpu_count() -> usize {
+    CPU_COUNT.load(Ordering::Acquire)
+}
+
+/// Flushes `count` pages from `start` out of the TLBs of all the CPUs, after they're unmapped in
+/// the kernel half, which all the CPUs share. This waits for the other CPUs to flush, so it must
+/// not be called with a spinlock held, as another CPU might be waiting for it with the interrupts
+/// disabled.
+pub fn flush_tlb_everywhere(start: VirtAddr, count: u64) {
+    flush_tlb(start, count);
+    let cpu_count = cpu_count();
+    if cpu_count == 1 {
+        return;
+    }
+    let others = (u64::MAX >> (64 - cpu_count)) & !(1 << current_cpu());
+    // A CPU waiting here still flushes for the shootdown in progress, which may be waiting for it.
+    while SHOOTDOWN_IN_PROGRESS
+        .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+        .is_err()
+    {
+        handle_tlb_shootdown();
+        core::hint::spin_loop();
+    }
+    SHOOTDOWN_START.store(start.as_u64(), Ordering::Relaxed);
+    SHOOTDOWN_PAGES.store(count, Ordering::Relaxed);
+    SHOOTDOWN_PENDING.store(others, Ordering::Release);
+    send_ipi(
+        0,
+        DESTINATION_ALL_EXCLUDING_SELF | LEVEL_ASSERT | InterruptIndex::TLBShootdown as u32,
+    )
+    .expect("Failed to send the TLB shootdown");
+    while SHOOTDOWN_PENDING.load(Ordering::Acquire) != 0 {
+        core::hint::spin_loop();
+    }
+    SHOOTDOWN_IN_PROGRESS.store(false, Ordering::Release);
+}
+
+/// Makes the CPU `cpu` run its scheduler once it enables the interrupts.
+pub(crate) fn send_reschedule(cpu: usize) {
+    let command = LEVEL_ASSERT | InterruptIndex::Reschedule as u32;
+    let result = if cpu == current_cpu() {
+        send_ipi(0, DESTINATION_SELF | command)
+    } else {
+        send_ipi(APIC_IDS[cpu].load(Ordering::Relaxed), command)
+    };
+    // The task still runs on the next preemption, and logging here might wake up a task again.
+    result.ok();
+}
+
+/// Flushes the range of the shootdown in progress, if the calling CPU hasn't yet.
+pub(crate) fn handle_tlb_shootdown() {
+    let bit = 1 << current_cpu();
+    if SHOOTDOWN_PENDING.load(Ordering::Acquire) & bit == 0 {
+        return;
+    }
+    flush_tlb(
+        VirtAddr::new(SHOOTDOWN_START.load(Ordering::Relaxed)),
+        SHOOTDOWN_PAGES.load(Ordering::Relaxed),
+    );
+    SHOOTDOWN_PENDING.fetch_and(!bit, Ordering::Release);
+}
+
+fn flush_tlb(start: VirtAddr, count: u64) {
+    let start = Page::<Size4KiB>::containing_address(start);
+    for page in Page::range(start, start + count) {
+        x86_64::instructions::tlb::flush(page.start_address());
+    }
+}
+
+/// Starts the APs listed in the MADT. The BSP has to have started its timer and tasks.
+pub fn initialize() -> Result<()> {
+    let processor_info = acpi_tables::tables()?
+        .platform_info()?
+        .processor_info
+        .ok_or(Error::Whatever("The MADT lists no processors"))?;
+    let trampoline = prepare_trampoline()?;
+    APIC_IDS[0].store(interrupts::local_apic_id(), Ordering::Relaxed);
+    for processor in processor_info.application_processors.iter() {
+        if !matches!(processor.state, ProcessorState::WaitingForSipi) {
+            continue;
+        }
+        let apic_id = match u8::try_from(processor.local_apic_id) {
+            Ok(apic_id) => apic_id,
+            Err(_) => {
+                log::warn!(
+                    "Skipping the CPU with x2APIC ID {}",
+                    processor.local_apic_id
+                );
+                continue;
+            }
+        };
+        let cpu = CPU_COUNT.load(Ordering::Acquire);
+        if cpu == MAX_CPUS {
+            log::warn!("Ignoring the CPUs beyond {}", MAX_CPUS);
+            break;
+        }
+        if let Err(e) = start_ap(trampoline, cpu, apic_id) {
+            // The AP might still wake up later and use the parameters, so no more APs are started.
+            log::warn!("Failed to start the CPU with APIC ID {}: {:?}", apic_id, e);
+            break;
+        }
+    }
+    log::info!("{} CPUs are running", cpu_count());
+    Ok(())
+}
+
+fn trampoline_offset(symbol: &u8) -> usize {
+    symbol as *const u8 as usize - unsafe { addr_of!(ap_trampoline_start) } as usize
+}
+
+/// Copies the trampoline below 1 MiB, and maps it at the same address for when the APs enable
+/// paging.
+fn prepare_trampoline() -> Result<PhysAddr> {
+    let frames = memory_manager::with_memory_manager(|mm| {
+        mm.allocate_below(1, PhysAddr::new(TRAMPOLINE_LIMIT))
+    })
+    .ok_or(Error::Whatever(
+        "No memory is left below 1 MiB for the trampoline",
+    ))?;
+    let base = frames.start.start_address();
+    let size = unsafe { trampoline_offset(&ap_trampoline_end) };
+    assert!(size as u64 <= Size4KiB::SIZE);
+    let cr3 = u32::try_from(paging::kernel_pml4_address().as_u64())
+        .map_err(|_| Error::Whatever("The kernel page table is out of the reach of the APs"))?;
+    unsafe {
+        paging::identity_map(frames, PageTableFlags::WRITABLE)?;
+        let trampoline = paging::phys_to_virt(base).as_mut_ptr::<u8>();
+        core::ptr::copy_nonoverlapping(addr_of!(ap_trampoline_start), trampoline, size);
+        let write_u32 = |symbol: &u8, value: u32| {
+            trampoline
+                .add(trampoline_offset(symbol))
+                .cast::<u32>()
+                .write_unaligned(value)
+        };
+        // The GDT base follows the 16-bit limit.
+        trampoline
+            .add(trampoline_offset(&ap_trampoline_gdtr) + 2)
+            .cast::<u32>()
+            .write_unaligned(base.as_u64() as u32 + trampoline_offset(&ap_trampoline_gdt) as u32);
+        write_u32(
+            &ap_trampoline_long_mode_target,
+            base.as_u64() as u32 + trampoline_offset(&ap_trampoline_long_mode) as u32,
+        );
+        write_u32(&ap_trampoline_cr3, cr3);
+    }
+    Ok(base)
+}
+
+fn start_ap(trampoline: PhysAddr, cpu: usize, apic_id: u8) -> Result<()> {
+    // The stack is taken from the heap, which is mapped in full, as no page fault can be handled
+    // until the AP has loaded its IDT.
+    let stack = vec![0u8; AP_STACK_SIZE].leak();
+    let stack_top = (stack.as_ptr() as u64 + AP_STACK_SIZE as u64) & !0xF;
+    unsafe {
+        let trampoline_ptr = paging::phys_to_virt(trampoline).as_mut_ptr::<u8>();
+        let write_u64 = |symbol: &u8, value: u64| {
+            trampoline_ptr
+                .add(trampoline_offset(symbol))
+                .cast::<u64>()
+                .write_volatile(value)
+        };
+        write_u64(&ap_trampoline_stack, stack_top);
+        write_u64(&ap_trampoline_entry, ap_main as usize as u64);
+        write_u64(&ap_trampoline_argument, cpu as u64);
+    }
+    AP_STARTED.store(false, Ordering::SeqCst);
+    APIC_IDS[cpu].store(apic_id, Ordering::Relaxed);
+    send_startup_sequence(trampoline, apic_id)?;
+    CPU_COUNT.store(cpu + 1, Ordering::Release);
+    Ok(())
+}
+
+fn send_startup_sequence(trampoline: PhysAddr, apic_id: u8) -> Result<()> {
+    let vector = (trampoline.as_u64() / Size4KiB::SIZE) as u32;
+    send_ipi(apic_id, DELIVERY_MODE_INIT | LEVEL_ASSERT)?;
+    timer::busy_wait_micros(10_000);
+    for _ in 0..2 {
+        send_ipi(apic_id, DELIVERY_MODE_STARTUP | vector)?;
+        timer::busy_wait_micros(200);
+        if AP_STARTED.load(Ordering::SeqCst) {
+            break;
+        }
+    }
+    for _ in 0..AP_START_TIMEOUT_MILLIS {
+        if AP_STARTED.load(Ordering::SeqCst) {
+            return Ok(());
+        }
+        timer::busy_wait_micros(1_000);
+    }
+    Err(Error::Whatever("The AP didn't respond"))
+}
+
+fn send_ipi(apic_id: u8, command: u32) -> Result<()> {
+    // An interrupt handler sending an IPI in between would overwrite the destination.
+    x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+        core::ptr::write_volatile(
+            local_apic_register(INTERRUPT_COMMAND_REGISTER_HIGH),
+            (apic_id as u32) << 24,
+        );
+        // Writing the low half sends the IPI.
+        core::ptr::write_volatile(local_apic_register(INTERRUPT_COMMAND_REGISTER_LOW), command);
+    });
+    for _ in 0..100 {
+        let status = unsafe {
+            core::ptr::read_volatile(local_apic_register(INTERRUPT_COMMAND_REGISTER_LOW))
+        };
+        if status & DELIVERY_STATUS_PENDING == 0 {
+            return Ok(());
+        }
+        timer::busy_wait_micros(10);
+    }
+    Err(Error::Whatever("The IPI wasn't delivered"))
+}
+
+/// Where the APs come from the trampoline, with their own stacks.
+extern "sysv64" fn ap_main(cpu: u64) -> ! {
+    let cpu = cpu as usize;
+    gdt::initialize(cpu);
+    percpu::initialize(cpu);
+    interrupts::initialize();
+    syscall::initialize();
+    timer::initialize_ap();
+    task::initialize_ap(cpu);
+    AP_STARTED.store(true, Ordering::SeqCst);
+    log::info!("CPU {} has started", cpu);
+    loop {
+        x86_64::instructions::interrupts::enable_and_hlt();
+    }
+}
@@ -0,0 +1,585 @@
+//! System calls for the tasks running in ring 3.
+//!
+//! # ABI
+//! A system call is made with the `syscall` instruction. The number goes in RAX, and the
+//! arguments in RDI, RSI, RDX, R10 and R8. The result comes back in RAX, and the error code
+//! (0 on success, otherwise a `SyscallError`) in RDX. RCX, RSI, RDI and R8-R11 are clobbered,
+//! and the other registers are preserved. The numbers are listed in `number`, and won't change.
+
+use core::{
+    arch::asm,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use alloc::{
+    boxed::Box,
+    collections::{BTreeMap, VecDeque},
+    string::ToString,
+    sync::Arc,
+};
+use spinning_top::Spinlock;
+use x86_64::{
+    registers::{
+        model_specific::{Efer, EferFlags, LStar, SFMask, Star},
+        rflags::RFlags,
+    },
+    VirtAddr,
+};
+
+use crate::{
+    events,
+    fs::{self, dev, OpenFile, OpenFlags, SeekFrom},
+    gdt,
+    graphics::{buffer::VecBufferCanvas, canvas::Canvas, Color, Rectangle, UCoordinate},
+    gui::{
+        widgets::{Framed, Widget},
+        window_manager::{WindowBuilder, WindowId},
+        windows::{Window, WindowEvent, WindowEventHandler},
+    },
+    paging::{USER_SPACE_END, USER_SPACE_START},
+    prelude::*,
+    task::{self, Receiver, TaskBuilder, TaskId, TypedTaskHandle},
+    timer,
+};
+
+pub mod number {
+    /// `exit(code) -> !`
+    pub const EXIT: u64 = 0;
+    /// `write(buffer, length) -> written`: writes UTF-8 text to the standard output, fd 1, which
+    /// is the terminal the task was started from, or the console.
+    pub const WRITE: u64 = 1;
+    /// `sleep(millis) -> 0`
+    pub const SLEEP: u64 = 2;
+    /// `get_tick() -> tick`: the number of timer ticks since boot.
+    pub const GET_TICK: u64 = 3;
+    /// `open_window(title, title_length, width, height) -> window id`: events of the window are
+    /// delivered as messages.
+    pub const OPEN_WINDOW: u64 = 4;
+    /// `send(task id, value) -> 0`
+    pub const SEND: u64 = 5;
+    /// `receive(message: *mut RawMessage, block) -> 0`: fails with `WouldBlock` if `block` is 0
+    /// and there's no message.
+    pub const RECEIVE: u64 = 6;
+    /// `get_task_id() -> task id`
+    pub const GET_TASK_ID: u64 = 7;
+    /// `open(path, path_length, flags) -> fd`: `flags` is a combination of 1 for reading, 2 for
+    /// writing, 4 to create the file if missing and 8 to empty it.
+    pub const OPEN: u64 = 8;
+    /// `close(fd) -> 0`
+    pub const CLOSE: u64 = 9;
+    /// `read(fd, buffer, length) -> read`: 0 at the end of the file.
+    pub const READ: u64 = 10;
+    /// `write_file(fd, buffer, length) -> written`
+    pub const WRITE_FILE: u64 = 11;
+    /// `seek(fd, offset, whence) -> new offset`: `whence` is 0 for the start of the file, 1 for
+    /// the current offset and 2 for the end of the file, as in `lseek`.
+    pub const SEEK: u64 = 12;
+}
+
+#[repr(u64)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SyscallError {
+    UnknownSyscall = 1,
+    InvalidArgument = 2,
+    BadAddress = 3,
+    WouldBlock = 4,
+    NoSuchTask = 5,
+    /// The caller isn't a task running in ring 3.
+    NotUserTask = 6,
+    Failed = 7,
+    BadFileDescriptor = 8,
+}
+type SyscallResult<T> = core::result::Result<T, SyscallError>;
+
+/// A message received by `receive`.
+#[repr(C)]
+#[derive(Clone, Copy, Default, Debug)]
+pub struct RawMessage {
+    pub kind: u64,
+    pub arg0: u64,
+    pub arg1: u64,
+}
+pub mod message_kind {
+    /// `arg0`: the sender task id, `arg1`: the value
+    pub const MESSAGE: u64 = 1;
+    /// `arg0`: the window id, `arg1`: the character, or 0 if the key doesn't have one
+    pub const KEY_PRESS: u64 = 2;
+    /// `arg0`: the window id
+    pub const FOCUS: u64 = 3;
+    /// `arg0`: the window id
+    pub const BLUR: u64 = 4;
+}
+
+/// Messages sent to the tasks running in ring 3.
+#[derive(Clone, Copy, Debug)]
+pub enum UserMessage {
+    Message { sender: TaskId, value: u64 },
+    Window { id: WindowId, event: WindowEvent },
+    WindowOpened(WindowId),
+    Timer(u64),
+}
+impl UserMessage {
+    /// Whether the message is meant for `receive` rather than as the reply for a system call.
+    fn is_for_user(&self) -> bool {
+        matches!(self, Self::Message { .. } | Self::Window { .. })
+    }
+
+    fn to_raw(self) -> RawMessage {
+        use message_kind::*;
+        match self {
+            Self::Message { sender, value } => RawMessage {
+                kind: MESSAGE,
+                arg0: sender.as_u64(),
+                arg1: value,
+            },
+            Self::Window { id, event } => {
+                let (kind, arg1) = match event {
+                    WindowEvent::KeyPress(key) => {
+                        (KEY_PRESS, key.to_char().map(|c| c as u64).unwrap_or(0))
+                    }
+                    WindowEvent::Focus => (FOCUS, 0),
+                    WindowEvent::Blur => (BLUR, 0),
+                };
+                RawMessage {
+                    kind,
+                    arg0: id.as_usize() as u64,
+                    arg1,
+                }
+            }
+            Self::WindowOpened(_) | Self::Timer(_) => RawMessage::default(),
+        }
+    }
+}
+
+struct UserTask {
+    handle: TypedTaskHandle<UserMessage>,
+    /// Messages that arrived while waiting for the reply for a system call.
+    pending: VecDeque<UserMessage>,
+}
+static USER_TASKS: Spinlock<BTreeMap<TaskId, UserTask>> = Spinlock::new(BTreeMap::new());
+
+fn with_user_tasks<T, F: FnOnce(&mut BTreeMap<TaskId, UserTask>) -> T>(f: F) -> T {
+    x86_64::instructions::interrupts::without_interrupts(|| f(&mut USER_TASKS.lock()))
+}
+
+/// Spawns a task running in ring 3, so that the other tasks can send messages to it.
+/// The standard output and error of the task go to `output`, or to the console if it's `None`.
+pub fn spawn_user_task(
+    task_builder: TaskBuilder<UserMessage, !, !>,
+    output: Option<Box<dyn FnMut(&str) + Send>>,
+) -> TypedTaskHandle<UserMessage> {
+    // The task is started once it's in `USER_TASKS`, since its system calls look it up there.
+    let waking = task_builder.waking();
+    let handle = task::spawn_user_task(task_builder.set_waking(false));
+    if let Some(output) = output {
+        let file = OpenFile::new(dev::output(output), OpenFlags::WRITE);
+        let replaced = handle.with_files(|files| (files.set(1, file.clone()), files.set(2, file)));
+        // The console is released outside of the lock of the table.
+        drop(replaced);
+    }
+    with_user_tasks(|tasks| {
+        tasks.retain(|_, task| !task.handle.has_exited());
+        tasks.insert(
+            handle.id(),
+            UserTask {
+                handle: handle.clone(),
+                pending: VecDeque::new(),
+            },
+        );
+    });
+    if waking {
+        handle.awake();
+    }
+    handle
+}
+
+pub fn initialize() {
+    let selectors = gdt::selectors();
+    Star::write(
+        selectors.user_code_selector,
+        selectors.user_data_selector,
+        selectors.kernel_code_selector,
+        selectors.kernel_data_selector,
+    )
+    .expect("The GDT doesn't have the layout SYSCALL/SYSRET expect");
+    USER_CODE_SELECTOR.store(selectors.user_code_selector.0 as u64, Ordering::Relaxed);
+    USER_DATA_SELECTOR.store(selectors.user_data_selector.0 as u64, Ordering::Relaxed);
+    LStar::write(VirtAddr::new(syscall_entry as usize as u64));
+    // Interrupts stay disabled until we're on the kernel stack.
+    SFMask::write(RFlags::INTERRUPT_FLAG | RFlags::TRAP_FLAG | RFlags::DIRECTION_FLAG);
+    unsafe { Efer::update(|flags| flags.insert(EferFlags::SYSTEM_CALL_EXTENSIONS)) };
+}
+
+/// For the IRETQ in `syscall_entry`.
+static USER_CODE_SELECTOR: AtomicU64 = AtomicU64::new(0);
+static USER_DATA_SELECTOR: AtomicU64 = AtomicU64::new(0);
+
+extern "C" {
+    /// The IRETQ `syscall_entry` returns with when SYSRET can't be used.
+    static syscall_return_iretq: u8;
+}
+
+/// Whether an exception at `instruction_pointer` comes from the IRETQ `syscall_entry` returns to
+/// ring 3 with, which faults with the GS base of ring 3 when the return address is bad.
+pub(crate) fn is_return_to_user(instruction_pointer: VirtAddr) -> bool {
+    instruction_pointer.as_ptr() == unsafe { core::ptr::addr_of!(syscall_return_iretq) }
+}
+
+#[naked]
+extern "sysv64" fn syscall_entry() {
+    unsafe {
+        asm! {
+            // RCX holds the user RIP, and R11 holds the user RFLAGS.
+            // SWAPGS points GS to the per-CPU data, where the user RSP is kept until it's pushed
+            // to the kernel stack.
+            "swapgs",
+            "mov gs:[0x10], rsp",
+            "mov rsp, gs:[0x08]",
+            "push qword ptr gs:[0x10]",
+            "push rcx",
+            "push r11",
+            "push rbp",
+            "mov rbp, rsp",
+
+            // Shuffle the registers into the arguments of `dispatch`.
+            "mov rcx, r10",
+            "mov r9, rax",
+            "call {dispatch}",
+
+            "cli",
+            "pop rbp",
+            "pop r11",
+            "pop rcx",
+            // SYSRET to a non-canonical RIP faults in ring 0 with the user RSP, which would let
+            // ring 3 choose the stack of the kernel. IRETQ faults on the kernel stack instead.
+            "mov rsi, rcx",
+            "shl rsi, 16",
+            "sar rsi, 16",
+            "cmp rsi, rcx",
+            "jne 2f",
+            "pop rsp",
+            "swapgs",
+            "sysretq",
+            "2:",
+            "pop rsi",
+            "push qword ptr [rip + {user_data_selector}]",
+            "push rsi",
+            "push r11",
+            "push qword ptr [rip + {user_code_selector}]",
+            "push rcx",
+            "swapgs",
+            ".global syscall_return_iretq",
+            "syscall_return_iretq:",
+            "iretq",
+            dispatch = sym dispatch,
+            user_code_selector = sym USER_CODE_SELECTOR,
+            user_data_selector = sym USER_DATA_SELECTOR,
+            options(noreturn)
+        }
+    }
+}
+
+/// Returned in RAX and RDX.
+#[repr(C)]
+struct ReturnValue {
+    value: u64,
+    error: u64,
+}
+
+type SyscallHandler = fn([u64; 5]) -> SyscallResult<u64>;
+const SYSCALL_TABLE: [SyscallHandler; 13] = [
+    sys_exit,
+    sys_write,
+    sys_sleep,
+    sys_get_tick,
+    sys_open_window,
+    sys_send,
+    sys_receive,
+    sys_get_task_id,
+    sys_open,
+    sys_close,
+    sys_read,
+    sys_write_file,
+    sys_seek,
+];
+
+extern "sysv64" fn dispatch(
+    arg0: u64,
+    arg1: u64,
+    arg2: u64,
+    arg3: u64,
+    arg4: u64,
+    number: u64,
+) -> ReturnValue {
+    x86_64::instructions::interrupts::enable();
+    let result = match SYSCALL_TABLE.get(number as usize) {
+        Some(handler) => handler([arg0, arg1, arg2, arg3, arg4]),
+        None => Err(SyscallError::UnknownSyscall),
+    };
+    match result {
+        Ok(value) => ReturnValue { value, error: 0 },
+        Err(e) => ReturnValue {
+            value: 0,
+            error: e as u64,
+        },
+    }
+}
+
+fn current_receiver() -> SyscallResult<&'static mut Receiver<UserMessage>> {
+    // SAFETY: We're serving a system call of the current task.
+    unsafe { task::current_user_receiver() }.ok_or(SyscallError::NotUserTask)
+}
+
+/// Waits for a message `f` accepts. Messages for `receive` are kept for later.
+fn wait_for<T>(
+    receiver: &mut Receiver<UserMessage>,
+    mut f: impl FnMut(UserMessage) -> Option<T>,
+) -> T {
+    let id = receiver.handle().id();
+    loop {
+        let message = receiver.dequeue_or_wait();
+        if let Some(value) = f(message) {
+            return value;
+        }
+        if message.is_for_user() {
+            with_user_tasks(|tasks| {
+                if let Some(task) = tasks.get_mut(&id) {
+                    task.pending.push_back(message);
+                }
+            });
+        }
+    }
+}
+
+fn check_user_range(address: u64, length: u64) -> SyscallResult<()> {
+    let end = address
+        .checked_add(length)
+        .ok_or(SyscallError::BadAddress)?;
+    if address < USER_SPACE_START.as_u64() || end > USER_SPACE_END.as_u64() {
+        return Err(SyscallError::BadAddress);
+    }
+    Ok(())
+}
+
+/// Maps the pages behind the range first, so that the kernel doesn't fault on them, possibly
+/// holding locks.
+fn populate_user_range(address: u64, length: u64, writable: bool) -> SyscallResult<()> {
+    check_user_range(address, length)?;
+    task::current_task()
+        .with_address_space(|address_space| {
+            address_space.populate(VirtAddr::new(address), length, writable)
+        })
+        .ok_or(SyscallError::NotUserTask)?
+        .map_err(|_| SyscallError::BadAddress)
+}
+
+fn user_slice(address: u64, length: u64) -> SyscallResult<&'static [u8]> {
+    populate_user_range(address, length, false)?;
+    Ok(unsafe { core::slice::from_raw_parts(address as *const u8, length as usize) })
+}
+
+fn user_slice_mut(address: u64, length: u64) -> SyscallResult<&'static mut [u8]> {
+    populate_user_range(address, length, true)?;
+    Ok(unsafe { core::slice::from_raw_parts_mut(address as *mut u8, length as usize) })
+}
+
+fn user_file(fd: u64) -> SyscallResult<Arc<OpenFile>> {
+    fs::file(fd as usize).map_err(|_| SyscallError::BadFileDescriptor)
+}
+
+fn sys_exit([code, ..]: [u64; 5]) -> SyscallResult<u64> {
+    let current = task::current_task();
+    log::info!("Task {} exited with {}", current.name(), code as i64);
+    with_user_tasks(|tasks| tasks.remove(&current.id()));
+    let e = task::exit_current_task().unwrap_err();
+    log::error!("Failed to exit: {:?}", e);
+    Err(SyscallError::Failed)
+}
+
+fn sys_write([buffer, length, ..]: [u64; 5]) -> SyscallResult<u64> {
+    let data = user_slice(buffer, length)?;
+    core::str::from_utf8(data).map_err(|_| SyscallError::InvalidArgument)?;
+    let written = user_file(1)?
+        .write(data)
+        .map_err(|_| SyscallError::Failed)?;
+    Ok(written as u64)
+}
+
+fn sys_sleep([millis, ..]: [u64; 5]) -> SyscallResult<u64> {
+    static NEXT_TOKEN: AtomicU64 = AtomicU64::new(0);
+    let receiver = current_receiver()?;
+    if millis == 0 {
+        return Ok(0);
+    }
+    let token = NEXT_TOKEN.fetch_add(1, Ordering::SeqCst);
+    timer::register(millis, receiver.handle(), UserMessage::Timer(token));
+    wait_for(receiver, |message| match message {
+        UserMessage::Timer(t) if t == token => Some(()),
+        _ => None,
+    });
+    Ok(0)
+}
+
+fn sys_get_tick(_: [u64; 5]) -> SyscallResult<u64> {
+    Ok(timer::current_tick())
+}
+
+const MAX_WINDOW_SIZE: u64 = 4096;
+
+struct UserWindow {
+    width: UCoordinate,
+    height: UCoordinate,
+}
+impl Widget for UserWindow {
+    fn render(&self, canvas: &mut VecBufferCanvas) {
+        let size = Size::new(self.width, self.height);
+        canvas.resize(size);
+        canvas.fill_rectangle(Color::WHITE, Rectangle::new(Point::zero(), size));
+    }
+}
+
+/// Passes the events of a window opened by a task running in ring 3 to the task.
+struct UserWindowHandler {
+    window: Window<Framed<UserWindow>>,
+    task: TypedTaskHandle<UserMessage>,
+}
+impl UserWindowHandler {
+    fn forward(&mut self, event: WindowEvent) {
+        let id = self.window.window_id();
+        self.window.widget_mut().handle_window_event(event);
+        self.window.buffer();
+        events::fire_redraw_window(id);
+        self.task.send(UserMessage::Window { id, event });
+    }
+}
+impl WindowEventHandler for UserWindowHandler {
+    fn on_focus(&mut self) {
+        self.forward(WindowEvent::Focus);
+    }
+
+    fn on_blur(&mut self) {
+        self.forward(WindowEvent::Blur);
+    }
+
+    fn on_key_press(&mut self, key: crate::keyboard::KeyCode) {
+        self.forward(WindowEvent::KeyPress(key));
+    }
+}
+
+fn sys_open_window([title, title_length, width, height, ..]: [u64; 5]) -> SyscallResult<u64> {
+    let receiver = current_receiver()?;
+    if width == 0 || height == 0 || width > MAX_WINDOW_SIZE || height > MAX_WINDOW_SIZE {
+        return Err(SyscallError::InvalidArgument);
+    }
+    let title = core::str::from_utf8(user_slice(title, title_length)?)
+        .map_err(|_| SyscallError::InvalidArgument)?
+        .to_string();
+    let widget = UserWindow {
+        width: width as UCoordinate,
+        height: height as UCoordinate,
+    };
+    let handle = receiver.handle();
+    events::request_window_manager(Box::new(move |window_manager| {
+        let builder =
+            WindowBuilder::new(Framed::new(title, widget)).set_position(Point::new(100, 100));
+        let id = window_manager.create_with_handler(builder, |window| UserWindowHandler {
+            window,
+            task: handle.clone(),
+        });
+        events::fire_redraw_window(id);
+        handle.send(UserMessage::WindowOpened(id));
+    }));
+    let id = wait_for(receiver, |message| match message {
+        UserMessage::WindowOpened(id) => Some(id),
+        _ => None,
+    });
+    Ok(id.as_usize() as u64)
+}
+
+fn sys_send([destination, value, ..]: [u64; 5]) -> SyscallResult<u64> {
+    let sender = task::current_task().id();
+    let destination = TaskId::from_u64(destination);
+    let handle = with_user_tasks(|tasks| match tasks.get(&destination) {
+        Some(task) if task.handle.has_exited() => {
+            tasks.remove(&destination);
+            None
+        }
+        Some(task) => Some(task.handle.clone()),
+        None => None,
+    })
+    .ok_or(SyscallError::NoSuchTask)?;
+    handle.send(UserMessage::Message { sender, value });
+    Ok(0)
+}
+
+fn sys_receive([message, block, ..]: [u64; 5]) -> SyscallResult<u64> {
+    populate_user_range(message, core::mem::size_of::<RawMessage>() as u64, true)?;
+    let receiver = current_receiver()?;
+    let id = receiver.handle().id();
+    let pending = with_user_tasks(|tasks| tasks.get_mut(&id).and_then(|t| t.pending.pop_front()));
+    let received = match pending {
+        Some(received) => received,
+        None if block != 0 => wait_for(receiver, |m| m.is_for_user().then_some(m)),
+        None => loop {
+            match receiver.try_dequeue() {
+                Some(m) if m.is_for_user() => break m,
+                Some(_) => continue,
+                None => return Err(SyscallError::WouldBlock),
+            }
+        },
+    };
+    unsafe { core::ptr::write_unaligned(message as *mut RawMessage, received.to_raw()) };
+    Ok(0)
+}
+
+fn sys_get_task_id(_: [u64; 5]) -> SyscallResult<u64> {
+    Ok(task::current_task().id().as_u64())
+}
+
+fn sys_open([path, path_length, flags, ..]: [u64; 5]) -> SyscallResult<u64> {
+    let path = core::str::from_utf8(user_slice(path, path_length)?)
+        .map_err(|_| SyscallError::InvalidArgument)?;
+    let flags = u32::try_from(flags)
+        .ok()
+        .and_then(OpenFlags::from_bits)
+        .filter(|flags| !flags.is_empty())
+        .ok_or(SyscallError::InvalidArgument)?;
+    let fd = fs::open(path, flags).map_err(|e| {
+        log::debug!("Failed to open {}: {:?}", path, e);
+        SyscallError::Failed
+    })?;
+    Ok(fd as u64)
+}
+
+fn sys_close([fd, ..]: [u64; 5]) -> SyscallResult<u64> {
+    fs::close(fd as usize).map_err(|_| SyscallError::BadFileDescriptor)?;
+    Ok(0)
+}
+
+fn sys_read([fd, buffer, length, ..]: [u64; 5]) -> SyscallResult<u64> {
+    let file = user_file(fd)?;
+    let read = file
+        .read(user_slice_mut(buffer, length)?)
+        .map_err(|_| SyscallError::Failed)?;
+    Ok(read as u64)
+}
+
+fn sys_write_file([fd, buffer, length, ..]: [u64; 5]) -> SyscallResult<u64> {
+    let file = user_file(fd)?;
+    let written = file
+        .write(user_slice(buffer, length)?)
+        .map_err(|_| SyscallError::Failed)?;
+    Ok(written as u64)
+}
+
+fn sys_seek([fd, offset, whence, ..]: [u64; 5]) -> SyscallResult<u64> {
+    let file = user_file(fd)?;
+    let position = match whence {
+        0 => SeekFrom::Start(offset),
+        1 => SeekFrom::Current(offset as i64),
+        2 => SeekFrom::End(offset as i64),
+        _ => return Err(SyscallError::InvalidArgument),
+    };
+    file.seek(position)
+        .map_err(|_| SyscallError::InvalidArgument)
+}
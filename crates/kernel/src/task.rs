@@ -1,12 +1,22 @@
 use core::{
     arch::asm,
     marker::PhantomData,
-    sync::atomic::{AtomicU32, AtomicU64, AtomicU8, AtomicUsize, Ordering},
+    sync::atomic::{AtomicBool, AtomicU64, AtomicU8, AtomicUsize, Ordering},
 };
 
 use crate::{
+    cpu_spinlock::{
+        const_cpu_spinlock, lock_unless_held_here, CpuSpinlock, CpuSpinlockGuard,
+        MappedCpuSpinlockGuard,
+    },
+    fs::FileDescriptorTable,
+    gdt,
     mpsc::{MPSCConsumer, MPSCProducer},
+    paging::{kernel_stack::KernelStack, AddressSpace, PageFaultError},
+    percpu,
     prelude::*,
+    smp::{self, MAX_CPUS},
+    syscall::UserMessage,
 };
 use alloc::{
     boxed::Box,
@@ -16,24 +26,27 @@ use alloc::{
     vec::Vec,
 };
 use delegate::delegate;
-use spinning_top::{MappedSpinlockGuard, Spinlock, SpinlockGuard};
+use spinning_top::Spinlock;
+use x86_64::{structures::idt::PageFaultErrorCode, VirtAddr};
 
-lazy_static! {
-    static ref TASK_MANAGER: Spinlock<Option<TaskManager>> = Spinlock::new(None);
-}
+/// The run queue of each CPU, indexed by the CPU number. They're only locked with the interrupts
+/// disabled, and nothing that might wake up a task, such as logging, is done while holding one.
+static RUN_QUEUES: [CpuSpinlock<Option<RunQueue>>; MAX_CPUS] = [NO_RUN_QUEUE; MAX_CPUS];
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_RUN_QUEUE: CpuSpinlock<Option<RunQueue>> = const_cpu_spinlock(None);
+/// The number of CPUs with a run queue.
+static RUN_QUEUE_COUNT: AtomicUsize = AtomicUsize::new(0);
+/// The CPU the next task goes to.
+static NEXT_CPU: AtomicUsize = AtomicUsize::new(0);
 
 pub type TaskMain<T> = extern "sysv64" fn(Box<Receiver<T>>);
 pub type TaskMainWithArg<T, U> = extern "sysv64" fn(Box<Receiver<T>>, Box<U>);
 pub type TaskPriority = u8;
 type AtomicTaskPriority = AtomicU8;
-type Generation = u64;
-type AtomicGeneration = AtomicU64;
-type LockedManager<'a> = MappedSpinlockGuard<'a, TaskManager>;
+type LockedRunQueue<'a> = MappedCpuSpinlockGuard<'a, RunQueue>;
 
 const PREEMPTION_FREQUENCY: u32 = 50; // 20 ms
 const TICKS_PER_PREEMPTION: u32 = crate::timer::TARGET_FREQUENCY / PREEMPTION_FREQUENCY;
-static TICKS_UNTIL_NEXT_PREEMPTION: AtomicU32 = AtomicU32::new(0);
-static TASK_CONFIG_GENERATION: AtomicGeneration = AtomicGeneration::new(0);
 
 pub struct Receiver<T> {
     handle: TaskHandle,
@@ -75,19 +88,27 @@ impl<T> Receiver<T> {
 
 pub fn initialize<T>() -> (Receiver<T>, TypedTaskHandle<T>) {
     x86_64::instructions::interrupts::without_interrupts(|| {
-        let mut task_manager = TASK_MANAGER.lock();
-        if task_manager.is_some() {
-            panic!("Initializing task manager more than once!");
-        }
-        let (manager, receiver, handle) = TaskManager::create();
-        *task_manager = Some(manager);
+        let (main_task, receiver, handle) = Task::empty("main", 10, 0);
+        // The idle task runs when nothing else is waking, without being queued.
+        let (idle_task, _, _) = Task::create_with_handle(
+            builder("idle", idle_task_main)
+                .set_priority(0)
+                .set_waking(false),
+            0,
+        );
+        let per_cpu = percpu::current();
+        per_cpu.set_current_task(main_task.handle.clone());
+        per_cpu.set_idle_task(idle_task.handle.clone());
+        let running = main_task.handle.clone();
+        add_run_queue(0, vec![main_task, idle_task], Some(running));
         (receiver, handle)
     })
 }
 
 pub fn tick_and_check_context_switch() -> bool {
     x86_64::instructions::interrupts::without_interrupts(|| {
-        let ret = TICKS_UNTIL_NEXT_PREEMPTION
+        let ret = percpu::current()
+            .ticks_until_next_preemption()
             .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |prev| {
                 Some(prev.saturating_sub(1))
             })
@@ -96,61 +117,201 @@ pub fn tick_and_check_context_switch() -> bool {
     })
 }
 
-fn with_task_manager<T, F: FnOnce(LockedManager) -> T>(f: F) -> Result<T> {
+/// Registers the calling AP, whose current context becomes its idle task.
+pub fn initialize_ap(cpu: usize) {
+    assert!(
+        RUN_QUEUE_COUNT.load(Ordering::SeqCst) > 0,
+        "The task manager should be initialized before the APs start"
+    );
+    let (idle_task, _, _) = Task::empty::<()>("idle", 0, cpu);
+    let per_cpu = percpu::current();
+    per_cpu.set_current_task(idle_task.handle.clone());
+    per_cpu.set_idle_task(idle_task.handle.clone());
+    add_run_queue(cpu, vec![idle_task], None);
+}
+
+/// Gives the CPU `cpu` its run queue with `tasks`, and makes it take new tasks.
+fn add_run_queue(cpu: usize, tasks: Vec<Task>, running: Option<TaskHandle>) {
     x86_64::instructions::interrupts::without_interrupts(|| {
-        if let Some(locked) = TASK_MANAGER.try_lock() {
-            let manager = SpinlockGuard::try_map(locked, Option::as_mut)
-                .map_err(|_| Error::Whatever("Task manager is not initialized yet"))?;
-            Ok(f(manager))
-        } else {
-            Err(Error::Whatever(
-                "Failed to take the lock for task schedular...",
-            ))
+        let mut run_queue = RUN_QUEUES[cpu].lock();
+        if run_queue.is_some() {
+            panic!("Initializing the run queue of CPU {} more than once!", cpu);
         }
+        let mut queue = RunQueue::new(running);
+        for task in tasks {
+            queue.insert(task);
+        }
+        *run_queue = Some(queue);
+    });
+    assert_eq!(
+        RUN_QUEUE_COUNT.fetch_add(1, Ordering::SeqCst),
+        cpu,
+        "The CPUs have to be added in order"
+    );
+}
+
+fn with_run_queue<T, F: FnOnce(LockedRunQueue) -> T>(cpu: usize, f: F) -> Result<T> {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        // Other CPUs hold the lock only for a moment, so it's worth waiting for.
+        let locked = RUN_QUEUES[cpu].lock();
+        let queue = CpuSpinlockGuard::try_map(locked, Option::as_mut)
+            .map_err(|_| Error::Whatever("Task manager is not initialized yet"))?;
+        Ok(f(queue))
+    })
+}
+
+/// Tasks stay on their CPU, so the run queue is that of the current task.
+fn with_current_run_queue<T, F: FnOnce(LockedRunQueue) -> T>(f: F) -> Result<T> {
+    with_run_queue(percpu::current().cpu(), f)
+}
+
+/// The CPU a new task goes to, in turn.
+fn next_cpu() -> usize {
+    let count = RUN_QUEUE_COUNT.load(Ordering::SeqCst).max(1);
+    NEXT_CPU.fetch_add(1, Ordering::Relaxed) % count
+}
+
+/// Gives the task to the run queue of its CPU, which runs it if it's waking.
+fn insert_task(task: Task) {
+    let handle = task.handle.clone();
+    let cpu = handle.cpu();
+    let preempt = with_run_queue(cpu, |mut queue| {
+        queue.insert(task);
+        handle.waking() && queue.push(&handle)
     })
+    .unwrap();
+    if preempt {
+        smp::send_reschedule(cpu);
+    }
 }
 
 pub fn spawn_task<T, A>(task_builder: TaskBuilder<T, A, A>) -> TypedTaskHandle<T> {
-    with_task_manager(|mut manager| manager.spawn(task_builder)).unwrap()
+    let (mut task, handle, receiver) = Task::create_with_handle(task_builder, next_cpu());
+    task._receiver = receiver.map(OwnedReceiver::new);
+    insert_task(task);
+    handle
+}
+
+/// Spawns a task running in ring 3 whose receiver serves its system calls, see
+/// `current_user_receiver`.
+pub fn spawn_user_task<A>(
+    task_builder: TaskBuilder<UserMessage, A, A>,
+) -> TypedTaskHandle<UserMessage> {
+    let (mut task, handle, receiver) = Task::create_with_handle(task_builder, next_cpu());
+    task.user_receiver = receiver;
+    insert_task(task);
+    handle
 }
 
 pub fn try_switch_context() -> Result<()> {
+    // Freeing a stack waits for the other CPUs, so it's done without the lock.
+    for (task, _) in with_current_run_queue(|mut queue| queue.take_freeable())? {
+        // Other tasks may still hold the handle, so the files are closed explicitly.
+        task.handle.inner.files.lock().clear();
+    }
     loop {
-        let need_retry = with_task_manager(|mut manager| match manager.start_context_switch() {
+        let switched = with_current_run_queue(|mut queue| match queue.start_context_switch() {
             Ok(s) => {
-                log::trace!("Switching context: {:?}", s);
-                s.switch(manager);
-                false
-            }
-            Err(ContextSwitchError::NothingToRun) => {
-                // Since we have the idle task, there should be something to run
-                log::error!("Nothing to run");
-                drop(manager);
-                x86_64::instructions::interrupts::enable_and_hlt();
+                s.switch(queue);
                 true
             }
+            Err(ContextSwitchError::NothingToRun) => false,
         })?;
-        if !need_retry {
+        if switched {
             break Ok(());
         }
+        // Since we have the idle task, there should be something to run
+        log::error!("Nothing to run");
+        x86_64::instructions::interrupts::enable_and_hlt();
     }
 }
 
+/// The task running on the calling CPU. This doesn't take the lock of the run queue.
 pub fn current_task() -> TaskHandle {
-    with_task_manager(|m| m.current_handle()).unwrap()
+    percpu::current()
+        .current_task()
+        .expect("The task manager is not initialized yet")
 }
 
-fn bump_global_generation() {
-    TASK_CONFIG_GENERATION.fetch_add(1, Ordering::SeqCst);
+/// Returns the receiver of the current task if it runs in ring 3.
+///
+/// # Safety
+/// The receiver belongs to the current task, so the caller must be that task (e.g. serving its
+/// system call), and must not keep the reference after returning to ring 3.
+pub(crate) unsafe fn current_user_receiver() -> Option<&'static mut Receiver<UserMessage>> {
+    let id = current_task().id();
+    let receiver = with_current_run_queue(|mut queue| {
+        queue
+            .tasks
+            .get_mut(&id)
+            .and_then(|task| task.user_receiver.as_mut())
+            .map(|receiver| &mut **receiver as *mut Receiver<UserMessage>)
+    })
+    .ok()??;
+    Some(&mut *receiver)
+}
+
+/// Terminates the current task. Its stack and address space are freed once another task runs.
+pub fn exit_current_task() -> Result<!> {
+    with_current_run_queue(|mut queue| queue.exit_current())??;
+    try_switch_context()?;
+    unreachable!("An exited task has been resumed")
+}
+
+/// Terminates the current task from an exception raised in ring 3. The faulting CPU can't be
+/// holding its run queue then, and this panics if it does, instead of waiting forever.
+pub fn exit_faulting_task() -> Result<!> {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let locked = lock_unless_held_here(&RUN_QUEUES[percpu::current().cpu()])
+            .expect("The faulting CPU holds its run queue");
+        CpuSpinlockGuard::try_map(locked, Option::as_mut)
+            .map_err(|_| Error::Whatever("Task manager is not initialized yet"))?
+            .exit_current()
+    })?;
+    try_switch_context()?;
+    unreachable!("An exited task has been resumed")
+}
+
+/// Tries to resolve a page fault in the user half of the current task's address space.
+/// This doesn't wait for the lock if the faulting code might be holding it, which is only when
+/// the faulting CPU holds it.
+pub(crate) fn resolve_page_fault(
+    address: VirtAddr,
+    error_code: PageFaultErrorCode,
+) -> core::result::Result<(), PageFaultError> {
+    let handle = percpu::current()
+        .current_task()
+        .ok_or(PageFaultError::NotResolvable)?;
+    let mut address_space =
+        lock_unless_held_here(&handle.inner.address_space).ok_or(PageFaultError::NotResolvable)?;
+    let address_space = address_space
+        .as_mut()
+        .ok_or(PageFaultError::NotResolvable)?;
+    address_space
+        .resolve_fault(address, error_code)
+        .map_err(|e| match e {
+            PageFaultError::StackOverflow(None) => {
+                PageFaultError::StackOverflow(Some(handle.name()))
+            }
+            e => e,
+        })
 }
 
 #[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
-struct TaskId(usize);
+pub struct TaskId(usize);
 impl TaskId {
     fn new() -> Self {
         static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
         Self(NEXT_ID.fetch_add(1, Ordering::SeqCst))
     }
+
+    pub fn from_u64(id: u64) -> Self {
+        Self(id as usize)
+    }
+
+    pub fn as_u64(&self) -> u64 {
+        self.0 as u64
+    }
 }
 
 struct TaskHandleImpl {
@@ -161,31 +322,78 @@ struct TaskHandleImpl {
     /// Bit 0 corresponds to the waking flag, and
     /// the other 63 bits correspond to the generation.
     state: AtomicU64,
-    last_run_global_generation: AtomicGeneration,
+    /// Whether the run queue of the CPU has an entry for the task. Only changed with it locked.
+    queued: AtomicBool,
+    exited: AtomicBool,
+    /// The CPU the task runs on.
+    cpu: usize,
+    /// `None` for the tasks running on the kernel page table, and for the tasks that have exited.
+    address_space: CpuSpinlock<Option<AddressSpace>>,
+    files: Spinlock<FileDescriptorTable>,
 }
 #[derive(Clone)]
 pub struct TaskHandle {
     inner: Arc<TaskHandleImpl>,
 }
 impl TaskHandle {
-    fn initialize(id: TaskId, name: &'static str, priority: TaskPriority, waking: bool) -> Self {
+    fn initialize(
+        id: TaskId,
+        name: &'static str,
+        priority: TaskPriority,
+        waking: bool,
+        cpu: usize,
+        address_space: Option<AddressSpace>,
+    ) -> Self {
         let state = if waking { 1 } else { 0 };
         let inner = TaskHandleImpl {
             id,
             name,
             priority: AtomicTaskPriority::new(priority),
             state: AtomicU64::new(state),
-            last_run_global_generation: AtomicGeneration::new(0),
+            queued: AtomicBool::new(false),
+            exited: AtomicBool::new(false),
+            cpu,
+            address_space: CpuSpinlock::new(address_space),
+            files: Spinlock::new(FileDescriptorTable::with_console()),
         };
         Self {
             inner: Arc::new(inner),
         }
     }
 
-    fn id(&self) -> TaskId {
+    pub fn id(&self) -> TaskId {
         self.inner.id
     }
 
+    pub fn name(&self) -> &'static str {
+        self.inner.name
+    }
+
+    /// Runs `f` with the address space of the task, or returns `None` if the task runs on the
+    /// kernel page table or has exited.
+    pub fn with_address_space<T, F: FnOnce(&mut AddressSpace) -> T>(&self, f: F) -> Option<T> {
+        x86_64::instructions::interrupts::without_interrupts(|| {
+            self.inner.address_space.lock().as_mut().map(f)
+        })
+    }
+
+    /// Runs `f` with the files opened by the task.
+    pub fn with_files<T, F: FnOnce(&mut FileDescriptorTable) -> T>(&self, f: F) -> T {
+        x86_64::instructions::interrupts::without_interrupts(|| f(&mut self.inner.files.lock()))
+    }
+
+    pub fn has_exited(&self) -> bool {
+        self.inner.exited.load(Ordering::SeqCst)
+    }
+
+    pub fn cpu(&self) -> usize {
+        self.inner.cpu
+    }
+
+    fn cr3(&self) -> Option<u64> {
+        self.with_address_space(|a| a.pml4_frame().start_address().as_u64())
+    }
+
     pub fn priority(&self) -> TaskPriority {
         self.inner.priority.load(Ordering::SeqCst)
     }
@@ -195,8 +403,12 @@ impl TaskHandle {
     }
 
     pub fn set_priority(&self, priority: TaskPriority) {
-        self.inner.priority.store(priority, Ordering::SeqCst);
-        bump_global_generation();
+        let cpu = self.cpu();
+        match with_run_queue(cpu, |mut queue| queue.set_priority(self, priority)) {
+            Ok(true) => smp::send_reschedule(cpu),
+            Ok(false) => {}
+            Err(_) => self.inner.priority.store(priority, Ordering::SeqCst),
+        }
     }
 
     pub fn set_waking(&self, waking: bool) {
@@ -207,20 +419,30 @@ impl TaskHandle {
         }
     }
 
+    /// Queues the task on its CPU if it wasn't waking, and makes the CPU switch to it if it runs
+    /// a task of a lower priority.
     pub fn awake(&self) {
-        self.inner
+        let previous = self
+            .inner
             .state
             .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |s| Some((s + 2) | 1))
-            .ok();
-        bump_global_generation();
+            .unwrap_or_else(|s| s);
+        // A task that was waking already is queued or running.
+        if previous & 1 == 1 || self.has_exited() {
+            return;
+        }
+        let cpu = self.cpu();
+        if matches!(with_run_queue(cpu, |mut queue| queue.push(self)), Ok(true)) {
+            smp::send_reschedule(cpu);
+        }
     }
 
+    /// The task keeps its entry in the run queue, which is dropped when it comes up.
     pub fn put_sleep(&self) {
         self.inner
             .state
             .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |s| Some((s + 2) & !1))
             .ok();
-        bump_global_generation();
     }
 
     pub fn load_state(&self) -> u64 {
@@ -235,7 +457,6 @@ impl TaskHandle {
             .is_ok();
         if success {
             x86_64::instructions::interrupts::enable();
-            bump_global_generation();
         }
         success
     }
@@ -256,6 +477,10 @@ impl<T> Clone for TypedTaskHandle<T> {
 impl<T> TypedTaskHandle<T> {
     delegate! {
         to self.inner {
+            pub fn id(&self) -> TaskId;
+            pub fn name(&self) -> &'static str;
+            pub fn has_exited(&self) -> bool;
+            pub fn cpu(&self) -> usize;
             pub fn priority(&self) -> TaskPriority;
             pub fn waking(&self) -> bool;
             pub fn set_priority(&self, priority: TaskPriority);
@@ -267,6 +492,11 @@ impl<T> TypedTaskHandle<T> {
         }
     }
 
+    /// Runs `f` with the files opened by the task.
+    pub fn with_files<U, F: FnOnce(&mut FileDescriptorTable) -> U>(&self, f: F) -> U {
+        self.inner.with_files(f)
+    }
+
     pub fn send(&self, value: T) {
         log::trace!("Sending value to {}", self.inner.inner.name);
         self.producer.enqueue(value);
@@ -282,7 +512,8 @@ struct TaskContext {
     cr3: u64,
     rip: u64,
     rflags: u64,
-    reserved1: u64,
+    /// The stack the CPU switches to on interrupts in ring 3. 0 if the task never leaves ring 0.
+    kernel_stack: u64,
     // Offset 0x20
     cs: u64,
     ss: u64,
@@ -317,26 +548,6 @@ impl Default for TaskContext {
     }
 }
 
-#[repr(C, align(16))]
-#[derive(Clone, Copy, Default)]
-struct Align16 {
-    _data: [u8; 16],
-}
-#[repr(C, align(16))]
-struct TaskStack {
-    stack: Vec<Align16>,
-}
-impl TaskStack {
-    fn new(size: usize) -> Self {
-        Self {
-            stack: vec![Align16::default(); size.div_ceil(16)],
-        }
-    }
-    fn bottom_address(&self) -> u64 {
-        self.stack.as_ptr_range().end as u64
-    }
-}
-
 type Empty = !;
 pub struct TaskBuilder<Message, Arg, GivenArg> {
     name: &'static str,
@@ -345,8 +556,17 @@ pub struct TaskBuilder<Message, Arg, GivenArg> {
     stack_size: usize,
     priority: TaskPriority,
     waking: bool,
+    address_space: Option<AddressSpace>,
+    user_mode: Option<UserModeEntry>,
     _phantom: PhantomData<(Message, Arg, GivenArg)>,
 }
+
+/// Where a task running in ring 3 starts.
+#[derive(Clone, Copy, Debug)]
+struct UserModeEntry {
+    entry: VirtAddr,
+    stack_pointer: VirtAddr,
+}
 pub fn builder<T>(name: &'static str, main: TaskMain<T>) -> TaskBuilder<T, Empty, Empty> {
     TaskBuilder {
         name,
@@ -355,6 +575,8 @@ pub fn builder<T>(name: &'static str, main: TaskMain<T>) -> TaskBuilder<T, Empty
         stack_size: 128 * 1024, // 128 KiB,
         priority: 5,
         waking: true,
+        address_space: None,
+        user_mode: None,
         _phantom: Default::default(),
     }
 }
@@ -369,9 +591,21 @@ pub fn builder_with_arg<T, A>(
         stack_size: 128 * 1024, // 128 KiB,
         priority: 5,
         waking: true,
+        address_space: None,
+        user_mode: None,
         _phantom: Default::default(),
     }
 }
+/// A builder for a task running in ring 3, starting at `entry` with `stack_pointer`.
+/// Both have to be mapped as user accessible in the address space given to the builder.
+/// The receiver of the task is kept by the kernel to serve its system calls.
+pub fn user_builder(
+    name: &'static str,
+    entry: VirtAddr,
+    stack_pointer: VirtAddr,
+) -> TaskBuilder<UserMessage, Empty, Empty> {
+    builder(name, _whatever).set_user_mode(entry, stack_pointer)
+}
 impl<T, A> TaskBuilder<T, A, Empty> {
     #[must_use]
     pub fn set_arg(self, arg: Box<A>) -> TaskBuilder<T, A, A> {
@@ -382,6 +616,8 @@ impl<T, A> TaskBuilder<T, A, Empty> {
             stack_size: self.stack_size,
             priority: self.priority,
             waking: self.waking,
+            address_space: self.address_space,
+            user_mode: self.user_mode,
             _phantom: Default::default(),
         }
     }
@@ -407,17 +643,75 @@ impl<T, A, B> TaskBuilder<T, A, B> {
         self.waking = waking;
         self
     }
+
+    /// Runs the task on the given address space instead of a fresh one.
+    #[must_use]
+    pub fn set_address_space(mut self, address_space: AddressSpace) -> Self {
+        self.address_space = Some(address_space);
+        self
+    }
+
+    /// Runs the task in ring 3, starting at `entry` with `stack_pointer`, instead of calling
+    /// the main function. Both have to be mapped as user accessible in the task's address space.
+    /// The main function and the argument are ignored, and the receiver is kept by the kernel.
+    #[must_use]
+    pub fn set_user_mode(mut self, entry: VirtAddr, stack_pointer: VirtAddr) -> Self {
+        self.user_mode = Some(UserModeEntry {
+            entry,
+            stack_pointer,
+        });
+        self
+    }
 }
 
 pub struct Task {
     context: Box<TaskContext>,
     handle: TaskHandle,
-    _stack: TaskStack,
+    /// `None` for the main task and the idle tasks of the APs, which keep running on the stacks
+    /// their CPUs started with.
+    _stack: Option<KernelStack>,
+    /// The receiver of a task running in ring 3 spawned with `spawn_user_task`.
+    user_receiver: Option<Box<Receiver<UserMessage>>>,
+    /// The receiver of any other task running in ring 3.
+    _receiver: Option<OwnedReceiver>,
+}
+
+/// Owns the `Box<Receiver<T>>` of a task whose main function doesn't take it, i.e. a task
+/// running in ring 3.
+struct OwnedReceiver {
+    ptr: *mut (),
+    drop: unsafe fn(*mut ()),
+}
+
+// SAFETY: The receiver is only accessed by the task it belongs to.
+unsafe impl Send for OwnedReceiver {}
+
+impl OwnedReceiver {
+    fn new<T>(receiver: Box<Receiver<T>>) -> Self {
+        unsafe fn drop_receiver<T>(ptr: *mut ()) {
+            drop(Box::from_raw(ptr as *mut Receiver<T>));
+        }
+        Self {
+            ptr: Box::into_raw(receiver) as *mut (),
+            drop: drop_receiver::<T>,
+        }
+    }
+}
+
+impl Drop for OwnedReceiver {
+    fn drop(&mut self) {
+        unsafe { (self.drop)(self.ptr) }
+    }
 }
 
 impl Task {
-    fn empty<T>() -> (Self, Receiver<T>, TypedTaskHandle<T>) {
-        let handle = TaskHandle::initialize(TaskId::new(), "main", 10, true);
+    /// A task for the context the CPU `cpu` is running, which is saved on the first switch.
+    fn empty<T>(
+        name: &'static str,
+        priority: TaskPriority,
+        cpu: usize,
+    ) -> (Self, Receiver<T>, TypedTaskHandle<T>) {
+        let handle = TaskHandle::initialize(TaskId::new(), name, priority, true, cpu, None);
         let receiver = Receiver::new(handle.clone());
         let producer = receiver.producer();
         let context = Box::new(TaskContext::default());
@@ -425,7 +719,9 @@ impl Task {
             Self {
                 context,
                 handle: handle.clone(),
-                _stack: TaskStack::new(0),
+                _stack: None,
+                user_receiver: None,
+                _receiver: None,
             },
             receiver,
             TypedTaskHandle {
@@ -434,45 +730,72 @@ impl Task {
             },
         )
     }
-    fn create_with_handle<T, A>(task_builder: TaskBuilder<T, A, A>) -> (Self, TypedTaskHandle<T>) {
+    /// Also returns the receiver of a task running in ring 3, which the caller has to keep.
+    fn create_with_handle<T, A>(
+        task_builder: TaskBuilder<T, A, A>,
+        cpu: usize,
+    ) -> (Self, TypedTaskHandle<T>, Option<Box<Receiver<T>>>) {
         use x86_64::instructions::segmentation::{Segment, CS, FS, GS, SS};
 
+        let address_space = match task_builder.address_space {
+            Some(address_space) => address_space,
+            None => AddressSpace::new().expect("Failed to create an address space for a task"),
+        };
         let handle = TaskHandle::initialize(
             TaskId::new(),
             task_builder.name,
             task_builder.priority,
             task_builder.waking,
+            cpu,
+            Some(address_space),
         );
         let receiver = Box::new(Receiver::new(handle.clone()));
         let producer = receiver.producer();
 
         let mut context = Box::new(TaskContext::default());
-        let stack = TaskStack::new(task_builder.stack_size);
-        context.rip = task_builder.task_main;
-        context.rdi = Box::into_raw(receiver) as u64;
-        context.rsi = task_builder.arg;
-        unsafe {
-            asm!("mov {}, cr3", out(reg) context.cr3, options(nomem, nostack, preserves_flags))
-        };
+        let stack = KernelStack::new(task_builder.stack_size, task_builder.name)
+            .expect("Failed to allocate a stack for a task");
+        context.cr3 = handle
+            .cr3()
+            .expect("Spawned tasks should have an address space");
         context.rflags = 0x202; // interrupt flag
-        context.cs = CS::get_reg().0 as u64; // DescriptorFlags::KERNEL_CODE64.bits();
-        context.ss = SS::get_reg().0 as u64; //DescriptorFlags::KERNEL_DATA.bits();
-        context.fs = FS::get_reg().0 as u64; // DescriptorFlags::KERNEL_CODE64.bits();
-        context.gs = GS::get_reg().0 as u64; //DescriptorFlags::KERNEL_DATA.bits();
-        context.rsp = stack.bottom_address() - 8;
-        assert!(context.rsp & 0xf == 8);
+        let user_receiver = if let Some(user_mode) = task_builder.user_mode {
+            let selectors = gdt::selectors();
+            context.rip = user_mode.entry.as_u64();
+            context.rsp = user_mode.stack_pointer.as_u64();
+            context.cs = selectors.user_code_selector.0 as u64;
+            context.ss = selectors.user_data_selector.0 as u64;
+            context.fs = selectors.user_data_selector.0 as u64;
+            context.gs = selectors.user_data_selector.0 as u64;
+            context.kernel_stack = stack.bottom_address();
+            Some(receiver)
+        } else {
+            context.rip = task_builder.task_main;
+            context.rdi = Box::into_raw(receiver) as u64;
+            context.rsi = task_builder.arg;
+            context.cs = CS::get_reg().0 as u64; // DescriptorFlags::KERNEL_CODE64.bits();
+            context.ss = SS::get_reg().0 as u64; //DescriptorFlags::KERNEL_DATA.bits();
+            context.fs = FS::get_reg().0 as u64; // DescriptorFlags::KERNEL_CODE64.bits();
+            context.gs = GS::get_reg().0 as u64; //DescriptorFlags::KERNEL_DATA.bits();
+            context.rsp = stack.bottom_address() - 8;
+            assert!(context.rsp & 0xf == 8);
+            None
+        };
         // Clear MXCSR interrupthions
         context.fxsave_area[24..28].copy_from_slice(&0x1f80u32.to_le_bytes());
         (
             Self {
                 context,
                 handle: handle.clone(),
-                _stack: stack,
+                _stack: Some(stack),
+                user_receiver: None,
+                _receiver: None,
             },
             TypedTaskHandle {
                 inner: handle,
                 producer,
             },
+            user_receiver,
         )
     }
 
@@ -492,6 +815,7 @@ struct ContextSwitchPartial {
     current: TaskContextPtr,
     next_name: &'static str,
     next: TaskContextPtr,
+    next_kernel_stack: u64,
 }
 #[derive(Debug)]
 enum ContextSwitchError {
@@ -499,111 +823,171 @@ enum ContextSwitchError {
     // NotNeeded,
 }
 impl ContextSwitchPartial {
-    fn switch(self, guard: LockedManager) {
+    fn switch(self, guard: LockedRunQueue) {
         drop(guard);
-        TICKS_UNTIL_NEXT_PREEMPTION.store(TICKS_PER_PREEMPTION, Ordering::SeqCst);
+        log::trace!("Switching context: {:?}", self);
+        if self.next_kernel_stack != 0 {
+            gdt::set_kernel_stack(VirtAddr::new(self.next_kernel_stack));
+        }
+        percpu::current()
+            .ticks_until_next_preemption()
+            .store(TICKS_PER_PREEMPTION, Ordering::SeqCst);
         switch_context(self.next, self.current);
     }
 }
 
-type TaskQueueEntry = (TaskHandle, TaskContextPtr);
-struct TaskManager {
+/// The tasks of a CPU. Every task stays on the CPU it's given when it's spawned.
+struct RunQueue {
     tasks: BTreeMap<TaskId, Task>,
-    task_queue: VecDeque<TaskQueueEntry>,
-    current_generation: Generation,
-    current_task: TaskQueueEntry,
-}
-impl TaskManager {
-    fn create<T>() -> (Self, Receiver<T>, TypedTaskHandle<T>) {
-        let (main_task, receiver, typed_handle) = Task::empty();
-        let mut tasks = BTreeMap::new();
-        let handle = main_task.handle.clone();
-        let ptr = main_task.context_ptr();
-        tasks.insert(handle.id(), main_task);
-        let old_generation = TASK_CONFIG_GENERATION.fetch_add(1, Ordering::SeqCst);
-        let mut ret = Self {
-            tasks,
-            task_queue: VecDeque::new(),
-            current_generation: old_generation,
-            current_task: (handle, ptr),
-        };
-        ret.spawn(builder("idle", idle_task_main).set_priority(0));
-        (ret, receiver, typed_handle)
+    /// The tasks to run by priority, each in the order they run, except for the running task and
+    /// the idle task. The entries of the tasks that have gone to sleep are dropped when they come
+    /// up.
+    ready: BTreeMap<TaskPriority, VecDeque<TaskHandle>>,
+    /// `None` while the CPU runs its idle task.
+    running: Option<TaskHandle>,
+    /// Tasks that have exited, with their address spaces. They're freed once they stop being the
+    /// current task of the CPU, since until then it's running on their stacks.
+    exited: Vec<(Task, Option<AddressSpace>)>,
+}
+impl RunQueue {
+    fn new(running: Option<TaskHandle>) -> Self {
+        Self {
+            tasks: BTreeMap::new(),
+            ready: BTreeMap::new(),
+            running,
+            exited: Vec::new(),
+        }
     }
 
-    fn spawn<T, A>(&mut self, task_builder: TaskBuilder<T, A, A>) -> TypedTaskHandle<T> {
-        let (task, handle) = Task::create_with_handle(task_builder);
+    fn insert(&mut self, task: Task) {
         assert!(
             self.tasks.insert(task.id(), task).is_none(),
             "Conflict task id???? What????"
         );
-        handle
     }
 
-    fn refresh_task_queue_if_necessary(&mut self) {
-        let global_generation = TASK_CONFIG_GENERATION.load(Ordering::SeqCst);
-        if global_generation <= self.current_generation {
-            return;
+    /// Queues the task unless it's queued already. Returns whether it should preempt the running
+    /// task.
+    fn push(&mut self, handle: &TaskHandle) -> bool {
+        if handle.inner.queued.swap(true, Ordering::Relaxed) {
+            return false;
         }
-        self.task_queue.clear();
-        let mut current_priority = 0;
-        for task in self.tasks.values() {
-            if !task.handle.waking() {
-                continue;
+        self.ready
+            .entry(handle.priority())
+            .or_default()
+            .push_back(handle.clone());
+        self.running
+            .as_ref()
+            .map_or(true, |running| handle.priority() > running.priority())
+    }
+
+    /// Takes out the waking task of the highest priority that has waited the longest.
+    fn pop(&mut self) -> Option<TaskHandle> {
+        while let Some(mut entries) = self.ready.last_entry() {
+            let handle = entries.get_mut().pop_front();
+            if entries.get().is_empty() {
+                entries.remove();
             }
-            log::trace!(
-                "Task {}: {}",
-                task.handle.inner.name,
-                task.handle.inner.state.load(Ordering::SeqCst)
-            );
-            match task.handle.priority().cmp(&current_priority) {
-                core::cmp::Ordering::Less => continue,
-                core::cmp::Ordering::Equal => self
-                    .task_queue
-                    .push_back((task.handle.clone(), task.context_ptr())),
-                core::cmp::Ordering::Greater => {
-                    current_priority = task.handle.priority();
-                    self.task_queue.clear();
-                    self.task_queue
-                        .push_back((task.handle.clone(), task.context_ptr()));
+            if let Some(handle) = handle {
+                handle.inner.queued.store(false, Ordering::Relaxed);
+                if handle.waking() && self.tasks.contains_key(&handle.id()) {
+                    return Some(handle);
                 }
             }
         }
-        self.task_queue
-            .make_contiguous()
-            .sort_by_key(|t| t.0.inner.last_run_global_generation.load(Ordering::SeqCst));
-        self.current_generation = global_generation;
+        None
+    }
+
+    /// Moves the entry of a queued task to its new priority. Returns whether it should preempt
+    /// the running task.
+    fn set_priority(&mut self, handle: &TaskHandle, priority: TaskPriority) -> bool {
+        if !handle.inner.queued.load(Ordering::Relaxed) {
+            handle.inner.priority.store(priority, Ordering::SeqCst);
+            return false;
+        }
+        if let Some(entries) = self.ready.get_mut(&handle.priority()) {
+            entries.retain(|entry| entry.id() != handle.id());
+            if entries.is_empty() {
+                self.ready.remove(&handle.priority());
+            }
+        }
+        handle.inner.priority.store(priority, Ordering::SeqCst);
+        handle.inner.queued.store(false, Ordering::Relaxed);
+        self.push(handle)
+    }
+
+    /// The context of a task that hasn't been freed.
+    fn context_ptr(&self, id: TaskId) -> Option<TaskContextPtr> {
+        self.tasks
+            .get(&id)
+            .or_else(|| {
+                self.exited
+                    .iter()
+                    .map(|(task, _)| task)
+                    .find(|task| task.id() == id)
+            })
+            .map(Task::context_ptr)
+    }
+
+    /// Takes out the exited tasks that the CPU no longer runs on.
+    fn take_freeable(&mut self) -> Vec<(Task, Option<AddressSpace>)> {
+        let current_id = percpu::current().current_task().map(|task| task.id());
+        let (freeable, exited) = core::mem::take(&mut self.exited)
+            .into_iter()
+            .partition(|(task, _)| Some(task.id()) != current_id);
+        self.exited = exited;
+        freeable
     }
 
     fn start_context_switch(
         &mut self,
     ) -> core::result::Result<ContextSwitchPartial, ContextSwitchError> {
-        if !self.task_queue.is_empty() {
-            self.task_queue.rotate_left(1);
-        }
-        self.refresh_task_queue_if_necessary();
-        if let Some((handle, ptr)) = self.task_queue.front().cloned() {
-            let current_name = self.current_task.0.inner.name;
-            let current = self.current_task.1;
-            let next_name = handle.inner.name;
-            handle
-                .inner
-                .last_run_global_generation
-                .store(self.current_generation, Ordering::SeqCst);
-            self.current_task = (handle, ptr);
-            Ok(ContextSwitchPartial {
-                current,
-                current_name,
-                next: ptr,
-                next_name,
-            })
-        } else {
-            Err(ContextSwitchError::NothingToRun)
+        let per_cpu = percpu::current();
+        let current_task = per_cpu
+            .current_task()
+            .ok_or(ContextSwitchError::NothingToRun)?;
+        let idle_task = per_cpu
+            .idle_task()
+            .ok_or(ContextSwitchError::NothingToRun)?;
+        let current = self
+            .context_ptr(current_task.id())
+            .ok_or(ContextSwitchError::NothingToRun)?;
+        // The current task goes behind the others of its priority.
+        if current_task.id() != idle_task.id() && current_task.waking() {
+            self.push(&current_task);
         }
+        let handle = self.pop().unwrap_or_else(|| idle_task.clone());
+        let ptr = self
+            .tasks
+            .get(&handle.id())
+            .ok_or(ContextSwitchError::NothingToRun)?
+            .context_ptr();
+        let current_name = current_task.inner.name;
+        let next_name = handle.inner.name;
+        // SAFETY: The context is owned by a task in `tasks`.
+        let next_kernel_stack = unsafe { (*(ptr as *const TaskContext)).kernel_stack };
+        self.running = (handle.id() != idle_task.id()).then(|| handle.clone());
+        per_cpu.set_current_task(handle);
+        Ok(ContextSwitchPartial {
+            current,
+            current_name,
+            next: ptr,
+            next_name,
+            next_kernel_stack,
+        })
     }
 
-    fn current_handle(&self) -> TaskHandle {
-        self.current_task.0.clone()
+    fn exit_current(&mut self) -> Result<()> {
+        let handle = current_task();
+        let task = self
+            .tasks
+            .remove(&handle.id())
+            .ok_or(Error::Whatever("The current task has already exited"))?;
+        handle.inner.exited.store(true, Ordering::SeqCst);
+        let address_space = handle.inner.address_space.lock().take();
+        handle.put_sleep();
+        self.exited.push((task, address_space));
+        Ok(())
     }
 }
 
@@ -670,8 +1054,7 @@ extern "sysv64" fn switch_context(next: TaskContextPtr, current: TaskContextPtr)
             "mov cr3, rax",
             "mov rax, [rdi + 0x30]",
             "mov fs, ax",
-            "mov rax, [rdi + 0x38]",
-            "mov gs, ax",
+            // GS isn't loaded, as that would clear its base, which points to the per-CPU data.
 
             "mov rax, [rdi + 0x40]",
             "mov rbx, [rdi + 0x48]",
@@ -690,6 +1073,11 @@ extern "sysv64" fn switch_context(next: TaskContextPtr, current: TaskContextPtr)
 
             "mov rdi, [rdi + 0x60]",
 
+            // This enters ring 3 if the saved CS is a user segment, with the GS base of ring 3.
+            "test byte ptr [rsp + 0x08], 3",
+            "jz 2f",
+            "swapgs",
+            "2:",
             "iretq",
             options(noreturn)
         }
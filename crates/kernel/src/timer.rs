@@ -1,9 +1,17 @@
-use core::cmp::Reverse;
+use core::{
+    cmp::Reverse,
+    sync::atomic::{AtomicU32, Ordering},
+};
 
 use alloc::{boxed::Box, collections::binary_heap::BinaryHeap, rc::Rc};
 use spinning_top::Spinlock;
 
-use crate::{interrupts::InterruptIndex, prelude::*, task::TypedTaskHandle};
+use crate::{
+    acpi_tables,
+    interrupts::{local_apic_register, InterruptIndex},
+    prelude::*,
+    task::TypedTaskHandle,
+};
 
 /// The target value of LAPIC timer frequency
 pub const TARGET_FREQUENCY: u32 = 100; // once per 10 ms
@@ -15,42 +23,21 @@ const MAX_TIMER_COUNT: u32 = u32::MAX;
 const DEFAULT_TIMER_COUNT: u32 = 10000000;
 const PM_TIMER_FREQUENCY: u32 = 3579545;
 
-const LVT_TIMER_ADDRESS: *mut u32 = 0xFEE00320 as *mut u32;
-const DIVIDE_CONFIGURATION_ADDRESS: *mut u32 = 0xFEE003E0 as *mut u32;
-const INITIAL_COUNT_ADDRESS: *mut u32 = 0xFEE00380 as *mut u32;
-const CURRENT_COUNT_ADDRESS: *const u32 = 0xFEE00390 as *const u32;
+const LVT_TIMER_REGISTER: u64 = 0x320;
+const DIVIDE_CONFIGURATION_REGISTER: u64 = 0x3E0;
+const INITIAL_COUNT_REGISTER: u64 = 0x380;
+const CURRENT_COUNT_REGISTER: u64 = 0x390;
 
 const DIVIDE_1_1: u32 = 0b1011;
 const ONESHOT: u32 = 0b01 << 16;
 const PERIODIC_INTERRUPT: u32 = 0b10 << 16;
 const VECTOR: u32 = InterruptIndex::LAPICTimer as u32;
 
-fn get_lapic_frequency(acpi2_rsdp: Option<*const core::ffi::c_void>) -> Result<u64> {
-    let rsdp = acpi2_rsdp.ok_or(Error::Whatever("No ACPI RSDP"))?;
-    #[derive(Copy, Clone)]
-    struct Handler;
-    impl acpi::AcpiHandler for Handler {
-        unsafe fn map_physical_region<T>(
-            &self,
-            physical_address: usize,
-            size: usize,
-        ) -> acpi::PhysicalMapping<Self, T> {
-            //TODO: Need a fix when we use non-identity mapping. Also better to do this phys->virt
-            // address conversion in somewhere central place so that it's easier to change paging
-            // from the identity mapping to something else.
-            let virtual_start = core::ptr::NonNull::new(physical_address as *mut T).unwrap();
-            acpi::PhysicalMapping::new(
-                physical_address,
-                virtual_start,
-                physical_address,
-                size,
-                Handler,
-            )
-        }
+/// The initial count of the LAPIC timer for a tick, found by the BSP.
+static TIMER_COUNT: AtomicU32 = AtomicU32::new(DEFAULT_TIMER_COUNT);
 
-        fn unmap_physical_region<T>(region: &acpi::PhysicalMapping<Self, T>) {}
-    }
-    let table = unsafe { acpi::AcpiTables::from_rsdp(Handler, rsdp as usize)? };
+fn get_lapic_frequency() -> Result<u64> {
+    let table = acpi_tables::tables()?;
     let timer = table
         .platform_info()?
         .pm_timer
@@ -78,12 +65,15 @@ fn get_lapic_frequency(acpi2_rsdp: Option<*const core::ffi::c_void>) -> Result<u
 
     let count_lapic = |f: &mut dyn FnMut()| {
         unsafe {
-            core::ptr::write_volatile(DIVIDE_CONFIGURATION_ADDRESS, DIVIDE_1_1);
-            core::ptr::write_volatile(LVT_TIMER_ADDRESS, ONESHOT);
-            core::ptr::write_volatile(INITIAL_COUNT_ADDRESS, MAX_TIMER_COUNT);
+            core::ptr::write_volatile(
+                local_apic_register(DIVIDE_CONFIGURATION_REGISTER),
+                DIVIDE_1_1,
+            );
+            core::ptr::write_volatile(local_apic_register(LVT_TIMER_REGISTER), ONESHOT);
+            core::ptr::write_volatile(local_apic_register(INITIAL_COUNT_REGISTER), MAX_TIMER_COUNT);
         }
         f();
-        let end = unsafe { core::ptr::read_volatile(CURRENT_COUNT_ADDRESS) };
+        let end = unsafe { core::ptr::read_volatile(local_apic_register(CURRENT_COUNT_REGISTER)) };
         MAX_TIMER_COUNT - end
     };
     const WAIT_PM_TIMER_COUNT: u32 =
@@ -102,8 +92,8 @@ fn get_lapic_frequency(acpi2_rsdp: Option<*const core::ffi::c_void>) -> Result<u
     Ok(freq)
 }
 
-pub fn initialize(acpi2_rsdp: Option<*const core::ffi::c_void>) {
-    let timer_count = match get_lapic_frequency(acpi2_rsdp) {
+pub fn initialize() {
+    let timer_count = match get_lapic_frequency() {
         Ok(freq) => (freq / TARGET_FREQUENCY as u64) as u32,
         Err(e) => {
             log::warn!(
@@ -114,10 +104,49 @@ pub fn initialize(acpi2_rsdp: Option<*const core::ffi::c_void>) {
         }
     };
     log::info!("Set lapic count as {}", timer_count);
+    TIMER_COUNT.store(timer_count, Ordering::Relaxed);
+    start_lapic_timer();
+}
+
+/// Starts the LAPIC timer of an AP with the count the BSP has found.
+pub fn initialize_ap() {
+    start_lapic_timer();
+}
+
+fn start_lapic_timer() {
+    let timer_count = TIMER_COUNT.load(Ordering::Relaxed);
     unsafe {
-        core::ptr::write_volatile(DIVIDE_CONFIGURATION_ADDRESS, DIVIDE_1_1);
-        core::ptr::write_volatile(LVT_TIMER_ADDRESS, PERIODIC_INTERRUPT | VECTOR);
-        core::ptr::write_volatile(INITIAL_COUNT_ADDRESS, timer_count);
+        core::ptr::write_volatile(
+            local_apic_register(DIVIDE_CONFIGURATION_REGISTER),
+            DIVIDE_1_1,
+        );
+        core::ptr::write_volatile(
+            local_apic_register(LVT_TIMER_REGISTER),
+            PERIODIC_INTERRUPT | VECTOR,
+        );
+        core::ptr::write_volatile(local_apic_register(INITIAL_COUNT_REGISTER), timer_count);
+    }
+}
+
+/// Spins for at least `micros` microseconds by watching the LAPIC timer count down, so that it
+/// works with the interrupts disabled. The timer has to be running.
+pub fn busy_wait_micros(micros: u64) {
+    let timer_count = TIMER_COUNT.load(Ordering::Relaxed) as u64;
+    let goal = timer_count * TARGET_FREQUENCY as u64 * micros / 1_000_000;
+    let read =
+        || unsafe { core::ptr::read_volatile(local_apic_register(CURRENT_COUNT_REGISTER)) as u64 };
+    let mut elapsed = 0;
+    let mut previous = read();
+    while elapsed < goal {
+        let current = read();
+        // The count is reloaded when it reaches zero.
+        elapsed += if current <= previous {
+            previous - current
+        } else {
+            previous + timer_count - current
+        };
+        previous = current;
+        core::hint::spin_loop();
     }
 }
 
@@ -127,6 +156,10 @@ lazy_static! {
 pub fn tick() {
     x86_64::instructions::interrupts::without_interrupts(|| GLOBAL_TIMER.lock().tick());
 }
+/// The number of ticks since the timer started. A tick is `1000 / TARGET_FREQUENCY` ms.
+pub fn current_tick() -> u64 {
+    x86_64::instructions::interrupts::without_interrupts(|| GLOBAL_TIMER.lock().get_tick())
+}
 pub fn register<T: 'static + Send>(delay_millis: u64, handle: TypedTaskHandle<T>, message: T) {
     x86_64::instructions::interrupts::without_interrupts(|| {
         GLOBAL_TIMER
@@ -0,0 +1,355 @@
+//! The virtio PCI transport and split virtqueues, which the virtio device drivers are built on.
+
+pub mod blk;
+pub mod net;
+
+use core::sync::atomic::{fence, Ordering};
+
+use alloc::vec::Vec;
+use x86_64::{PhysAddr, VirtAddr};
+
+use crate::{
+    dma::DmaMemory,
+    pci::{PCIFunction, CAPABILITY_VENDOR_SPECIFIC},
+    prelude::*,
+};
+
+const VENDOR_ID: u16 = 0x1AF4;
+
+const CONFIG_TYPE_COMMON: u8 = 1;
+const CONFIG_TYPE_NOTIFY: u8 = 2;
+const CONFIG_TYPE_ISR: u8 = 3;
+const CONFIG_TYPE_DEVICE: u8 = 4;
+
+// The common configuration structure
+const COMMON_DEVICE_FEATURE_SELECT: usize = 0x00;
+const COMMON_DEVICE_FEATURE: usize = 0x04;
+const COMMON_DRIVER_FEATURE_SELECT: usize = 0x08;
+const COMMON_DRIVER_FEATURE: usize = 0x0C;
+const COMMON_DEVICE_STATUS: usize = 0x14;
+const COMMON_CONFIG_GENERATION: usize = 0x15;
+const COMMON_QUEUE_SELECT: usize = 0x16;
+const COMMON_QUEUE_SIZE: usize = 0x18;
+const COMMON_QUEUE_MSIX_VECTOR: usize = 0x1A;
+const COMMON_QUEUE_ENABLE: usize = 0x1C;
+const COMMON_QUEUE_NOTIFY_OFF: usize = 0x1E;
+const COMMON_QUEUE_DESCRIPTOR: usize = 0x20;
+const COMMON_QUEUE_DRIVER: usize = 0x28;
+const COMMON_QUEUE_DEVICE: usize = 0x30;
+
+/// Written to a vector register for no interrupts, and read back when the device couldn't assign
+/// the vector.
+const NO_VECTOR: u16 = 0xFFFF;
+
+const STATUS_ACKNOWLEDGE: u8 = 1;
+const STATUS_DRIVER: u8 = 2;
+const STATUS_DRIVER_OK: u8 = 4;
+const STATUS_FEATURES_OK: u8 = 8;
+const STATUS_FAILED: u8 = 128;
+
+/// The device conforms to the virtio 1.0 spec or later, rather than the legacy interface.
+const FEATURE_VERSION_1: u64 = 1 << 32;
+
+/// A window of memory-mapped registers.
+#[derive(Copy, Clone, Debug)]
+struct Mmio {
+    base: VirtAddr,
+}
+
+impl Mmio {
+    fn read<T: Copy>(&self, offset: usize) -> T {
+        unsafe { core::ptr::read_volatile((self.base + offset).as_ptr()) }
+    }
+
+    fn write<T: Copy>(&self, offset: usize, value: T) {
+        unsafe { core::ptr::write_volatile((self.base + offset).as_mut_ptr(), value) }
+    }
+
+    /// Writes a 64-bit field as two 32-bit halves, which all devices accept.
+    fn write_u64(&self, offset: usize, value: u64) {
+        self.write(offset, value as u32);
+        self.write(offset + 4, (value >> 32) as u32);
+    }
+}
+
+/// The modern (virtio 1.0) PCI interface of a device, found through its vendor capabilities.
+pub struct Transport {
+    common: Mmio,
+    notify: Mmio,
+    notify_off_multiplier: u32,
+    device: Mmio,
+}
+
+impl Transport {
+    pub fn new(func: &PCIFunction) -> Result<Self> {
+        let (mut common, mut notify, mut isr, mut device) = (None, None, None, None);
+        let mut notify_off_multiplier = 0;
+        for header in func.capability_headers() {
+            if header.data().capability_id() != CAPABILITY_VENDOR_SPECIFIC {
+                continue;
+            }
+            let address = *header.capability_address();
+            let config_type = (header.data().capability() >> 8) as u8;
+            let bar = func.read_conf_register(address + 4) as u8;
+            let offset = func.read_conf_register(address + 8) as u64;
+            // Skip the types we don't know about, which may point to nonexistent BARs.
+            let slot = match config_type {
+                CONFIG_TYPE_COMMON => &mut common,
+                CONFIG_TYPE_NOTIFY => {
+                    notify_off_multiplier = func.read_conf_register(address + 16);
+                    &mut notify
+                }
+                CONFIG_TYPE_ISR => &mut isr,
+                CONFIG_TYPE_DEVICE => &mut device,
+                _ => continue,
+            };
+            // The spec says to use the first capability of each type.
+            if slot.is_none() {
+                *slot = Some(Mmio {
+                    base: func.map_memory_bar(bar as usize)? + offset,
+                });
+            }
+        }
+        let missing = Error::Whatever("The virtio device lacks a configuration structure");
+        let (common, notify, device) = match (common, notify, isr, device) {
+            (Some(common), Some(notify), Some(_), Some(device)) => (common, notify, device),
+            _ => return Err(missing),
+        };
+        // Enable the memory space and bus mastering, leaving the status bits as they are.
+        let command = func.read_conf_register(0x04);
+        func.write_conf_register(0x04, (command & 0xFFFF) | 0b110);
+        Ok(Self {
+            common,
+            notify,
+            notify_off_multiplier,
+            device,
+        })
+    }
+
+    /// Stops the device, after which it doesn't touch the memory it was given anymore.
+    pub fn reset(&self) {
+        self.common.write::<u8>(COMMON_DEVICE_STATUS, 0);
+        while self.common.read::<u8>(COMMON_DEVICE_STATUS) != 0 {
+            core::hint::spin_loop();
+        }
+    }
+
+    fn add_status(&self, status: u8) {
+        let current = self.common.read::<u8>(COMMON_DEVICE_STATUS);
+        self.common.write(COMMON_DEVICE_STATUS, current | status);
+    }
+
+    /// Resets the device and agrees on the features in `supported` that the device offers.
+    /// Returns the agreed features, or marks the device as failed if they don't agree.
+    pub fn negotiate(&self, supported: u64) -> Result<u64> {
+        let result = self.try_negotiate(supported);
+        if result.is_err() {
+            self.add_status(STATUS_FAILED);
+        }
+        result
+    }
+
+    fn try_negotiate(&self, supported: u64) -> Result<u64> {
+        self.reset();
+        self.add_status(STATUS_ACKNOWLEDGE);
+        self.add_status(STATUS_DRIVER);
+        let mut offered = 0;
+        for select in 0..2u32 {
+            self.common.write(COMMON_DEVICE_FEATURE_SELECT, select);
+            offered |= (self.common.read::<u32>(COMMON_DEVICE_FEATURE) as u64) << (32 * select);
+        }
+        if offered & FEATURE_VERSION_1 == 0 {
+            return Err(Error::Whatever(
+                "The virtio device doesn't support version 1",
+            ));
+        }
+        let features = offered & (supported | FEATURE_VERSION_1);
+        for select in 0..2u32 {
+            self.common.write(COMMON_DRIVER_FEATURE_SELECT, select);
+            self.common
+                .write(COMMON_DRIVER_FEATURE, (features >> (32 * select)) as u32);
+        }
+        self.add_status(STATUS_FEATURES_OK);
+        if self.common.read::<u8>(COMMON_DEVICE_STATUS) & STATUS_FEATURES_OK == 0 {
+            return Err(Error::Whatever("The virtio device rejected the features"));
+        }
+        Ok(features)
+    }
+
+    /// Sets up the queue `index` with at most `max_size` entries. If `msix_entry` is given, the
+    /// device interrupts with that MSI-X table entry when it uses buffers.
+    pub fn setup_queue(
+        &self,
+        index: u16,
+        max_size: u16,
+        msix_entry: Option<u16>,
+    ) -> Result<VirtQueue> {
+        self.common.write(COMMON_QUEUE_SELECT, index);
+        if let Some(entry) = msix_entry {
+            self.common.write(COMMON_QUEUE_MSIX_VECTOR, entry);
+            if self.common.read::<u16>(COMMON_QUEUE_MSIX_VECTOR) == NO_VECTOR {
+                return Err(Error::Whatever(
+                    "The virtio device rejected the MSI-X vector",
+                ));
+            }
+        }
+        let size = self.common.read::<u16>(COMMON_QUEUE_SIZE).min(max_size);
+        if size == 0 {
+            return Err(Error::Whatever("The virtqueue is not available"));
+        }
+        // The size has to be a power of 2.
+        let size: u16 = 1 << (15 - size.leading_zeros());
+        self.common.write(COMMON_QUEUE_SIZE, size);
+        let notify_off = self.common.read::<u16>(COMMON_QUEUE_NOTIFY_OFF);
+        let notify = self.notify.base + notify_off as u64 * self.notify_off_multiplier as u64;
+        let queue = VirtQueue::new(index, size, notify)?;
+        self.common.write_u64(
+            COMMON_QUEUE_DESCRIPTOR,
+            queue.memory.physical_address(0).as_u64(),
+        );
+        self.common.write_u64(
+            COMMON_QUEUE_DRIVER,
+            queue
+                .memory
+                .physical_address(queue.available_offset)
+                .as_u64(),
+        );
+        self.common.write_u64(
+            COMMON_QUEUE_DEVICE,
+            queue.memory.physical_address(queue.used_offset).as_u64(),
+        );
+        self.common.write::<u16>(COMMON_QUEUE_ENABLE, 1);
+        Ok(queue)
+    }
+
+    /// Tells the device that the driver is ready, after which it starts processing the queues.
+    pub fn finish_initialization(&self) {
+        self.add_status(STATUS_DRIVER_OK);
+    }
+
+    /// Reads a field of the device specific configuration, retrying while the device changes it.
+    pub fn read_device_config<T: Copy>(&self, offset: usize) -> T {
+        loop {
+            let generation = self.common.read::<u8>(COMMON_CONFIG_GENERATION);
+            let value = self.device.read(offset);
+            if self.common.read::<u8>(COMMON_CONFIG_GENERATION) == generation {
+                return value;
+            }
+        }
+    }
+}
+
+/// A part of a request in a virtqueue.
+#[derive(Copy, Clone, Debug)]
+pub struct Buffer {
+    pub address: PhysAddr,
+    pub length: u32,
+    /// Whether the device writes into the buffer rather than reading from it.
+    pub device_writable: bool,
+}
+
+const DESCRIPTOR_SIZE: usize = 16;
+const DESCRIPTOR_FLAG_NEXT: u16 = 1;
+const DESCRIPTOR_FLAG_WRITE: u16 = 2;
+
+/// A split virtqueue, in which the driver puts chains of descriptors in the available ring and
+/// the device returns them in the used ring.
+pub struct VirtQueue {
+    index: u16,
+    size: u16,
+    memory: DmaMemory,
+    available_offset: usize,
+    used_offset: usize,
+    notify: VirtAddr,
+    free_descriptors: Vec<u16>,
+    next_available: u16,
+    last_used: u16,
+}
+
+impl VirtQueue {
+    fn new(index: u16, size: u16, notify: VirtAddr) -> Result<Self> {
+        let entries = size as usize;
+        let available_offset = DESCRIPTOR_SIZE * entries;
+        // The used ring has to be aligned to 4 bytes.
+        let used_offset = (available_offset + 6 + 2 * entries).next_multiple_of(4);
+        let memory = DmaMemory::new(used_offset + 6 + 8 * entries)?;
+        Ok(Self {
+            index,
+            size,
+            memory,
+            available_offset,
+            used_offset,
+            notify,
+            free_descriptors: (0..size).rev().collect(),
+            next_available: 0,
+            last_used: 0,
+        })
+    }
+
+    /// Puts a request made of `buffers` in the available ring, and returns the ID to find it in
+    /// the used ring. The device doesn't see it until `notify` is called.
+    pub fn add(&mut self, buffers: &[Buffer]) -> Result<u16> {
+        if buffers.is_empty() || buffers.len() > self.free_descriptors.len() {
+            return Err(Error::Whatever("The virtqueue is full"));
+        }
+        let ids = self
+            .free_descriptors
+            .split_off(self.free_descriptors.len() - buffers.len());
+        for (i, buffer) in buffers.iter().enumerate() {
+            let offset = DESCRIPTOR_SIZE * ids[i] as usize;
+            let mut flags = 0;
+            if buffer.device_writable {
+                flags |= DESCRIPTOR_FLAG_WRITE;
+            }
+            let next = ids.get(i + 1).copied().unwrap_or(0);
+            if i + 1 < ids.len() {
+                flags |= DESCRIPTOR_FLAG_NEXT;
+            }
+            self.memory.write(offset, buffer.address.as_u64());
+            self.memory.write(offset + 8, buffer.length);
+            self.memory.write(offset + 12, flags);
+            self.memory.write(offset + 14, next);
+        }
+        let slot = (self.next_available % self.size) as usize;
+        self.memory
+            .write(self.available_offset + 4 + 2 * slot, ids[0]);
+        // The device must see the descriptors before the new index.
+        fence(Ordering::SeqCst);
+        self.next_available = self.next_available.wrapping_add(1);
+        self.memory
+            .write(self.available_offset + 2, self.next_available);
+        Ok(ids[0])
+    }
+
+    /// Tells the device that there are new requests.
+    pub fn notify(&self) {
+        fence(Ordering::SeqCst);
+        unsafe { core::ptr::write_volatile(self.notify.as_mut_ptr(), self.index) }
+    }
+
+    /// Takes a request the device has finished, and returns its ID and the number of bytes the
+    /// device wrote.
+    pub fn pop_used(&mut self) -> Option<(u16, u32)> {
+        let used_index = self.memory.read::<u16>(self.used_offset + 2);
+        if used_index == self.last_used {
+            return None;
+        }
+        // Read the element only after seeing the index.
+        fence(Ordering::SeqCst);
+        let element = self.used_offset + 4 + 8 * (self.last_used % self.size) as usize;
+        let id = self.memory.read::<u32>(element) as u16;
+        let length = self.memory.read::<u32>(element + 4);
+        self.last_used = self.last_used.wrapping_add(1);
+        // Free the whole chain.
+        let mut descriptor = id;
+        loop {
+            self.free_descriptors.push(descriptor);
+            let offset = DESCRIPTOR_SIZE * descriptor as usize;
+            if self.memory.read::<u16>(offset + 12) & DESCRIPTOR_FLAG_NEXT == 0 {
+                break;
+            }
+            descriptor = self.memory.read(offset + 14);
+        }
+        Some((id, length))
+    }
+}
@@ -0,0 +1,302 @@
+//! virtio-blk, a disk provided by the hypervisor.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use alloc::{collections::VecDeque, sync::Arc};
+use spinning_top::Spinlock;
+use x86_64::instructions::interrupts;
+
+use super::{Buffer, Transport, VirtQueue, VENDOR_ID};
+use crate::{
+    block::{self, BlockDevice},
+    dma::DmaMemory,
+    driver::{DeviceMatch, Driver},
+    pci::PCIFunction,
+    prelude::*,
+    task::{self, TaskHandle},
+};
+
+const FEATURE_READ_ONLY: u64 = 1 << 5;
+
+const CONFIG_CAPACITY: usize = 0x00;
+
+const REQUEST_IN: u32 = 0;
+const REQUEST_OUT: u32 = 1;
+const REQUEST_HEADER_SIZE: usize = 16;
+const STATUS_OK: u8 = 0;
+
+/// The device always counts in 512-byte sectors, whatever its physical block size is.
+const SECTOR_SIZE: usize = 512;
+const QUEUE_SIZE: u16 = 16;
+/// The size of the bounce buffer, which bounds the size of a single request.
+const BUFFER_SIZE: usize = 64 * 1024;
+/// How many times the used ring is polled before giving up on a request, which is some seconds.
+const MAX_POLLS: usize = 100_000_000;
+
+pub const DRIVER: Driver = Driver {
+    name: "virtio-blk",
+    matches: &[
+        // The transitional device and the modern one.
+        DeviceMatch::Id {
+            vendor_id: VENDOR_ID,
+            device_id: 0x1001,
+        },
+        DeviceMatch::Id {
+            vendor_id: VENDOR_ID,
+            device_id: 0x1042,
+        },
+    ],
+    probe: |_| true,
+    attach: initialize,
+};
+
+/// Sets up the virtio-blk device `func` and registers it as a block device.
+pub fn initialize(func: &PCIFunction) -> Result<()> {
+    let transport = Transport::new(func)?;
+    let disk = Disk::new(transport)?;
+    let (capacity, read_only) = (disk.capacity, disk.read_only);
+    let name = block::register("virtio", Arc::new(disk));
+    log::info!(
+        "{}: virtio-blk ({} sectors{})",
+        name,
+        capacity,
+        if read_only { ", read-only" } else { "" }
+    );
+    Ok(())
+}
+
+/// What the driver shares with the device, which is used by one request at a time.
+struct Requests {
+    queue: VirtQueue,
+    /// The request header, followed by the status byte.
+    header: DmaMemory,
+    buffer: DmaMemory,
+}
+
+/// Who runs a request, so that the others sleep rather than spin while it waits for the device.
+#[derive(Default)]
+struct RequestQueue {
+    busy: bool,
+    waiters: VecDeque<TaskHandle>,
+}
+
+/// The right to run a request, which is passed to the next waiter when dropped.
+struct RequestClaim<'a> {
+    disk: &'a Disk,
+}
+
+impl Drop for RequestClaim<'_> {
+    fn drop(&mut self) {
+        let next = interrupts::without_interrupts(|| {
+            let mut queue = self.disk.queue.lock();
+            queue.busy = false;
+            queue.waiters.pop_front()
+        });
+        if let Some(next) = next {
+            next.awake();
+        }
+    }
+}
+
+/// The data moved by a request.
+enum Transfer<'a> {
+    FromDevice(&'a mut [u8]),
+    ToDevice(&'a [u8]),
+}
+
+pub struct Disk {
+    transport: Transport,
+    capacity: u64,
+    read_only: bool,
+    requests: Spinlock<Requests>,
+    queue: Spinlock<RequestQueue>,
+    /// Set when the device is reset after not answering, which leaves it unusable.
+    failed: AtomicBool,
+}
+
+impl Disk {
+    fn new(transport: Transport) -> Result<Self> {
+        let features = transport.negotiate(FEATURE_READ_ONLY)?;
+        let requests = transport
+            .setup_queue(0, QUEUE_SIZE, None)
+            .and_then(|queue| {
+                Ok(Requests {
+                    queue,
+                    header: DmaMemory::new(REQUEST_HEADER_SIZE + 1)?,
+                    buffer: DmaMemory::new(BUFFER_SIZE)?,
+                })
+            });
+        let requests = match requests {
+            Ok(requests) => requests,
+            Err(e) => {
+                transport.reset();
+                return Err(e);
+            }
+        };
+        let capacity = transport.read_device_config(CONFIG_CAPACITY);
+        transport.finish_initialization();
+        Ok(Self {
+            transport,
+            capacity,
+            read_only: features & FEATURE_READ_ONLY != 0,
+            requests: Spinlock::new(requests),
+            queue: Spinlock::new(RequestQueue::default()),
+            failed: AtomicBool::new(false),
+        })
+    }
+
+    /// Waits until no other task runs a request.
+    fn claim(&self) -> RequestClaim<'_> {
+        let try_claim = |waiter: Option<&TaskHandle>| {
+            interrupts::without_interrupts(|| {
+                let mut queue = self.queue.lock();
+                if !queue.busy {
+                    queue.busy = true;
+                    return true;
+                }
+                if let Some(waiter) = waiter {
+                    if !queue.waiters.iter().any(|w| w.id() == waiter.id()) {
+                        queue.waiters.push_back(waiter.clone());
+                    }
+                }
+                false
+            })
+        };
+        // The disk is identified before there may be other tasks, so only the contended case
+        // needs the current task.
+        if !try_claim(None) {
+            let current = task::current_task();
+            loop {
+                let state = current.load_state();
+                if try_claim(Some(&current)) {
+                    break;
+                }
+                current.try_compare_and_sleep(state);
+            }
+        }
+        RequestClaim { disk: self }
+    }
+
+    /// Polls the used ring until the request `id` comes back.
+    fn wait_for(&self, id: u16) -> Result<()> {
+        for _ in 0..MAX_POLLS {
+            match self.requests.lock().queue.pop_used() {
+                Some((used, _)) if used == id => return Ok(()),
+                Some(_) => log::warn!("virtio-blk returned an unknown request"),
+                None => core::hint::spin_loop(),
+            }
+        }
+        // The request may still be in flight, so stop the device before the memory is reused.
+        self.failed.store(true, Ordering::SeqCst);
+        self.transport.reset();
+        Err(Error::Whatever("The disk didn't respond"))
+    }
+
+    /// Runs a request of `kind` on the sectors from `sector`, and waits for its completion.
+    fn request(&self, kind: u32, sector: u64, transfer: Transfer) -> Result<()> {
+        let (length, device_writable) = match &transfer {
+            Transfer::FromDevice(buffer) => (buffer.len(), true),
+            Transfer::ToDevice(data) => (data.len(), false),
+        };
+        assert!(length > 0 && length <= BUFFER_SIZE);
+        let _claim = self.claim();
+        if self.failed.load(Ordering::SeqCst) {
+            return Err(Error::Whatever("The disk was reset after not responding"));
+        }
+        let mut requests = self.requests.lock();
+        let Requests {
+            queue,
+            header,
+            buffer,
+        } = &mut *requests;
+        if let Transfer::ToDevice(data) = &transfer {
+            buffer.as_mut_slice()[..length].copy_from_slice(data);
+        }
+        header.write(0, kind);
+        header.write(4, 0u32);
+        header.write(8, sector);
+        header.write(REQUEST_HEADER_SIZE, u8::MAX);
+        let id = queue.add(&[
+            Buffer {
+                address: header.physical_address(0),
+                length: REQUEST_HEADER_SIZE as u32,
+                device_writable: false,
+            },
+            Buffer {
+                address: buffer.physical_address(0),
+                length: length as u32,
+                device_writable,
+            },
+            Buffer {
+                address: header.physical_address(REQUEST_HEADER_SIZE),
+                length: 1,
+                device_writable: true,
+            },
+        ])?;
+        queue.notify();
+        // There are no interrupts without MSI-X, so poll the used ring. The claim keeps the others
+        // away from the buffers, so the lock isn't held while waiting.
+        drop(requests);
+        self.wait_for(id)?;
+        let mut requests = self.requests.lock();
+        let Requests { header, buffer, .. } = &mut *requests;
+        if header.read::<u8>(REQUEST_HEADER_SIZE) != STATUS_OK {
+            return Err(Error::Whatever("The disk reported an error"));
+        }
+        if let Transfer::FromDevice(destination) = transfer {
+            destination.copy_from_slice(&buffer.as_mut_slice()[..length]);
+        }
+        Ok(())
+    }
+
+    /// Checks the arguments of a transfer of `length` bytes from `lba`.
+    fn check_range(&self, lba: u64, length: usize) -> Result<()> {
+        if length % SECTOR_SIZE != 0 {
+            return Err(Error::Whatever(
+                "The buffer is not a multiple of the block size",
+            ));
+        }
+        lba.checked_add((length / SECTOR_SIZE) as u64)
+            .filter(|&end| end <= self.capacity)
+            .map(drop)
+            .ok_or(Error::Whatever("Accessing beyond the end of the device"))
+    }
+}
+
+impl BlockDevice for Disk {
+    fn block_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+
+    fn block_count(&self) -> u64 {
+        self.capacity
+    }
+
+    fn read_blocks(&self, lba: u64, buffer: &mut [u8]) -> Result<()> {
+        self.check_range(lba, buffer.len())?;
+        for (i, chunk) in buffer.chunks_mut(BUFFER_SIZE).enumerate() {
+            let sector = lba + (i * BUFFER_SIZE / SECTOR_SIZE) as u64;
+            self.request(REQUEST_IN, sector, Transfer::FromDevice(chunk))?;
+        }
+        Ok(())
+    }
+
+    fn write_blocks(&self, lba: u64, data: &[u8]) -> Result<()> {
+        if self.read_only {
+            return Err(Error::Whatever("The device is read-only"));
+        }
+        self.check_range(lba, data.len())?;
+        for (i, chunk) in data.chunks(BUFFER_SIZE).enumerate() {
+            let sector = lba + (i * BUFFER_SIZE / SECTOR_SIZE) as u64;
+            self.request(REQUEST_OUT, sector, Transfer::ToDevice(chunk))?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Disk {
+    fn drop(&mut self) {
+        // Stop the device before the memory it uses is freed.
+        self.transport.reset();
+    }
+}
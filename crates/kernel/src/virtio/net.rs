@@ -0,0 +1,242 @@
+//! virtio-net, a network card provided by the hypervisor.
+
+use alloc::{collections::BTreeMap, sync::Arc, vec::Vec};
+use spinning_top::Spinlock;
+
+use super::{Buffer, Transport, VirtQueue, VENDOR_ID};
+use crate::{
+    dma::DmaMemory,
+    driver::{DeviceMatch, Driver},
+    interrupts::InterruptHandler,
+    msi::{configure_msix_fixed_destination, DeliveryMode, TriggerMode, MSIX},
+    net::{self, MacAddress, NetworkDevice},
+    pci::PCIFunction,
+    prelude::*,
+};
+
+const FEATURE_MAC: u64 = 1 << 5;
+
+const CONFIG_MAC: usize = 0x00;
+
+const QUEUE_RECEIVE: u16 = 0;
+const QUEUE_TRANSMIT: u16 = 1;
+const QUEUE_SIZE: u16 = 32;
+/// The device interrupts when packets arrive and when it has sent some, with these MSI-X entries.
+const MSIX_ENTRY_RECEIVE: u16 = 0;
+const MSIX_ENTRY_TRANSMIT: u16 = 1;
+/// Every packet starts with this header, which is all zeros as we don't use any offloading.
+const HEADER_SIZE: usize = 12;
+/// Large enough for the header and a full Ethernet frame.
+const SLOT_SIZE: usize = 2048;
+
+pub const DRIVER: Driver = Driver {
+    name: "virtio-net",
+    matches: &[
+        // The transitional device and the modern one.
+        DeviceMatch::Id {
+            vendor_id: VENDOR_ID,
+            device_id: 0x1000,
+        },
+        DeviceMatch::Id {
+            vendor_id: VENDOR_ID,
+            device_id: 0x1041,
+        },
+    ],
+    probe: |_| true,
+    attach: initialize,
+};
+
+/// Sets up the virtio-net device `func` and brings up the network on it.
+pub fn initialize(func: &PCIFunction) -> Result<()> {
+    let transport = Transport::new(func)?;
+    let msix = configure_msix_fixed_destination(
+        func,
+        TriggerMode::Edge,
+        DeliveryMode::Fixed,
+        "virtio-net",
+        &[
+            InterruptHandler::Function(net::fire_interrupt),
+            InterruptHandler::Function(net::fire_interrupt),
+        ],
+    );
+    // virtio devices interrupt only with MSI-X or the legacy INTx, so the network is polled
+    // without MSI-X.
+    let msix = match msix {
+        Ok(msix) => Some(msix),
+        Err(e) => {
+            log::warn!("Polling the network card as MSI-X is unavailable: {:?}", e);
+            None
+        }
+    };
+    let has_interrupts = msix.is_some();
+    let nic = Nic::new(transport, msix)?;
+    net::initialize(Arc::new(nic), has_interrupts)
+}
+
+/// Packet buffers shared with the device, split into slots of `SLOT_SIZE` bytes.
+struct Slots {
+    memory: DmaMemory,
+    /// Which slot each request in the queue uses.
+    in_queue: BTreeMap<u16, usize>,
+}
+
+impl Slots {
+    fn new(count: usize) -> Result<Self> {
+        Ok(Self {
+            memory: DmaMemory::new(count * SLOT_SIZE)?,
+            in_queue: BTreeMap::new(),
+        })
+    }
+
+    fn buffer(&self, slot: usize, length: usize, device_writable: bool) -> Buffer {
+        Buffer {
+            address: self.memory.physical_address(slot * SLOT_SIZE),
+            length: length as u32,
+            device_writable,
+        }
+    }
+
+    fn data(&mut self, slot: usize) -> &mut [u8] {
+        &mut self.memory.as_mut_slice()[slot * SLOT_SIZE..][..SLOT_SIZE]
+    }
+}
+
+struct Queues {
+    receive: VirtQueue,
+    receive_slots: Slots,
+    transmit: VirtQueue,
+    transmit_slots: Slots,
+    free_transmit_slots: Vec<usize>,
+}
+
+impl Queues {
+    /// Gives the slot `slot` to the device to receive a packet into.
+    fn post_receive_buffer(&mut self, slot: usize) -> Result<()> {
+        let buffer = self.receive_slots.buffer(slot, SLOT_SIZE, true);
+        let id = self.receive.add(&[buffer])?;
+        self.receive_slots.in_queue.insert(id, slot);
+        Ok(())
+    }
+
+    /// Takes back the slots of the packets the device has sent.
+    fn reclaim_transmit_slots(&mut self) {
+        while let Some((id, _)) = self.transmit.pop_used() {
+            if let Some(slot) = self.transmit_slots.in_queue.remove(&id) {
+                self.free_transmit_slots.push(slot);
+            }
+        }
+    }
+}
+
+pub struct Nic {
+    transport: Transport,
+    mac: MacAddress,
+    queues: Spinlock<Queues>,
+    /// Keeps the interrupt vector while the device may use it.
+    _msix: Option<MSIX>,
+}
+
+impl Nic {
+    fn new(transport: Transport, msix: Option<MSIX>) -> Result<Self> {
+        let queues = Self::setup_queues(&transport, msix.is_some());
+        let queues = match queues {
+            Ok(queues) => queues,
+            Err(e) => {
+                transport.reset();
+                return Err(e);
+            }
+        };
+        let mut mac = MacAddress::default();
+        for (i, byte) in mac.0.iter_mut().enumerate() {
+            *byte = transport.read_device_config(CONFIG_MAC + i);
+        }
+        transport.finish_initialization();
+        queues.receive.notify();
+        Ok(Self {
+            transport,
+            mac,
+            queues: Spinlock::new(queues),
+            _msix: msix,
+        })
+    }
+
+    fn setup_queues(transport: &Transport, has_msix: bool) -> Result<Queues> {
+        let features = transport.negotiate(FEATURE_MAC)?;
+        if features & FEATURE_MAC == 0 {
+            return Err(Error::Whatever("The network card has no MAC address"));
+        }
+        let entry = |entry| has_msix.then_some(entry);
+        let receive =
+            transport.setup_queue(QUEUE_RECEIVE, QUEUE_SIZE, entry(MSIX_ENTRY_RECEIVE))?;
+        let transmit =
+            transport.setup_queue(QUEUE_TRANSMIT, QUEUE_SIZE, entry(MSIX_ENTRY_TRANSMIT))?;
+        let mut queues = Queues {
+            receive_slots: Slots::new(receive.size as usize)?,
+            receive,
+            transmit_slots: Slots::new(transmit.size as usize)?,
+            free_transmit_slots: (0..transmit.size as usize).collect(),
+            transmit,
+        };
+        for slot in 0..queues.receive.size as usize {
+            queues.post_receive_buffer(slot)?;
+        }
+        Ok(queues)
+    }
+}
+
+impl NetworkDevice for Nic {
+    fn mac_address(&self) -> MacAddress {
+        self.mac
+    }
+
+    fn transmit(&self, frame: &[u8]) -> Result<()> {
+        let length = HEADER_SIZE + frame.len();
+        if length > SLOT_SIZE {
+            return Err(Error::Whatever("The frame is too large"));
+        }
+        let mut queues = self.queues.lock();
+        queues.reclaim_transmit_slots();
+        let Queues {
+            transmit,
+            transmit_slots,
+            free_transmit_slots,
+            ..
+        } = &mut *queues;
+        let slot = free_transmit_slots
+            .pop()
+            .ok_or(Error::Whatever("The transmit queue is full"))?;
+        let data = transmit_slots.data(slot);
+        data[..HEADER_SIZE].fill(0);
+        data[HEADER_SIZE..length].copy_from_slice(frame);
+        let id = transmit.add(&[transmit_slots.buffer(slot, length, false)])?;
+        transmit_slots.in_queue.insert(id, slot);
+        transmit.notify();
+        Ok(())
+    }
+
+    fn can_transmit(&self) -> bool {
+        let mut queues = self.queues.lock();
+        queues.reclaim_transmit_slots();
+        !queues.free_transmit_slots.is_empty()
+    }
+
+    fn receive(&self) -> Option<Vec<u8>> {
+        let mut queues = self.queues.lock();
+        let (id, length) = queues.receive.pop_used()?;
+        let slot = queues.receive_slots.in_queue.remove(&id)?;
+        let length = (length as usize).clamp(HEADER_SIZE, SLOT_SIZE);
+        let frame = queues.receive_slots.data(slot)[HEADER_SIZE..length].to_vec();
+        if let Err(e) = queues.post_receive_buffer(slot) {
+            log::warn!("Failed to reuse a receive buffer: {:?}", e);
+        }
+        queues.receive.notify();
+        Some(frame)
+    }
+}
+
+impl Drop for Nic {
+    fn drop(&mut self) {
+        // Stop the device before the memory it uses is freed.
+        self.transport.reset();
+    }
+}
@@ -1,56 +1,116 @@
+use alloc::vec::Vec;
 use mikanos_usb;
 use spinning_top::Spinlock;
+use x86_64::structures::paging::{frame::PhysFrameRange, PageTableFlags};
 
-use crate::{gui::mouse, keyboard, pci};
+use crate::{
+    driver::{DeviceMatch, Driver},
+    events,
+    gui::mouse,
+    interrupts::{self, InterruptHandler},
+    keyboard, memory_manager,
+    msi::{configure_msi_fixed_destination, disable_msi, DeliveryMode, TriggerMode},
+    paging, pci,
+    prelude::*,
+};
 
 lazy_static! {
-    static ref XHC: Spinlock<Option<&'static mut mikanos_usb::xhci::Controller>> =
-        Spinlock::new(Option::None);
+    static ref CONTROLLERS: Spinlock<Vec<&'static mut mikanos_usb::xhci::Controller>> =
+        Spinlock::new(Vec::new());
 }
+/// The memory pool of the USB driver, which is a global of the driver shared by all the
+/// controllers. The lock is held while a controller starts, so only one takes from it at a time.
+static MEMORY_POOL: Spinlock<Option<PhysFrameRange>> = Spinlock::new(None);
+const MEMORY_POOL_FRAMES: usize = 64;
 
-/// Assumes the given func is a xHC, or panic.
-pub fn initialize(func: &pci::PCIFunction) {
-    XHC.lock().get_or_insert_with(|| {
-        assert!(matches!(
-            func.class(),
-            pci::PCIClass::SerialBusController(pci::SerialBusSubclass::USBController(
-                pci::USBProgramInterface::XHCI
-            ))
-        ));
-        let bars = func.bars();
-        log::trace!("bars: {:?}", bars);
-        let bar = bars[0] as u64;
-        let mmio_base = if bar & 4 == 0 {
-            bar
-        } else {
-            let upper = bars[1] as u64;
-            (upper << 32) | bar
-        };
-        let mmio_base = mmio_base & !0xF;
-        log::trace!("mmio base: {:016x}", mmio_base);
-
-        const BUFFER_LEN: usize = 4096 * 32;
-        static mut BUFFER: [u8; BUFFER_LEN] = [0; BUFFER_LEN];
-        unsafe {
-            mikanos_usb::set_memory_pool(BUFFER.as_mut_ptr() as u64, BUFFER.len());
+pub const DRIVER: Driver = Driver {
+    name: "xhci",
+    matches: &[DeviceMatch::Class(pci::PCIClass::SerialBusController(
+        pci::SerialBusSubclass::USBController(pci::USBProgramInterface::XHCI),
+    ))],
+    probe: |_| true,
+    attach: initialize,
+};
+
+/// Sets up the xHC `func` and the USB devices connected to it.
+pub fn initialize(func: &pci::PCIFunction) -> Result<()> {
+    log::trace!("bars: {:?}", func.regions());
+    let mmio_base = func.map_memory_bar(0)?.as_u64();
+
+    let vector =
+        interrupts::allocate_vector("xhci", InterruptHandler::Function(events::fire_xhci))?;
+    if let Err(e) =
+        configure_msi_fixed_destination(func, TriggerMode::Level, DeliveryMode::Fixed, vector, 0)
+    {
+        interrupts::free_vectors(&[vector]);
+        return Err(e);
+    }
+    let result = start(mmio_base);
+    if result.is_err() {
+        disable_msi(func);
+        interrupts::free_vectors(&[vector]);
+    }
+    result
+}
+
+fn start(mmio_base: u64) -> Result<()> {
+    let mut memory_pool = MEMORY_POOL.lock();
+    let pool = match *memory_pool {
+        Some(pool) => pool,
+        None => *memory_pool.insert(allocate_memory_pool()?),
+    };
+
+    let xhc = unsafe { mikanos_usb::xhci::Controller::new(mmio_base) };
+    xhc.init();
+    if xhc.run().is_err() {
+        // The driver never gives the memory back, but nothing else has taken from the pool if
+        // no controller is running.
+        if CONTROLLERS.lock().is_empty() {
+            unsafe { reset_memory_pool(pool) };
         }
+        return Err(Error::Whatever("Failed to initialize xhc"));
+    }
+    mikanos_usb::HidMouseDriver::set_default_observer(mouse::observe_cursor_move);
+    mikanos_usb::HidKeyboardDriver::set_default_observer(keyboard::observe_keyboard_event);
+    xhc.configure_connected_ports();
+    drop(memory_pool);
+    // The events are handled once the controller is ready, including the ones that came while
+    // it was being set up.
+    CONTROLLERS.lock().push(xhc);
+    events::fire_xhci();
+    Ok(())
+}
+
+/// Allocates the memory pool and gives it to the driver.
+fn allocate_memory_pool() -> Result<PhysFrameRange> {
+    let pool = memory_manager::with_memory_manager(|mm| mm.allocate(MEMORY_POOL_FRAMES)).ok_or(
+        Error::Whatever("Unable to allocate the memory pool for xhci"),
+    )?;
+    // The driver hands the addresses in the memory pool to the controller as is, so the pool
+    // has to be mapped at the same virtual address as its physical address.
+    unsafe {
+        paging::identity_map(pool, PageTableFlags::WRITABLE)?;
+        reset_memory_pool(pool);
+    }
+    Ok(pool)
+}
 
-        let xhc = unsafe { mikanos_usb::xhci::Controller::new(mmio_base) };
-        xhc.init();
-        xhc.run().map_err(|_| "Failed to initialize xhc").unwrap();
-        mikanos_usb::HidMouseDriver::set_default_observer(mouse::observe_cursor_move);
-        mikanos_usb::HidKeyboardDriver::set_default_observer(keyboard::observe_keyboard_event);
-        xhc.configure_connected_ports();
-        xhc
-    });
+/// Clears the pool and makes the driver allocate from its start.
+///
+/// # Safety
+/// Nothing the driver has allocated from the pool may be in use.
+unsafe fn reset_memory_pool(pool: PhysFrameRange) {
+    let start = pool.start.start_address().as_u64();
+    core::ptr::write_bytes(start as *mut u8, 0, MEMORY_POOL_FRAMES * 4096);
+    mikanos_usb::set_memory_pool(start, MEMORY_POOL_FRAMES * 4096);
 }
 
 pub(crate) fn handle_events() {
-    let mut xhc = XHC.lock();
-    let xhc = xhc.as_mut().unwrap();
-    while xhc.has_event() {
-        if let Err(e) = xhc.process_event() {
-            log::error!("Something went wrong while processing xhc event: {}", e.0);
+    for xhc in CONTROLLERS.lock().iter_mut() {
+        while xhc.has_event() {
+            if let Err(e) = xhc.process_event() {
+                log::error!("Something went wrong while processing xhc event: {}", e.0);
+            }
         }
     }
 }